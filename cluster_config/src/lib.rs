@@ -0,0 +1,237 @@
+//! Describes a cluster in one TOML file that the launcher and every server read, so they agree on its members:
+//!
+//! ```toml
+//! [raft]
+//! leader_heartbeat_ms = 50
+//! min_election_timeout_ms = 150
+//! max_election_timeout_ms = 300
+//!
+//! [[servers]]
+//! id = 0
+//! address = "127.0.0.1:5000"
+//! data_dir = "target/wal-logs/0"
+//! metrics_port = 6000
+//! ```
+//!
+//! Relative data directories are relative to the directory of the file. The `[raft]` table is optional, its fields
+//! default to the values above.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use raft_consensus::RaftConfig;
+use serde::Deserialize;
+
+/// The cluster file could not be read or does not describe a valid cluster
+#[derive(Debug)]
+pub enum ClusterConfigError {
+    Read { path: PathBuf, source: io::Error },
+    Parse(String),
+    Invalid(String),
+}
+impl fmt::Display for ClusterConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClusterConfigError::Read { path, source } => {
+                write!(f, "Could not read cluster config {:?}: {}", path, source)
+            }
+            ClusterConfigError::Parse(message) => {
+                write!(f, "Could not parse cluster config: {}", message)
+            }
+            ClusterConfigError::Invalid(message) => {
+                write!(f, "Invalid cluster config: {}", message)
+            }
+        }
+    }
+}
+impl std::error::Error for ClusterConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClusterConfigError::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// The `--cluster-members` argument could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidClusterMembers(pub String);
+impl fmt::Display for InvalidClusterMembers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid cluster members: {}", self.0)
+    }
+}
+impl std::error::Error for InvalidClusterMembers {}
+
+/// Parses a comma delimited list of server IDs and addresses, the `--cluster-members` format the servers and the
+/// client take the cluster's members in, i.e. `1,127.0.0.1:123,2,127.0.0.1:234,3,127.0.0.1:345`. The addresses are
+/// not checked, the client also takes host names.
+pub fn parse_cluster_members(
+    cluster_members: &str,
+) -> Result<BTreeMap<u64, String>, InvalidClusterMembers> {
+    let mut cluster_members = cluster_members.split(',');
+    let mut cluster = BTreeMap::new();
+    while let Some(id) = cluster_members.next() {
+        let id: u64 = id
+            .trim()
+            .parse()
+            .map_err(|_| InvalidClusterMembers(format!("{:?} is not a server ID", id)))?;
+        let address = cluster_members
+            .next()
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .ok_or_else(|| InvalidClusterMembers(format!("No address for server {}", id)))?;
+        if cluster.insert(id, address.to_string()).is_some() {
+            return Err(InvalidClusterMembers(format!(
+                "Server {} is listed more than once",
+                id
+            )));
+        }
+    }
+    if cluster.is_empty() {
+        return Err(InvalidClusterMembers("No servers given".to_string()));
+    }
+    Ok(cluster)
+}
+
+/// Timing of every server's Raft node, see `RaftConfig`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RaftSettings {
+    pub leader_heartbeat_ms: u64,
+    pub min_election_timeout_ms: u32,
+    pub max_election_timeout_ms: u32,
+}
+impl Default for RaftSettings {
+    fn default() -> Self {
+        RaftSettings {
+            leader_heartbeat_ms: 50,
+            min_election_timeout_ms: 150,
+            max_election_timeout_ms: 300,
+        }
+    }
+}
+impl RaftSettings {
+    pub fn raft_config(&self) -> RaftConfig {
+        RaftConfig {
+            leader_heartbeat_interval: Duration::from_millis(self.leader_heartbeat_ms),
+            min_election_timeout_ms: self.min_election_timeout_ms,
+            max_election_timeout_ms: self.max_election_timeout_ms,
+        }
+    }
+}
+
+/// A member of the cluster
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub id: u64,
+    /// Address the other servers and clients connect to, the server listens on its port
+    pub address: SocketAddr,
+    /// Directory to store the raft log, term, vote and snapshots of the store in
+    pub data_dir: PathBuf,
+    /// Port to serve prometheus metrics on at `/metrics`, metrics are not served if this is not set
+    #[serde(default)]
+    pub metrics_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    #[serde(default)]
+    pub raft: RaftSettings,
+    pub servers: Vec<ServerConfig>,
+}
+impl ClusterConfig {
+    pub fn load(path: &Path) -> Result<Self, ClusterConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ClusterConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        ClusterConfig::parse(&text, base_dir)
+    }
+
+    /// Parses and validates a cluster file, relative data directories are resolved against `base_dir`
+    pub fn parse(text: &str, base_dir: &Path) -> Result<Self, ClusterConfigError> {
+        let mut config: ClusterConfig =
+            toml::from_str(text).map_err(|e| ClusterConfigError::Parse(e.to_string()))?;
+        for server in &mut config.servers {
+            server.data_dir = base_dir.join(&server.data_dir);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ClusterConfigError> {
+        let invalid = |message: String| Err(ClusterConfigError::Invalid(message));
+        let raft = &self.raft;
+        if raft.min_election_timeout_ms >= raft.max_election_timeout_ms {
+            return invalid(format!(
+                "min_election_timeout_ms ({}) must be less than max_election_timeout_ms ({})",
+                raft.min_election_timeout_ms, raft.max_election_timeout_ms
+            ));
+        }
+        // Followers would start elections while the leader is still up
+        if raft.leader_heartbeat_ms == 0
+            || raft.leader_heartbeat_ms >= raft.min_election_timeout_ms.into()
+        {
+            return invalid(format!(
+                "leader_heartbeat_ms ({}) must be more than 0 and less than min_election_timeout_ms ({})",
+                raft.leader_heartbeat_ms, raft.min_election_timeout_ms
+            ));
+        }
+        if self.servers.is_empty() {
+            return invalid("No servers".to_string());
+        }
+
+        let mut ids = HashSet::new();
+        let mut ports = HashMap::new();
+        let mut data_dirs = HashMap::new();
+        for server in &self.servers {
+            if !ids.insert(server.id) {
+                return invalid(format!("Server ID {} is used twice", server.id));
+            }
+            let server_ports = [Some(server.address.port()), server.metrics_port];
+            for port in server_ports.into_iter().flatten() {
+                if let Some(other) = ports.insert(port, server.id) {
+                    return invalid(format!(
+                        "Port {} is used by servers {} and {}",
+                        port, other, server.id
+                    ));
+                }
+            }
+            if let Some(other) = data_dirs.insert(&server.data_dir, server.id) {
+                return invalid(format!(
+                    "Data directory {:?} is used by servers {} and {}",
+                    server.data_dir, other, server.id
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn server(&self, id: u64) -> Option<&ServerConfig> {
+        self.servers.iter().find(|server| server.id == id)
+    }
+
+    pub fn addresses(&self) -> HashMap<u64, SocketAddr> {
+        self.servers
+            .iter()
+            .map(|server| (server.id, server.address))
+            .collect()
+    }
+
+    /// The members in the `--cluster-members` format, i.e. `0,127.0.0.1:5000,1,127.0.0.1:5001`, see
+    /// `parse_cluster_members`
+    pub fn cluster_members(&self) -> String {
+        self.servers
+            .iter()
+            .map(|server| format!("{},{}", server.id, server.address))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
@@ -0,0 +1,181 @@
+use std::path::Path;
+use std::time::Duration;
+
+use cluster_config::{
+    parse_cluster_members, ClusterConfig, ClusterConfigError, InvalidClusterMembers,
+};
+
+const CLUSTER: &str = r#"
+[raft]
+leader_heartbeat_ms = 20
+min_election_timeout_ms = 100
+max_election_timeout_ms = 200
+
+[[servers]]
+id = 0
+address = "127.0.0.1:5000"
+data_dir = "wal-logs/0"
+metrics_port = 6000
+
+[[servers]]
+id = 1
+address = "127.0.0.1:5001"
+data_dir = "/var/lib/raft/1"
+"#;
+
+fn parse(text: &str) -> Result<ClusterConfig, ClusterConfigError> {
+    ClusterConfig::parse(text, Path::new("/etc/cluster"))
+}
+
+fn assert_invalid(text: &str, expected_message: &str) {
+    match parse(text) {
+        Err(ClusterConfigError::Invalid(message)) => assert!(
+            message.contains(expected_message),
+            "{:?} does not contain {:?}",
+            message,
+            expected_message
+        ),
+        other => panic!("Expected invalid config, got {:?}", other),
+    }
+}
+
+#[test]
+fn parses_servers_and_raft_settings() {
+    let config = parse(CLUSTER).unwrap();
+
+    let raft_config = config.raft.raft_config();
+    assert_eq!(
+        raft_config.leader_heartbeat_interval,
+        Duration::from_millis(20)
+    );
+    assert_eq!(raft_config.min_election_timeout_ms, 100);
+    assert_eq!(raft_config.max_election_timeout_ms, 200);
+
+    assert_eq!(config.servers.len(), 2);
+    let server = config.server(0).unwrap();
+    assert_eq!(server.address, "127.0.0.1:5000".parse().unwrap());
+    assert_eq!(server.data_dir, Path::new("/etc/cluster/wal-logs/0"));
+    assert_eq!(server.metrics_port, Some(6000));
+    let server = config.server(1).unwrap();
+    assert_eq!(server.data_dir, Path::new("/var/lib/raft/1"));
+    assert_eq!(server.metrics_port, None);
+    assert!(config.server(2).is_none());
+
+    assert_eq!(
+        config.cluster_members(),
+        "0,127.0.0.1:5000,1,127.0.0.1:5001"
+    );
+    assert_eq!(config.addresses().len(), 2);
+}
+
+#[test]
+fn raft_settings_default() {
+    let config = parse(
+        r#"
+[[servers]]
+id = 0
+address = "127.0.0.1:5000"
+data_dir = "wal-logs/0"
+"#,
+    )
+    .unwrap();
+    assert_eq!(config.raft.leader_heartbeat_ms, 50);
+    assert_eq!(config.raft.min_election_timeout_ms, 150);
+    assert_eq!(config.raft.max_election_timeout_ms, 300);
+}
+
+#[test]
+fn rejects_timeouts_out_of_order() {
+    assert_invalid(
+        &CLUSTER.replace(
+            "max_election_timeout_ms = 200",
+            "max_election_timeout_ms = 100",
+        ),
+        "min_election_timeout_ms (100) must be less than max_election_timeout_ms (100)",
+    );
+    assert_invalid(
+        &CLUSTER.replace("leader_heartbeat_ms = 20", "leader_heartbeat_ms = 100"),
+        "leader_heartbeat_ms (100)",
+    );
+}
+
+#[test]
+fn rejects_duplicate_ids_ports_and_data_dirs() {
+    assert_invalid(
+        &CLUSTER.replace("id = 1", "id = 0"),
+        "Server ID 0 is used twice",
+    );
+    assert_invalid(
+        &CLUSTER.replace("127.0.0.1:5001", "127.0.0.2:5000"),
+        "Port 5000 is used by servers 0 and 1",
+    );
+    assert_invalid(
+        &CLUSTER.replace("127.0.0.1:5001", "127.0.0.1:6000"),
+        "Port 6000 is used by servers 0 and 1",
+    );
+    assert_invalid(
+        &CLUSTER.replace("/var/lib/raft/1", "/etc/cluster/wal-logs/0"),
+        "is used by servers 0 and 1",
+    );
+    assert_invalid("servers = []", "No servers");
+}
+
+#[test]
+fn rejects_unknown_fields() {
+    let result = parse(&CLUSTER.replace("metrics_port", "metric_port"));
+    assert!(matches!(result, Err(ClusterConfigError::Parse(_))));
+}
+
+#[test]
+fn loads_relative_to_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cluster.toml");
+    std::fs::write(&path, CLUSTER).unwrap();
+
+    let config = ClusterConfig::load(&path).unwrap();
+    assert_eq!(
+        config.server(0).unwrap().data_dir,
+        dir.path().join("wal-logs/0")
+    );
+    assert!(matches!(
+        ClusterConfig::load(&dir.path().join("missing.toml")),
+        Err(ClusterConfigError::Read { .. })
+    ));
+}
+
+#[test]
+fn parses_cluster_members_it_formats() {
+    let config = parse(CLUSTER).unwrap();
+    let members = parse_cluster_members(&config.cluster_members()).unwrap();
+    assert_eq!(
+        members.into_iter().collect::<Vec<_>>(),
+        [
+            (0, "127.0.0.1:5000".to_string()),
+            (1, "127.0.0.1:5001".to_string())
+        ]
+    );
+    let members = parse_cluster_members(" 2, localhost:5002 ,1,127.0.0.1:5001").unwrap();
+    assert_eq!(members[&2], "localhost:5002");
+    assert_eq!(members[&1], "127.0.0.1:5001");
+}
+
+#[test]
+fn rejects_invalid_cluster_members() {
+    for (members, expected_message) in [
+        ("", "\"\" is not a server ID"),
+        ("a,127.0.0.1:5000", "\"a\" is not a server ID"),
+        ("0,127.0.0.1:5000,1", "No address for server 1"),
+        ("0,", "No address for server 0"),
+        (
+            "0,127.0.0.1:5000,0,127.0.0.1:5001",
+            "Server 0 is listed more than once",
+        ),
+    ] {
+        assert_eq!(
+            parse_cluster_members(members),
+            Err(InvalidClusterMembers(expected_message.to_string())),
+            "{:?}",
+            members
+        );
+    }
+}
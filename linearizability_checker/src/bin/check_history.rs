@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+use linearizability_checker::{check_history, CheckResult, History, Operation, Register};
+
+/// Checks a history of register operations for linearizability, exits with a non-zero status if it is not
+/// linearizable.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// History file with one event per line, i.e. `1500 3 ok read 42` (see `History`)
+    history: PathBuf,
+
+    /// Value the register held before the history started
+    #[arg(long, default_value_t = 0)]
+    initial_value: u64,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let text = match std::fs::read_to_string(&cli.history) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Could not read {:?}: {}", cli.history, e);
+            return ExitCode::from(2);
+        }
+    };
+    let history = match History::parse(&text) {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("Invalid history: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let initial = Register {
+        value: cli.initial_value,
+    };
+    let (operations, result) = match check_history(&history, initial) {
+        Ok(checked) => checked,
+        Err(e) => {
+            eprintln!("Invalid history: {}", e);
+            return ExitCode::from(2);
+        }
+    };
+    println!(
+        "Checked {} events, {} operations",
+        history.events().len(),
+        operations.len()
+    );
+    match result {
+        CheckResult::Linearizable(_) => {
+            println!("OK: history is linearizable");
+            ExitCode::SUCCESS
+        }
+        CheckResult::NotLinearizable {
+            longest_linearizable,
+            state,
+        } => {
+            println!(
+                "NOT LINEARIZABLE: at most {} of {} operations can be put in order, the register then holds {}",
+                longest_linearizable.len(),
+                operations.len(),
+                state.value
+            );
+            println!("Last operations of the longest order:");
+            let start = longest_linearizable.len().saturating_sub(10);
+            for index in &longest_linearizable[start..] {
+                print_operation(&operations[*index]);
+            }
+            println!("None of these can go next:");
+            let mut remaining: Vec<_> = (0..operations.len())
+                .filter(|index| !longest_linearizable.contains(index))
+                .map(|index| &operations[index])
+                .collect();
+            remaining.sort_by_key(|operation| operation.invoke_ns);
+            for operation in remaining.into_iter().take(10) {
+                print_operation(operation);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn print_operation(operation: &Operation) {
+    let completed = operation
+        .complete_ns
+        .map_or("never".to_string(), |complete_ns| complete_ns.to_string());
+    println!(
+        "  process {:>4}: {:<16} invoked {:>14} completed {:>14}",
+        operation.process,
+        operation.op.to_string(),
+        operation.invoke_ns,
+        completed
+    );
+}
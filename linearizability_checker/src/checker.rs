@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+use std::mem;
+
+use crate::history::{Operation, RegisterOp};
+use crate::model::Model;
+
+/// Outcome of checking a history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckResult<M> {
+    /// Indices of the operations in an order they could have taken effect in
+    Linearizable(Vec<usize>),
+    NotLinearizable {
+        /// The most operations that could be put in an order, none of the remaining operations can come next
+        longest_linearizable: Vec<usize>,
+        /// The state after `longest_linearizable`
+        state: M,
+    },
+}
+impl<M> CheckResult<M> {
+    pub fn is_linearizable(&self) -> bool {
+        matches!(self, CheckResult::Linearizable(_))
+    }
+}
+
+/// Operations that have been put in order so far
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BitSet(Vec<u64>);
+impl BitSet {
+    fn new(len: usize) -> Self {
+        BitSet(vec![0; len.div_ceil(64)])
+    }
+
+    fn set(&mut self, index: usize) {
+        self.0[index / 64] |= 1 << (index % 64);
+    }
+
+    fn clear(&mut self, index: usize) {
+        self.0[index / 64] &= !(1 << (index % 64));
+    }
+}
+
+/// Invocations and completions of the operations ordered by time, as a doubly linked list that operations can be
+/// taken out of and put back into in reverse order
+struct Entries {
+    next: Vec<usize>,
+    prev: Vec<usize>,
+    /// Index of the operation of every entry
+    operation: Vec<usize>,
+    /// The completion of every invocation entry, `None` for completions
+    completion: Vec<Option<usize>>,
+}
+const HEAD: usize = 0;
+impl Entries {
+    fn new(operations: &[Operation]) -> Self {
+        // Operations that may not have completed can take effect after everything else
+        let mut entries: Vec<(u64, bool, usize)> = operations
+            .iter()
+            .enumerate()
+            .flat_map(|(index, operation)| {
+                [
+                    (operation.invoke_ns, false, index),
+                    (operation.complete_ns.unwrap_or(u64::MAX), true, index),
+                ]
+            })
+            .collect();
+        // Invocations go before completions at the same time, so those operations count as concurrent
+        entries.sort();
+
+        // Node 0 is the head and the node after the last entry the tail
+        let len = entries.len() + 2;
+        let tail = len - 1;
+        let mut list = Entries {
+            next: (1..=len).collect(),
+            prev: (0..len).map(|node| node.saturating_sub(1)).collect(),
+            operation: vec![0; len],
+            completion: vec![None; len],
+        };
+        list.next[tail] = tail;
+        let mut invocation_node = vec![0; operations.len()];
+        for (position, (_, is_completion, index)) in entries.into_iter().enumerate() {
+            let node = position + 1;
+            list.operation[node] = index;
+            if is_completion {
+                list.completion[invocation_node[index]] = Some(node);
+            } else {
+                invocation_node[index] = node;
+            }
+        }
+        list
+    }
+
+    fn is_empty(&self) -> bool {
+        self.next[HEAD] == self.next.len() - 1
+    }
+
+    fn remove(&mut self, node: usize) {
+        let (prev, next) = (self.prev[node], self.next[node]);
+        self.next[prev] = next;
+        self.prev[next] = prev;
+    }
+
+    fn reinsert(&mut self, node: usize) {
+        let (prev, next) = (self.prev[node], self.next[node]);
+        self.next[prev] = node;
+        self.prev[next] = node;
+    }
+
+    /// Takes the operation of `invocation` out of the list
+    fn lift(&mut self, invocation: usize, completion: usize) {
+        self.remove(invocation);
+        self.remove(completion);
+    }
+
+    /// Puts the operation of `invocation` back, operations have to be put back in the reverse order of `lift`
+    fn unlift(&mut self, invocation: usize, completion: usize) {
+        self.reinsert(completion);
+        self.reinsert(invocation);
+    }
+}
+
+/// Checks whether the operations could have taken effect one at a time in some order, each one between its invocation
+/// and completion, with every operation's result matching the model started from `initial`.
+///
+/// This is the search of Wing & Gong with the state cache of Lowe ("Testing for linearizability", 2017), as in
+/// Knossos and Porcupine: it repeatedly picks an operation that was invoked before every remaining operation's
+/// completion, applies it to the model, and backtracks when no operation can go next. Operations that may not have
+/// completed may also have taken no effect at all. The search is exponential in the number of concurrent
+/// operations, the cache of (linearized operations, state) pairs that were already explored keeps it manageable for
+/// histories of a few clients.
+pub fn check_operations<M: Model<Op = RegisterOp>>(
+    initial: M,
+    operations: &[Operation],
+) -> CheckResult<M> {
+    let mut entries = Entries::new(operations);
+    let mut state = initial;
+    let mut linearized = BitSet::new(operations.len());
+    let mut explored: HashSet<(BitSet, M)> = HashSet::new();
+    // The invocation entries that were lifted, the state before them and the alternative they were applied with
+    let mut applied: Vec<(usize, M, usize)> = vec![];
+    let mut longest: (Vec<usize>, M) = (vec![], state.clone());
+
+    let mut entry = entries.next[HEAD];
+    let mut alternative = 0;
+    while !entries.is_empty() {
+        let completion = match entries.completion[entry] {
+            Some(completion) => completion,
+            // Every operation invoked before this completion has been tried, backtrack
+            None => match applied.pop() {
+                Some((invocation, previous_state, previous_alternative)) => {
+                    let completion = entries.completion[invocation]
+                        .expect("CHECKER: Applied a completion entry");
+                    state = previous_state;
+                    linearized.clear(entries.operation[invocation]);
+                    entries.unlift(invocation, completion);
+                    entry = invocation;
+                    alternative = previous_alternative + 1;
+                    continue;
+                }
+                None => {
+                    let (longest_linearizable, state) = longest;
+                    return CheckResult::NotLinearizable {
+                        longest_linearizable,
+                        state,
+                    };
+                }
+            },
+        };
+        let index = entries.operation[entry];
+        let operation = &operations[index];
+        // An operation that may not have completed either took effect or didn't
+        let alternatives = if operation.complete_ns.is_some() {
+            1
+        } else {
+            2
+        };
+        let mut lifted = false;
+        while alternative < alternatives {
+            let next_state = match alternative {
+                0 => state.step(&operation.op),
+                _ => Some(state.clone()),
+            };
+            if let Some(next_state) = next_state {
+                let mut next_linearized = linearized.clone();
+                next_linearized.set(index);
+                if explored.insert((next_linearized.clone(), next_state.clone())) {
+                    applied.push((entry, mem::replace(&mut state, next_state), alternative));
+                    linearized = next_linearized;
+                    entries.lift(entry, completion);
+                    if applied.len() > longest.0.len() {
+                        longest = (
+                            applied
+                                .iter()
+                                .map(|(invocation, _, _)| entries.operation[*invocation])
+                                .collect(),
+                            state.clone(),
+                        );
+                    }
+                    lifted = true;
+                    break;
+                }
+            }
+            alternative += 1;
+        }
+        entry = if lifted {
+            entries.next[HEAD]
+        } else {
+            entries.next[entry]
+        };
+        alternative = 0;
+    }
+    CheckResult::Linearizable(
+        applied
+            .iter()
+            .map(|(invocation, _, _)| entries.operation[*invocation])
+            .collect(),
+    )
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// What happened to an operation, as in Jepsen histories
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventType {
+    /// A client sent the operation
+    Invoke,
+    /// The operation completed and took effect
+    Ok,
+    /// The operation definitely did not take effect
+    Fail,
+    /// The client does not know whether the operation took effect, i.e. its request timed out. It may take effect
+    /// at any time after it was invoked.
+    Info,
+}
+impl fmt::Display for EventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EventType::Invoke => "invoke",
+            EventType::Ok => "ok",
+            EventType::Fail => "fail",
+            EventType::Info => "info",
+        };
+        f.write_str(name)
+    }
+}
+impl FromStr for EventType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "invoke" => Ok(EventType::Invoke),
+            "ok" => Ok(EventType::Ok),
+            "fail" => Ok(EventType::Fail),
+            "info" => Ok(EventType::Info),
+            _ => Err(format!("Unknown event type {:?}", s)),
+        }
+    }
+}
+
+/// An operation on a register. A compare and set that did not match is recorded as failed, as in Jepsen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegisterOp {
+    /// The value read, `None` until the read completed
+    Read(Option<u64>),
+    Write(u64),
+    Cas {
+        expected: u64,
+        new: u64,
+    },
+}
+impl fmt::Display for RegisterOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterOp::Read(Some(value)) => write!(f, "read {}", value),
+            RegisterOp::Read(None) => write!(f, "read nil"),
+            RegisterOp::Write(value) => write!(f, "write {}", value),
+            RegisterOp::Cas { expected, new } => write!(f, "cas {} {}", expected, new),
+        }
+    }
+}
+
+/// An event of a history, see `History` for its text format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    /// Nanoseconds since the history started, events are ordered by it
+    pub time_ns: u64,
+    /// The client that sent the operation, a client has at most one operation in flight
+    pub process: u64,
+    pub event_type: EventType,
+    pub op: RegisterOp,
+}
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.time_ns, self.process, self.event_type, self.op
+        )
+    }
+}
+
+/// A line of a history file could not be parsed, or the events don't make up a valid history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryError(pub String);
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl std::error::Error for HistoryError {}
+
+fn parse_value(value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{:?} is not a value", value))
+}
+
+fn parse_event(line: &str) -> Result<Event, String> {
+    let mut fields = line.split_whitespace();
+    let mut next_field = |name: &str| {
+        fields
+            .next()
+            .ok_or_else(|| format!("Missing {}", name))
+            .map(str::to_string)
+    };
+    let time_ns = next_field("time")?
+        .parse()
+        .map_err(|_| "Time is not a number".to_string())?;
+    let process = next_field("process")?
+        .parse()
+        .map_err(|_| "Process is not a number".to_string())?;
+    let event_type = next_field("event type")?.parse()?;
+    let op = match next_field("operation")?.as_str() {
+        "read" => match next_field("value")?.as_str() {
+            "nil" => RegisterOp::Read(None),
+            value => RegisterOp::Read(Some(parse_value(value)?)),
+        },
+        "write" => RegisterOp::Write(parse_value(&next_field("value")?)?),
+        "cas" => RegisterOp::Cas {
+            expected: parse_value(&next_field("expected value")?)?,
+            new: parse_value(&next_field("new value")?)?,
+        },
+        op => return Err(format!("Unknown operation {:?}", op)),
+    };
+    if let Some(field) = fields.next() {
+        return Err(format!("Unexpected {:?} after the operation", field));
+    }
+    Ok(Event {
+        time_ns,
+        process,
+        event_type,
+        op,
+    })
+}
+
+/// An operation with its invocation and completion matched up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Operation {
+    pub process: u64,
+    pub invoke_ns: u64,
+    /// `None` if it is not known whether the operation took effect, it may have taken effect any time after it was
+    /// invoked
+    pub complete_ns: Option<u64>,
+    /// The operation with the completion's value, i.e. the value a read returned
+    pub op: RegisterOp,
+}
+
+/// Events recorded by clients operating on a register. The text format has one event per line, blank lines and lines
+/// starting with `#` are skipped:
+///
+/// ```text
+/// <time in ns> <process> invoke|ok|fail|info read <value>|nil
+/// <time in ns> <process> invoke|ok|fail|info write <value>
+/// <time in ns> <process> invoke|ok|fail|info cas <expected value> <new value>
+/// ```
+///
+/// i.e. `1500 3 ok read 42`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    events: Vec<Event>,
+}
+impl History {
+    pub fn new() -> Self {
+        History::default()
+    }
+
+    pub fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn parse(text: &str) -> Result<Self, HistoryError> {
+        let mut history = History::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let event = parse_event(line)
+                .map_err(|message| HistoryError(format!("Line {}: {}", index + 1, message)))?;
+            history.push(event);
+        }
+        Ok(history)
+    }
+
+    /// Matches every invocation with its completion. Failed operations are left out, they did not take effect, and
+    /// so are reads that may not have completed, they don't change the register.
+    pub fn operations(&self) -> Result<Vec<Operation>, HistoryError> {
+        let mut events: Vec<(usize, &Event)> = self.events.iter().enumerate().collect();
+        // Stable, so events recorded at the same time stay in the order they were recorded in
+        events.sort_by_key(|(_, event)| event.time_ns);
+
+        let mut operations: Vec<Option<Operation>> = vec![];
+        // Index in `operations` of every process's operation in flight
+        let mut in_flight: HashMap<u64, usize> = HashMap::new();
+        for (index, event) in events {
+            let error = |message: String| HistoryError(format!("Event {}: {}", index + 1, message));
+            if event.event_type == EventType::Invoke {
+                if in_flight.contains_key(&event.process) {
+                    return Err(error(format!(
+                        "Process {} invoked an operation while another one is in flight",
+                        event.process
+                    )));
+                }
+                let _ = in_flight.insert(event.process, operations.len());
+                operations.push(Some(Operation {
+                    process: event.process,
+                    invoke_ns: event.time_ns,
+                    complete_ns: None,
+                    op: event.op,
+                }));
+                continue;
+            }
+            let operation_index = in_flight.remove(&event.process).ok_or_else(|| {
+                error(format!(
+                    "Process {} completed an operation it did not invoke",
+                    event.process
+                ))
+            })?;
+            let operation = &mut operations[operation_index];
+            let invoked_op = operation.as_ref().map(|operation| operation.op);
+            let same_op = match (invoked_op, event.op) {
+                (Some(RegisterOp::Read(_)), RegisterOp::Read(_)) => true,
+                (invoked, completed) => invoked == Some(completed),
+            };
+            if !same_op {
+                return Err(error(format!(
+                    "Process {} completed {} but invoked {}",
+                    event.process,
+                    event.op,
+                    invoked_op.map_or("nothing".to_string(), |op| op.to_string())
+                )));
+            }
+            match event.event_type {
+                EventType::Ok => {
+                    if let Some(operation) = operation {
+                        operation.complete_ns = Some(event.time_ns);
+                        operation.op = event.op;
+                    }
+                }
+                EventType::Fail => *operation = None,
+                EventType::Info | EventType::Invoke => {}
+            }
+        }
+        Ok(operations
+            .into_iter()
+            .flatten()
+            .filter(|operation| {
+                !matches!(operation.op, RegisterOp::Read(_)) || operation.complete_ns.is_some()
+            })
+            .collect())
+    }
+}
+impl fmt::Display for History {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for event in &self.events {
+            writeln!(f, "{}", event)?;
+        }
+        Ok(())
+    }
+}
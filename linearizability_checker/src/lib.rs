@@ -0,0 +1,23 @@
+//! Checks histories of operations on a register, recorded by concurrent clients, for linearizability: whether every
+//! operation could have taken effect at a single point between its invocation and completion, in an order in which
+//! every read returns the last value written.
+//!
+//! Record a `History` of invoke/ok/fail/info events the way Jepsen does, then check it with `check_history`. The
+//! `check_history` binary checks history files.
+mod checker;
+mod history;
+mod model;
+
+pub use checker::{check_operations, CheckResult};
+pub use history::{Event, EventType, History, HistoryError, Operation, RegisterOp};
+pub use model::{Model, Register};
+
+/// Checks the history's operations against a register that starts out holding `initial`, see `check_operations`
+pub fn check_history(
+    history: &History,
+    initial: Register,
+) -> Result<(Vec<Operation>, CheckResult<Register>), HistoryError> {
+    let operations = history.operations()?;
+    let result = check_operations(initial, &operations);
+    Ok((operations, result))
+}
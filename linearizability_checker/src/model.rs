@@ -0,0 +1,33 @@
+use std::hash::Hash;
+
+use crate::history::RegisterOp;
+
+/// Sequential specification of an object that a history's operations are checked against
+pub trait Model: Clone + Eq + Hash {
+    type Op;
+
+    /// The state after `op` is applied in this state, `None` if `op` could not have completed the way it did in this
+    /// state, i.e. a read returned a different value
+    fn step(&self, op: &Self::Op) -> Option<Self>;
+}
+
+/// A register holding a single value that supports reads, writes and compare and set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Register {
+    pub value: u64,
+}
+impl Model for Register {
+    type Op = RegisterOp;
+
+    fn step(&self, op: &RegisterOp) -> Option<Self> {
+        match *op {
+            RegisterOp::Read(Some(value)) if value != self.value => None,
+            RegisterOp::Read(_) => Some(*self),
+            RegisterOp::Write(value) => Some(Register { value }),
+            RegisterOp::Cas { expected, new } if expected == self.value => {
+                Some(Register { value: new })
+            }
+            RegisterOp::Cas { .. } => None,
+        }
+    }
+}
@@ -0,0 +1,305 @@
+/// Tests for checking register histories for linearizability
+use linearizability_checker::{
+    check_history, CheckResult, Event, EventType, History, Register, RegisterOp,
+};
+
+fn check(text: &str) -> CheckResult<Register> {
+    let history = History::parse(text).expect("Invalid history");
+    check_history(&history, Register::default())
+        .expect("Invalid history")
+        .1
+}
+
+#[test]
+fn should_accept_sequential_history() {
+    let result = check(
+        "
+        0 0 invoke write 1
+        10 0 ok write 1
+        20 1 invoke read nil
+        30 1 ok read 1
+        40 0 invoke cas 1 2
+        50 0 ok cas 1 2
+        60 1 invoke read nil
+        70 1 ok read 2
+        ",
+    );
+    assert_eq!(result, CheckResult::Linearizable(vec![0, 1, 2, 3]));
+}
+
+#[test]
+fn should_reject_stale_read_after_write_completed() {
+    let result = check(
+        "
+        0 0 invoke write 1
+        10 0 ok write 1
+        20 1 invoke read nil
+        30 1 ok read 0
+        ",
+    );
+    assert_eq!(
+        result,
+        CheckResult::NotLinearizable {
+            longest_linearizable: vec![0],
+            state: Register { value: 1 },
+        }
+    );
+}
+
+#[test]
+fn should_accept_either_value_for_read_concurrent_with_write() {
+    for read_value in [0, 1] {
+        let result = check(&format!(
+            "
+            0 0 invoke write 1
+            5 1 invoke read nil
+            10 1 ok read {}
+            20 0 ok write 1
+            ",
+            read_value
+        ));
+        assert!(result.is_linearizable(), "Read {} rejected", read_value);
+    }
+}
+
+#[test]
+fn should_reject_reads_that_go_back_to_an_older_value() {
+    let result = check(
+        "
+        0 0 invoke write 1
+        5 1 invoke read nil
+        6 1 ok read 1
+        7 1 invoke read nil
+        8 1 ok read 0
+        20 0 ok write 1
+        ",
+    );
+    assert!(!result.is_linearizable());
+}
+
+#[test]
+fn should_let_indeterminate_write_take_effect_later_or_never() {
+    let took_effect_later = check(
+        "
+        0 0 invoke write 1
+        10 0 info write 1
+        20 1 invoke read nil
+        30 1 ok read 0
+        40 1 invoke read nil
+        50 1 ok read 1
+        ",
+    );
+    assert!(took_effect_later.is_linearizable());
+
+    let never_took_effect = check(
+        "
+        0 0 invoke write 1
+        20 1 invoke read nil
+        30 1 ok read 0
+        ",
+    );
+    assert!(never_took_effect.is_linearizable());
+}
+
+#[test]
+fn should_let_indeterminate_cas_take_no_effect_if_it_did_not_match() {
+    let result = check(
+        "
+        0 0 invoke cas 5 6
+        10 0 info cas 5 6
+        20 1 invoke write 3
+        30 1 ok write 3
+        40 1 invoke read nil
+        50 1 ok read 3
+        ",
+    );
+    assert!(result.is_linearizable());
+}
+
+#[test]
+fn should_ignore_failed_operations_but_not_successful_cas_that_could_not_match() {
+    let failed_write = check(
+        "
+        0 0 invoke write 1
+        10 0 fail write 1
+        20 1 invoke read nil
+        30 1 ok read 0
+        ",
+    );
+    assert!(failed_write.is_linearizable());
+
+    let cas_that_could_not_match = check(
+        "
+        0 0 invoke cas 1 2
+        10 0 ok cas 1 2
+        ",
+    );
+    assert!(!cas_that_could_not_match.is_linearizable());
+}
+
+#[test]
+fn should_write_history_that_parses_to_the_same_events() {
+    let mut history = History::new();
+    for (time_ns, event_type, op) in [
+        (0, EventType::Invoke, RegisterOp::Read(None)),
+        (1, EventType::Ok, RegisterOp::Read(Some(7))),
+        (
+            2,
+            EventType::Invoke,
+            RegisterOp::Cas {
+                expected: 7,
+                new: 8,
+            },
+        ),
+        (
+            3,
+            EventType::Info,
+            RegisterOp::Cas {
+                expected: 7,
+                new: 8,
+            },
+        ),
+    ] {
+        history.push(Event {
+            time_ns,
+            process: 4,
+            event_type,
+            op,
+        });
+    }
+    let text = history.to_string();
+    assert_eq!(
+        text,
+        "0 4 invoke read nil\n1 4 ok read 7\n2 4 invoke cas 7 8\n3 4 info cas 7 8\n"
+    );
+    assert_eq!(History::parse(&text), Ok(history));
+}
+
+#[test]
+fn should_report_invalid_histories() {
+    let error = History::parse("# comment\n0 0 invoke write\n").unwrap_err();
+    assert_eq!(error.to_string(), "Line 2: Missing value");
+
+    let history = History::parse("0 0 ok write 1").unwrap();
+    assert!(history.operations().is_err());
+
+    let history = History::parse("0 0 invoke write 1\n1 0 invoke write 2").unwrap();
+    assert!(history.operations().is_err());
+}
+
+/// Small deterministic random number generator, so the generated histories are the same on every run
+struct Lcg(u64);
+impl Lcg {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % bound
+    }
+}
+
+/// History of concurrent clients operating on an atomic register, every operation takes effect at some point
+/// between its invocation and completion
+fn generate_linearizable_history(seed: u64, processes: u64, operations: usize) -> History {
+    #[derive(Clone, Copy)]
+    enum Process {
+        Idle,
+        Invoked(RegisterOp),
+        Applied(RegisterOp, bool),
+    }
+    let mut rng = Lcg(seed);
+    let mut register = 0;
+    let mut states = vec![Process::Idle; processes as usize];
+    let mut history = History::new();
+    let mut invoked = 0;
+    let mut time_ns = 0;
+    while invoked < operations || states.iter().any(|state| !matches!(state, Process::Idle)) {
+        time_ns += 1;
+        let process = rng.next(processes);
+        let state = &mut states[process as usize];
+        let mut record = |event_type, op| {
+            history.push(Event {
+                time_ns,
+                process,
+                event_type,
+                op,
+            })
+        };
+        *state = match *state {
+            Process::Idle if invoked < operations => {
+                invoked += 1;
+                let op = match rng.next(3) {
+                    0 => RegisterOp::Read(None),
+                    1 => RegisterOp::Write(rng.next(5)),
+                    _ => RegisterOp::Cas {
+                        expected: rng.next(5),
+                        new: rng.next(5),
+                    },
+                };
+                record(EventType::Invoke, op);
+                Process::Invoked(op)
+            }
+            Process::Idle => Process::Idle,
+            Process::Invoked(op) => match op {
+                RegisterOp::Read(_) => Process::Applied(RegisterOp::Read(Some(register)), true),
+                RegisterOp::Write(value) => {
+                    register = value;
+                    Process::Applied(op, true)
+                }
+                RegisterOp::Cas { expected, new } => {
+                    let matched = register == expected;
+                    if matched {
+                        register = new;
+                    }
+                    Process::Applied(op, matched)
+                }
+            },
+            Process::Applied(op, succeeded) => {
+                record(
+                    if succeeded {
+                        EventType::Ok
+                    } else {
+                        EventType::Fail
+                    },
+                    op,
+                );
+                Process::Idle
+            }
+        };
+    }
+    history
+}
+
+#[test]
+fn should_accept_generated_linearizable_histories() {
+    for seed in 0..20 {
+        let history = generate_linearizable_history(seed, 5, 200);
+        let (_, result) = check_history(&history, Register::default()).unwrap();
+        assert!(
+            result.is_linearizable(),
+            "Generated history was rejected:\n{}",
+            history
+        );
+    }
+}
+
+#[test]
+fn should_reject_generated_history_with_read_of_value_never_written() {
+    let history = generate_linearizable_history(7, 5, 200);
+    let mut corrupted = History::new();
+    let mut replaced = false;
+    for event in history.events() {
+        let mut event = *event;
+        if !replaced && event.event_type == EventType::Ok {
+            if let RegisterOp::Read(Some(_)) = event.op {
+                event.op = RegisterOp::Read(Some(100));
+                replaced = true;
+            }
+        }
+        corrupted.push(event);
+    }
+    assert!(replaced);
+    let (_, result) = check_history(&corrupted, Register::default()).unwrap();
+    assert!(!result.is_linearizable());
+}
@@ -0,0 +1,201 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use raft_consensus::{
+    inspect_default_storage, truncate_log_after, DefaultStorageInfo, LogIndex, LogRecordKind,
+    PersistentStorageError, StorageErrorKind,
+};
+
+/// Inspects the election file and log file `DefaultPersistentStorage` keeps for a raft node that is not running. Only
+/// `truncate` writes to them.
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+#[command(propagate_version = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+
+    /// Path to the node's write ahead log directory, the one holding its `election` and `log` files
+    #[arg(short, long)]
+    wal_log_dir: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Print the persisted term and vote and a summary of the log
+    Dump,
+    /// List the records of the log file with their offset, size, checksum and contents
+    List,
+    /// Check the checksum of every record and the log, exits with a non-zero status if either is corrupt
+    Verify,
+    /// Remove all log entries after the given index
+    Truncate {
+        #[arg(long)]
+        after: u64,
+    },
+}
+
+fn print_storage(info: &DefaultStorageInfo) {
+    match &info.election_file {
+        Some(election_file) => println!(
+            "Election file: {:?}, {} bytes, version {}, checksum {:#010x}",
+            election_file.path,
+            election_file.file_size,
+            election_file.format_version,
+            election_file.checksum
+        ),
+        None => println!("Election file: none, the term and vote are kept in the log file"),
+    }
+    let log_file = &info.log_file;
+    println!(
+        "Log file:      {:?}, {} bytes, {} records",
+        log_file.path,
+        log_file.file_size,
+        log_file.records.len()
+    );
+    println!("  Current term:   {}", info.current_term.0);
+    match info.voted_for {
+        Some((term, server_id)) => println!("  Voted for:      {} in term {}", server_id.0, term.0),
+        None => println!("  Voted for:      nobody"),
+    }
+    match (log_file.entries.first(), log_file.entries.last()) {
+        (Some((first_index, first_term)), Some((last_index, last_term))) => println!(
+            "  Log:            {} entries, {} (term {}) to {} (term {})",
+            log_file.entries.len(),
+            first_index.0,
+            first_term.0,
+            last_index.0,
+            last_term.0
+        ),
+        _ => println!("  Log:            empty"),
+    }
+    if let Some((index, term)) = log_file.compacted_through() {
+        println!(
+            "  Compacted:      entries up to {} (term {}) were dropped",
+            index.0, term.0
+        );
+    }
+    if log_file.torn_bytes > 0 {
+        println!(
+            "  Torn record:    {} bytes after the last complete record, dropped when the node starts",
+            log_file.torn_bytes
+        );
+    }
+}
+
+/// Finds problems in the log that the checksums do not catch, raft never writes a log like this
+fn check_storage(info: &DefaultStorageInfo) -> Vec<String> {
+    let mut problems = vec![];
+    if let Some((vote_term, server_id)) = info.voted_for {
+        if vote_term > info.current_term {
+            problems.push(format!(
+                "Vote for {} in term {} is after the current term {}",
+                server_id.0, vote_term.0, info.current_term.0
+            ));
+        }
+    }
+    for pair in info.log_file.entries.windows(2) {
+        let ((previous_index, previous_term), (index, term)) = (pair[0], pair[1]);
+        if term < previous_term {
+            problems.push(format!(
+                "Entry {} has term {}, before the term {} of the entry {} before it",
+                index.0, term.0, previous_term.0, previous_index.0
+            ));
+        }
+    }
+    if let Some((last_index, last_term)) = info.log_file.entries.last() {
+        if *last_term > info.current_term {
+            problems.push(format!(
+                "Last entry {} has term {}, after the current term {}",
+                last_index.0, last_term.0, info.current_term.0
+            ));
+        }
+    }
+    problems
+}
+
+fn run(command: &Commands, path: &Path) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    match command {
+        Commands::Dump => print_storage(&inspect_default_storage(path)?),
+        Commands::List => {
+            let info = inspect_default_storage(path)?;
+            println!(
+                "{:>10} {:>8} {:>10} {:>10}  RECORD",
+                "OFFSET", "BYTES", "CRC", "INDEX"
+            );
+            for record in &info.log_file.records {
+                let (index, description) = match record.kind {
+                    LogRecordKind::Entry { index, term } => {
+                        (index.0.to_string(), format!("entry in term {}", term.0))
+                    }
+                    LogRecordKind::HardState {
+                        current_term,
+                        voted_for: Some((vote_term, server_id)),
+                    } => (
+                        "-".to_string(),
+                        format!(
+                            "term {}, voted for {} in term {}",
+                            current_term.0, server_id.0, vote_term.0
+                        ),
+                    ),
+                    LogRecordKind::HardState {
+                        current_term,
+                        voted_for: None,
+                    } => ("-".to_string(), format!("term {}", current_term.0)),
+                    LogRecordKind::Compacted { index, term } => (
+                        index.0.to_string(),
+                        format!("compacted through this entry in term {}", term.0),
+                    ),
+                };
+                println!(
+                    "{:>10} {:>8} {:#010x} {:>10}  {}",
+                    record.offset, record.size, record.checksum, index, description
+                );
+            }
+        }
+        Commands::Verify => {
+            let info = inspect_default_storage(path)?;
+            print_storage(&info);
+            let problems = check_storage(&info);
+            if !problems.is_empty() {
+                for problem in problems {
+                    eprintln!("CORRUPT: {}", problem);
+                }
+                return Ok(ExitCode::FAILURE);
+            }
+            println!(
+                "OK: the checksums of the election file and all {} log records match and the log has no gaps",
+                info.log_file.records.len()
+            );
+        }
+        Commands::Truncate { after } => {
+            let removed = truncate_log_after(path, LogIndex(*after))?;
+            println!("Removed {} entries after index {}", removed, after);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let path = &cli.wal_log_dir;
+    if !path.join("log").exists() {
+        eprintln!("No log file in {:?}", path);
+        return ExitCode::FAILURE;
+    }
+
+    match run(&cli.command, path) {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("{}", e);
+            let is_corrupt = e
+                .downcast_ref::<PersistentStorageError>()
+                .is_some_and(|e| e.kind() == StorageErrorKind::Corruption);
+            if is_corrupt {
+                eprintln!("The node will refuse to start until the storage is restored or removed");
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use crate::rpc_messages::{ReplyTo, Request, RpcMessage};
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::hash::Hash;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 #[derive(Eq, PartialEq, PartialOrd, Ord, Clone, Copy, Debug, Serialize, Deserialize, Hash)]
@@ -12,7 +14,7 @@ pub struct ServerId(pub u64);
 pub trait LogCommand: Debug + Clone + Send + Eq + PartialEq {}
 impl<T> LogCommand for T where T: Debug + Clone + Send + Eq + PartialEq {}
 
-#[derive(Eq, PartialEq, PartialOrd, Clone, Copy, Debug)]
+#[derive(Eq, PartialEq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
 /// The index of a log entry.
 pub struct LogIndex(pub u64);
 
@@ -33,8 +35,9 @@ pub struct LogEntry<T: LogCommand> {
     pub index: LogIndex,
     /// The term of the log entry.
     pub term: TermIndex,
-    /// The command that was applied to the state machine to produce this log entry.
-    pub command: T,
+    /// The command that was applied to the state machine to produce this log entry, `None` for the no-op entry a
+    /// leader appends when it is elected.
+    pub command: Option<T>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -48,13 +51,99 @@ pub struct RaftConfig {
     pub max_election_timeout_ms: u32,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The persistent storage operation that was being performed when an error occurred.
+pub enum StorageOperation {
+    /// Opening/creating a storage file.
+    Open,
+    /// Reading and deserializing data from a storage file.
+    Read,
+    /// Serializing and writing data to a storage file.
+    Write,
+    /// Flushing/fsyncing pending writes to disk.
+    Sync,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Whether a persistent storage error is likely to go away if the operation is retried.
+pub enum StorageErrorKind {
+    /// The operation failed (i.e. disk full, I/O error) but the data already on disk is still valid,
+    /// restarting the node may succeed.
+    Transient,
+    /// The data on disk could not be decoded, restarting the node will not fix this.
+    Corruption,
+}
+
+#[derive(Debug)]
 /// Defines errors that can occur when interacting with the persistent storage layer.
-pub enum PersistentStorageError {
-    /// An error occurred while reading from/writing to disk.
-    IoError,
-    /// An error occurred while serializing/deserializing data.
-    SerdeError,
+pub struct PersistentStorageError {
+    kind: StorageErrorKind,
+    operation: StorageOperation,
+    path: PathBuf,
+    source: io::Error,
+}
+impl PersistentStorageError {
+    /// Creates an error for an operation that failed but may succeed if retried.
+    pub fn transient(
+        operation: StorageOperation,
+        path: impl Into<PathBuf>,
+        source: io::Error,
+    ) -> Self {
+        PersistentStorageError {
+            kind: StorageErrorKind::Transient,
+            operation,
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Creates an error for data on disk that is corrupt and cannot be decoded.
+    pub fn corruption(
+        operation: StorageOperation,
+        path: impl Into<PathBuf>,
+        source: io::Error,
+    ) -> Self {
+        PersistentStorageError {
+            kind: StorageErrorKind::Corruption,
+            operation,
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Whether the error is transient or caused by corrupt data.
+    pub fn kind(&self) -> StorageErrorKind {
+        self.kind
+    }
+
+    /// The operation that failed.
+    pub fn operation(&self) -> StorageOperation {
+        self.operation
+    }
+
+    /// The file that was being read from/written to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The underlying I/O error.
+    pub fn io_error(&self) -> &io::Error {
+        &self.source
+    }
+}
+impl fmt::Display for PersistentStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} storage error during {:?} of {:?}: {}",
+            self.kind, self.operation, self.path, self.source
+        )
+    }
+}
+impl std::error::Error for PersistentStorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
 }
 
 /// A trait that defines the interface for a persistent storage layer for Raft.
@@ -71,13 +160,35 @@ pub trait PersistentStorage<C: LogCommand>: Send {
 
     /// Returns the log index of the last entry in the log.
     fn last_entry_index(&self) -> Option<LogIndex>;
+    /// Returns the term of the last entry in the log.
+    fn last_entry_term(&self) -> Option<TermIndex>;
+    /// Returns the term of the entry at `index`, `None` if the log has no entry at that index.
+    fn entry_term(&self, index: LogIndex) -> Option<TermIndex>;
     /// Returns true if the log contains an entry with the given index and term.
-    fn has_entry(self, index: LogIndex, term: TermIndex) -> bool;
+    fn has_entry(&self, index: LogIndex, term: TermIndex) -> bool;
+    /// Returns up to `max_entries` entries in log order, starting with the entry at index `from`.
+    fn entries(
+        &self,
+        from: LogIndex,
+        max_entries: usize,
+    ) -> Result<Vec<LogEntry<C>>, PersistentStorageError>;
 
     /// Appends the given entries to the log.
     fn append(&mut self, entries: Vec<LogEntry<C>>) -> &mut Self;
 
+    /// Returns the index and term of the last entry dropped from the log by `compact`, `None` if none were.
+    fn compacted_through(&self) -> Option<(LogIndex, TermIndex)>;
+    /// Drops the entries up to and including `index` from the log, the application has persisted a snapshot of the
+    /// state they produced. The index and term of the last one are kept: `entry_term` and `has_entry` still answer for
+    /// it, and `last_entry_index`/`last_entry_term` return it while no entries follow it. Does nothing if there is no
+    /// entry at `index`, the change is persisted on the next `sync`.
+    fn compact(&mut self, index: LogIndex) -> &mut Self;
+
     /// Writes/fsyncs any pending changes to disk.
+    ///
+    /// The raft thread calls this once after handling each message, before sending any replies, so every term/vote
+    /// update and appended entry since the last sync should become durable together. Syncing with nothing pending
+    /// should be cheap.
     fn sync(&mut self) -> Result<(), PersistentStorageError>;
 }
 
@@ -93,6 +204,8 @@ pub enum RaftTransportError {
 /// Using a trait for this allows us to swap a different implementation for testing that uses a simulated network.
 pub trait RaftTransportConnector<C: LogCommand>: Send {
     /// Returns the next incoming message from the network.
+    /// Should return `Ok(None)` before `max_wait` has passed if the raft thread is unparked, i.e. by
+    /// `RaftProposer::propose`, so the raft thread picks up new proposals without waiting for a message.
     fn wait_for_next_incoming_message(
         &mut self,
         max_wait: Duration,
@@ -102,19 +215,38 @@ pub trait RaftTransportConnector<C: LogCommand>: Send {
     fn enqueue_reply(&mut self, reply: ReplyTo) -> Result<(), RaftTransportError>;
 
     /// Enqueues a request to be sent to the given server.
+    /// This is called inside the request's `send_rpc` span, transports can propagate `tracing::Span::current()` to the
+    /// receiving server so it handles the request as part of the same trace.
     fn enqueue_outgoing_request(&mut self, request: Request<C>) -> Result<(), RaftTransportError>;
+
+    /// Span the transport received the last incoming message in, the raft thread handles the message inside it.
+    /// Transports that don't propagate trace context between servers don't need to implement this.
+    fn take_incoming_message_span(&mut self) -> Option<tracing::Span> {
+        None
+    }
 }
 
 /// A trait that defines the interface for a state machine that can be used with Raft.
-/// The state machine is responsible for applying commands to its state and returning
-/// an error if the command cannot be queued for applying to the application's state machine.
+/// The raft thread hands each committed entry to `apply` once, in log order, and stops with
+/// `RaftThreadExit::ApplicationError` if applying an entry fails since the application would diverge from the rest of
+/// the cluster.
 ///
 /// Additionally, the state machine must be able to return the index of the last command successfully applied.
-/// This is used by Raft to determine if it can commit a new entry
-trait ApplicationThatNeedsConsensus: Send {
+/// Entries up to this index are skipped, i.e. when the application state survived a restart.
+pub trait ApplicationThatNeedsConsensus: Send {
+    /// The command type stored in the raft log.
     type Command: LogCommand;
+    /// The result of applying a command, handed back to the server that proposed it.
+    type Response: Debug + Send + 'static;
+    /// The error returned when a command cannot be applied.
     type Error: Debug + Clone + Send + Eq + PartialEq;
 
-    fn apply(&mut self, log_index: LogIndex, command: Self::Command) -> Result<(), Self::Error>;
+    /// Applies the command of the committed entry at `log_index` to the application's state.
+    fn apply(
+        &mut self,
+        log_index: LogIndex,
+        command: Self::Command,
+    ) -> Result<Self::Response, Self::Error>;
+    /// Returns the index of the last entry that was applied, 0 if none were.
     fn last_applied_index(&self) -> LogIndex;
 }
@@ -1,24 +1,75 @@
-use crate::PersistentStorageError;
+use crate::{PersistentStorageError, StorageOperation};
 
 use super::common::{LogCommand, LogEntry, LogIndex, PersistentStorage, ServerId, TermIndex};
 use std::fmt::Debug;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Seek, Write};
-use std::marker::PhantomData;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use bincode::Options;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 
 use fault_injection::maybe;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 struct Election {
     current_term: TermIndex,
     voted_for: Option<(TermIndex, ServerId)>,
 }
+impl Default for Election {
+    fn default() -> Self {
+        Election {
+            current_term: TermIndex(0),
+            voted_for: None,
+        }
+    }
+}
+impl Election {
+    fn vote_for_current_term(&self) -> Option<ServerId> {
+        self.voted_for.and_then(|(last_vote_term, server_id)| {
+            if last_vote_term == self.current_term {
+                Some(server_id)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The term only goes up and a vote is cast at most once in a term, so a state with a higher term, or the same
+    /// term and a vote, was written after the other one
+    fn is_newer_than(&self, other: &Election) -> bool {
+        let progress = |election: &Election| {
+            (
+                election.current_term.0,
+                election.vote_for_current_term().is_some(),
+            )
+        };
+        progress(self) > progress(other)
+    }
+}
+
+/// The newer of the election state in the election file and in the log file, whichever way the directory was last
+/// written
+fn newer_election(file_election: Option<Election>, log_election: Option<Election>) -> Election {
+    match (file_election, log_election) {
+        (Some(file_election), Some(log_election)) if log_election.is_newer_than(&file_election) => {
+            log_election
+        }
+        (Some(election), _) | (None, Some(election)) => election,
+        (None, None) => Election::default(),
+    }
+}
+
+/// Identifies a file as a raft election file, so we don't try to decode some other file (or an election file
+/// written before the file had a header) as election state.
+const ELECTION_FILE_MAGIC: [u8; 4] = *b"RFTE";
+/// Bump this whenever the layout of the header or the encoding of `Election` changes
+const ELECTION_FILE_VERSION: u16 = 1;
+/// magic (4 bytes) + version (u16) + payload length (u16) + CRC32 of payload (u32), all little endian
+const ELECTION_FILE_HEADER_LEN: usize = 12;
 
 type WALBincodeOptions = bincode::config::WithOtherEndian<
     bincode::config::WithOtherIntEncoding<
@@ -42,111 +93,910 @@ fn get_election_bincode() -> WALBincodeOptions {
         .with_little_endian()
 }
 
-fn bincode_to_io_error(error_kind: Box<bincode::ErrorKind>) -> std::io::Error {
-    std::io::Error::new(
-        std::io::ErrorKind::Other,
-        format!("Bincode error: {:?}", error_kind),
+fn bincode_to_io_error(error_kind: Box<bincode::ErrorKind>) -> io::Error {
+    io::Error::other(format!("Bincode error: {:?}", error_kind))
+}
+
+fn corrupt_election_file(path: &Path, reason: String) -> PersistentStorageError {
+    PersistentStorageError::corruption(
+        StorageOperation::Read,
+        path,
+        io::Error::new(io::ErrorKind::InvalidData, reason),
     )
 }
 
+/// Encodes the election state with a header so a torn or otherwise corrupted write is detected when the
+/// file is read back, instead of silently starting up with a garbage term.
+fn encode_election_file(election: &Election) -> Result<Vec<u8>, io::Error> {
+    let payload = get_election_bincode()
+        .serialize(election)
+        .map_err(bincode_to_io_error)?;
+    let payload_len: u16 = payload
+        .len()
+        .try_into()
+        .expect("ELEC FILE: Election state should always fit in a u16 length");
+
+    let mut bytes = Vec::with_capacity(ELECTION_FILE_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&ELECTION_FILE_MAGIC);
+    bytes.extend_from_slice(&ELECTION_FILE_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&payload_len.to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// Validates the header and checksum of an election file and decodes the election state from it.
+fn decode_election_file(path: &Path, bytes: &[u8]) -> Result<Election, PersistentStorageError> {
+    if bytes.len() < ELECTION_FILE_HEADER_LEN {
+        return Err(corrupt_election_file(
+            path,
+            format!(
+                "Election file is truncated, only {} bytes long",
+                bytes.len()
+            ),
+        ));
+    }
+    let (header, payload) = bytes.split_at(ELECTION_FILE_HEADER_LEN);
+
+    if header[0..4] != ELECTION_FILE_MAGIC {
+        return Err(corrupt_election_file(
+            path,
+            format!("Election file has invalid magic number {:?}", &header[0..4]),
+        ));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != ELECTION_FILE_VERSION {
+        return Err(corrupt_election_file(
+            path,
+            format!(
+                "Election file has unsupported format version {} (expected {})",
+                version, ELECTION_FILE_VERSION
+            ),
+        ));
+    }
+    let payload_len = u16::from_le_bytes([header[6], header[7]]) as usize;
+    if payload.len() != payload_len {
+        return Err(corrupt_election_file(
+            path,
+            format!(
+                "Election file payload is {} bytes, header says {} bytes",
+                payload.len(),
+                payload_len
+            ),
+        ));
+    }
+    let expected_crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    let actual_crc = crc32fast::hash(payload);
+    if actual_crc != expected_crc {
+        return Err(corrupt_election_file(
+            path,
+            format!(
+                "Election file checksum mismatch, expected {:#010x} but was {:#010x}",
+                expected_crc, actual_crc
+            ),
+        ));
+    }
+
+    get_election_bincode()
+        .deserialize(payload)
+        .map_err(|e| corrupt_election_file(path, format!("Bincode error: {:?}", e)))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Contents of the election file in a `DefaultPersistentStorage` directory, see `inspect_election_file`.
+pub struct ElectionFileInfo {
+    /// Path of the election file.
+    pub path: PathBuf,
+    /// Size of the election file in bytes, including the header.
+    pub file_size: usize,
+    /// Format version from the file header.
+    pub format_version: u16,
+    /// CRC32 of the election state, already verified against the file contents.
+    pub checksum: u32,
+    /// The persisted current term.
+    pub current_term: TermIndex,
+    /// The last vote that was persisted and the term it was cast in.
+    pub voted_for: Option<(TermIndex, ServerId)>,
+    /// True if a temp file from an update that never completed is left in the directory. This is harmless, the
+    /// election file still holds the state from before the update.
+    pub has_incomplete_update: bool,
+}
+
+/// Reads and verifies the election file in `log_path` without modifying anything, for tools that inspect the
+/// storage of a node that is not running. Fails with a corruption error if the header or checksum is invalid.
+pub fn inspect_election_file(log_path: &Path) -> Result<ElectionFileInfo, PersistentStorageError> {
+    let election_path = log_path.join("election");
+    let bytes = fs::read(&election_path).map_err(|e| {
+        PersistentStorageError::transient(StorageOperation::Read, &election_path, e)
+    })?;
+    let election = decode_election_file(&election_path, &bytes)?;
+
+    Ok(ElectionFileInfo {
+        file_size: bytes.len(),
+        format_version: ELECTION_FILE_VERSION,
+        checksum: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        current_term: election.current_term,
+        voted_for: election.voted_for,
+        has_incomplete_update: election_path.with_extension("tmp").exists(),
+        path: election_path,
+    })
+}
+
+/// Identifies a file as a raft log file
+const LOG_FILE_MAGIC: [u8; 4] = *b"RFTL";
+/// Bump this whenever the layout of the header, the records or the encoding of `LogRecord` changes
+const LOG_FILE_VERSION: u16 = 3;
+/// magic (4 bytes) + version (u16), all little endian
+const LOG_FILE_HEADER_LEN: u64 = 6;
+/// payload length (u32) + CRC32 of the payload length (u32) + CRC32 of payload (u32), all little endian
+const LOG_RECORD_HEADER_LEN: usize = 12;
+
+/// A record of the log file, the payload of a record is the bincode encoded `LogRecord`. Records are only ever
+/// appended: an entry replaces the entries at its index and after it that were written before it, and the last hard
+/// state replaces the ones before it. A compacted log file starts with the index and term of the last entry that was
+/// dropped, written when the file is rewritten without the compacted entries. `C` is the entry's optional command, see
+/// `LogEntry::command`.
+#[derive(Debug, Serialize, Deserialize)]
+enum LogRecord<C> {
+    Entry { index: u64, term: u64, command: C },
+    HardState(Election),
+    Compacted { index: u64, term: u64 },
+}
+
+fn log_file_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(LOG_FILE_HEADER_LEN as usize);
+    header.extend_from_slice(&LOG_FILE_MAGIC);
+    header.extend_from_slice(&LOG_FILE_VERSION.to_le_bytes());
+    header
+}
+
+fn corrupt_log_file(path: &Path, reason: String) -> PersistentStorageError {
+    PersistentStorageError::corruption(
+        StorageOperation::Read,
+        path,
+        io::Error::new(io::ErrorKind::InvalidData, reason),
+    )
+}
+
+fn encode_log_record<C: Serialize>(record: &LogRecord<C>) -> Result<Vec<u8>, io::Error> {
+    let payload = bincode::serialize(record).map_err(bincode_to_io_error)?;
+    let payload_len: u32 = payload.len().try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Log record of {} bytes is too large to write",
+                payload.len()
+            ),
+        )
+    })?;
+
+    let mut bytes = Vec::with_capacity(LOG_RECORD_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&payload_len.to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&payload_len.to_le_bytes()).to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// A complete record of a log file, its checksum has been verified
+struct LogFrame<'a> {
+    offset: u64,
+    checksum: u32,
+    /// The record header followed by the payload
+    bytes: &'a [u8],
+}
+impl LogFrame<'_> {
+    fn payload(&self) -> &[u8] {
+        &self.bytes[LOG_RECORD_HEADER_LEN..]
+    }
+}
+
+/// Checks the header of a log file and splits it into records, returning them and the offset the last complete one
+/// ends at. An incomplete or mismatched last record is what a crash in the middle of appending leaves behind, it was
+/// never synced so it is left out. A bad record followed by more records means the file is corrupt, dropping
+/// everything after it could lose committed entries. The length of a record has its own checksum, so a corrupted
+/// length is reported as corruption instead of making the record look like it runs past the end of the file.
+fn split_log_file<'a>(
+    path: &Path,
+    bytes: &'a [u8],
+) -> Result<(Vec<LogFrame<'a>>, u64), PersistentStorageError> {
+    if bytes.len() < LOG_FILE_HEADER_LEN as usize {
+        return Err(corrupt_log_file(
+            path,
+            format!("Log file is truncated, only {} bytes long", bytes.len()),
+        ));
+    }
+    if bytes[0..4] != LOG_FILE_MAGIC {
+        return Err(corrupt_log_file(
+            path,
+            format!("Log file has invalid magic number {:?}", &bytes[0..4]),
+        ));
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != LOG_FILE_VERSION {
+        return Err(corrupt_log_file(
+            path,
+            format!(
+                "Log file has unsupported format version {} (expected {})",
+                version, LOG_FILE_VERSION
+            ),
+        ));
+    }
+
+    let mut frames = vec![];
+    let mut offset = LOG_FILE_HEADER_LEN as usize;
+    while offset < bytes.len() {
+        let record = &bytes[offset..];
+        let header = match record.get(..LOG_RECORD_HEADER_LEN) {
+            Some(header) => header,
+            None => break,
+        };
+        let expected_len_crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let actual_len_crc = crc32fast::hash(&header[0..4]);
+        if actual_len_crc != expected_len_crc {
+            return Err(corrupt_log_file(
+                path,
+                format!(
+                    "Log record at offset {} has length checksum {:#010x} but was {:#010x}",
+                    offset, expected_len_crc, actual_len_crc
+                ),
+            ));
+        }
+        let payload_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let record_len = LOG_RECORD_HEADER_LEN + payload_len;
+        // The length is intact, so a record running past the end of the file was only partly appended
+        let payload = match record.get(LOG_RECORD_HEADER_LEN..record_len) {
+            Some(payload) => payload,
+            None => break,
+        };
+        let expected_crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        let actual_crc = crc32fast::hash(payload);
+        if actual_crc != expected_crc {
+            if offset + record_len == bytes.len() {
+                break;
+            }
+            return Err(corrupt_log_file(
+                path,
+                format!(
+                    "Log record at offset {} has checksum {:#010x} but was {:#010x}",
+                    offset, expected_crc, actual_crc
+                ),
+            ));
+        }
+        frames.push(LogFrame {
+            offset: offset as u64,
+            checksum: expected_crc,
+            bytes: &record[..record_len],
+        });
+        offset += record_len;
+    }
+    Ok((frames, offset.min(bytes.len()) as u64))
+}
+
+/// The start of a `LogRecord`, decoded without the command so the log file can be read without knowing its type
+#[derive(Debug, Deserialize)]
+enum LogRecordHeader {
+    Entry { index: u64, term: u64 },
+    HardState(Election),
+    Compacted { index: u64, term: u64 },
+}
+
+fn decode_log_record_header(
+    path: &Path,
+    frame: &LogFrame,
+) -> Result<LogRecordHeader, PersistentStorageError> {
+    // Unlike the bincode options of the election file, this allows the command to follow the header
+    bincode::deserialize(frame.payload())
+        .map_err(|e| corrupt_log_file(path, format!("Bincode error: {:?}", e)))
+}
+
+/// Position an entry read back from the log file goes to in the log read before it. Raft only ever appends the entry
+/// after the last one, or replaces conflicting entries, so any other index means the file is corrupt. The first entry
+/// of a compacted log follows the last compacted entry.
+fn replay_position(
+    path: &Path,
+    frame: &LogFrame,
+    compacted_index: Option<u64>,
+    first_index: Option<LogIndex>,
+    len: usize,
+    index: u64,
+) -> Result<usize, PersistentStorageError> {
+    let first_index = match (first_index, compacted_index) {
+        (Some(first_index), _) => first_index.0,
+        (None, None) => return Ok(0),
+        (None, Some(compacted_index)) if index == compacted_index + 1 => return Ok(0),
+        (None, Some(compacted_index)) => {
+            return Err(corrupt_log_file(
+                path,
+                format!(
+                    "Log entry {} at offset {} does not follow the compacted entries up to {}",
+                    index, frame.offset, compacted_index
+                ),
+            ))
+        }
+    };
+    let last_index = first_index + len as u64 - 1;
+    if index < first_index || index > last_index + 1 {
+        return Err(corrupt_log_file(
+            path,
+            format!(
+                "Log entry {} at offset {} does not follow entries {} to {}",
+                index, frame.offset, first_index, last_index
+            ),
+        ));
+    }
+    Ok((index - first_index) as usize)
+}
+
+/// The log and hard state read back from a log file
+struct DecodedLog<C: LogCommand> {
+    entries: Vec<LogEntry<C>>,
+    /// The last hard state written to the log file, if any was
+    election: Option<Election>,
+    /// Index and term of the last entry dropped when the log was compacted
+    compacted_through: Option<(LogIndex, TermIndex)>,
+    /// Offset the last complete record ends at
+    end: u64,
+}
+
+/// Decodes every record of a log file and replays them, see `split_log_file` for which records are dropped
+fn decode_log_file<C: LogCommand + DeserializeOwned>(
+    path: &Path,
+    bytes: &[u8],
+) -> Result<DecodedLog<C>, PersistentStorageError> {
+    let (frames, end) = split_log_file(path, bytes)?;
+    let mut log = DecodedLog {
+        entries: vec![],
+        election: None,
+        compacted_through: None,
+        end,
+    };
+    for frame in &frames {
+        let record: LogRecord<Option<C>> = bincode::deserialize(frame.payload())
+            .map_err(|e| corrupt_log_file(path, format!("Bincode error: {:?}", e)))?;
+        match record {
+            LogRecord::Entry {
+                index,
+                term,
+                command,
+            } => {
+                let compacted_index = log.compacted_through.map(|(index, _)| index.0);
+                let first_index = log.entries.first().map(|entry| entry.index);
+                let position = replay_position(
+                    path,
+                    frame,
+                    compacted_index,
+                    first_index,
+                    log.entries.len(),
+                    index,
+                )?;
+                log.entries.truncate(position);
+                log.entries.push(LogEntry {
+                    index: LogIndex(index),
+                    term: TermIndex(term),
+                    command,
+                });
+            }
+            LogRecord::HardState(election) => log.election = Some(election),
+            LogRecord::Compacted { index, term } => {
+                log.entries.clear();
+                log.compacted_through = Some((LogIndex(index), TermIndex(term)));
+            }
+        }
+    }
+    Ok(log)
+}
+
+/// A record of the log file in a `DefaultPersistentStorage` directory, see `inspect_log_file`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecordInfo {
+    /// Offset of the record in the log file.
+    pub offset: u64,
+    /// Size of the record in bytes, including its header.
+    pub size: u64,
+    /// CRC32 of the record, already verified against the file contents.
+    pub checksum: u32,
+    pub kind: LogRecordKind,
+}
+
+/// What a record of the log file holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogRecordKind {
+    /// A log entry, it replaces the entries at its index and after it that were written before it.
+    Entry { index: LogIndex, term: TermIndex },
+    /// The term and vote, written by `DefaultPersistentStorage::with_hard_state_in_log` and when the log is compacted.
+    HardState {
+        current_term: TermIndex,
+        voted_for: Option<(TermIndex, ServerId)>,
+    },
+    /// The last entry dropped when the log was compacted, the entries before it were dropped as well.
+    Compacted { index: LogIndex, term: TermIndex },
+}
+
+/// Contents of the log file in a `DefaultPersistentStorage` directory, see `inspect_log_file`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFileInfo {
+    /// Path of the log file.
+    pub path: PathBuf,
+    /// Size of the log file in bytes, including the header.
+    pub file_size: u64,
+    /// Every complete record in the order it was written, including entries replaced by later records.
+    pub records: Vec<LogRecordInfo>,
+    /// Index and term of every entry in the log once the records are replayed, ordered by index.
+    pub entries: Vec<(LogIndex, TermIndex)>,
+    /// Bytes after the last complete record. A crash while appending leaves these behind, the node drops them when
+    /// it starts.
+    pub torn_bytes: u64,
+}
+
+impl LogFileInfo {
+    /// The last term and vote written to the log file, if any were.
+    pub fn hard_state(&self) -> Option<(TermIndex, Option<(TermIndex, ServerId)>)> {
+        self.records
+            .iter()
+            .rev()
+            .find_map(|record| match record.kind {
+                LogRecordKind::HardState {
+                    current_term,
+                    voted_for,
+                } => Some((current_term, voted_for)),
+                LogRecordKind::Entry { .. } | LogRecordKind::Compacted { .. } => None,
+            })
+    }
+
+    /// Index and term of the last entry dropped when the log was compacted, if it was.
+    pub fn compacted_through(&self) -> Option<(LogIndex, TermIndex)> {
+        self.records
+            .iter()
+            .rev()
+            .find_map(|record| match record.kind {
+                LogRecordKind::Compacted { index, term } => Some((index, term)),
+                LogRecordKind::Entry { .. } | LogRecordKind::HardState { .. } => None,
+            })
+    }
+}
+
+fn read_log_file(log_path: &Path) -> Result<(PathBuf, Vec<u8>), PersistentStorageError> {
+    let path = log_path.join("log");
+    let bytes = fs::read(&path)
+        .map_err(|e| PersistentStorageError::transient(StorageOperation::Read, &path, e))?;
+    Ok((path, bytes))
+}
+
+/// Reads and verifies the checksum of every record of the log file in `log_path` without modifying anything or
+/// decoding the commands, for tools that inspect the storage of a node that is not running. Fails with a corruption
+/// error if the node would refuse to start with it.
+pub fn inspect_log_file(log_path: &Path) -> Result<LogFileInfo, PersistentStorageError> {
+    let (path, bytes) = read_log_file(log_path)?;
+    let (frames, end) = split_log_file(&path, &bytes)?;
+    let mut info = LogFileInfo {
+        file_size: bytes.len() as u64,
+        records: Vec::with_capacity(frames.len()),
+        entries: vec![],
+        torn_bytes: bytes.len() as u64 - end,
+        path: path.clone(),
+    };
+    for frame in &frames {
+        let kind = match decode_log_record_header(&path, frame)? {
+            LogRecordHeader::Entry { index, term } => {
+                let compacted_index = info.compacted_through().map(|(index, _)| index.0);
+                let first_index = info.entries.first().map(|(index, _)| *index);
+                let position = replay_position(
+                    &path,
+                    frame,
+                    compacted_index,
+                    first_index,
+                    info.entries.len(),
+                    index,
+                )?;
+                info.entries.truncate(position);
+                info.entries.push((LogIndex(index), TermIndex(term)));
+                LogRecordKind::Entry {
+                    index: LogIndex(index),
+                    term: TermIndex(term),
+                }
+            }
+            LogRecordHeader::HardState(election) => LogRecordKind::HardState {
+                current_term: election.current_term,
+                voted_for: election.voted_for,
+            },
+            LogRecordHeader::Compacted { index, term } => {
+                info.entries.clear();
+                LogRecordKind::Compacted {
+                    index: LogIndex(index),
+                    term: TermIndex(term),
+                }
+            }
+        };
+        info.records.push(LogRecordInfo {
+            offset: frame.offset,
+            size: frame.bytes.len() as u64,
+            checksum: frame.checksum,
+            kind,
+        });
+    }
+    Ok(info)
+}
+
+/// Contents of a `DefaultPersistentStorage` directory, see `inspect_default_storage`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultStorageInfo {
+    /// The election file, there is none if the storage was only ever opened with `with_hard_state_in_log`.
+    pub election_file: Option<ElectionFileInfo>,
+    pub log_file: LogFileInfo,
+    /// The term the node starts with, the newer of the election file's and the log file's.
+    pub current_term: TermIndex,
+    /// The last vote and the term it was cast in, which may be before the current term.
+    pub voted_for: Option<(TermIndex, ServerId)>,
+}
+
+/// Reads and verifies the election file and log file in `log_path` without modifying anything, and works out the term
+/// and vote the node starts with like `DefaultPersistentStorage` does. Fails with a corruption error if the node
+/// would refuse to start with them.
+pub fn inspect_default_storage(
+    log_path: &Path,
+) -> Result<DefaultStorageInfo, PersistentStorageError> {
+    let election_file = if log_path.join("election").exists() {
+        Some(inspect_election_file(log_path)?)
+    } else {
+        None
+    };
+    let log_file = inspect_log_file(log_path)?;
+
+    let file_election = election_file.as_ref().map(|info| Election {
+        current_term: info.current_term,
+        voted_for: info.voted_for,
+    });
+    let log_election = log_file
+        .hard_state()
+        .map(|(current_term, voted_for)| Election {
+            current_term,
+            voted_for,
+        });
+    let election = newer_election(file_election, log_election);
+    Ok(DefaultStorageInfo {
+        election_file,
+        log_file,
+        current_term: election.current_term,
+        voted_for: election.voted_for,
+    })
+}
+
+/// Removes every log entry after `index` from the log file in `log_path` of a node that is not running, returning how
+/// many were removed. The log file is rewritten with only the remaining entries, the last hard state and the point the
+/// log was compacted to, and replaced atomically. Entries that may have been committed must not be removed, the other servers could rely on them.
+pub fn truncate_log_after(log_path: &Path, index: LogIndex) -> Result<u64, PersistentStorageError> {
+    let (path, bytes) = read_log_file(log_path)?;
+    let (frames, _) = split_log_file(&path, &bytes)?;
+    let mut entries = vec![];
+    let mut entry_frames = vec![];
+    let mut hard_state_frame = None;
+    let mut compacted = None;
+    for frame in &frames {
+        match decode_log_record_header(&path, frame)? {
+            LogRecordHeader::Entry {
+                index: entry_index,
+                term,
+            } => {
+                let compacted_index = compacted.map(|(index, _)| index);
+                let first_index = entries.first().map(|(index, _)| *index);
+                let position = replay_position(
+                    &path,
+                    frame,
+                    compacted_index,
+                    first_index,
+                    entries.len(),
+                    entry_index,
+                )?;
+                entries.truncate(position);
+                entries.push((LogIndex(entry_index), TermIndex(term)));
+                entry_frames.truncate(position);
+                entry_frames.push(frame);
+            }
+            LogRecordHeader::HardState(_) => hard_state_frame = Some(frame),
+            LogRecordHeader::Compacted { index, .. } => {
+                entries.clear();
+                entry_frames.clear();
+                compacted = Some((index, frame));
+            }
+        }
+    }
+
+    let kept = entries
+        .iter()
+        .take_while(|(entry_index, _)| *entry_index <= index)
+        .count();
+    let removed = (entries.len() - kept) as u64;
+    if removed == 0 {
+        return Ok(0);
+    }
+    // The records are copied as they are, so the commands don't need to be decoded
+    let mut new_bytes = bytes[..LOG_FILE_HEADER_LEN as usize].to_vec();
+    for frame in compacted
+        .map(|(_, frame)| frame)
+        .into_iter()
+        .chain(hard_state_frame)
+        .chain(entry_frames[..kept].iter().copied())
+    {
+        new_bytes.extend_from_slice(frame.bytes);
+    }
+    replace_file(&new_bytes, &path)?;
+    Ok(removed)
+}
+
+/// Atomically replaces the file at `path`, the new contents are written and fsynced to a temp file which is
+/// then renamed over the file. A crash at any point leaves either the old or the new contents on
+/// disk, never a mix of both.
+fn replace_file(bytes: &[u8], path: &Path) -> Result<(), PersistentStorageError> {
+    let temp_path = path.with_extension("tmp");
+    let mut temp_file = maybe!(File::create(&temp_path))
+        .map_err(|e| PersistentStorageError::transient(StorageOperation::Open, &temp_path, e))?;
+    maybe!(temp_file.write_all(bytes))
+        .map_err(|e| PersistentStorageError::transient(StorageOperation::Write, &temp_path, e))?;
+    maybe!(temp_file.sync_data())
+        .map_err(|e| PersistentStorageError::transient(StorageOperation::Sync, &temp_path, e))?;
+
+    maybe!(fs::rename(&temp_path, path))
+        .map_err(|e| PersistentStorageError::transient(StorageOperation::Write, path, e))?;
+    // The rename is only durable once the directory entry has been synced
+    if let Some(log_dir) = path.parent() {
+        maybe!(File::open(log_dir).and_then(|dir| dir.sync_all()))
+            .map_err(|e| PersistentStorageError::transient(StorageOperation::Sync, log_dir, e))?;
+    }
+    Ok(())
+}
+
 /// WAL, should only be used from one thread
+///
+/// The log is kept in a log file that records are only ever appended to. Entries removed because they conflict with
+/// the leader's stay in the file, the records of the entries that replace them are written after them and win when
+/// the file is read back.
+///
+/// Where the term and vote go depends on how the storage was opened. With `new` they are kept in an election file
+/// that is replaced atomically before the log file is written, so a `sync` that changes both is two durable writes and
+/// a crash between them can leave the new term or vote on disk without the entries appended with it. That is safe
+/// for raft, which never relies on entries it has not acknowledged, but it costs two fsyncs. With
+/// `with_hard_state_in_log` they are appended to the log file as a record, and a `sync` makes them durable together
+/// with the entries in a single write and fsync.
+///
+/// Compacting the log rewrites the log file without the compacted entries and replaces it atomically on the next
+/// `sync`, the new file starts with the point the log was compacted to and the term and vote.
 #[derive(Debug)]
 pub struct DefaultPersistentStorage<C: LogCommand> {
     election: Election,
-    election_writer: BufWriter<File>,
-    /// This is a placeholder so we can use the generic type parameters C:LogCommand
-    /// We will need this type param later when we implement the WAL
-    placeholder: PhantomData<C>,
+    election_path: PathBuf,
+    /// True if the election state has changed since it was last written to disk
+    election_dirty: bool,
+    /// True if the election state is written to the log file instead of the election file
+    hard_state_in_log: bool,
+    /// The whole log, the entries are kept in memory and only written to the log file
+    log: Vec<LogEntry<C>>,
+    log_path: PathBuf,
+    log_file: File,
+    /// Number of entries at the start of `log` whose records are in the log file, the entries after them are written
+    /// on the next sync
+    synced_entries: usize,
+    /// Offset the last complete record in the log file ends at
+    log_file_len: u64,
+    /// True if the last write to the log file failed, the file may continue with part of a record after `log_file_len`
+    log_file_torn: bool,
+    /// Index and term of the last entry dropped from the log by `compact`
+    compacted_through: Option<(LogIndex, TermIndex)>,
+    /// True if the log was compacted since the log file was last rewritten
+    compaction_pending: bool,
 }
-impl<C: LogCommand> DefaultPersistentStorage<C> {
-    pub fn new(log_path: &Path) -> Self {
-        // TODO: Currently we only care about persisting election data, will eventually need WAL
-        let (election, election_writer) = Self::open_election_file(log_path);
+impl<C: LogCommand + Serialize + DeserializeOwned> DefaultPersistentStorage<C> {
+    /// Opens the election state and log stored in `log_path`, creating them if they do not exist yet. Fails with a
+    /// corruption error if the election file exists but its header or checksum is invalid, starting with
+    /// a bad term or vote could break election safety. A log file that is corrupt anywhere but in the record that
+    /// was being appended when the node stopped fails the same way.
+    pub fn new(log_path: &Path) -> Result<Self, PersistentStorageError> {
+        Self::open(log_path, false)
+    }
 
-        DefaultPersistentStorage {
+    /// Opens the storage like `new`, but writes the term and vote to the log file instead of the election file, so
+    /// every `sync` is a single durable write. A directory written by `new` can be opened with this and the other way
+    /// around.
+    pub fn with_hard_state_in_log(log_path: &Path) -> Result<Self, PersistentStorageError> {
+        Self::open(log_path, true)
+    }
+
+    fn open(log_path: &Path, hard_state_in_log: bool) -> Result<Self, PersistentStorageError> {
+        let election_path = log_path.join("election");
+        let file_election = Self::open_election_file(&election_path, !hard_state_in_log)?;
+        let log_path = log_path.join("log");
+        let (log_file, log) = Self::open_log_file(&log_path)?;
+        let election = newer_election(file_election, log.election);
+        Ok(DefaultPersistentStorage {
             election,
-            election_writer,
-            placeholder: PhantomData,
-        }
-    }
-
-    fn open_election_file(log_path: &Path) -> (Election, BufWriter<File>) {
-        let file_size: usize = mem::size_of::<Election>();
-        let election_file_exists = log_path.join("election").exists();
-        let (reader, mut writer) = maybe!(File::options()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(log_path.join("election"))
-            .and_then(|f| f.set_len(file_size as u64).map(|_| f))
-            .and_then(|f| {
-                f.try_clone()
-                    .map(|f_cloned| (BufReader::new(f), BufWriter::new(f_cloned)))
-            }))
-        .expect(
-            format!(
-                "OPEN ELEC FILE: Could not open election file {:?} and set file size!",
-                log_path
-            )
-            .as_str(),
-        );
-
-        if election_file_exists {
-            let header = get_election_bincode()
-                .deserialize_from(reader)
-                .expect("OPEN ELEC FILE: Could not deserialize election file!");
-            (header, writer)
+            election_path,
+            election_dirty: false,
+            hard_state_in_log,
+            synced_entries: log.entries.len(),
+            log: log.entries,
+            log_path,
+            log_file,
+            log_file_len: log.end,
+            log_file_torn: false,
+            compacted_through: log.compacted_through,
+            compaction_pending: false,
+        })
+    }
+
+    /// Reads the election file, `None` if there is none. If `create` is set a missing file is created.
+    fn open_election_file(
+        election_path: &Path,
+        create: bool,
+    ) -> Result<Option<Election>, PersistentStorageError> {
+        if election_path.exists() {
+            let bytes = maybe!(fs::read(election_path)).map_err(|e| {
+                PersistentStorageError::transient(StorageOperation::Read, election_path, e)
+            })?;
+            decode_election_file(election_path, &bytes).map(Some)
+        } else if create {
+            let election = Election::default();
+            let bytes = encode_election_file(&election).map_err(|e| {
+                PersistentStorageError::transient(StorageOperation::Write, election_path, e)
+            })?;
+            replace_file(&bytes, election_path)?;
+            Ok(Some(election))
         } else {
-            let election = Election {
-                current_term: TermIndex(0),
-                voted_for: None,
+            Ok(None)
+        }
+    }
+
+    /// Opens the log file, creating an empty one if it does not exist yet, and cuts a record that was torn by a
+    /// crash off its end.
+    fn open_log_file(log_path: &Path) -> Result<(File, DecodedLog<C>), PersistentStorageError> {
+        if !log_path.exists() {
+            // A crash while creating the log file must not leave a file without a header behind
+            replace_file(&log_file_header(), log_path)?;
+        }
+
+        let mut log_file = Self::open_log_file_for_writing(log_path)?;
+        let mut bytes = vec![];
+        maybe!(log_file.read_to_end(&mut bytes))
+            .map_err(|e| PersistentStorageError::transient(StorageOperation::Read, log_path, e))?;
+        let log = decode_log_file(log_path, &bytes)?;
+
+        if log.end < bytes.len() as u64 {
+            maybe!(log_file.set_len(log.end)).map_err(|e| {
+                PersistentStorageError::transient(StorageOperation::Write, log_path, e)
+            })?;
+            maybe!(log_file.sync_data()).map_err(|e| {
+                PersistentStorageError::transient(StorageOperation::Sync, log_path, e)
+            })?;
+        }
+        Ok((log_file, log))
+    }
+
+    fn open_log_file_for_writing(log_path: &Path) -> Result<File, PersistentStorageError> {
+        maybe!(OpenOptions::new().read(true).write(true).open(log_path))
+            .map_err(|e| PersistentStorageError::transient(StorageOperation::Open, log_path, e))
+    }
+
+    /// Replaces the log file with one that holds the point the log was compacted to, the term and vote and the entries
+    /// that are left. A crash before the new file is in place leaves the old one, which still has every entry.
+    fn rewrite_log_file(&mut self) -> Result<(), PersistentStorageError> {
+        let write_error =
+            |e| PersistentStorageError::transient(StorageOperation::Write, &self.log_path, e);
+        let mut bytes = log_file_header();
+        if let Some((index, term)) = self.compacted_through {
+            let record = LogRecord::<Option<&C>>::Compacted {
+                index: index.0,
+                term: term.0,
+            };
+            bytes.extend_from_slice(&encode_log_record(&record).map_err(write_error)?);
+        }
+        // The hard state may only be in the old log file, even if it is kept in the election file now
+        let record = LogRecord::<Option<&C>>::HardState(self.election);
+        bytes.extend_from_slice(&encode_log_record(&record).map_err(write_error)?);
+        for entry in &self.log {
+            let record = LogRecord::Entry {
+                index: entry.index.0,
+                term: entry.term.0,
+                command: entry.command.as_ref(),
+            };
+            bytes.extend_from_slice(&encode_log_record(&record).map_err(write_error)?);
+        }
+        replace_file(&bytes, &self.log_path)?;
+
+        self.log_file = Self::open_log_file_for_writing(&self.log_path)?;
+        self.log_file_len = bytes.len() as u64;
+        self.log_file_torn = false;
+        self.synced_entries = self.log.len();
+        self.compaction_pending = false;
+        if self.hard_state_in_log {
+            self.election_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Appends the records of the entries that were added since the last sync, and of the hard state if it is kept
+    /// in the log and has changed, to the log file in one write and fsyncs it.
+    fn write_log_changes(&mut self) -> Result<(), PersistentStorageError> {
+        let write_hard_state = self.hard_state_in_log && self.election_dirty;
+        if !write_hard_state && self.synced_entries == self.log.len() {
+            return Ok(());
+        }
+        let write_error =
+            |e| PersistentStorageError::transient(StorageOperation::Write, &self.log_path, e);
+
+        if self.log_file_torn {
+            // Cut off what the failed write left behind, so the new records don't follow part of a record
+            maybe!(self.log_file.set_len(self.log_file_len)).map_err(write_error)?;
+            self.log_file_torn = false;
+        }
+
+        let mut bytes = vec![];
+        if write_hard_state {
+            let record = LogRecord::<Option<&C>>::HardState(self.election);
+            bytes.extend_from_slice(&encode_log_record(&record).map_err(write_error)?);
+        }
+        for entry in &self.log[self.synced_entries..] {
+            let record = LogRecord::Entry {
+                index: entry.index.0,
+                term: entry.term.0,
+                command: entry.command.as_ref(),
             };
-            Self::write_election_state(&election, &mut writer)
-                .expect("OPEN ELEC FILE: Could not write initial state to election file!");
-            maybe!(writer.flush()).expect("OPEN ELEC FILE: Could not fsync header to WAL!");
-            (election, writer)
-        }
-    }
-
-    fn write_election_state(
-        election: &Election,
-        election_writer: &mut BufWriter<File>,
-    ) -> Result<(), PersistentStorageError> {
-        maybe!(election_writer.rewind()).map_err(|_| PersistentStorageError::IoError)?;
-        maybe!(get_election_bincode()
-            .serialize_into(election_writer, election)
-            .map_err(bincode_to_io_error))
-        .map_err(|_| PersistentStorageError::IoError)?;
+            bytes.extend_from_slice(&encode_log_record(&record).map_err(write_error)?);
+        }
+        self.log_file_torn = true;
+        maybe!(self.log_file.seek(SeekFrom::Start(self.log_file_len))).map_err(write_error)?;
+        maybe!(self.log_file.write_all(&bytes)).map_err(write_error)?;
+        maybe!(self.log_file.sync_data()).map_err(|e| {
+            PersistentStorageError::transient(StorageOperation::Sync, &self.log_path, e)
+        })?;
+        self.log_file_torn = false;
+        self.log_file_len += bytes.len() as u64;
+        self.synced_entries = self.log.len();
+        if write_hard_state {
+            self.election_dirty = false;
+        }
         Ok(())
     }
+
+    /// Position of the entry at `index` in the log, the log has no gaps so this doesn't need to search for it
+    fn position(&self, index: LogIndex) -> Option<usize> {
+        let first_index = self.log.first()?.index;
+        let position = usize::try_from(index.0.checked_sub(first_index.0)?).ok()?;
+        (position < self.log.len()).then_some(position)
+    }
 }
 
-impl<C: LogCommand> PersistentStorage<C> for DefaultPersistentStorage<C> {
+impl<C: LogCommand + Serialize + DeserializeOwned> PersistentStorage<C>
+    for DefaultPersistentStorage<C>
+{
     fn vote_for_current_term(&self) -> Option<ServerId> {
-        self.election
-            .voted_for
-            .and_then(|(last_vote_term, server_id)| {
-                if last_vote_term == self.election.current_term {
-                    Some(server_id)
-                } else {
-                    None
-                }
-            })
+        self.election.vote_for_current_term()
     }
 
     fn update_term(&mut self, term: TermIndex) -> &mut Self {
         self.election.current_term = term;
+        self.election_dirty = true;
         self
     }
 
     fn record_vote(&mut self, voted_for: ServerId) -> &mut Self {
         self.election.voted_for = Some((self.current_term(), voted_for));
+        self.election_dirty = true;
         self
     }
 
     fn sync(&mut self) -> Result<(), PersistentStorageError> {
-        Self::write_election_state(&self.election, &mut self.election_writer)?;
-        maybe!(self.election_writer.flush()).map_err(|_| PersistentStorageError::IoError)t
+        // The raft thread syncs after every message it handles, most of which don't change the term or vote
+        if self.election_dirty && !self.hard_state_in_log {
+            let bytes = encode_election_file(&self.election).map_err(|e| {
+                PersistentStorageError::transient(StorageOperation::Write, &self.election_path, e)
+            })?;
+            replace_file(&bytes, &self.election_path)?;
+            self.election_dirty = false;
+        }
+        if self.compaction_pending {
+            self.rewrite_log_file()
+        } else {
+            self.write_log_changes()
+        }
     }
 
     fn current_term(&self) -> TermIndex {
@@ -154,15 +1004,81 @@ impl<C: LogCommand> PersistentStorage<C> for DefaultPersistentStorage<C> {
     }
 
     fn last_entry_index(&self) -> Option<LogIndex> {
-        todo!()
+        self.log
+            .last()
+            .map(|entry| entry.index)
+            .or(self.compacted_through.map(|(index, _)| index))
+    }
+
+    fn last_entry_term(&self) -> Option<TermIndex> {
+        self.log
+            .last()
+            .map(|entry| entry.term)
+            .or(self.compacted_through.map(|(_, term)| term))
+    }
+
+    fn entry_term(&self, index: LogIndex) -> Option<TermIndex> {
+        match self.compacted_through {
+            Some((compacted_index, term)) if compacted_index == index => Some(term),
+            _ => self.position(index).map(|position| self.log[position].term),
+        }
     }
 
     /// Checks if there is a log entry with matching log index & log term
-    fn has_entry(self, _index: LogIndex, _term: TermIndex) -> bool {
-        todo!()
+    fn has_entry(&self, index: LogIndex, term: TermIndex) -> bool {
+        self.entry_term(index) == Some(term)
     }
+
+    fn entries(
+        &self,
+        from: LogIndex,
+        max_entries: usize,
+    ) -> Result<Vec<LogEntry<C>>, PersistentStorageError> {
+        let start = match self.log.first() {
+            Some(first) if from < first.index => 0,
+            _ => match self.position(from) {
+                Some(position) => position,
+                None => return Ok(vec![]),
+            },
+        };
+        Ok(self.log[start..]
+            .iter()
+            .take(max_entries)
+            .cloned()
+            .collect())
+    }
+
     /// Appends new entries to log, first deleting any conflicting entries (same index but different terms)
-    fn append(&mut self, _entries: Vec<LogEntry<C>>) -> &mut Self {
-        todo!()
+    fn append(&mut self, entries: Vec<LogEntry<C>>) -> &mut Self {
+        let compacted_index = self.compacted_through.map(|(index, _)| index);
+        // Compacted entries were committed, the leader's are the same
+        for entry in entries
+            .into_iter()
+            .filter(|entry| Some(entry.index) > compacted_index)
+        {
+            if let Some(position) = self.position(entry.index) {
+                if self.log[position].term == entry.term {
+                    continue;
+                }
+                // Their records stay in the log file, the records written for the entries replacing them win
+                self.log.truncate(position);
+                self.synced_entries = self.synced_entries.min(position);
+            }
+            self.log.push(entry);
+        }
+        self
+    }
+    fn compacted_through(&self) -> Option<(LogIndex, TermIndex)> {
+        self.compacted_through
+    }
+
+    fn compact(&mut self, index: LogIndex) -> &mut Self {
+        if let Some(position) = self.position(index) {
+            self.compacted_through = Some((index, self.log[position].term));
+            let _ = self.log.drain(..=position);
+            self.synced_entries = self.synced_entries.saturating_sub(position + 1);
+            self.compaction_pending = true;
+        }
+        self
     }
 }
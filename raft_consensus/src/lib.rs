@@ -26,7 +26,13 @@
 )]
 mod common;
 mod default_storage;
+mod memory_storage;
+mod metrics;
+mod raft_events;
 mod raft_thread;
+mod raw_node;
+#[cfg(feature = "redb_storage")]
+mod redb_storage;
 pub mod rpc_messages;
 mod state_machine;
 pub mod system_clock;
@@ -39,9 +45,42 @@ pub use common::RaftConfig;
 pub use common::ServerId;
 pub use common::TermIndex;
 pub use common::*;
+pub use default_storage::inspect_election_file;
+pub use default_storage::DefaultPersistentStorage;
+pub use default_storage::ElectionFileInfo;
+pub use default_storage::{
+    inspect_default_storage, inspect_log_file, truncate_log_after, DefaultStorageInfo, LogFileInfo,
+    LogRecordInfo, LogRecordKind,
+};
+pub use memory_storage::MemoryStorage;
+#[cfg(feature = "metrics")]
+pub use metrics::{encode_metrics, METRICS_CONTENT_TYPE};
+pub use raft_events::NoOpRaftEventCollector;
+pub use raft_events::RaftEvent;
+pub use raft_events::RaftEventKind;
+pub use raft_events::RaftStateEventCollector;
+pub use raft_events::RaftTransition;
+pub use raft_events::SteppedDownReason;
 pub use raft_thread::start_raft_in_new_thread;
-pub use raft_thread::NoOpRaftEventCollector;
+pub use raft_thread::FollowerStatus;
+pub use raft_thread::ProposalResult;
+pub use raft_thread::RaftNodeHandle;
+pub use raft_thread::RaftNodeSetup;
 pub use raft_thread::RaftNodeState;
+pub use raft_thread::RaftNodeStatus;
+pub use raft_thread::RaftNodeStatusReader;
+pub use raft_thread::RaftProposer;
+pub use raft_thread::RaftShutdownStatus;
 pub use raft_thread::RaftStateEvent;
-pub use raft_thread::RaftStateEventCollector;
+pub use raft_thread::RaftThreadExit;
+pub use raft_thread::ReadIndexResult;
+pub use raw_node::ProposeError;
+pub use raw_node::RawNode;
+pub use raw_node::ReadState;
+pub use raw_node::Ready;
+#[cfg(feature = "redb_storage")]
+pub use redb_storage::{
+    inspect_redb_storage, truncate_redb_log_after, LogEntryInfo, RedbPersistentStorage,
+    RedbStorageInfo, SnapshotMetadata,
+};
 pub use rpc_messages::*;
@@ -0,0 +1,262 @@
+use crate::{PersistentStorageError, StorageOperation};
+
+use super::common::{LogCommand, LogEntry, LogIndex, PersistentStorage, ServerId, TermIndex};
+use fault_injection::maybe;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Reported as the path of errors from a memory storage, there is no file
+const MEMORY_STORAGE_PATH: &str = "<memory>";
+
+#[derive(Debug, Clone)]
+struct MemoryState<C: LogCommand> {
+    current_term: TermIndex,
+    voted_for: Option<(TermIndex, ServerId)>,
+    log: Vec<LogEntry<C>>,
+    compacted_through: Option<(LogIndex, TermIndex)>,
+}
+impl<C: LogCommand> MemoryState<C> {
+    /// Position of the entry at `index` in the log, the entries' indexes are consecutive
+    fn position(&self, index: LogIndex) -> Option<usize> {
+        let first_index = self.log.first()?.index;
+        let position = usize::try_from(index.0.checked_sub(first_index.0)?).ok()?;
+        (position < self.log.len()).then_some(position)
+    }
+}
+
+/// Persistent storage that keeps everything in memory, for tests and for embedding raft where durability is handled
+/// elsewhere. State that has been "persisted" is shared between a storage and the storages created from it by `restart`,
+/// so a raft node can be restarted without losing its term/vote.
+///
+/// With crash simulation enabled only state that has been `sync`ed survives a restart, which models a node that crashes
+/// before its fsync completes.
+#[derive(Debug)]
+pub struct MemoryStorage<C: LogCommand> {
+    state: MemoryState<C>,
+    durable_state: Arc<Mutex<MemoryState<C>>>,
+    discard_unsynced_on_restart: bool,
+    /// Term, vote or compaction changed since the state was last persisted
+    hard_state_changed: bool,
+    /// Index of the first log entry appended or truncated since the state was last persisted
+    first_changed_index: Option<LogIndex>,
+}
+impl<C: LogCommand> MemoryStorage<C> {
+    /// Creates an empty storage, every write is kept across restarts even if it was not synced.
+    pub fn new() -> Self {
+        let state = MemoryState {
+            current_term: TermIndex(0),
+            voted_for: None,
+            log: vec![],
+            compacted_through: None,
+        };
+        MemoryStorage {
+            durable_state: Arc::new(Mutex::new(state.clone())),
+            state,
+            discard_unsynced_on_restart: false,
+            hard_state_changed: false,
+            first_changed_index: None,
+        }
+    }
+
+    /// Creates an empty storage that simulates crashes, writes that were not synced are lost on restart.
+    pub fn with_crash_simulation() -> Self {
+        MemoryStorage {
+            discard_unsynced_on_restart: true,
+            ..Self::new()
+        }
+    }
+
+    /// Creates a new storage for a restarted node, it sees everything that was persisted by this storage (and only
+    /// what was synced if crash simulation is enabled).
+    pub fn restart(&self) -> Self {
+        let durable_state = self
+            .durable_state
+            .lock()
+            .expect("MEM STORAGE: Durable state lock poisoned!");
+        MemoryStorage {
+            state: durable_state.clone(),
+            durable_state: self.durable_state.clone(),
+            discard_unsynced_on_restart: self.discard_unsynced_on_restart,
+            hard_state_changed: false,
+            first_changed_index: None,
+        }
+    }
+
+    /// Copies what changed since the last time to the persisted state, the rest of the log is already there
+    fn persist(&mut self) {
+        if !self.hard_state_changed && self.first_changed_index.is_none() {
+            return;
+        }
+        let mut durable_state = self
+            .durable_state
+            .lock()
+            .expect("MEM STORAGE: Durable state lock poisoned!");
+        durable_state.current_term = self.state.current_term;
+        durable_state.voted_for = self.state.voted_for;
+        if durable_state.compacted_through != self.state.compacted_through {
+            durable_state.compacted_through = self.state.compacted_through;
+            if let Some((compacted_index, _)) = self.state.compacted_through {
+                let compacted = durable_state
+                    .log
+                    .partition_point(|entry| entry.index <= compacted_index);
+                let _ = durable_state.log.drain(..compacted);
+            }
+        }
+        if let Some(first_changed_index) = self.first_changed_index.take() {
+            let unchanged = durable_state
+                .log
+                .partition_point(|entry| entry.index < first_changed_index);
+            durable_state.log.truncate(unchanged);
+            let changed = self
+                .state
+                .log
+                .partition_point(|entry| entry.index < first_changed_index);
+            durable_state
+                .log
+                .extend_from_slice(&self.state.log[changed..]);
+        }
+        self.hard_state_changed = false;
+    }
+
+    fn log_changed_from(&mut self, index: LogIndex) {
+        self.first_changed_index = Some(
+            self.first_changed_index
+                .map_or(index, |first_changed_index| first_changed_index.min(index)),
+        );
+    }
+
+    fn persist_unless_simulating_crashes(&mut self) {
+        if !self.discard_unsynced_on_restart {
+            self.persist();
+        }
+    }
+}
+impl<C: LogCommand> Default for MemoryStorage<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: LogCommand> PersistentStorage<C> for MemoryStorage<C> {
+    fn current_term(&self) -> TermIndex {
+        self.state.current_term
+    }
+
+    fn vote_for_current_term(&self) -> Option<ServerId> {
+        self.state
+            .voted_for
+            .and_then(|(last_vote_term, server_id)| {
+                if last_vote_term == self.state.current_term {
+                    Some(server_id)
+                } else {
+                    None
+                }
+            })
+    }
+
+    fn update_term(&mut self, term: TermIndex) -> &mut Self {
+        self.state.current_term = term;
+        self.hard_state_changed = true;
+        self.persist_unless_simulating_crashes();
+        self
+    }
+
+    fn record_vote(&mut self, voted_for: ServerId) -> &mut Self {
+        self.state.voted_for = Some((self.state.current_term, voted_for));
+        self.hard_state_changed = true;
+        self.persist_unless_simulating_crashes();
+        self
+    }
+
+    fn last_entry_index(&self) -> Option<LogIndex> {
+        self.state
+            .log
+            .last()
+            .map(|entry| entry.index)
+            .or(self.state.compacted_through.map(|(index, _)| index))
+    }
+
+    fn last_entry_term(&self) -> Option<TermIndex> {
+        self.state
+            .log
+            .last()
+            .map(|entry| entry.term)
+            .or(self.state.compacted_through.map(|(_, term)| term))
+    }
+
+    fn entry_term(&self, index: LogIndex) -> Option<TermIndex> {
+        match self.state.compacted_through {
+            Some((compacted_index, term)) if compacted_index == index => Some(term),
+            _ => self
+                .state
+                .position(index)
+                .map(|position| self.state.log[position].term),
+        }
+    }
+
+    /// Checks if there is a log entry with matching log index & log term
+    fn has_entry(&self, index: LogIndex, term: TermIndex) -> bool {
+        self.entry_term(index) == Some(term)
+    }
+
+    fn entries(
+        &self,
+        from: LogIndex,
+        max_entries: usize,
+    ) -> Result<Vec<LogEntry<C>>, PersistentStorageError> {
+        let start = self.state.log.partition_point(|entry| entry.index < from);
+        Ok(self
+            .state
+            .log
+            .iter()
+            .skip(start)
+            .take(max_entries)
+            .cloned()
+            .collect())
+    }
+
+    /// Appends new entries to log, first deleting any conflicting entries (same index but different terms)
+    fn append(&mut self, entries: Vec<LogEntry<C>>) -> &mut Self {
+        let compacted_index = self.state.compacted_through.map(|(index, _)| index);
+        // Compacted entries were committed, the leader's are the same
+        for entry in entries
+            .into_iter()
+            .filter(|entry| Some(entry.index) > compacted_index)
+        {
+            if let Some(position) = self.state.position(entry.index) {
+                if self.state.log[position].term == entry.term {
+                    continue;
+                }
+                self.state.log.truncate(position);
+            }
+            self.log_changed_from(entry.index);
+            self.state.log.push(entry);
+        }
+        self.persist_unless_simulating_crashes();
+        self
+    }
+
+    fn compacted_through(&self) -> Option<(LogIndex, TermIndex)> {
+        self.state.compacted_through
+    }
+
+    fn compact(&mut self, index: LogIndex) -> &mut Self {
+        if let Some(position) = self.state.position(index) {
+            self.state.compacted_through = Some((index, self.state.log[position].term));
+            let _ = self.state.log.drain(..=position);
+            self.hard_state_changed = true;
+            self.persist_unless_simulating_crashes();
+        }
+        self
+    }
+
+    /// Fails like an fsync when IO faults are injected, in which case nothing written since the last successful sync
+    /// survives a simulated crash.
+    fn sync(&mut self) -> Result<(), PersistentStorageError> {
+        maybe!(Ok::<(), io::Error>(())).map_err(|e| {
+            PersistentStorageError::transient(StorageOperation::Sync, MEMORY_STORAGE_PATH, e)
+        })?;
+        self.persist();
+        Ok(())
+    }
+}
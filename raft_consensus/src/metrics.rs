@@ -0,0 +1,216 @@
+//! Prometheus metrics for the raft thread and state machine, only collected when the `metrics` feature is enabled.
+//! Without the feature every function here is a no-op so call sites don't need their own `#[cfg]`s.
+//!
+//! Metrics are registered in the prometheus default registry and labelled with the server ID, so several nodes can run
+//! in the same process (i.e. in the simulator).
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+use crate::common::{LogCommand, ServerId};
+use crate::raft_thread::RaftNodeStatus;
+use crate::rpc_messages::RpcMessage;
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+use crate::common::LogIndex;
+#[cfg(feature = "metrics")]
+use crate::system_clock;
+#[cfg(feature = "metrics")]
+use crate::system_clock::Instant;
+#[cfg(feature = "metrics")]
+use lazy_static::lazy_static;
+#[cfg(feature = "metrics")]
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec,
+    IntCounterVec, TextEncoder,
+};
+#[cfg(feature = "metrics")]
+use std::collections::VecDeque;
+
+/// Content type of the text returned by `encode_metrics`
+#[cfg(feature = "metrics")]
+pub const METRICS_CONTENT_TYPE: &str = prometheus::TEXT_FORMAT;
+
+#[cfg(feature = "metrics")]
+lazy_static! {
+    static ref ELECTIONS_STARTED: IntCounterVec = register_int_counter_vec!(
+        "raft_elections_started_total",
+        "Number of elections this node started as a candidate",
+        &["server_id"]
+    )
+    .expect("METRICS: Could not register raft_elections_started_total");
+    static ref ELECTIONS_WON: IntCounterVec = register_int_counter_vec!(
+        "raft_elections_won_total",
+        "Number of elections this node won",
+        &["server_id"]
+    )
+    .expect("METRICS: Could not register raft_elections_won_total");
+    static ref TERM_CHANGES: IntCounterVec = register_int_counter_vec!(
+        "raft_term_changes_total",
+        "Number of times this node moved to a new term",
+        &["server_id"]
+    )
+    .expect("METRICS: Could not register raft_term_changes_total");
+    static ref RPCS_SENT: IntCounterVec = register_int_counter_vec!(
+        "raft_rpcs_sent_total",
+        "Number of RPC requests and replies sent by this node",
+        &["server_id", "rpc"]
+    )
+    .expect("METRICS: Could not register raft_rpcs_sent_total");
+    static ref RPCS_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "raft_rpcs_received_total",
+        "Number of RPC requests and replies received by this node",
+        &["server_id", "rpc"]
+    )
+    .expect("METRICS: Could not register raft_rpcs_received_total");
+    static ref APPEND_ENTRIES_REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "raft_append_entries_rejections_total",
+        "Number of AppendEntries requests this node rejected",
+        &["server_id"]
+    )
+    .expect("METRICS: Could not register raft_append_entries_rejections_total");
+    static ref STORAGE_SYNCS: IntCounterVec = register_int_counter_vec!(
+        "raft_storage_syncs_total",
+        "Number of times the raft thread synced persistent storage",
+        &["server_id"]
+    )
+    .expect("METRICS: Could not register raft_storage_syncs_total");
+    static ref STORAGE_SYNC_SECONDS: HistogramVec = register_histogram_vec!(
+        "raft_storage_sync_duration_seconds",
+        "Time taken to sync persistent storage",
+        &["server_id"],
+        exponential_buckets(0.0001, 2.0, 16).expect("METRICS: Invalid sync buckets")
+    )
+    .expect("METRICS: Could not register raft_storage_sync_duration_seconds");
+    static ref COMMIT_LATENCY_SECONDS: HistogramVec = register_histogram_vec!(
+        "raft_commit_latency_seconds",
+        "Time from a log entry being appended to this node's log until it was committed",
+        &["server_id"],
+        exponential_buckets(0.001, 2.0, 14).expect("METRICS: Invalid commit latency buckets")
+    )
+    .expect("METRICS: Could not register raft_commit_latency_seconds");
+    static ref APPLY_LAG_ENTRIES: HistogramVec = register_histogram_vec!(
+        "raft_apply_lag_entries",
+        "Number of committed log entries not yet applied by the application",
+        &["server_id"],
+        exponential_buckets(1.0, 2.0, 12).expect("METRICS: Invalid apply lag buckets")
+    )
+    .expect("METRICS: Could not register raft_apply_lag_entries");
+}
+
+/// Encodes every metric in the prometheus default registry in the prometheus text format, for serving at `/metrics`.
+#[cfg(feature = "metrics")]
+pub fn encode_metrics() -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer).expect("METRICS: Text encoder wrote invalid UTF-8"))
+}
+
+pub(crate) fn election_started(server_id: ServerId) {
+    #[cfg(feature = "metrics")]
+    ELECTIONS_STARTED
+        .with_label_values(&[&server_id.0.to_string()])
+        .inc();
+}
+
+pub(crate) fn election_won(server_id: ServerId) {
+    #[cfg(feature = "metrics")]
+    ELECTIONS_WON
+        .with_label_values(&[&server_id.0.to_string()])
+        .inc();
+}
+
+pub(crate) fn term_changed(server_id: ServerId) {
+    #[cfg(feature = "metrics")]
+    TERM_CHANGES
+        .with_label_values(&[&server_id.0.to_string()])
+        .inc();
+}
+
+pub(crate) fn rpc_sent<C: LogCommand>(server_id: ServerId, message: &RpcMessage<C>) {
+    #[cfg(feature = "metrics")]
+    RPCS_SENT
+        .with_label_values(&[&server_id.0.to_string(), message.rpc_name()])
+        .inc();
+}
+
+pub(crate) fn rpc_received<C: LogCommand>(server_id: ServerId, message: &RpcMessage<C>) {
+    #[cfg(feature = "metrics")]
+    RPCS_RECEIVED
+        .with_label_values(&[&server_id.0.to_string(), message.rpc_name()])
+        .inc();
+}
+
+pub(crate) fn append_entries_rejected(server_id: ServerId) {
+    #[cfg(feature = "metrics")]
+    APPEND_ENTRIES_REJECTIONS
+        .with_label_values(&[&server_id.0.to_string()])
+        .inc();
+}
+
+pub(crate) fn storage_synced(server_id: ServerId, sync_duration: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        let server_id = server_id.0.to_string();
+        STORAGE_SYNCS.with_label_values(&[&server_id]).inc();
+        STORAGE_SYNC_SECONDS
+            .with_label_values(&[&server_id])
+            .observe(sync_duration.as_secs_f64());
+    }
+}
+
+/// Tracks when log entries were appended so the commit latency can be observed once the commit index passes them,
+/// one per raft thread.
+#[derive(Debug)]
+pub(crate) struct CommitLatencyTracker {
+    #[cfg(feature = "metrics")]
+    server_id: ServerId,
+    #[cfg(feature = "metrics")]
+    last_log_index: LogIndex,
+    #[cfg(feature = "metrics")]
+    appended_at: VecDeque<(LogIndex, Instant)>,
+}
+impl CommitLatencyTracker {
+    pub(crate) fn new(server_id: ServerId) -> Self {
+        CommitLatencyTracker {
+            #[cfg(feature = "metrics")]
+            server_id,
+            #[cfg(feature = "metrics")]
+            last_log_index: LogIndex(0),
+            #[cfg(feature = "metrics")]
+            appended_at: VecDeque::new(),
+        }
+    }
+
+    /// Called with the status published after each iteration of the raft thread
+    pub(crate) fn observe_status(&mut self, status: &RaftNodeStatus) {
+        #[cfg(feature = "metrics")]
+        {
+            let server_id = self.server_id.0.to_string();
+            let now = system_clock::now();
+
+            // Entries after the last index were truncated when our log was replaced by the leader's
+            if status.last_log_index < self.last_log_index {
+                self.appended_at
+                    .retain(|(index, _)| *index <= status.last_log_index);
+            }
+            if status.last_log_index > self.last_log_index {
+                self.appended_at.push_back((status.last_log_index, now));
+            }
+            self.last_log_index = status.last_log_index;
+
+            while let Some((index, appended_at)) = self.appended_at.front().copied() {
+                if index > status.commit_index {
+                    break;
+                }
+                COMMIT_LATENCY_SECONDS
+                    .with_label_values(&[&server_id])
+                    .observe((now - appended_at).as_secs_f64());
+                self.appended_at.pop_front();
+            }
+
+            APPLY_LAG_ENTRIES
+                .with_label_values(&[&server_id])
+                .observe(status.commit_index.0.saturating_sub(status.last_applied.0) as f64);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use crate::common::{LogIndex, ServerId, TermIndex};
+use crate::raft_thread::{RaftNodeState, RaftNodeStatus, RaftStateEvent};
+use crate::system_clock;
+use crate::system_clock::Instant;
+
+/// Why a leader stopped being leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SteppedDownReason {
+    /// Another server is in a newer term, it may have already elected a new leader
+    HigherTermObserved(TermIndex),
+    /// The node was shut down, if leadership was handed off this is the server that was asked to take over
+    Shutdown { transferred_to: Option<ServerId> },
+}
+
+/// A change in the state of a raft node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftTransition {
+    /// Any of the role, term, vote or known leader of the node changed, carries the new state
+    StateChanged(RaftStateEvent),
+    /// The node won the election for `term`
+    BecameLeader { term: TermIndex },
+    /// The node was leader in `term` and now is not
+    SteppedDown {
+        term: TermIndex,
+        reason: SteppedDownReason,
+    },
+    /// The node voted for another server (candidates voting for themselves are not reported)
+    VoteGranted { to: ServerId, term: TermIndex },
+    /// Entries up to and including `index` are known to be committed
+    CommitAdvanced { index: LogIndex },
+    /// The application applied entries up to and including `last_applied`
+    EntriesApplied { last_applied: LogIndex },
+}
+impl RaftTransition {
+    pub fn kind(&self) -> RaftEventKind {
+        match self {
+            RaftTransition::StateChanged(_) => RaftEventKind::StateChanged,
+            RaftTransition::BecameLeader { .. } => RaftEventKind::BecameLeader,
+            RaftTransition::SteppedDown { .. } => RaftEventKind::SteppedDown,
+            RaftTransition::VoteGranted { .. } => RaftEventKind::VoteGranted,
+            RaftTransition::CommitAdvanced { .. } => RaftEventKind::CommitAdvanced,
+            RaftTransition::EntriesApplied { .. } => RaftEventKind::EntriesApplied,
+        }
+    }
+}
+
+/// The kinds of transitions, used by collectors to choose which events they receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RaftEventKind {
+    StateChanged,
+    BecameLeader,
+    SteppedDown,
+    VoteGranted,
+    CommitAdvanced,
+    EntriesApplied,
+}
+
+/// A transition of a raft node and when it happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RaftEvent {
+    pub server_id: ServerId,
+    pub timestamp: Instant,
+    pub transition: RaftTransition,
+}
+
+/// Receives events from the raft thread. Events are pushed from the raft thread so collectors should not block,
+/// send the event to another thread if it needs expensive processing.
+pub trait RaftStateEventCollector: Send {
+    /// Only events of kinds this returns true for are pushed, by default every kind is
+    fn is_subscribed_to(&self, _kind: RaftEventKind) -> bool {
+        true
+    }
+
+    fn push_event(&mut self, event: RaftEvent);
+}
+
+pub struct NoOpRaftEventCollector;
+impl RaftStateEventCollector for NoOpRaftEventCollector {
+    fn is_subscribed_to(&self, _kind: RaftEventKind) -> bool {
+        false
+    }
+
+    fn push_event(&mut self, _event: RaftEvent) {}
+}
+
+/// Pushes the events of a raft node to its collector. Elections, step downs and votes are reported by the state
+/// machine as they happen (see `transition`), changes of the state, commit index and applied index are found by
+/// comparing the state after each iteration of the raft thread with the state after the previous one, so several
+/// changes in one iteration are reported as one.
+pub(crate) struct TransitionDetector<E: RaftStateEventCollector> {
+    event_collector: E,
+    last_state: Option<RaftStateEvent>,
+    last_commit_index: LogIndex,
+    last_applied: LogIndex,
+}
+impl<E: RaftStateEventCollector> TransitionDetector<E> {
+    pub(crate) fn new(event_collector: E) -> Self {
+        TransitionDetector {
+            event_collector,
+            last_state: None,
+            last_commit_index: LogIndex(0),
+            last_applied: LogIndex(0),
+        }
+    }
+
+    /// Pushes a transition the state machine reported
+    pub(crate) fn transition(&mut self, server_id: ServerId, transition: RaftTransition) {
+        if self.event_collector.is_subscribed_to(transition.kind()) {
+            self.event_collector.push_event(RaftEvent {
+                server_id,
+                timestamp: system_clock::now(),
+                transition,
+            });
+        }
+    }
+
+    pub(crate) fn observe(&mut self, state: RaftStateEvent, status: &RaftNodeStatus) {
+        let server_id = state.server_id;
+        if status.commit_index > self.last_commit_index {
+            self.transition(
+                server_id,
+                RaftTransition::CommitAdvanced {
+                    index: status.commit_index,
+                },
+            );
+            self.last_commit_index = status.commit_index;
+        }
+        if status.last_applied > self.last_applied {
+            self.transition(
+                server_id,
+                RaftTransition::EntriesApplied {
+                    last_applied: status.last_applied,
+                },
+            );
+            self.last_applied = status.last_applied;
+        }
+
+        if self.last_state != Some(state) {
+            self.transition(server_id, RaftTransition::StateChanged(state));
+            self.last_state = Some(state);
+        }
+    }
+
+    /// The node is shutting down, if it was leader it no longer is
+    pub(crate) fn shutdown(&mut self, transferred_to: Option<ServerId>) {
+        if let Some(last) = self.last_state {
+            if last.current_state == RaftNodeState::Leader {
+                self.transition(
+                    last.server_id,
+                    RaftTransition::SteppedDown {
+                        term: last.current_term,
+                        reason: SteppedDownReason::Shutdown { transferred_to },
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps the transitions of the subscribed kinds
+    struct RecordingCollector {
+        kinds: Vec<RaftEventKind>,
+        transitions: Vec<RaftTransition>,
+    }
+    impl RaftStateEventCollector for RecordingCollector {
+        fn is_subscribed_to(&self, kind: RaftEventKind) -> bool {
+            self.kinds.contains(&kind)
+        }
+
+        fn push_event(&mut self, event: RaftEvent) {
+            self.transitions.push(event.transition);
+        }
+    }
+
+    fn detector(kinds: &[RaftEventKind]) -> TransitionDetector<RecordingCollector> {
+        TransitionDetector::new(RecordingCollector {
+            kinds: kinds.to_vec(),
+            transitions: vec![],
+        })
+    }
+
+    fn state(current_state: RaftNodeState, term: u64) -> RaftStateEvent {
+        RaftStateEvent {
+            server_id: ServerId(0),
+            current_state,
+            current_term: TermIndex(term),
+            voted_for: Some(ServerId(0)),
+            leader_for_term: None,
+        }
+    }
+
+    fn status(commit_index: u64, last_applied: u64) -> RaftNodeStatus {
+        RaftNodeStatus {
+            server_id: ServerId(0),
+            state: RaftNodeState::Follower,
+            current_term: TermIndex(1),
+            leader_for_term: None,
+            commit_index: LogIndex(commit_index),
+            last_applied: LogIndex(last_applied),
+            last_log_index: LogIndex(commit_index),
+            last_log_term: TermIndex(1),
+            followers: vec![],
+        }
+    }
+
+    #[test]
+    fn should_report_state_changes_only_when_the_state_changes() {
+        let mut detector = detector(&[RaftEventKind::StateChanged]);
+
+        detector.observe(state(RaftNodeState::Follower, 1), &status(0, 0));
+        detector.observe(state(RaftNodeState::Follower, 1), &status(0, 0));
+        detector.observe(state(RaftNodeState::Candidate, 2), &status(0, 0));
+
+        assert_eq!(
+            detector.event_collector.transitions,
+            vec![
+                RaftTransition::StateChanged(state(RaftNodeState::Follower, 1)),
+                RaftTransition::StateChanged(state(RaftNodeState::Candidate, 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_report_commit_and_applied_index_when_they_advance() {
+        let mut detector =
+            detector(&[RaftEventKind::CommitAdvanced, RaftEventKind::EntriesApplied]);
+
+        detector.observe(state(RaftNodeState::Follower, 1), &status(3, 2));
+        detector.observe(state(RaftNodeState::Follower, 1), &status(3, 2));
+        detector.observe(state(RaftNodeState::Follower, 1), &status(3, 3));
+
+        assert_eq!(
+            detector.event_collector.transitions,
+            vec![
+                RaftTransition::CommitAdvanced { index: LogIndex(3) },
+                RaftTransition::EntriesApplied {
+                    last_applied: LogIndex(2)
+                },
+                RaftTransition::EntriesApplied {
+                    last_applied: LogIndex(3)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_keep_transitions_reported_in_the_same_iteration() {
+        let mut detector = detector(&[RaftEventKind::BecameLeader, RaftEventKind::SteppedDown]);
+        detector.observe(state(RaftNodeState::Leader, 1), &status(0, 0));
+
+        // Stepped down and won the next election before the raft thread observed the state again
+        detector.transition(
+            ServerId(0),
+            RaftTransition::SteppedDown {
+                term: TermIndex(1),
+                reason: SteppedDownReason::HigherTermObserved(TermIndex(2)),
+            },
+        );
+        detector.transition(
+            ServerId(0),
+            RaftTransition::BecameLeader { term: TermIndex(3) },
+        );
+        detector.observe(state(RaftNodeState::Leader, 3), &status(0, 0));
+
+        assert_eq!(
+            detector.event_collector.transitions,
+            vec![
+                RaftTransition::SteppedDown {
+                    term: TermIndex(1),
+                    reason: SteppedDownReason::HigherTermObserved(TermIndex(2)),
+                },
+                RaftTransition::BecameLeader { term: TermIndex(3) },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_report_stepping_down_when_a_leader_shuts_down() {
+        let mut detector = detector(&[RaftEventKind::SteppedDown]);
+        detector.observe(state(RaftNodeState::Leader, 4), &status(0, 0));
+
+        detector.shutdown(Some(ServerId(2)));
+
+        assert_eq!(
+            detector.event_collector.transitions,
+            vec![RaftTransition::SteppedDown {
+                term: TermIndex(4),
+                reason: SteppedDownReason::Shutdown {
+                    transferred_to: Some(ServerId(2))
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn should_not_report_stepping_down_when_a_follower_shuts_down() {
+        let mut detector = detector(&[RaftEventKind::SteppedDown]);
+        detector.observe(state(RaftNodeState::Follower, 4), &status(0, 0));
+
+        detector.shutdown(None);
+
+        assert_eq!(detector.event_collector.transitions, vec![]);
+    }
+}
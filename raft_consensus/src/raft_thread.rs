@@ -1,18 +1,23 @@
 pub use crate::common::*;
-pub use crate::default_storage::DefaultPersistentStorage;
-use crate::rpc_messages::RpcMessage;
+use crate::metrics;
+use crate::metrics::CommitLatencyTracker;
+use crate::raft_events::{RaftStateEventCollector, TransitionDetector};
+use crate::raw_node::ProposeError;
+use crate::rpc_messages::{ReplyTo, Request, RpcMessage};
 use crate::state_machine::*;
 use crate::system_clock;
+use crate::system_clock::Instant;
 use rand_chacha::ChaCha8Rng;
 
-use std::collections::HashSet;
-use std::path::Path;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{mpsc, Arc, Mutex};
 use std::time::Duration;
-use std::{thread, vec};
+use std::{mem, thread, vec};
 
 use crate::common::RaftTransportConnector;
 
-use tracing::{info, trace};
+use tracing::{error, info, info_span, trace, Span};
+use uuid::Uuid;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RaftNodeState {
@@ -30,46 +35,564 @@ pub struct RaftStateEvent {
     pub leader_for_term: Option<ServerId>,
 }
 
-pub trait RaftStateEventCollector: Send {
-    fn push_event(&mut self, event: RaftStateEvent);
+/// Replication progress of a single follower, as tracked by the leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FollowerStatus {
+    pub server_id: ServerId,
+    /// Index of the next log entry the leader will send to this follower
+    pub next_index: LogIndex,
+    /// Index of the highest log entry known to be replicated on this follower
+    pub match_index: LogIndex,
+    /// When the leader last received a reply from this follower in the current term, `None` if it has not replied yet
+    pub last_contact: Option<Instant>,
+}
+
+/// Point in time view of a raft node, returned by `RaftNodeHandle::status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftNodeStatus {
+    pub server_id: ServerId,
+    pub state: RaftNodeState,
+    pub current_term: TermIndex,
+    pub leader_for_term: Option<ServerId>,
+    pub commit_index: LogIndex,
+    pub last_applied: LogIndex,
+    /// Index of the last entry in the log, 0 if the log is empty
+    pub last_log_index: LogIndex,
+    /// Term of the last entry in the log, 0 if the log is empty
+    pub last_log_term: TermIndex,
+    /// Replication progress of each follower ordered by server ID, empty unless this node is leader
+    pub followers: Vec<FollowerStatus>,
+}
+
+/// Reads the latest status of a raft node, can be cloned and used after the `RaftNodeHandle` has been consumed
+/// (i.e. by a gRPC server).
+///
+/// The raft thread publishes a new status every time it wakes up, once the thread has stopped
+/// the last published status is returned.
+#[derive(Debug, Clone)]
+pub struct RaftNodeStatusReader {
+    status: Arc<Mutex<RaftNodeStatus>>,
+}
+impl RaftNodeStatusReader {
+    pub fn status(&self) -> RaftNodeStatus {
+        self.status
+            .lock()
+            .expect("RAFT THREAD: Status lock poisoned!")
+            .clone()
+    }
+
+    fn publish(&self, status: RaftNodeStatus) {
+        *self
+            .status
+            .lock()
+            .expect("RAFT THREAD: Status lock poisoned!") = status;
+    }
+}
+
+/// Reason the raft thread stopped running, returned when the thread is joined or shutdown.
+#[derive(Debug)]
+pub enum RaftThreadExit {
+    /// Raft node was shutdown by calling `RaftNodeHandle::shutdown`
+    Shutdown(RaftShutdownStatus),
+    /// The transport was shutdown/disconnected
+    TransportShutdown,
+    /// Reading from/writing to persistent storage failed
+    PersistentStorageError(PersistentStorageError),
+    /// The application failed to apply a committed entry, holds the debug output of the application's error. Also
+    /// returned at startup if the application has not applied entries that were compacted from the log.
+    ApplicationError(String),
+}
+
+/// Final status of a raft node that was shutdown gracefully.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RaftShutdownStatus {
+    /// State of the node at the time it was shutdown
+    pub final_state: RaftStateEvent,
+    /// If this node was leader and leadership transfer was requested, the server that acknowledged it is taking over
+    pub leadership_transferred_to: Option<ServerId>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ShutdownRequest {
+    transfer_leadership: bool,
+}
+
+/// Outcome of a proposal, the index of the command's entry and the application's response to applying it
+pub type ProposalResult<R> = Result<(LogIndex, R), ProposeError>;
+
+/// Outcome of a read, the index of the last entry the application had applied when the read could be served
+pub type ReadIndexResult = Result<LogIndex, ProposeError>;
+
+/// A command sent to the raft thread by `RaftProposer::propose`
+#[derive(Debug)]
+struct Proposal<LC: LogCommand, R> {
+    command: LC,
+    result_tx: oneshot::Sender<ProposalResult<R>>,
 }
 
-pub struct NoOpRaftEventCollector;
-impl RaftStateEventCollector for NoOpRaftEventCollector {
-    fn push_event(&mut self, _event: RaftStateEvent) {}
+/// A proposal that was appended to our log while we were leader in `term`, waiting to be committed and applied
+#[derive(Debug)]
+struct PendingProposal<R> {
+    term: TermIndex,
+    result_tx: oneshot::Sender<ProposalResult<R>>,
 }
 
-pub fn start_raft_in_new_thread<LC: LogCommand>(
+/// Proposes commands to a raft node running in its own thread, can be cloned and used after the `RaftNodeHandle` has
+/// been consumed (i.e. by a gRPC server).
+#[derive(Debug)]
+pub struct RaftProposer<LC: LogCommand, R> {
+    proposal_tx: mpsc::Sender<Proposal<LC, R>>,
+    read_index_tx: mpsc::Sender<oneshot::Sender<ReadIndexResult>>,
+    raft_thread: thread::Thread,
+}
+impl<LC: LogCommand, R> Clone for RaftProposer<LC, R> {
+    fn clone(&self) -> Self {
+        RaftProposer {
+            proposal_tx: self.proposal_tx.clone(),
+            read_index_tx: self.read_index_tx.clone(),
+            raft_thread: self.raft_thread.clone(),
+        }
+    }
+}
+impl<LC: LogCommand, R> RaftProposer<LC, R> {
+    /// Asks the raft thread to append `command` to the log. The returned receiver can be awaited or blocked on with
+    /// `recv`, it resolves with the index of the command's entry and the application's response once the entry is
+    /// committed and applied to the application.
+    ///
+    /// Fails with `ProposeError::NotLeader` if this node is not the leader, the command was not appended. Fails with
+    /// `ProposeError::LeadershipLost` if it stops being the leader before the entry is committed, the new leader may
+    /// still commit the entry. The receiver is closed without a result if the raft thread stops first.
+    pub fn propose(&self, command: LC) -> oneshot::Receiver<ProposalResult<R>> {
+        let (result_tx, result_rx) = oneshot::channel();
+        // If the thread already stopped the proposal is dropped, which closes the receiver
+        let _ = self.proposal_tx.send(Proposal { command, result_tx });
+        self.raft_thread.unpark();
+        result_rx
+    }
+
+    /// Asks the raft thread when the application can be read without returning stale state, without appending
+    /// anything to the log. The returned receiver resolves once a majority has confirmed this node was still the
+    /// leader after the request arrived and the application has applied every entry that was committed by then, the
+    /// application can be read as soon as it does.
+    ///
+    /// A new leader only knows which entries are committed once it has committed one from its own term, so reads wait
+    /// until the no-op entry it appends when it is elected is committed.
+    ///
+    /// Fails with `ProposeError::NotLeader` if this node is not the leader, or stops being the leader before the read
+    /// is confirmed. The receiver is closed without a result if the raft thread stops first.
+    pub fn read_index(&self) -> oneshot::Receiver<ReadIndexResult> {
+        let (result_tx, result_rx) = oneshot::channel();
+        // If the thread already stopped the request is dropped, which closes the receiver
+        let _ = self.read_index_tx.send(result_tx);
+        self.raft_thread.unpark();
+        result_rx
+    }
+}
+
+/// Handle to a raft node running in its own thread, used to propose commands and to shut the node down.
+#[derive(Debug)]
+pub struct RaftNodeHandle<LC: LogCommand, R> {
+    thread_handle: thread::JoinHandle<RaftThreadExit>,
+    shutdown_tx: mpsc::Sender<ShutdownRequest>,
+    status_reader: RaftNodeStatusReader,
+    proposer: RaftProposer<LC, R>,
+}
+impl<LC: LogCommand, R> RaftNodeHandle<LC, R> {
+    /// The thread the raft node is running in, transports use this to unpark the thread when a new message arrives.
+    pub fn thread(&self) -> &thread::Thread {
+        self.thread_handle.thread()
+    }
+
+    /// Returns the role, term, commit/log indexes and, if this node is leader, the replication progress of each
+    /// follower. This does not wait for the raft thread, the status is as of the last time the thread woke up.
+    pub fn status(&self) -> RaftNodeStatus {
+        self.status_reader.status()
+    }
+
+    /// Returns a reader for the node status that can outlive this handle
+    pub fn status_reader(&self) -> RaftNodeStatusReader {
+        self.status_reader.clone()
+    }
+
+    /// Returns a proposer for appending commands to the log that can outlive this handle
+    pub fn proposer(&self) -> RaftProposer<LC, R> {
+        self.proposer.clone()
+    }
+
+    /// Returns true if the raft thread has stopped running
+    pub fn is_finished(&self) -> bool {
+        self.thread_handle.is_finished()
+    }
+
+    /// Gracefully shuts down the raft node. If this node is leader and `transfer_leadership` is true, it stops
+    /// accepting proposals, sends the most up to date follower the entries it is missing and then asks it to start an
+    /// election immediately so the cluster does not need to wait for an election timeout. Any pending state is synced
+    /// to storage before the thread stops.
+    ///
+    /// The raft thread checks for shutdown requests each time it wakes up, so this blocks for at most the
+    /// current wait time (leader heartbeat interval or election timeout) plus the time needed to sync storage. A
+    /// leadership transfer adds up to one maximum election timeout.
+    pub fn shutdown(self, transfer_leadership: bool) -> thread::Result<RaftThreadExit> {
+        self.request_shutdown(transfer_leadership);
+        self.join()
+    }
+
+    /// Same as `shutdown` but does not wait for the raft thread to stop, use `join` to wait for it.
+    pub fn request_shutdown(&self, transfer_leadership: bool) {
+        // If the thread already stopped the receiver is gone, in that case joining returns why it stopped
+        let _ = self.shutdown_tx.send(ShutdownRequest {
+            transfer_leadership,
+        });
+        self.thread_handle.thread().unpark();
+    }
+
+    /// Waits for the raft thread to stop and returns the reason it stopped, or the panic if the raft thread panicked.
+    pub fn join(self) -> thread::Result<RaftThreadExit> {
+        self.thread_handle.join()
+    }
+}
+
+fn raft_node_state(state: &Node) -> RaftNodeState {
+    match state {
+        Node::Follower(_) => RaftNodeState::Follower,
+        Node::Candidate(_) => RaftNodeState::Candidate,
+        Node::Leader(_) => RaftNodeState::Leader,
+    }
+}
+
+pub(crate) fn raft_state_event<LC: LogCommand>(
+    server_id: ServerId,
+    state: &Node,
+    storage: &impl PersistentStorage<LC>,
+) -> RaftStateEvent {
+    RaftStateEvent {
+        server_id,
+        current_state: raft_node_state(state),
+        current_term: storage.current_term(),
+        voted_for: storage.vote_for_current_term(),
+        leader_for_term: match state {
+            Node::Leader(_) => Some(server_id),
+            Node::Follower(follower) => follower.inner.leader_id,
+            _ => None,
+        },
+    }
+}
+
+pub(crate) fn raft_node_status<LC: LogCommand>(
     server_id: ServerId,
-    other_servers: HashSet<ServerId>,
-    storage_path: String,
-    config: RaftConfig,
-    mut rng: ChaCha8Rng,
-    mut transport_connector: impl RaftTransportConnector<LC> + 'static,
-    mut event_collector: impl RaftStateEventCollector + 'static,
-) -> thread::JoinHandle<()> {
-    thread::Builder::new()
+    state: &Node,
+    storage: &impl PersistentStorage<LC>,
+) -> RaftNodeStatus {
+    let (commit_index, last_applied) = match state {
+        Node::Leader(leader) => (leader.commit_index, leader.last_applied),
+        Node::Follower(follower) => (follower.commit_index, follower.last_applied),
+        Node::Candidate(candidate) => (candidate.commit_index, candidate.last_applied),
+    };
+    let mut followers = match state {
+        Node::Leader(leader) => leader
+            .inner
+            .next_index
+            .iter()
+            .map(|(follower_id, next_index)| FollowerStatus {
+                server_id: *follower_id,
+                next_index: *next_index,
+                match_index: leader
+                    .inner
+                    .match_index
+                    .get(follower_id)
+                    .copied()
+                    .unwrap_or(LogIndex(0)),
+                last_contact: leader.inner.last_contact.get(follower_id).copied(),
+            })
+            .collect(),
+        _ => vec![],
+    };
+    followers.sort_by_key(|follower| follower.server_id);
+
+    let state_event = raft_state_event(server_id, state, storage);
+    RaftNodeStatus {
+        server_id,
+        state: state_event.current_state,
+        current_term: state_event.current_term,
+        leader_for_term: state_event.leader_for_term,
+        commit_index,
+        last_applied,
+        last_log_index: storage.last_entry_index().unwrap_or(LogIndex(0)),
+        last_log_term: storage.last_entry_term().unwrap_or(TermIndex(0)),
+        followers,
+    }
+}
+
+/// Span for sending `message` to another server, transports propagate it so the receiver's handling of the message
+/// is part of the same trace.
+fn send_rpc_span<LC: LogCommand>(
+    server_id: ServerId,
+    term: TermIndex,
+    message: &RpcMessage<LC>,
+) -> Span {
+    info_span!(
+        "send_rpc",
+        server_id = server_id.0,
+        term = term.0,
+        request_id = %message.request_id(),
+        to = message.to().0,
+        rpc = message.rpc_name(),
+    )
+}
+
+/// A shutdown that waits for this leader to hand off leadership first. The raft thread keeps running meanwhile so the
+/// target can be sent the entries it is missing, then TimeoutNow (§3.10).
+struct LeadershipTransfer {
+    shutdown_request: ShutdownRequest,
+    /// The transfer is abandoned after an election timeout, the target would have won an election by then
+    deadline: Instant,
+    /// ID and target of the TimeoutNow request, once the target has caught up
+    timeout_now: Option<(Uuid, ServerId)>,
+    /// Set once the target acknowledged the TimeoutNow request by starting an election
+    transferred_to: Option<ServerId>,
+}
+
+impl LeadershipTransfer {
+    /// Done once the target acknowledged the TimeoutNow request, its vote request may reach us before the
+    /// acknowledgement so stepping down only ends the transfer if TimeoutNow wasn't sent
+    fn is_done(&self, state: &Node) -> bool {
+        self.transferred_to.is_some()
+            || (self.timeout_now.is_none() && !state.is_leader())
+            || system_clock::now() >= self.deadline
+    }
+}
+
+/// What the raft thread lends to `shutdown_raft_node` besides the node's state
+struct ShutdownContext<'a, PS, E: RaftStateEventCollector> {
+    server_id: ServerId,
+    storage: &'a mut PS,
+    transition_detector: &'a mut TransitionDetector<E>,
+}
+
+/// Syncs storage before the thread stops, once leadership was handed off to `leadership_transferred_to` if requested.
+fn shutdown_raft_node<LC, PS, E>(
+    state: Node,
+    shutdown_request: ShutdownRequest,
+    leadership_transferred_to: Option<ServerId>,
+    context: ShutdownContext<PS, E>,
+) -> RaftThreadExit
+where
+    LC: LogCommand,
+    PS: PersistentStorage<LC>,
+    E: RaftStateEventCollector,
+{
+    let ShutdownContext {
+        server_id,
+        storage,
+        transition_detector,
+    } = context;
+    info!(
+        "{:?}: Shutting down raft node (transfer leadership: {:?}, transferred to: {:?})...",
+        server_id, shutdown_request.transfer_leadership, leadership_transferred_to
+    );
+
+    // Make sure anything written to storage has been persisted before we stop
+    if let Err(e) = storage.sync() {
+        return RaftThreadExit::PersistentStorageError(e);
+    }
+
+    transition_detector.shutdown(leadership_transferred_to);
+
+    RaftThreadExit::Shutdown(RaftShutdownStatus {
+        final_state: raft_state_event(server_id, &state, storage),
+        leadership_transferred_to,
+    })
+}
+
+/// Applies committed entries the application has not applied yet (i.e. before a restart) and resolves the proposals
+/// waiting for them. A proposal whose index was taken by an entry from another term was overwritten by a new leader.
+/// No-op entries are not handed to the application.
+fn apply_log_entries<A: ApplicationThatNeedsConsensus>(
+    entries: Vec<LogEntry<A::Command>>,
+    application: &mut A,
+    pending_proposals: &mut BTreeMap<LogIndex, PendingProposal<A::Response>>,
+    leader_hint: Option<ServerId>,
+) -> Result<(), RaftThreadExit> {
+    for entry in entries {
+        let (index, term) = (entry.index, entry.term);
+        let pending = pending_proposals.remove(&index);
+        if index <= application.last_applied_index() {
+            // Applied before a restart, there is no response for a proposal waiting on it so its receiver is closed
+            continue;
+        }
+        let response = match entry.command {
+            Some(command) => Some(
+                application
+                    .apply(index, command)
+                    .map_err(|e| RaftThreadExit::ApplicationError(format!("{:?}", e)))?,
+            ),
+            None => None,
+        };
+        if let Some(pending) = pending {
+            let result = match response {
+                Some(response) if pending.term == term => Ok((index, response)),
+                _ => Err(ProposeError::NotLeader { leader_hint }),
+            };
+            // The proposer may have stopped waiting for the result
+            let _ = pending.result_tx.send(result);
+        }
+    }
+    Ok(())
+}
+
+/// Everything a raft node needs to run on its own thread, see `start_raft_in_new_thread`.
+#[derive(Debug)]
+pub struct RaftNodeSetup<PS, A, T, E> {
+    /// ID of this server
+    pub server_id: ServerId,
+    /// IDs of the other servers in the cluster
+    pub other_servers: HashSet<ServerId>,
+    /// Where the term, vote and log are persisted, a restarted node picks up from what was synced to it
+    pub storage: PS,
+    /// Committed commands are applied to this application
+    pub application: A,
+    /// Heartbeat interval and election timeouts
+    pub config: RaftConfig,
+    /// Source of the randomness in election timeouts and request IDs
+    pub rng: ChaCha8Rng,
+    /// Sends messages to and receives messages from the other servers
+    pub transport_connector: T,
+    /// Notified of elections, role changes and commit progress
+    pub event_collector: E,
+}
+
+/// Starts a raft node on a new thread, returns a handle for proposing commands to it and shutting it down.
+pub fn start_raft_in_new_thread<LC, PS, A, T, E>(
+    setup: RaftNodeSetup<PS, A, T, E>,
+) -> RaftNodeHandle<LC, A::Response>
+where
+    LC: LogCommand + 'static,
+    PS: PersistentStorage<LC> + 'static,
+    A: ApplicationThatNeedsConsensus<Command = LC> + 'static,
+    T: RaftTransportConnector<LC> + 'static,
+    E: RaftStateEventCollector + 'static,
+{
+    let RaftNodeSetup {
+        server_id,
+        other_servers,
+        mut storage,
+        mut application,
+        config,
+        mut rng,
+        mut transport_connector,
+        event_collector,
+    } = setup;
+    let (shutdown_tx, shutdown_rx) = mpsc::channel::<ShutdownRequest>();
+    let (proposal_tx, proposal_rx) = mpsc::channel::<Proposal<LC, A::Response>>();
+    let (read_index_tx, read_index_rx) = mpsc::channel::<oneshot::Sender<ReadIndexResult>>();
+    let (initial_state, first_election_timeout) = Node::new(
+        server_id,
+        other_servers,
+        &config,
+        &mut rng,
+        system_clock::now(),
+    );
+    let status_reader = RaftNodeStatusReader {
+        status: Arc::new(Mutex::new(raft_node_status(
+            server_id,
+            &initial_state,
+            &storage,
+        ))),
+    };
+    let thread_status_reader = status_reader.clone();
+    let thread_handle = thread::Builder::new()
         .name(format!("raft-server-{server_id}", server_id = server_id.0))
         .spawn(move || {
             let start_time = system_clock::now();
+            if let Some((compacted_index, _)) = storage.compacted_through() {
+                if application.last_applied_index() < compacted_index {
+                    let message = format!(
+                        "Application has applied entries up to {:?} but the log was compacted through {:?}, the snapshot it was compacted for is missing",
+                        application.last_applied_index(),
+                        compacted_index
+                    );
+                    error!("{}, shutting down raft thread", message);
+                    return RaftThreadExit::ApplicationError(message);
+                }
+            }
 
-            let mut storage = DefaultPersistentStorage::new(Path::new(&storage_path));
-
-            let (mut state, first_election_timeout) =
-                Node::new(server_id, other_servers, &config, &mut rng);
+            let mut state = initial_state;
+            let mut pending_proposals = BTreeMap::<LogIndex, PendingProposal<A::Response>>::new();
+            // Reads waiting for the leader to confirm them by ID, then for the application to apply their index
+            let mut next_read_id = 0;
+            let mut unconfirmed_reads = BTreeMap::<u64, oneshot::Sender<ReadIndexResult>>::new();
+            let mut confirmed_reads = Vec::<(LogIndex, oneshot::Sender<ReadIndexResult>)>::new();
+            let mut commit_latency_tracker = CommitLatencyTracker::new(server_id);
+            let mut transition_detector = TransitionDetector::new(event_collector);
+            transition_detector.observe(
+                raft_state_event(server_id, &state, &storage),
+                &raft_node_status(server_id, &state, &storage),
+            );
             info!(
                 "{:?}: Starting raft node with state: {:?}, term: {:?}",
                 server_id,
-                match state {
-                    Node::Follower(_) => RaftNodeState::Follower,
-                    Node::Candidate(_) => RaftNodeState::Candidate,
-                    Node::Leader(_) => RaftNodeState::Leader,
-                },
+                raft_node_state(&state),
                 storage.current_term(),
             );
 
             let mut max_wait_time = first_election_timeout.0;
+            let mut leadership_transfer: Option<LeadershipTransfer> = None;
+            let mut transfer_actions = vec![];
             loop {
+                if let Some(transfer) = &leadership_transfer {
+                    if transfer.is_done(&state) {
+                        return shutdown_raft_node(
+                            state,
+                            transfer.shutdown_request,
+                            transfer.transferred_to,
+                            ShutdownContext {
+                                server_id,
+                                storage: &mut storage,
+                                transition_detector: &mut transition_detector,
+                            },
+                        );
+                    }
+                } else if let Ok(shutdown_request) = shutdown_rx.try_recv() {
+                    if shutdown_request.transfer_leadership && state.is_leader() {
+                        (state, transfer_actions) = match state.next(
+                            Event::TransferLeadership,
+                            &mut storage,
+                            &config,
+                            &mut rng,
+                        ) {
+                            Ok((new_state, actions)) => (new_state, actions),
+                            Err(e) => {
+                                error!("Persistent storage error, shutting down raft thread: {}", e);
+                                return RaftThreadExit::PersistentStorageError(e);
+                            }
+                        };
+                    }
+                    // Without another server to take over there is nothing to wait for
+                    if state.accepts_proposals() || !state.is_leader() {
+                        return shutdown_raft_node(
+                            state,
+                            shutdown_request,
+                            None,
+                            ShutdownContext {
+                                server_id,
+                                storage: &mut storage,
+                                transition_detector: &mut transition_detector,
+                            },
+                        );
+                    }
+                    info!(
+                        "{:?}: Transferring leadership before shutting down...",
+                        server_id
+                    );
+                    leadership_transfer = Some(LeadershipTransfer {
+                        shutdown_request,
+                        deadline: system_clock::now()
+                            + Duration::from_millis(config.max_election_timeout_ms as u64),
+                        timeout_now: None,
+                        transferred_to: None,
+                    });
+                }
+
                 trace!(
                     "Waiting {:?}ms for next message at time {:?}...",
                     max_wait_time.as_millis(),
@@ -94,84 +617,252 @@ pub fn start_raft_in_new_thread<LC: LogCommand>(
                     &mut rng,
                 ) {
                     Ok((new_state, actions)) => (new_state, actions),
-                    Err(_) => {
-                        info!("Persistent storage error, shutting down raft thread...");
-                        return;
+                    Err(e) => {
+                        error!("Persistent storage error, shutting down raft thread: {}", e);
+                        return RaftThreadExit::PersistentStorageError(e);
                     }
                 };
 
-                if let Err(_) = maybe_next_message {
+                if maybe_next_message.is_err() {
                     info!("Transport shutdown, shutting down raft thread...");
-                    return;
+                    return RaftThreadExit::TransportShutdown;
                 }
 
-                let mut actions_after_processing_message =
-                    if let Ok(Some(incoming_message)) = maybe_next_message {
-                        let actions;
-                        (new_state, actions) = match new_state.next(
-                            Event::IncomingRpc(incoming_message),
-                            &mut storage,
-                            &config,
-                            &mut rng,
-                        ) {
-                            Ok((new_state, actions)) => (new_state, actions),
-                            Err(_) => {
-                                info!("Persistent storage error, shutting down raft thread...");
-                                return;
-                            }
+                // The message and the messages sent because of it are handled in the span the transport received it in,
+                // so they show up in the sender's trace
+                let incoming_message_span = transport_connector
+                    .take_incoming_message_span()
+                    .unwrap_or_else(Span::none);
+                let mut actions_after_processing_message = if let Ok(Some(incoming_message)) =
+                    maybe_next_message
+                {
+                    metrics::rpc_received(server_id, &incoming_message);
+                    let _entered = incoming_message_span.enter();
+                    if let (
+                        Some(transfer),
+                        RpcMessage::Reply(ReplyTo::TimeoutNow(ack)),
+                    ) = (&mut leadership_transfer, &incoming_message)
+                    {
+                        if ack.success && transfer.timeout_now == Some((ack.request_id, ack.from)) {
+                            transfer.transferred_to = Some(ack.from);
+                        }
+                    }
+                    let actions;
+                    (new_state, actions) = match new_state.next(
+                        Event::IncomingRpc(incoming_message),
+                        &mut storage,
+                        &config,
+                        &mut rng,
+                    ) {
+                        Ok((new_state, actions)) => (new_state, actions),
+                        Err(e) => {
+                            error!("Persistent storage error, shutting down raft thread: {}", e);
+                            return RaftThreadExit::PersistentStorageError(e);
+                        }
+                    };
+                    actions
+                } else {
+                    vec![]
+                };
+
+                // Proposals wake the thread up by unparking it, the leader appends them to its log and followers
+                // reject them right away. So does a leader handing off leadership, the new leader is not known yet.
+                let mut proposal_actions = mem::take(&mut transfer_actions);
+                while let Ok(proposal) = proposal_rx.try_recv() {
+                    if !new_state.accepts_proposals() {
+                        let leader_hint = if new_state.is_leader() {
+                            None
+                        } else {
+                            raft_state_event(server_id, &new_state, &storage).leader_for_term
                         };
-                        actions
-                    } else {
-                        vec![]
+                        let _ = proposal
+                            .result_tx
+                            .send(Err(ProposeError::NotLeader { leader_hint }));
+                        continue;
+                    }
+                    let mut actions;
+                    (new_state, actions) = match new_state.next(
+                        Event::Propose(proposal.command),
+                        &mut storage,
+                        &config,
+                        &mut rng,
+                    ) {
+                        Ok((new_state, actions)) => (new_state, actions),
+                        Err(e) => {
+                            error!("Persistent storage error, shutting down raft thread: {}", e);
+                            return RaftThreadExit::PersistentStorageError(e);
+                        }
                     };
+                    let index = storage
+                        .last_entry_index()
+                        .expect("RAFT THREAD: Log is empty after appending a proposal!");
+                    let _ = pending_proposals.insert(
+                        index,
+                        PendingProposal {
+                            term: storage.current_term(),
+                            result_tx: proposal.result_tx,
+                        },
+                    );
+                    proposal_actions.append(&mut actions);
+                }
+                while let Ok(result_tx) = read_index_rx.try_recv() {
+                    if !new_state.is_leader() {
+                        let leader_hint =
+                            raft_state_event(server_id, &new_state, &storage).leader_for_term;
+                        let _ = result_tx.send(Err(ProposeError::NotLeader { leader_hint }));
+                        continue;
+                    }
+                    next_read_id += 1;
+                    let _ = unconfirmed_reads.insert(next_read_id, result_tx);
+                    let mut actions;
+                    (new_state, actions) = match new_state.next(
+                        Event::ReadIndex(next_read_id),
+                        &mut storage,
+                        &config,
+                        &mut rng,
+                    ) {
+                        Ok((new_state, actions)) => (new_state, actions),
+                        Err(e) => {
+                            error!("Persistent storage error, shutting down raft thread: {}", e);
+                            return RaftThreadExit::PersistentStorageError(e);
+                        }
+                    };
+                    proposal_actions.append(&mut actions);
+                }
+
+                // Term/vote changes and log entries appended while handling the tick, the incoming message and
+                // proposals are persisted with a single sync, this has to happen before any message is sent so we
+                // never act on a vote we could forget.
+                let time_before_sync = system_clock::now();
+                if let Err(e) = storage.sync() {
+                    error!("Persistent storage error, shutting down raft thread: {}", e);
+                    return RaftThreadExit::PersistentStorageError(e);
+                }
+                metrics::storage_synced(server_id, time_before_sync.elapsed());
 
                 max_wait_time = max_wait_time
                     .checked_sub(time_before_waiting.elapsed())
                     .unwrap_or(Duration::from_millis(0));
 
-                for action in tick_actions
+                for (action, parent_span) in tick_actions
                     .drain(..)
-                    .chain(actions_after_processing_message.drain(..))
+                    .map(|action| (action, Span::none()))
+                    .chain(
+                        actions_after_processing_message
+                            .drain(..)
+                            .map(|action| (action, incoming_message_span.clone())),
+                    )
+                    .chain(
+                        proposal_actions
+                            .drain(..)
+                            .map(|action| (action, Span::none())),
+                    )
                 {
+                    let _parent_entered = parent_span.enter();
+                    let span = match &action {
+                        Action::OutgoingRpc(message) => {
+                            metrics::rpc_sent(server_id, message);
+                            send_rpc_span(server_id, storage.current_term(), message)
+                        }
+                        _ => Span::none(),
+                    };
+                    let _entered = span.enter();
                     match action {
                         Action::OutgoingRpc(RpcMessage::Request(r)) => {
-                            if let Err(_) = transport_connector.enqueue_outgoing_request(r) {
+                            if let (Some(transfer), Request::TimeoutNow(timeout_now)) =
+                                (&mut leadership_transfer, &r)
+                            {
+                                transfer.timeout_now = Some((timeout_now.request_id, timeout_now.to));
+                            }
+                            if transport_connector.enqueue_outgoing_request(r).is_err() {
                                 info!("Transport shutdown, shutting down raft thread...");
-                                return;
+                                return RaftThreadExit::TransportShutdown;
                             }
                         }
                         Action::OutgoingRpc(RpcMessage::Reply(message)) => {
-                            if let Err(_) = transport_connector.enqueue_reply(message) {
+                            if transport_connector.enqueue_reply(message).is_err() {
                                 info!("Transport shutdown, shutting down raft thread...");
-                                return;
+                                return RaftThreadExit::TransportShutdown;
                             }
                         }
                         Action::SetNextTimeout(timer_duration) => {
                             trace!("Resetting wait timeout to duration {:?}", timer_duration);
                             max_wait_time = timer_duration;
                         }
-                        Action::ApplyLogEntries(_) => todo!(),
+                        // Entries are synced above, before any message is sent
+                        Action::AppendedToLog(_) => (),
+                        Action::Transition(transition) => {
+                            transition_detector.transition(server_id, transition)
+                        }
+                        Action::ReadIndexConfirmed { read_id, index } => {
+                            if let Some(result_tx) = unconfirmed_reads.remove(&read_id) {
+                                confirmed_reads.push((index, result_tx));
+                            }
+                        }
+                        Action::ApplyLogEntries(entries) => {
+                            let leader_hint =
+                                raft_state_event(server_id, &new_state, &storage).leader_for_term;
+                            if let Err(exit) = apply_log_entries(
+                                entries,
+                                &mut application,
+                                &mut pending_proposals,
+                                leader_hint,
+                            ) {
+                                error!("Application error, shutting down raft thread: {:?}", exit);
+                                return exit;
+                            }
+                        }
                     }
                 }
 
-                event_collector.push_event(RaftStateEvent {
-                    server_id,
-                    current_state: match new_state {
-                        Node::Follower(_) => RaftNodeState::Follower,
-                        Node::Candidate(_) => RaftNodeState::Candidate,
-                        Node::Leader(_) => RaftNodeState::Leader,
-                    },
-                    current_term: storage.current_term(),
-                    voted_for: storage.vote_for_current_term(),
-                    leader_for_term: match &new_state {
-                        Node::Leader(_) => Some(server_id),
-                        Node::Follower(follower) => follower.inner.leader_id,
-                        _ => None,
-                    },
-                });
+                // Entries we appended as leader may be committed or overwritten by the new leader, we can't tell which
+                if !new_state.is_leader() && !pending_proposals.is_empty() {
+                    let leader_hint =
+                        raft_state_event(server_id, &new_state, &storage).leader_for_term;
+                    for (_, pending) in mem::take(&mut pending_proposals) {
+                        let _ = pending
+                            .result_tx
+                            .send(Err(ProposeError::LeadershipLost { leader_hint }));
+                    }
+                }
+                // Reads that were confirmed can still be served, the entries they wait for are committed
+                if !new_state.is_leader() && !unconfirmed_reads.is_empty() {
+                    let leader_hint =
+                        raft_state_event(server_id, &new_state, &storage).leader_for_term;
+                    for (_, result_tx) in mem::take(&mut unconfirmed_reads) {
+                        let _ = result_tx.send(Err(ProposeError::NotLeader { leader_hint }));
+                    }
+                }
+                let status = raft_node_status(server_id, &new_state, &storage);
+                // No-op entries are handed out without reaching the application, so a read may wait on one of them
+                let last_applied = status.last_applied.max(application.last_applied_index());
+                let (servable_reads, waiting_reads) = mem::take(&mut confirmed_reads)
+                    .into_iter()
+                    .partition(|(index, _)| *index <= last_applied);
+                confirmed_reads = waiting_reads;
+                for (_, result_tx) in servable_reads {
+                    // The reader may have stopped waiting for the result
+                    let _ = result_tx.send(Ok(last_applied));
+                }
+
+                transition_detector
+                    .observe(raft_state_event(server_id, &new_state, &storage), &status);
+                commit_latency_tracker.observe_status(&status);
+                thread_status_reader.publish(status);
 
                 state = new_state;
             }
         })
-        .expect("Failed to spawn raft thread")
+        .expect("Failed to spawn raft thread");
+
+    RaftNodeHandle {
+        proposer: RaftProposer {
+            proposal_tx,
+            read_index_tx,
+            raft_thread: thread_handle.thread().clone(),
+        },
+        thread_handle,
+        shutdown_tx,
+        status_reader,
+    }
 }
@@ -0,0 +1,261 @@
+use crate::common::*;
+use crate::raft_thread::{raft_node_status, raft_state_event, RaftNodeStatus, RaftStateEvent};
+use crate::rpc_messages::RpcMessage;
+use crate::state_machine::{Action, Event, Node};
+use crate::system_clock::Instant;
+use rand_chacha::ChaCha8Rng;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+
+/// Output of a `RawNode` that the embedding event loop has to act on, returned by `RawNode::ready`.
+///
+/// The event loop should make `entries` (and any term/vote change) durable with `PersistentStorage::sync` before it
+/// sends `messages`, then apply `committed_entries` to its application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ready<C: LogCommand> {
+    /// Messages to send to other servers
+    pub messages: Vec<RpcMessage<C>>,
+    /// Entries appended to the log in storage since the last ready, that have not been synced yet
+    pub entries: Vec<LogEntry<C>>,
+    /// Entries that were committed since the last ready, in log order. The no-op entries a leader appends when it is
+    /// elected have no command, they only count as applied.
+    pub committed_entries: Vec<LogEntry<C>>,
+    /// If set, `tick` should be called once this much time has passed (it's fine to tick more often)
+    pub next_timeout: Option<Duration>,
+    /// Reads started with `read_index` that were confirmed since the last ready
+    pub read_states: Vec<ReadState>,
+}
+
+/// A read the leader confirmed, it can be served once the application has applied the entries up to `index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadState {
+    /// ID `RawNode::read_index` returned for the read
+    pub read_id: u64,
+    pub index: LogIndex,
+}
+impl<C: LogCommand> Ready<C> {
+    fn new() -> Self {
+        Ready {
+            messages: vec![],
+            entries: vec![],
+            committed_entries: vec![],
+            next_timeout: None,
+            read_states: vec![],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+            && self.entries.is_empty()
+            && self.committed_entries.is_empty()
+            && self.next_timeout.is_none()
+            && self.read_states.is_empty()
+    }
+}
+
+/// Why a command could not be proposed.
+#[derive(Debug)]
+pub enum ProposeError {
+    /// Only the leader accepts proposals, `leader_hint` is the leader for the current term if we know it
+    NotLeader { leader_hint: Option<ServerId> },
+    /// The command was appended to the log but this node stopped being the leader before it was committed. The new
+    /// leader may still commit it, so whether it was applied is unknown
+    LeadershipLost { leader_hint: Option<ServerId> },
+    /// The command could not be appended to the log
+    Storage(PersistentStorageError),
+}
+impl fmt::Display for ProposeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProposeError::NotLeader {
+                leader_hint: Some(leader_id),
+            } => write!(f, "Not the leader, the leader is {:?}", leader_id),
+            ProposeError::NotLeader { leader_hint: None } => {
+                write!(f, "Not the leader, the leader is unknown")
+            }
+            ProposeError::LeadershipLost { .. } => write!(
+                f,
+                "Lost leadership before the proposal was committed, the new leader may still commit it"
+            ),
+            ProposeError::Storage(e) => write!(f, "Could not append proposal to the log: {}", e),
+        }
+    }
+}
+impl std::error::Error for ProposeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProposeError::NotLeader { .. } | ProposeError::LeadershipLost { .. } => None,
+            ProposeError::Storage(e) => Some(e),
+        }
+    }
+}
+
+/// The raft protocol for a single server without any I/O or threads, for embedding raft in an existing event loop.
+/// `start_raft_in_new_thread` is the batteries included alternative.
+///
+/// The event loop feeds the node time with `tick`, messages from other servers with `step` and commands from clients
+/// with `propose`, then collects what the node needs done with `ready`. The node never reads the clock or OS
+/// randomness, so the same inputs (and RNG seed) always produce the same output.
+///
+/// If any method returns a storage error the node must not be used again, like the raft thread it should be
+/// restarted from what is on disk.
+#[derive(Debug)]
+pub struct RawNode<C: LogCommand, PS: PersistentStorage<C>> {
+    server_id: ServerId,
+    /// Only `None` while an event is being handled or after a storage error
+    node: Option<Node>,
+    storage: PS,
+    config: RaftConfig,
+    rng: ChaCha8Rng,
+    ready: Ready<C>,
+    /// ID of the last read started with `read_index`
+    last_read_id: u64,
+}
+impl<C: LogCommand, PS: PersistentStorage<C>> RawNode<C, PS> {
+    /// Creates a follower, `now` is the time its first election timer starts at
+    pub fn new(
+        server_id: ServerId,
+        other_servers: HashSet<ServerId>,
+        storage: PS,
+        config: RaftConfig,
+        mut rng: ChaCha8Rng,
+        now: Instant,
+    ) -> Self {
+        let (node, first_election_timeout) =
+            Node::new(server_id, other_servers, &config, &mut rng, now);
+        let mut ready = Ready::new();
+        ready.next_timeout = Some(first_election_timeout.0);
+        RawNode {
+            server_id,
+            node: Some(node),
+            storage,
+            config,
+            rng,
+            ready,
+            last_read_id: 0,
+        }
+    }
+
+    fn node(&self) -> &Node {
+        self.node
+            .as_ref()
+            .expect("RAW NODE: Used after a persistent storage error!")
+    }
+
+    fn handle_event(&mut self, event: Event<C>) -> Result<(), PersistentStorageError> {
+        let node = self
+            .node
+            .take()
+            .expect("RAW NODE: Used after a persistent storage error!");
+        let (node, actions) = node.next(event, &mut self.storage, &self.config, &mut self.rng)?;
+        self.node = Some(node);
+
+        for action in actions {
+            match action {
+                Action::OutgoingRpc(message) => self.ready.messages.push(message),
+                Action::SetNextTimeout(timeout) => self.ready.next_timeout = Some(timeout),
+                Action::ApplyLogEntries(entries) => self.ready.committed_entries.extend(entries),
+                Action::AppendedToLog(entries) => {
+                    // Unsynced entries that were replaced by newer ones don't need to be synced anymore
+                    if let Some(first_index) = entries.first().map(|entry| entry.index) {
+                        self.ready
+                            .entries
+                            .retain(|unsynced| unsynced.index < first_index);
+                    }
+                    self.ready.entries.extend(entries);
+                }
+                // There is no event collector, the application sees leadership changes in `state`
+                Action::Transition(_) => {}
+                Action::ReadIndexConfirmed { read_id, index } => {
+                    self.ready.read_states.push(ReadState { read_id, index })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Advances the node's clock to `now`, this is what triggers elections and heartbeats
+    pub fn tick(&mut self, now: Instant) -> Result<(), PersistentStorageError> {
+        self.handle_event(Event::Tick(now))
+    }
+
+    /// Handles a request or reply from another server
+    pub fn step(&mut self, message: RpcMessage<C>) -> Result<(), PersistentStorageError> {
+        self.handle_event(Event::IncomingRpc(message))
+    }
+
+    /// Appends `command` to the leader's log and starts replicating it, returns the index it will be committed at.
+    /// The command is applied once it shows up in `Ready::committed_entries`, if leadership changes before then the
+    /// entry at that index may end up holding a different command.
+    pub fn propose(&mut self, command: C) -> Result<LogIndex, ProposeError> {
+        if !self.node().is_leader() {
+            return Err(ProposeError::NotLeader {
+                leader_hint: self.state().leader_for_term,
+            });
+        }
+        // A leader handing off leadership is about to step down, the new leader is not known yet
+        if !self.node().accepts_proposals() {
+            return Err(ProposeError::NotLeader { leader_hint: None });
+        }
+        self.handle_event(Event::Propose(command))
+            .map_err(ProposeError::Storage)?;
+        Ok(self
+            .storage
+            .last_entry_index()
+            .expect("RAW NODE: Log is empty after appending a proposal!"))
+    }
+
+    /// Starts confirming that we are still the leader for a read without appending to the log, returns the read's ID.
+    /// Once it shows up in `Ready::read_states` the read can be served as soon as the application has applied the
+    /// entries up to its index. If leadership changes before then it never shows up, and should be retried on the new
+    /// leader. See `RaftProposer::read_index`.
+    pub fn read_index(&mut self) -> Result<u64, ProposeError> {
+        if !self.node().is_leader() {
+            return Err(ProposeError::NotLeader {
+                leader_hint: self.state().leader_for_term,
+            });
+        }
+        self.last_read_id += 1;
+        self.handle_event(Event::ReadIndex(self.last_read_id))
+            .map_err(ProposeError::Storage)?;
+        Ok(self.last_read_id)
+    }
+
+    /// If we are the leader, asks the follower with the most up to date log to take over, i.e. before shutting down.
+    /// Proposals are refused from then on, the follower is sent TimeoutNow once it has every entry in our log.
+    pub fn transfer_leadership(&mut self) -> Result<(), PersistentStorageError> {
+        self.handle_event(Event::TransferLeadership)
+    }
+
+    /// True if `ready` would return anything
+    pub fn has_ready(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    /// Returns everything the node needs done since the last call
+    pub fn ready(&mut self) -> Ready<C> {
+        mem::replace(&mut self.ready, Ready::new())
+    }
+
+    /// Role, term, vote and known leader of the node
+    pub fn state(&self) -> RaftStateEvent {
+        raft_state_event(self.server_id, self.node(), &self.storage)
+    }
+
+    /// Same status a raft thread publishes, see `RaftNodeHandle::status`
+    pub fn status(&self) -> RaftNodeStatus {
+        raft_node_status(self.server_id, self.node(), &self.storage)
+    }
+
+    pub fn storage(&self) -> &PS {
+        &self.storage
+    }
+
+    /// The event loop syncs storage through this, changing the term, vote or log directly breaks the protocol
+    pub fn storage_mut(&mut self) -> &mut PS {
+        &mut self.storage
+    }
+}
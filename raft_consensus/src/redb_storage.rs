@@ -0,0 +1,601 @@
+use crate::{PersistentStorageError, StorageOperation};
+
+use super::common::{LogCommand, LogEntry, LogIndex, PersistentStorage, ServerId, TermIndex};
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, TableError};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Term/vote and snapshot metadata, keyed by name
+const RAFT_STATE_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("raft_state");
+/// Log entries keyed by log index, values are bincode encoded `(TermIndex, C)`
+const RAFT_LOG_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("raft_log");
+
+const HARD_STATE_KEY: &str = "hard_state";
+const SNAPSHOT_METADATA_KEY: &str = "snapshot_metadata";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct HardState {
+    current_term: TermIndex,
+    voted_for: Option<(TermIndex, ServerId)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Describes the last snapshot taken of the application state, log entries up to and including
+/// `last_included_index` are covered by the snapshot and were dropped from the log (see `PersistentStorage::compact`).
+pub struct SnapshotMetadata {
+    /// Index of the last log entry included in the snapshot.
+    pub last_included_index: u64,
+    /// Term of the last log entry included in the snapshot.
+    pub last_included_term: TermIndex,
+}
+
+/// Size and term of an entry in the log of a redb database, see `inspect_redb_storage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogEntryInfo {
+    pub index: LogIndex,
+    pub term: TermIndex,
+    /// Size of the encoded term and command in bytes
+    pub size: usize,
+}
+
+/// Contents of a database written by `RedbPersistentStorage`, see `inspect_redb_storage`.
+#[derive(Debug, Clone)]
+pub struct RedbStorageInfo {
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub current_term: TermIndex,
+    /// The last vote and the term it was cast in, which may be before the current term
+    pub voted_for: Option<(TermIndex, ServerId)>,
+    pub snapshot_metadata: Option<SnapshotMetadata>,
+    /// Every entry in the log, ordered by index
+    pub log: Vec<LogEntryInfo>,
+}
+
+/// Log changes that have been made since the last sync, they are written to the database in a single transaction
+#[derive(Debug)]
+struct PendingLogChanges<C: LogCommand> {
+    truncate_from: Option<u64>,
+    entries: BTreeMap<u64, LogEntry<C>>,
+}
+impl<C: LogCommand> PendingLogChanges<C> {
+    fn new() -> Self {
+        PendingLogChanges {
+            truncate_from: None,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+/// Persistent storage backed by a redb database. The log, term/vote and snapshot metadata are all stored in the
+/// same database, so the application can use it to store its own state (see `database`).
+///
+/// Changes are buffered in memory and written in a single transaction on `sync`, so the hard state and any log
+/// entries appended with it become durable atomically.
+pub struct RedbPersistentStorage<C: LogCommand> {
+    database: Arc<Database>,
+    path: PathBuf,
+    hard_state: HardState,
+    snapshot_metadata: Option<SnapshotMetadata>,
+    hard_state_changed: bool,
+    snapshot_metadata_changed: bool,
+    /// Terms of every entry in the log (including pending changes), so the log can be checked without a read transaction
+    log_terms: BTreeMap<u64, TermIndex>,
+    pending_log_changes: PendingLogChanges<C>,
+}
+impl<C: LogCommand> Debug for RedbPersistentStorage<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedbPersistentStorage")
+            .field("path", &self.path)
+            .field("hard_state", &self.hard_state)
+            .field("snapshot_metadata", &self.snapshot_metadata)
+            .field("last_entry_index", &self.log_terms.keys().next_back())
+            .finish()
+    }
+}
+
+fn redb_error(
+    operation: StorageOperation,
+    path: &Path,
+    error: impl Into<redb::Error>,
+) -> PersistentStorageError {
+    match error.into() {
+        redb::Error::Io(io_error) => PersistentStorageError::transient(operation, path, io_error),
+        redb::Error::Corrupted(reason) => PersistentStorageError::corruption(
+            operation,
+            path,
+            io::Error::new(io::ErrorKind::InvalidData, reason),
+        ),
+        error => PersistentStorageError::transient(operation, path, io::Error::other(error)),
+    }
+}
+
+fn decode_error(path: &Path, error_kind: Box<bincode::ErrorKind>) -> PersistentStorageError {
+    PersistentStorageError::corruption(
+        StorageOperation::Read,
+        path,
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Bincode error: {:?}", error_kind),
+        ),
+    )
+}
+
+fn encode_error(path: &Path, error_kind: Box<bincode::ErrorKind>) -> PersistentStorageError {
+    PersistentStorageError::transient(
+        StorageOperation::Write,
+        path,
+        io::Error::other(format!("Bincode error: {:?}", error_kind)),
+    )
+}
+
+/// Entries are encoded as `(TermIndex, C)`, so the term can be read without knowing the command type
+fn decode_entry_term(path: &Path, bytes: &[u8]) -> Result<TermIndex, PersistentStorageError> {
+    bincode::deserialize(bytes).map_err(|e| decode_error(path, e))
+}
+
+/// Reads the term, vote, snapshot metadata and the index, term and size of every log entry from the database of a
+/// node that is not running, without knowing the type of its commands. Fails if there is no database at `path`, and
+/// with a corruption error if anything in it cannot be decoded.
+pub fn inspect_redb_storage(path: &Path) -> Result<RedbStorageInfo, PersistentStorageError> {
+    let file_size = std::fs::metadata(path)
+        .map_err(|e| PersistentStorageError::transient(StorageOperation::Open, path, e))?
+        .len();
+    let database = Database::open(path).map_err(|e| redb_error(StorageOperation::Open, path, e))?;
+    let txn = database
+        .begin_read()
+        .map_err(|e| redb_error(StorageOperation::Read, path, e))?;
+
+    let mut info = RedbStorageInfo {
+        path: path.to_path_buf(),
+        file_size,
+        current_term: TermIndex(0),
+        voted_for: None,
+        snapshot_metadata: None,
+        log: vec![],
+    };
+    // The tables are created when the storage is first opened, a database without them has no state yet
+    match txn.open_table(RAFT_STATE_TABLE) {
+        Ok(state_table) => {
+            if let Some(bytes) = state_table
+                .get(HARD_STATE_KEY)
+                .map_err(|e| redb_error(StorageOperation::Read, path, e))?
+            {
+                let hard_state: HardState =
+                    bincode::deserialize(bytes.value()).map_err(|e| decode_error(path, e))?;
+                info.current_term = hard_state.current_term;
+                info.voted_for = hard_state.voted_for;
+            }
+            if let Some(bytes) = state_table
+                .get(SNAPSHOT_METADATA_KEY)
+                .map_err(|e| redb_error(StorageOperation::Read, path, e))?
+            {
+                info.snapshot_metadata =
+                    Some(bincode::deserialize(bytes.value()).map_err(|e| decode_error(path, e))?);
+            }
+        }
+        Err(TableError::TableDoesNotExist(_)) => {}
+        Err(e) => return Err(redb_error(StorageOperation::Read, path, e)),
+    }
+    match txn.open_table(RAFT_LOG_TABLE) {
+        Ok(log_table) => {
+            for entry in log_table
+                .iter()
+                .map_err(|e| redb_error(StorageOperation::Read, path, e))?
+            {
+                let (index, bytes) =
+                    entry.map_err(|e| redb_error(StorageOperation::Read, path, e))?;
+                info.log.push(LogEntryInfo {
+                    index: LogIndex(index.value()),
+                    term: decode_entry_term(path, bytes.value())?,
+                    size: bytes.value().len(),
+                });
+            }
+        }
+        Err(TableError::TableDoesNotExist(_)) => {}
+        Err(e) => return Err(redb_error(StorageOperation::Read, path, e)),
+    }
+    Ok(info)
+}
+
+/// Removes every log entry after `index` from the database of a node that is not running, returning how many were
+/// removed. Entries that may have been committed must not be removed, the other servers could rely on them.
+pub fn truncate_redb_log_after(
+    path: &Path,
+    index: LogIndex,
+) -> Result<u64, PersistentStorageError> {
+    let database = Database::open(path).map_err(|e| redb_error(StorageOperation::Open, path, e))?;
+    let txn = database
+        .begin_write()
+        .map_err(|e| redb_error(StorageOperation::Write, path, e))?;
+    let removed = {
+        let mut log_table = txn
+            .open_table(RAFT_LOG_TABLE)
+            .map_err(|e| redb_error(StorageOperation::Write, path, e))?;
+        let len_before = log_table
+            .len()
+            .map_err(|e| redb_error(StorageOperation::Read, path, e))?;
+        log_table
+            .retain_in(index.0 + 1.., |_, _| false)
+            .map_err(|e| redb_error(StorageOperation::Write, path, e))?;
+        len_before
+            - log_table
+                .len()
+                .map_err(|e| redb_error(StorageOperation::Read, path, e))?
+    };
+    txn.commit()
+        .map_err(|e| redb_error(StorageOperation::Sync, path, e))?;
+    Ok(removed)
+}
+
+impl<C: LogCommand + Serialize + DeserializeOwned> RedbPersistentStorage<C> {
+    /// Opens (or creates) the redb database at `path`.
+    pub fn new(path: &Path) -> Result<Self, PersistentStorageError> {
+        let database =
+            Database::create(path).map_err(|e| redb_error(StorageOperation::Open, path, e))?;
+        Self::from_database(Arc::new(database), path)
+    }
+
+    /// Uses a database that is shared with the application, `path` is only used for error messages.
+    pub fn from_database(
+        database: Arc<Database>,
+        path: &Path,
+    ) -> Result<Self, PersistentStorageError> {
+        // Make sure the tables exist so read transactions don't fail on a new database
+        let txn = database
+            .begin_write()
+            .map_err(|e| redb_error(StorageOperation::Open, path, e))?;
+        {
+            let _ = txn
+                .open_table(RAFT_STATE_TABLE)
+                .map_err(|e| redb_error(StorageOperation::Open, path, e))?;
+            let _ = txn
+                .open_table(RAFT_LOG_TABLE)
+                .map_err(|e| redb_error(StorageOperation::Open, path, e))?;
+        }
+        txn.commit()
+            .map_err(|e| redb_error(StorageOperation::Open, path, e))?;
+
+        let txn = database
+            .begin_read()
+            .map_err(|e| redb_error(StorageOperation::Read, path, e))?;
+        let state_table = txn
+            .open_table(RAFT_STATE_TABLE)
+            .map_err(|e| redb_error(StorageOperation::Read, path, e))?;
+        let hard_state = match state_table
+            .get(HARD_STATE_KEY)
+            .map_err(|e| redb_error(StorageOperation::Read, path, e))?
+        {
+            Some(bytes) => {
+                bincode::deserialize(bytes.value()).map_err(|e| decode_error(path, e))?
+            }
+            None => HardState {
+                current_term: TermIndex(0),
+                voted_for: None,
+            },
+        };
+        let snapshot_metadata = match state_table
+            .get(SNAPSHOT_METADATA_KEY)
+            .map_err(|e| redb_error(StorageOperation::Read, path, e))?
+        {
+            Some(bytes) => {
+                Some(bincode::deserialize(bytes.value()).map_err(|e| decode_error(path, e))?)
+            }
+            None => None,
+        };
+
+        let log_table = txn
+            .open_table(RAFT_LOG_TABLE)
+            .map_err(|e| redb_error(StorageOperation::Read, path, e))?;
+        let mut log_terms = BTreeMap::new();
+        for entry in log_table
+            .iter()
+            .map_err(|e| redb_error(StorageOperation::Read, path, e))?
+        {
+            let (index, bytes) = entry.map_err(|e| redb_error(StorageOperation::Read, path, e))?;
+            // Only the term is kept in memory, decoding the commands of the whole log would slow down every start
+            let _ = log_terms.insert(index.value(), decode_entry_term(path, bytes.value())?);
+        }
+
+        Ok(RedbPersistentStorage {
+            database,
+            path: path.to_path_buf(),
+            hard_state,
+            snapshot_metadata,
+            hard_state_changed: false,
+            snapshot_metadata_changed: false,
+            log_terms,
+            pending_log_changes: PendingLogChanges::new(),
+        })
+    }
+
+    /// The database the storage is using, the application can store its own tables in it.
+    pub fn database(&self) -> Arc<Database> {
+        self.database.clone()
+    }
+
+    /// Metadata of the last snapshot, if one has been taken.
+    pub fn snapshot_metadata(&self) -> Option<SnapshotMetadata> {
+        self.snapshot_metadata
+    }
+
+    /// Reads a log entry, including entries that have not been synced yet.
+    pub fn entry(&self, index: LogIndex) -> Result<Option<LogEntry<C>>, PersistentStorageError> {
+        if let Some(entry) = self.pending_log_changes.entries.get(&index.0) {
+            return Ok(Some(entry.clone()));
+        }
+        if !self.log_terms.contains_key(&index.0) {
+            return Ok(None);
+        }
+
+        let txn = self
+            .database
+            .begin_read()
+            .map_err(|e| redb_error(StorageOperation::Read, &self.path, e))?;
+        let log_table = txn
+            .open_table(RAFT_LOG_TABLE)
+            .map_err(|e| redb_error(StorageOperation::Read, &self.path, e))?;
+        match log_table
+            .get(index.0)
+            .map_err(|e| redb_error(StorageOperation::Read, &self.path, e))?
+        {
+            Some(bytes) => Ok(Some(self.decode_entry(index, bytes.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn decode_entry(
+        &self,
+        index: LogIndex,
+        bytes: &[u8],
+    ) -> Result<LogEntry<C>, PersistentStorageError> {
+        let (term, command) =
+            bincode::deserialize(bytes).map_err(|e| decode_error(&self.path, e))?;
+        Ok(LogEntry {
+            index,
+            term,
+            command,
+        })
+    }
+
+    fn truncate_from(&mut self, index: u64) {
+        let _ = self.log_terms.split_off(&index);
+        let _ = self.pending_log_changes.entries.split_off(&index);
+        self.pending_log_changes.truncate_from = Some(
+            self.pending_log_changes
+                .truncate_from
+                .map_or(index, |truncate_from| truncate_from.min(index)),
+        );
+    }
+
+    fn write_pending_changes(&mut self) -> Result<(), PersistentStorageError> {
+        let has_pending_changes = self.hard_state_changed
+            || self.snapshot_metadata_changed
+            || self.pending_log_changes.truncate_from.is_some()
+            || !self.pending_log_changes.entries.is_empty();
+        if !has_pending_changes {
+            return Ok(());
+        }
+
+        let txn = self
+            .database
+            .begin_write()
+            .map_err(|e| redb_error(StorageOperation::Write, &self.path, e))?;
+        {
+            let mut state_table = txn
+                .open_table(RAFT_STATE_TABLE)
+                .map_err(|e| redb_error(StorageOperation::Write, &self.path, e))?;
+            if self.hard_state_changed {
+                let bytes = bincode::serialize(&self.hard_state)
+                    .map_err(|e| encode_error(&self.path, e))?;
+                let _ = state_table
+                    .insert(HARD_STATE_KEY, bytes.as_slice())
+                    .map_err(|e| redb_error(StorageOperation::Write, &self.path, e))?;
+            }
+            let mut log_table = txn
+                .open_table(RAFT_LOG_TABLE)
+                .map_err(|e| redb_error(StorageOperation::Write, &self.path, e))?;
+            if let (true, Some(snapshot_metadata)) =
+                (self.snapshot_metadata_changed, self.snapshot_metadata)
+            {
+                let bytes = bincode::serialize(&snapshot_metadata)
+                    .map_err(|e| encode_error(&self.path, e))?;
+                let _ = state_table
+                    .insert(SNAPSHOT_METADATA_KEY, bytes.as_slice())
+                    .map_err(|e| redb_error(StorageOperation::Write, &self.path, e))?;
+                log_table
+                    .retain_in(..=snapshot_metadata.last_included_index, |_, _| false)
+                    .map_err(|e| redb_error(StorageOperation::Write, &self.path, e))?;
+            }
+            if let Some(truncate_from) = self.pending_log_changes.truncate_from {
+                log_table
+                    .retain_in(truncate_from.., |_, _| false)
+                    .map_err(|e| redb_error(StorageOperation::Write, &self.path, e))?;
+            }
+            for (index, entry) in &self.pending_log_changes.entries {
+                let bytes = bincode::serialize(&(entry.term, &entry.command))
+                    .map_err(|e| encode_error(&self.path, e))?;
+                let _ = log_table
+                    .insert(*index, bytes.as_slice())
+                    .map_err(|e| redb_error(StorageOperation::Write, &self.path, e))?;
+            }
+        }
+        txn.commit()
+            .map_err(|e| redb_error(StorageOperation::Sync, &self.path, e))?;
+
+        self.hard_state_changed = false;
+        self.snapshot_metadata_changed = false;
+        self.pending_log_changes = PendingLogChanges::new();
+        Ok(())
+    }
+}
+
+impl<C: LogCommand + Serialize + DeserializeOwned> PersistentStorage<C>
+    for RedbPersistentStorage<C>
+{
+    fn current_term(&self) -> TermIndex {
+        self.hard_state.current_term
+    }
+
+    fn vote_for_current_term(&self) -> Option<ServerId> {
+        self.hard_state
+            .voted_for
+            .and_then(|(last_vote_term, server_id)| {
+                if last_vote_term == self.hard_state.current_term {
+                    Some(server_id)
+                } else {
+                    None
+                }
+            })
+    }
+
+    fn update_term(&mut self, term: TermIndex) -> &mut Self {
+        self.hard_state.current_term = term;
+        self.hard_state_changed = true;
+        self
+    }
+
+    fn record_vote(&mut self, voted_for: ServerId) -> &mut Self {
+        self.hard_state.voted_for = Some((self.hard_state.current_term, voted_for));
+        self.hard_state_changed = true;
+        self
+    }
+
+    fn last_entry_index(&self) -> Option<LogIndex> {
+        self.log_terms
+            .keys()
+            .next_back()
+            .map(|index| LogIndex(*index))
+            .or(self.compacted_through().map(|(index, _)| index))
+    }
+
+    fn last_entry_term(&self) -> Option<TermIndex> {
+        self.log_terms
+            .values()
+            .next_back()
+            .copied()
+            .or(self.compacted_through().map(|(_, term)| term))
+    }
+
+    fn entry_term(&self, index: LogIndex) -> Option<TermIndex> {
+        match self.compacted_through() {
+            Some((compacted_index, term)) if compacted_index == index => Some(term),
+            _ => self.log_terms.get(&index.0).copied(),
+        }
+    }
+
+    /// Checks if there is a log entry with matching log index & log term
+    fn has_entry(&self, index: LogIndex, term: TermIndex) -> bool {
+        self.entry_term(index) == Some(term)
+    }
+
+    fn entries(
+        &self,
+        from: LogIndex,
+        max_entries: usize,
+    ) -> Result<Vec<LogEntry<C>>, PersistentStorageError> {
+        let indexes: Vec<u64> = self
+            .log_terms
+            .range(from.0..)
+            .map(|(index, _)| *index)
+            .take(max_entries)
+            .collect();
+        let (first, last) = match (indexes.first(), indexes.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return Ok(vec![]),
+        };
+
+        // The synced entries are read in one transaction, unsynced ones replace them
+        let mut synced = BTreeMap::new();
+        if indexes
+            .iter()
+            .any(|index| !self.pending_log_changes.entries.contains_key(index))
+        {
+            let txn = self
+                .database
+                .begin_read()
+                .map_err(|e| redb_error(StorageOperation::Read, &self.path, e))?;
+            let log_table = txn
+                .open_table(RAFT_LOG_TABLE)
+                .map_err(|e| redb_error(StorageOperation::Read, &self.path, e))?;
+            for row in log_table
+                .range(first..=last)
+                .map_err(|e| redb_error(StorageOperation::Read, &self.path, e))?
+            {
+                let (index, bytes) =
+                    row.map_err(|e| redb_error(StorageOperation::Read, &self.path, e))?;
+                let index = index.value();
+                if self.pending_log_changes.entries.contains_key(&index) {
+                    continue;
+                }
+                let _ = synced.insert(index, self.decode_entry(LogIndex(index), bytes.value())?);
+            }
+        }
+        Ok(indexes
+            .into_iter()
+            .filter_map(|index| {
+                self.pending_log_changes
+                    .entries
+                    .get(&index)
+                    .cloned()
+                    .or_else(|| synced.remove(&index))
+            })
+            .collect())
+    }
+
+    /// Appends new entries to log, first deleting any conflicting entries (same index but different terms)
+    fn append(&mut self, entries: Vec<LogEntry<C>>) -> &mut Self {
+        let compacted_index = self.compacted_through().map(|(index, _)| index);
+        // Compacted entries were committed, the leader's are the same
+        for entry in entries
+            .into_iter()
+            .filter(|entry| Some(entry.index) > compacted_index)
+        {
+            match self.log_terms.get(&entry.index.0) {
+                Some(existing_term) if *existing_term == entry.term => continue,
+                Some(_) => self.truncate_from(entry.index.0),
+                None => {}
+            }
+            let _ = self.log_terms.insert(entry.index.0, entry.term);
+            let _ = self
+                .pending_log_changes
+                .entries
+                .insert(entry.index.0, entry);
+        }
+        self
+    }
+
+    fn compacted_through(&self) -> Option<(LogIndex, TermIndex)> {
+        self.snapshot_metadata.map(|snapshot_metadata| {
+            (
+                LogIndex(snapshot_metadata.last_included_index),
+                snapshot_metadata.last_included_term,
+            )
+        })
+    }
+
+    /// The entries are deleted from the database in the transaction written by the next `sync`
+    fn compact(&mut self, index: LogIndex) -> &mut Self {
+        if let Some(term) = self.log_terms.get(&index.0).copied() {
+            self.log_terms = self.log_terms.split_off(&(index.0 + 1));
+            self.pending_log_changes.entries =
+                self.pending_log_changes.entries.split_off(&(index.0 + 1));
+            self.snapshot_metadata = Some(SnapshotMetadata {
+                last_included_index: index.0,
+                last_included_term: term,
+            });
+            self.snapshot_metadata_changed = true;
+        }
+        self
+    }
+
+    fn sync(&mut self) -> Result<(), PersistentStorageError> {
+        self.write_pending_changes()
+    }
+}
@@ -1,6 +1,8 @@
 use std::fmt::Debug;
 
-use uuid::Uuid;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use uuid::{Uuid, Variant, Version};
 
 use super::common::*;
 
@@ -30,14 +32,28 @@ impl<C: LogCommand> RpcMessage<C> {
             RpcMessage::Request(request) => match request {
                 Request::AppendEntries(ae) => ae.request_id,
                 Request::RequestVote(rv) => rv.request_id,
+                Request::TimeoutNow(tn) => tn.request_id,
             },
             RpcMessage::Reply(reply) => match reply {
                 ReplyTo::AppendEntries(ae) => ae.request_id,
                 ReplyTo::RequestVote(rv) => rv.request_id,
+                ReplyTo::TimeoutNow(tn) => tn.request_id,
             },
         }
     }
 
+    /// Name of the RPC, used to label metrics and tracing spans
+    pub fn rpc_name(&self) -> &'static str {
+        match self {
+            RpcMessage::Request(Request::RequestVote(_)) => "request_vote",
+            RpcMessage::Request(Request::AppendEntries(_)) => "append_entries",
+            RpcMessage::Request(Request::TimeoutNow(_)) => "timeout_now",
+            RpcMessage::Reply(ReplyTo::RequestVote(_)) => "vote",
+            RpcMessage::Reply(ReplyTo::AppendEntries(_)) => "append_entries_ack",
+            RpcMessage::Reply(ReplyTo::TimeoutNow(_)) => "timeout_now_ack",
+        }
+    }
+
     pub fn append_entries(append_entries: AppendEntries<C>) -> Self {
         RpcMessage::Request(Request::AppendEntries(append_entries))
     }
@@ -53,6 +69,14 @@ impl<C: LogCommand> RpcMessage<C> {
     pub fn ack_append_entries(append_entries_ack: AppendEntriesAck) -> Self {
         RpcMessage::Reply(ReplyTo::AppendEntries(append_entries_ack))
     }
+
+    pub fn timeout_now(timeout_now: TimeoutNow) -> Self {
+        RpcMessage::Request(Request::TimeoutNow(timeout_now))
+    }
+
+    pub fn ack_timeout_now(timeout_now_ack: TimeoutNowAck) -> Self {
+        RpcMessage::Reply(ReplyTo::TimeoutNow(timeout_now_ack))
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -77,34 +101,50 @@ pub struct RequestVote {
     pub last_log_term: TermIndex,
 }
 
+/// Sent by a leader that is handing off leadership (i.e. because it is shutting down),
+/// tells the receiver to start an election immediately instead of waiting for its election timeout.
+/// See section 3.10 of the Raft thesis: <https://github.com/ongardie/dissertation>
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TimeoutNow {
+    pub request_id: Uuid,
+    pub from: ServerId,
+    pub to: ServerId,
+    pub term: TermIndex,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Request<C: LogCommand> {
     AppendEntries(AppendEntries<C>),
     RequestVote(RequestVote),
+    TimeoutNow(TimeoutNow),
 }
 impl<C: LogCommand> Request<C> {
     pub fn from(&self) -> ServerId {
         match self {
             Request::AppendEntries(ae) => ae.from,
             Request::RequestVote(rv) => rv.from,
+            Request::TimeoutNow(tn) => tn.from,
         }
     }
     pub fn to(&self) -> ServerId {
         match self {
             Request::AppendEntries(ae) => ae.to,
             Request::RequestVote(rv) => rv.to,
+            Request::TimeoutNow(tn) => tn.to,
         }
     }
     pub fn term(&self) -> TermIndex {
         match self {
             Request::AppendEntries(ae) => ae.term,
             Request::RequestVote(rv) => rv.term,
+            Request::TimeoutNow(tn) => tn.term,
         }
     }
     pub fn request_id(&self) -> Uuid {
         match self {
             Request::AppendEntries(ae) => ae.request_id,
             Request::RequestVote(rv) => rv.request_id,
+            Request::TimeoutNow(tn) => tn.request_id,
         }
     }
 }
@@ -116,6 +156,9 @@ pub struct AppendEntriesAck {
     pub to: ServerId,
     pub term: TermIndex,
     pub success: bool,
+    /// On success the index of the last entry known to match the leader's log, on failure the index of the last
+    /// entry in the follower's log so the leader can skip back past entries the follower doesn't have
+    pub match_index: LogIndex,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -127,34 +170,74 @@ pub struct Vote {
     pub vote_granted: bool,
 }
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TimeoutNowAck {
+    pub request_id: Uuid,
+    pub from: ServerId,
+    pub to: ServerId,
+    pub term: TermIndex,
+    /// True if the receiver started an election in response to the TimeoutNow request
+    pub success: bool,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum ReplyTo {
     AppendEntries(AppendEntriesAck),
     RequestVote(Vote),
+    TimeoutNow(TimeoutNowAck),
 }
 impl ReplyTo {
     pub fn from(&self) -> ServerId {
         match self {
             ReplyTo::AppendEntries(ae) => ae.from,
             ReplyTo::RequestVote(rv) => rv.from,
+            ReplyTo::TimeoutNow(tn) => tn.from,
         }
     }
     pub fn to(&self) -> ServerId {
         match self {
             ReplyTo::AppendEntries(ae) => ae.to,
             ReplyTo::RequestVote(rv) => rv.to,
+            ReplyTo::TimeoutNow(tn) => tn.to,
         }
     }
     pub fn term(&self) -> TermIndex {
         match self {
             ReplyTo::AppendEntries(ae) => ae.term,
             ReplyTo::RequestVote(rv) => rv.term,
+            ReplyTo::TimeoutNow(tn) => tn.term,
         }
     }
     pub fn request_id(&self) -> Uuid {
         match self {
             ReplyTo::AppendEntries(ae) => ae.request_id,
             ReplyTo::RequestVote(rv) => rv.request_id,
+            ReplyTo::TimeoutNow(tn) => tn.request_id,
         }
     }
 }
+
+/// Generates request IDs for the state machine from an RNG seeded from the raft thread's RNG, so the IDs are
+/// reproducible from the seed.
+/// IDs are random (version 4) UUIDs, so they don't collide with those of other servers or from before a restart as long
+/// as the raft thread's RNG is seeded differently on each start, e.g. from entropy.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestIdGenerator {
+    rng: ChaCha8Rng,
+}
+impl RequestIdGenerator {
+    pub(crate) fn from_rng(rng: &mut ChaCha8Rng) -> Self {
+        RequestIdGenerator {
+            rng: ChaCha8Rng::seed_from_u64(rng.next_u64()),
+        }
+    }
+
+    pub(crate) fn next_id(&mut self) -> Uuid {
+        let mut bytes = [0; 16];
+        self.rng.fill_bytes(&mut bytes);
+        uuid::Builder::from_bytes(bytes)
+            .set_variant(Variant::RFC4122)
+            .set_version(Version::Random)
+            .build()
+    }
+}
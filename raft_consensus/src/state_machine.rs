@@ -5,31 +5,65 @@ use self::state_defs::*;
 /// Currently, only implements the leader election part of the protocol
 use super::common::*;
 use super::rpc_messages::*;
-use crate::system_clock;
+use crate::metrics;
+use crate::raft_events::{RaftTransition, SteppedDownReason};
 use crate::system_clock::Instant;
-use divrem::DivCeil;
 use rand::Rng;
 use rand_chacha::ChaCha8Rng;
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashSet};
 use std::fmt::Debug;
 use std::time::Duration;
 use tracing::debug;
+use tracing::field;
 use tracing::info;
+use tracing::info_span;
 use tracing::trace;
+use tracing::trace_span;
+use tracing::warn;
 use uuid::Uuid;
 
+/// Maximum number of log entries sent to a follower in a single AppendEntries request
+const MAX_ENTRIES_PER_APPEND: usize = 64;
+/// Maximum number of unanswered requests remembered per follower, older ones are assumed to be lost
+const MAX_UNANSWERED_REQUESTS: usize = 256;
+
+/// Index of the last entry dropped from the log by compaction, 0 if none were
+fn compacted_index<C: LogCommand>(storage: &impl PersistentStorage<C>) -> LogIndex {
+    storage
+        .compacted_through()
+        .map_or(LogIndex(0), |(index, _)| index)
+}
+
 #[derive(Debug, Clone)]
 pub(crate) enum Event<C: LogCommand> {
     Tick(Instant),
-    LogEntryAppliedByApplication(LogIndex),
     IncomingRpc(RpcMessage<C>),
+    /// Leader should hand off leadership to one of the followers, other states ignore this
+    TransferLeadership,
+    /// Leader should append the command to its log and replicate it, other states ignore this
+    Propose(C),
+    /// Leader should confirm it is still the leader and tell when the read with this ID can be served, other states
+    /// ignore this
+    ReadIndex(u64),
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum Action<C: LogCommand> {
     SetNextTimeout(Duration),
-    ApplyLogEntries(Vec<C>),
+    /// Entries that were committed and can be applied to the application, in log order
+    ApplyLogEntries(Vec<LogEntry<C>>),
     OutgoingRpc(RpcMessage<C>),
+    /// Entries were appended to the log in storage, they must be synced before any message is sent
+    AppendedToLog(Vec<LogEntry<C>>),
+    /// The node won an election, stepped down or voted while handling the event, for the event collector
+    Transition(RaftTransition),
+    /// A majority confirmed we were still the leader after the read arrived, it can be served once the entries up to
+    /// `index` have been applied
+    ReadIndexConfirmed {
+        read_id: u64,
+        index: LogIndex,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -39,14 +73,18 @@ pub(crate) enum Node {
     Candidate(NodeState<Candidate>),
 }
 impl Node {
+    /// Creates a follower that starts its first election timer at `now`.
+    /// The node never reads the clock or generates randomness itself, time only advances with `Event::Tick` and
+    /// request IDs are generated from a generator seeded from `rng`, so `next` is a pure function of its inputs.
     pub(crate) fn new(
         server_id: ServerId,
         other_servers: HashSet<ServerId>,
         config: &RaftConfig,
         rng: &mut ChaCha8Rng,
+        now: Instant,
     ) -> (Self, FirstElectionTimeout) {
         let (initial_state, first_timer) =
-            NodeState::<Follower>::new(server_id, other_servers, config, rng);
+            NodeState::<Follower>::new(server_id, other_servers, config, rng, now);
 
         (initial_state.into(), first_timer)
     }
@@ -59,11 +97,23 @@ impl Node {
         }
     }
 
-    fn update_clock(&mut self) {
-        match self {
-            Node::Leader(state) => state.current_time = system_clock::now(),
-            Node::Follower(state) => state.current_time = system_clock::now(),
-            Node::Candidate(state) => state.current_time = system_clock::now(),
+    pub(crate) fn is_leader(&self) -> bool {
+        matches!(self, Node::Leader(_))
+    }
+
+    /// Only a leader that isn't handing leadership to another server appends proposals to its log
+    pub(crate) fn accepts_proposals(&self) -> bool {
+        matches!(self, Node::Leader(state) if state.inner.transfer_target.is_none())
+    }
+
+    /// Other events are handled at the time of the last tick
+    fn update_clock<C: LogCommand>(&mut self, event: &Event<C>) {
+        if let Event::Tick(now) = event {
+            match self {
+                Node::Leader(state) => state.current_time = *now,
+                Node::Follower(state) => state.current_time = *now,
+                Node::Candidate(state) => state.current_time = *now,
+            }
         }
     }
 
@@ -86,12 +136,18 @@ impl Node {
 
         if should_become_follower {
             info!(
-                "{:?}: Becoming follower because we've observed a higher term {:?} than ours {:?}",
-                self.server_id(),
-                new_term,
-                storage.current_term()
+                new_term = new_term.0,
+                "Becoming follower because we've observed a higher term than ours"
             );
-            storage.update_term(new_term).sync()?;
+            let mut actions = vec![];
+            if self.is_leader() {
+                actions.push(Action::Transition(RaftTransition::SteppedDown {
+                    term: storage.current_term(),
+                    reason: SteppedDownReason::HigherTermObserved(new_term),
+                }));
+            }
+            storage.update_term(new_term);
+            metrics::term_changed(self.server_id());
             let mut follower_state: NodeState<Follower> = match self {
                 Node::Leader(state) => state.transition_to(),
                 Node::Follower(state) => state,
@@ -103,10 +159,8 @@ impl Node {
             // if so new leader will send us a heartbeat eventually and we'll update this
             follower_state.inner.leader_id = None;
             let election_timeout = follower_state.reset_election_timer(config, rng);
-            Ok((
-                follower_state.into(),
-                vec![Action::SetNextTimeout(election_timeout)],
-            ))
+            actions.push(Action::SetNextTimeout(election_timeout));
+            Ok((follower_state.into(), actions))
         } else {
             Ok((self, vec![]))
         }
@@ -119,18 +173,57 @@ impl Node {
         config: &RaftConfig,
         rng: &mut ChaCha8Rng,
     ) -> Result<(Self, Vec<Action<C>>), PersistentStorageError> {
-        self.update_clock();
+        // Ticks happen every time the raft thread wakes up so their spans are only recorded at trace level
+        let span = match &event {
+            Event::Tick(_) => trace_span!(
+                "raft_event",
+                server_id = self.server_id().0,
+                term = storage.current_term().0,
+                event = "tick",
+                request_id = field::Empty,
+                from = field::Empty,
+            ),
+            _ => info_span!(
+                "raft_event",
+                server_id = self.server_id().0,
+                term = storage.current_term().0,
+                event = field::Empty,
+                request_id = field::Empty,
+                from = field::Empty,
+            ),
+        };
+        match &event {
+            Event::IncomingRpc(message) => {
+                span.record("event", message.rpc_name());
+                span.record("request_id", field::display(message.request_id()));
+                span.record("from", message.from().0);
+            }
+            Event::TransferLeadership => {
+                span.record("event", "transfer_leadership");
+            }
+            Event::Propose(_) => {
+                span.record("event", "propose");
+            }
+            Event::ReadIndex(_) => {
+                span.record("event", "read_index");
+            }
+            Event::Tick(_) => (),
+        }
+        let _entered = span.enter();
+
+        self.update_clock(&event);
 
         self.if_rpc_message_has_higher_term_become_follower(storage, &event, config, rng)
-            .and_then(|(new_node, mut maybe_tick_timer)| {
+            .and_then(|(new_node, mut step_down_actions)| {
                 let (new_node, mut actions) = match new_node {
                     Self::Leader(state) => state.handle_event(event, storage, config, rng)?,
                     Self::Follower(state) => state.handle_event(event, storage, config, rng)?,
                     Self::Candidate(state) => state.handle_event(event, storage, config, rng)?,
                 };
 
-                actions.append(&mut maybe_tick_timer);
-                Ok((new_node, actions))
+                // Stepping down happened first, so it is reported before anything the follower did
+                step_down_actions.append(&mut actions);
+                Ok((new_node, step_down_actions))
             })
     }
 }
@@ -155,9 +248,11 @@ pub(crate) struct NodeState<S: State> {
     server_id: ServerId,
     start_time: Instant,
     current_time: Instant,
-    other_servers: HashSet<ServerId>,
-    commit_index: LogIndex,
-    last_applied: LogIndex,
+    /// Ordered so messages to the other servers are always produced in the same order
+    other_servers: BTreeSet<ServerId>,
+    request_ids: RequestIdGenerator,
+    pub(crate) commit_index: LogIndex,
+    pub(crate) last_applied: LogIndex,
     pub(crate) inner: S,
 }
 
@@ -194,7 +289,7 @@ macro_rules! has_election_timer {
                 ));
 
                 self.inner.election_timeout = election_timeout;
-                self.inner.last_election_timer_started = system_clock::now();
+                self.inner.last_election_timer_started = self.current_time;
 
                 election_timeout
             }
@@ -205,33 +300,72 @@ macro_rules! has_election_timer {
 mod state_defs {
     use crate::common::LogIndex;
     use crate::common::ServerId;
-    use crate::system_clock;
     use crate::system_clock::Instant;
 
     use std::collections::HashMap;
     use std::collections::HashSet;
+    use std::collections::VecDeque;
     use std::fmt::Debug;
     use std::time::Duration;
+    use uuid::Uuid;
 
     #[derive(Debug, Clone)]
     struct Priv {}
 
     pub(crate) trait State: Debug {}
+
+    /// Conversion between states at the time of the transition
+    pub(crate) trait TransitionFrom<S: State> {
+        fn transition_from(state: S, now: Instant) -> Self;
+    }
     #[derive(Debug, Clone)]
     pub(crate) struct Leader {
         pub(crate) last_heartbeat_sent: Instant,
         pub(crate) next_index: HashMap<ServerId, LogIndex>,
         pub(crate) match_index: HashMap<ServerId, LogIndex>,
+        /// When we last received a reply from each follower in the current term
+        pub(crate) last_contact: HashMap<ServerId, Instant>,
+        /// Number of AppendEntries requests sent in this term, each request is numbered with the count at the time
+        pub(crate) requests_sent: u64,
+        /// Requests sent to each follower in the current term, to tell which reads its answers confirm
+        pub(crate) follower_requests: HashMap<ServerId, FollowerRequests>,
+        /// Reads waiting for a majority to confirm we are still the leader, oldest first
+        pub(crate) pending_reads: Vec<PendingRead>,
+        /// The follower we are handing leadership to, proposals are refused until we step down
+        pub(crate) transfer_target: Option<ServerId>,
+        /// Whether the transfer target has caught up with our log and was sent TimeoutNow
+        pub(crate) timeout_now_sent: bool,
         _priv: Priv,
     }
 
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct FollowerRequests {
+        /// Requests that haven't been answered, oldest first, with their numbers
+        pub(crate) unanswered: VecDeque<(Uuid, u64)>,
+        /// Number of the latest request the follower answered, which confirmed we were still its leader
+        pub(crate) answered_through: u64,
+    }
+
+    /// A read that arrived once `requests_sent` requests had been sent, it is confirmed by answers to later ones
+    #[derive(Debug, Clone)]
+    pub(crate) struct PendingRead {
+        pub(crate) read_id: u64,
+        pub(crate) requests_sent: u64,
+    }
+
     impl State for Leader {}
-    impl From<Candidate> for Leader {
-        fn from(_: Candidate) -> Self {
+    impl TransitionFrom<Candidate> for Leader {
+        fn transition_from(_: Candidate, now: Instant) -> Self {
             Leader {
-                last_heartbeat_sent: system_clock::now(),
+                last_heartbeat_sent: now,
                 next_index: HashMap::new(),
                 match_index: HashMap::new(),
+                last_contact: HashMap::new(),
+                requests_sent: 0,
+                follower_requests: HashMap::new(),
+                pending_reads: vec![],
+                transfer_target: None,
+                timeout_now_sent: false,
                 _priv: Priv {},
             }
         }
@@ -245,10 +379,10 @@ mod state_defs {
         _priv: Priv,
     }
     impl State for Candidate {}
-    impl From<Follower> for Candidate {
-        fn from(_: Follower) -> Self {
+    impl TransitionFrom<Follower> for Candidate {
+        fn transition_from(_: Follower, now: Instant) -> Self {
             Candidate {
-                last_election_timer_started: system_clock::now(),
+                last_election_timer_started: now,
                 election_timeout: Duration::from_millis(0),
                 votes_received: HashSet::new(),
                 _priv: Priv {},
@@ -264,9 +398,9 @@ mod state_defs {
         _priv: Priv,
     }
     impl Follower {
-        pub(crate) fn new() -> Self {
+        pub(crate) fn new(now: Instant) -> Self {
             Follower {
-                last_election_timer_started: system_clock::now(),
+                last_election_timer_started: now,
                 election_timeout: Duration::from_millis(0),
                 leader_id: None,
                 _priv: Priv {},
@@ -274,20 +408,20 @@ mod state_defs {
         }
     }
     impl State for Follower {}
-    impl From<Leader> for Follower {
-        fn from(_: Leader) -> Self {
+    impl TransitionFrom<Leader> for Follower {
+        fn transition_from(_: Leader, now: Instant) -> Self {
             Follower {
-                last_election_timer_started: system_clock::now(),
+                last_election_timer_started: now,
                 leader_id: None,
                 election_timeout: Duration::from_millis(0),
                 _priv: Priv {},
             }
         }
     }
-    impl From<Candidate> for Follower {
-        fn from(candidate: Candidate) -> Self {
+    impl TransitionFrom<Candidate> for Follower {
+        fn transition_from(candidate: Candidate, now: Instant) -> Self {
             Follower {
-                last_election_timer_started: system_clock::now(),
+                last_election_timer_started: now,
                 election_timeout: candidate.election_timeout,
                 leader_id: None,
                 _priv: Priv {},
@@ -297,16 +431,49 @@ mod state_defs {
 }
 
 impl<St: State> NodeState<St> {
+    /// Number of servers (including us) that make up a majority of the cluster
+    fn quorum(&self) -> usize {
+        let cluster_size = self.other_servers.len() + 1;
+        (cluster_size + 1).div_ceil(2)
+    }
+
+    /// Hands entries that were committed since we last did so to the application
+    fn apply_committed_entries<C, PS>(
+        &mut self,
+        storage: &PS,
+    ) -> Result<Vec<Action<C>>, PersistentStorageError>
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        // Compacted entries were applied to the snapshot the application restored its state from
+        let applied = self.last_applied.max(compacted_index(storage));
+        if self.commit_index <= applied {
+            self.last_applied = self.last_applied.max(self.commit_index);
+            return Ok(vec![]);
+        }
+        let entries = storage.entries(
+            LogIndex(applied.0 + 1),
+            (self.commit_index.0 - applied.0) as usize,
+        )?;
+        self.last_applied = self.commit_index;
+        Ok(vec![Action::ApplyLogEntries(entries)])
+    }
+
     fn ack_append_entries<C, PS>(
         &self,
         storage: &PS,
         append_entries_req: AppendEntries<C>,
         success: bool,
+        match_index: LogIndex,
     ) -> Vec<Action<C>>
     where
         C: LogCommand,
         PS: PersistentStorage<C>,
     {
+        if !success {
+            metrics::append_entries_rejected(self.server_id);
+        }
         vec![Action::OutgoingRpc(RpcMessage::ack_append_entries(
             AppendEntriesAck {
                 request_id: append_entries_req.request_id,
@@ -314,6 +481,28 @@ impl<St: State> NodeState<St> {
                 to: append_entries_req.from,
                 term: storage.current_term(),
                 success,
+                match_index,
+            },
+        ))]
+    }
+
+    fn ack_timeout_now<C, PS>(
+        &self,
+        storage: &PS,
+        timeout_now_req: TimeoutNow,
+        success: bool,
+    ) -> Vec<Action<C>>
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        vec![Action::OutgoingRpc(RpcMessage::ack_timeout_now(
+            TimeoutNowAck {
+                request_id: timeout_now_req.request_id,
+                from: self.server_id,
+                to: timeout_now_req.from,
+                term: storage.current_term(),
+                success,
             },
         ))]
     }
@@ -329,12 +518,10 @@ impl<St: State> NodeState<St> {
         PS: PersistentStorage<C>,
     {
         debug!(
-            "{server_id:?}: Vote NO for candidate {candidate_id:?} because {reason:?} (my term: {my_term:?}, vote term: {candidate_term:?})",
-            server_id = self.server_id,
-            candidate_id = vote_req.from,
-            reason = reason,
-            my_term=storage.current_term(),
-            candidate_term=vote_req.term,
+            candidate_id = vote_req.from.0,
+            candidate_term = vote_req.term.0,
+            reason,
+            "Vote NO for candidate"
         );
         vec![Action::OutgoingRpc(RpcMessage::vote(Vote {
             request_id: vote_req.request_id,
@@ -347,41 +534,447 @@ impl<St: State> NodeState<St> {
 }
 
 impl NodeState<Leader> {
+    /// Resets replication progress for a newly elected leader, we assume every follower has our whole log
+    /// until an AppendEntries rejection tells us otherwise (§5.3)
+    fn initialize_follower_progress<C, PS>(&mut self, storage: &PS)
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        let next_index = LogIndex(storage.last_entry_index().map(|index| index.0).unwrap_or(0) + 1);
+        for other_server in &self.other_servers {
+            self.inner.next_index.insert(*other_server, next_index);
+            self.inner.match_index.insert(*other_server, LogIndex(0));
+        }
+    }
+
+    fn record_contact_with_follower<C, PS>(&mut self, storage: &PS, from: ServerId, term: TermIndex)
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        if term == storage.current_term() {
+            self.inner.last_contact.insert(from, self.current_time);
+        }
+    }
+
+    /// AppendEntries request with the entries the follower is missing, starting at its next index (§5.3). The next
+    /// index moves past the entries as soon as they are sent, so later requests only carry entries the follower
+    /// hasn't been sent yet. If a request is lost the follower rejects the next one and the next index backs up.
+    fn append_entries_for_follower<C, PS>(
+        &mut self,
+        storage: &PS,
+        follower: ServerId,
+    ) -> Result<AppendEntries<C>, PersistentStorageError>
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        let next_index = self
+            .inner
+            .next_index
+            .get(&follower)
+            .copied()
+            .unwrap_or(LogIndex(1));
+        let (prev_log_index, entries) = if next_index <= compacted_index(storage) {
+            // The entries the follower is missing were compacted, all we can do is keep it from starting an election
+            (compacted_index(storage), vec![])
+        } else {
+            (
+                LogIndex(next_index.0 - 1),
+                storage.entries(next_index, MAX_ENTRIES_PER_APPEND)?,
+            )
+        };
+        let prev_log_term = storage.entry_term(prev_log_index).unwrap_or(TermIndex(0));
+        if let Some(last_entry) = entries.last() {
+            self.inner
+                .next_index
+                .insert(follower, LogIndex(last_entry.index.0 + 1));
+        }
+        let request_id = self.request_ids.next_id();
+        self.inner.requests_sent += 1;
+        let unanswered = &mut self
+            .inner
+            .follower_requests
+            .entry(follower)
+            .or_default()
+            .unanswered;
+        if unanswered.len() == MAX_UNANSWERED_REQUESTS {
+            let _ = unanswered.pop_front();
+        }
+        unanswered.push_back((request_id, self.inner.requests_sent));
+        Ok(AppendEntries {
+            request_id,
+            from: self.server_id,
+            to: follower,
+            term: storage.current_term(),
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: self.commit_index,
+        })
+    }
+
+    /// Sends each follower the entries it is missing, followers that are up to date get an empty heartbeat
     fn send_leader_heartbeat_to_cluster<C, PS>(
         &mut self,
         storage: &PS,
         config: &RaftConfig,
-    ) -> Vec<Action<C>>
+    ) -> Result<Vec<Action<C>>, PersistentStorageError>
     where
         C: LogCommand,
         PS: PersistentStorage<C>,
     {
         let mut actions = Vec::new();
 
+        let span = trace_span!(
+            "replication_batch",
+            followers = self.other_servers.len(),
+            entries = field::Empty,
+            leader_commit = self.commit_index.0,
+        );
+        let _entered = span.enter();
         trace!("Sending heartbeat to cluster...");
 
-        for other_server in &self.other_servers {
+        let mut entries_sent = 0;
+        let followers: Vec<ServerId> = self.other_servers.iter().copied().collect();
+        for follower in followers {
+            let append_entries = self.append_entries_for_follower(storage, follower)?;
+            entries_sent += append_entries.entries.len();
             actions.push(Action::OutgoingRpc(RpcMessage::append_entries(
-                AppendEntries {
-                    request_id: Uuid::new_v4(),
-                    from: self.server_id,
-                    to: *other_server,
-                    term: storage.current_term(),
-                    // TODO: Last log item index
-                    // TODO: missing prev log term
-                    prev_log_index: LogIndex(0),
-                    prev_log_term: TermIndex(0),
-                    entries: Vec::new(),
-                    leader_commit: self.commit_index,
-                },
+                append_entries,
             )));
         }
+        span.record("entries", entries_sent);
 
         self.inner.last_heartbeat_sent = self.current_time;
 
         actions.push(Action::SetNextTimeout(config.leader_heartbeat_interval));
 
-        actions
+        Ok(actions)
+    }
+
+    /// Commits the highest entry from our term that is replicated on a majority of servers, entries from earlier terms
+    /// are committed along with it (§5.3, §5.4.2)
+    fn advance_commit_index<C, PS>(&mut self, storage: &PS)
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        let last_index = storage.last_entry_index().unwrap_or(LogIndex(0));
+        let mut index = last_index;
+        while index > self.commit_index {
+            if storage.entry_term(index) != Some(storage.current_term()) {
+                break;
+            }
+            let replicas = 1 + self
+                .inner
+                .match_index
+                .values()
+                .filter(|match_index| **match_index >= index)
+                .count();
+            if replicas >= self.quorum() {
+                debug!(commit_index = index.0, "Advancing commit index");
+                self.commit_index = index;
+                break;
+            }
+            index = LogIndex(index.0 - 1);
+        }
+    }
+
+    /// Appends a command from a client, or a no-op entry if there is none, to our log and sends it to the followers,
+    /// along with any other entries they haven't been sent yet
+    fn append_entry<C, PS>(
+        &mut self,
+        storage: &mut PS,
+        config: &RaftConfig,
+        command: Option<C>,
+    ) -> Result<Vec<Action<C>>, PersistentStorageError>
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        let entry = LogEntry {
+            index: LogIndex(storage.last_entry_index().unwrap_or(LogIndex(0)).0 + 1),
+            term: storage.current_term(),
+            command,
+        };
+        trace!(
+            index = entry.index.0,
+            noop = entry.command.is_none(),
+            "Appending entry to log"
+        );
+        storage.append(vec![entry.clone()]);
+        let mut actions = vec![Action::AppendedToLog(vec![entry])];
+
+        // In a single server cluster the entry is committed as soon as it is in our log
+        self.advance_commit_index(storage);
+        actions.append(&mut self.apply_committed_entries(storage)?);
+        actions.append(&mut self.confirm_reads(storage, config)?);
+        actions.append(&mut self.send_leader_heartbeat_to_cluster(storage, config)?);
+        Ok(actions)
+    }
+
+    /// Starts confirming that we are still the leader for a read (ReadIndex, §6.4 of the Raft dissertation). Instead
+    /// of going through the log the read waits for a majority to answer AppendEntries requests sent after it arrived,
+    /// then it can be served at our commit index. If we were deposed the new leader's followers won't answer us.
+    ///
+    /// Reads that arrive while a round of requests is confirming earlier ones wait for the next round, which is sent
+    /// as soon as that one is confirmed.
+    fn read_index<C, PS>(
+        &mut self,
+        storage: &PS,
+        config: &RaftConfig,
+        read_id: u64,
+    ) -> Result<Vec<Action<C>>, PersistentStorageError>
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        let round_in_flight = !self.inner.pending_reads.is_empty();
+        self.inner.pending_reads.push(PendingRead {
+            read_id,
+            requests_sent: self.inner.requests_sent,
+        });
+        // In a single server cluster there is no one to ask
+        let mut actions = self.confirm_reads(storage, config)?;
+        if !round_in_flight && !self.other_servers.is_empty() {
+            actions.append(&mut self.send_leader_heartbeat_to_cluster(storage, config)?);
+        }
+        Ok(actions)
+    }
+
+    /// Records that a follower answered the request with `request_id`, requests sent to it before that one won't be
+    /// answered anymore
+    fn record_answer(&mut self, follower: ServerId, request_id: Uuid) {
+        let Some(requests) = self.inner.follower_requests.get_mut(&follower) else {
+            return;
+        };
+        let Some(position) = requests
+            .unanswered
+            .iter()
+            .position(|(id, _)| *id == request_id)
+        else {
+            return;
+        };
+        let (_, number) = requests.unanswered[position];
+        requests.unanswered.drain(..=position);
+        requests.answered_through = requests.answered_through.max(number);
+    }
+
+    /// Reports the reads a majority has confirmed, sending the next round of requests if reads are left waiting.
+    ///
+    /// A new leader doesn't know which entries from earlier terms are committed until it commits one from its own
+    /// term (§5.4.2), so reads wait for that as well, which is the no-op entry it appends when it is elected.
+    fn confirm_reads<C, PS>(
+        &mut self,
+        storage: &PS,
+        config: &RaftConfig,
+    ) -> Result<Vec<Action<C>>, PersistentStorageError>
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        if self.inner.pending_reads.is_empty()
+            || storage.entry_term(self.commit_index) != Some(storage.current_term())
+        {
+            return Ok(vec![]);
+        }
+        let mut answered: Vec<u64> = self
+            .other_servers
+            .iter()
+            .map(|server_id| {
+                self.inner
+                    .follower_requests
+                    .get(server_id)
+                    .map_or(0, |requests| requests.answered_through)
+            })
+            .collect();
+        // The quorum - 1 followers that answered most recently, together with us, are a majority
+        answered.sort_unstable_by(|a, b| b.cmp(a));
+        let confirmed_through = match self.quorum() - 1 {
+            0 => u64::MAX,
+            followers => answered[followers - 1],
+        };
+
+        let mut actions = vec![];
+        let index = self.commit_index;
+        self.inner.pending_reads.retain(|read| {
+            let confirmed = read.requests_sent < confirmed_through;
+            if confirmed {
+                actions.push(Action::ReadIndexConfirmed {
+                    read_id: read.read_id,
+                    index,
+                });
+            }
+            !confirmed
+        });
+        if !actions.is_empty() && !self.inner.pending_reads.is_empty() {
+            actions.append(&mut self.send_leader_heartbeat_to_cluster(storage, config)?);
+        }
+        Ok(actions)
+    }
+
+    fn handle_append_entries_ack<C, PS>(
+        &mut self,
+        storage: &PS,
+        config: &RaftConfig,
+        ack: AppendEntriesAck,
+    ) -> Result<Vec<Action<C>>, PersistentStorageError>
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        self.record_contact_with_follower(storage, ack.from, ack.term);
+        if ack.term != storage.current_term() {
+            return Ok(vec![]);
+        }
+        // Rejections also confirm we are the follower's leader, they only say its log doesn't match ours yet
+        self.record_answer(ack.from, ack.request_id);
+        let mut actions = self.confirm_reads(storage, config)?;
+
+        let next_index = self
+            .inner
+            .next_index
+            .get(&ack.from)
+            .copied()
+            .unwrap_or(LogIndex(1));
+        if ack.success {
+            let match_index = self
+                .inner
+                .match_index
+                .get(&ack.from)
+                .copied()
+                .unwrap_or(LogIndex(0))
+                .max(ack.match_index);
+            self.inner.match_index.insert(ack.from, match_index);
+            // Entries after the match index may already be on their way
+            self.inner
+                .next_index
+                .insert(ack.from, next_index.max(LogIndex(match_index.0 + 1)));
+
+            self.advance_commit_index(storage);
+            actions.append(&mut self.apply_committed_entries(storage)?);
+            // Reads confirmed before an entry from our term was committed can be served now
+            actions.append(&mut self.confirm_reads(storage, config)?);
+
+            // Keep sending entries until the follower has caught up instead of waiting for the next heartbeat
+            let last_index = storage.last_entry_index().unwrap_or(LogIndex(0));
+            if match_index < last_index && next_index <= last_index {
+                let append_entries = self.append_entries_for_follower(storage, ack.from)?;
+                actions.push(Action::OutgoingRpc(RpcMessage::append_entries(
+                    append_entries,
+                )));
+            }
+            actions.append(&mut self.send_timeout_now_if_caught_up(storage));
+            Ok(actions)
+        } else {
+            // The follower's log doesn't match ours at next index - 1, back up (skipping past the end of the
+            // follower's log) and retry (§5.3)
+            let new_next_index = LogIndex(
+                (next_index.0.saturating_sub(1))
+                    .min(ack.match_index.0 + 1)
+                    .max(1),
+            );
+            self.inner.next_index.insert(ack.from, new_next_index);
+            let compacted_index = compacted_index(storage);
+            if new_next_index <= compacted_index {
+                // Retrying right away would only be rejected again, the next heartbeat keeps it following us
+                if next_index > compacted_index {
+                    warn!(
+                        follower = ack.from.0,
+                        match_index = ack.match_index.0,
+                        compacted_index = compacted_index.0,
+                        "Follower is missing entries that were compacted, it can't catch up without a snapshot"
+                    );
+                }
+                return Ok(actions);
+            }
+            let append_entries = self.append_entries_for_follower(storage, ack.from)?;
+            actions.push(Action::OutgoingRpc(RpcMessage::append_entries(
+                append_entries,
+            )));
+            Ok(actions)
+        }
+    }
+
+    /// Picks the follower that should take over as leader, this is the follower with the most up to date log
+    /// (ties are broken by choosing the lowest server ID so the choice is deterministic)
+    fn leadership_transfer_target(&self) -> Option<ServerId> {
+        self.other_servers
+            .iter()
+            .max_by_key(|server_id| {
+                (
+                    self.inner
+                        .match_index
+                        .get(server_id)
+                        .map(|index| index.0)
+                        .unwrap_or(0),
+                    Reverse(server_id.0),
+                )
+            })
+            .copied()
+    }
+
+    /// Starts handing leadership to the follower with the most up to date log, it is sent the entries it is missing
+    /// and TimeoutNow once it has caught up (§3.10)
+    fn transfer_leadership<C, PS>(
+        &mut self,
+        storage: &PS,
+    ) -> Result<Vec<Action<C>>, PersistentStorageError>
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        if self.inner.transfer_target.is_some() {
+            return Ok(vec![]);
+        }
+        let target = match self.leadership_transfer_target() {
+            Some(target) => target,
+            None => return Ok(vec![]),
+        };
+        info!(target_server_id = target.0, "Transferring leadership");
+        self.inner.transfer_target = Some(target);
+        let timeout_now = self.send_timeout_now_if_caught_up(storage);
+        if !timeout_now.is_empty() {
+            return Ok(timeout_now);
+        }
+        let append_entries = self.append_entries_for_follower(storage, target)?;
+        Ok(vec![Action::OutgoingRpc(RpcMessage::append_entries(
+            append_entries,
+        ))])
+    }
+
+    /// Sends TimeoutNow to the transfer target once its log matches ours, no new entries are appended meanwhile
+    fn send_timeout_now_if_caught_up<C, PS>(&mut self, storage: &PS) -> Vec<Action<C>>
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        let target = match self.inner.transfer_target {
+            Some(target) if !self.inner.timeout_now_sent => target,
+            _ => return vec![],
+        };
+        let match_index = self
+            .inner
+            .match_index
+            .get(&target)
+            .copied()
+            .unwrap_or(LogIndex(0));
+        if match_index < storage.last_entry_index().unwrap_or(LogIndex(0)) {
+            return vec![];
+        }
+        info!(
+            target_server_id = target.0,
+            "Transfer target caught up, sending TimeoutNow"
+        );
+        self.inner.timeout_now_sent = true;
+        vec![Action::OutgoingRpc(RpcMessage::timeout_now(TimeoutNow {
+            request_id: self.request_ids.next_id(),
+            from: self.server_id,
+            to: target,
+            term: storage.current_term(),
+        }))]
     }
 }
 
@@ -400,8 +993,8 @@ impl Transitions for NodeState<Leader> {
         match event {
             Event::Tick(now) => {
                 let maybe_heartbeat =
-                    if now > self.inner.last_heartbeat_sent + config.leader_heartbeat_interval {
-                        self.send_leader_heartbeat_to_cluster(storage, config)
+                    if now >= self.inner.last_heartbeat_sent + config.leader_heartbeat_interval {
+                        self.send_leader_heartbeat_to_cluster(storage, config)?
                     } else {
                         vec![]
                     };
@@ -409,7 +1002,20 @@ impl Transitions for NodeState<Leader> {
                 Ok((self.into(), maybe_heartbeat))
             }
 
-            Event::LogEntryAppliedByApplication(_) => todo!(),
+            Event::TransferLeadership => {
+                let actions = self.transfer_leadership(storage)?;
+                Ok((self.into(), actions))
+            }
+
+            Event::Propose(command) => {
+                let actions = self.append_entry(storage, config, Some(command))?;
+                Ok((self.into(), actions))
+            }
+
+            Event::ReadIndex(read_id) => {
+                let actions = self.read_index(storage, config, read_id)?;
+                Ok((self.into(), actions))
+            }
 
             Event::IncomingRpc(RpcMessage::Request(rpc_req)) => match rpc_req {
                 Request::RequestVote(req) => {
@@ -421,21 +1027,31 @@ impl Transitions for NodeState<Leader> {
                     if req.term == storage.current_term() {
                         unreachable!("BUG: Leader should not receive append entries from another leader with same term")
                     } else if req.term < storage.current_term() {
-                        let ack = self.ack_append_entries(storage, req, false);
+                        let ack = self.ack_append_entries(storage, req, false, LogIndex(0));
                         Ok((self.into(), ack))
                     } else {
                         unreachable!("BUG: If leader receives an append entries from a higher term, it should have become a follower already")
                     }
                 }
+
+                // Only the leader sends TimeoutNow, so this can only be a stale request from a previous term
+                Request::TimeoutNow(req) => {
+                    let ack = self.ack_timeout_now(storage, req, false);
+                    Ok((self.into(), ack))
+                }
             },
             Event::IncomingRpc(RpcMessage::Reply(reply)) => match reply {
-                ReplyTo::AppendEntries(_) => {
-                    // TODO: When we receive an append entries ack, we should update the next index and match index for the node that sent the ack
-                    // TODO: We should also resend append entries if the follower is behind in the log
-                    Ok((self.into(), vec![]))
+                ReplyTo::AppendEntries(ack) => {
+                    let actions = self.handle_append_entries_ack(storage, config, ack)?;
+                    Ok((self.into(), actions))
                 }
 
                 ReplyTo::RequestVote(_) => Ok((self.into(), vec![])),
+
+                ReplyTo::TimeoutNow(ack) => {
+                    self.record_contact_with_follower(storage, ack.from, ack.term);
+                    Ok((self.into(), vec![]))
+                }
             },
         }
     }
@@ -453,14 +1069,19 @@ impl NodeState<Candidate> {
         PS: PersistentStorage<C>,
         C: LogCommand,
     {
-        trace!(
-            "{server_id:?}: Starting new election!",
-            server_id = self.server_id
-        );
         storage
             .update_term(storage.current_term().increment())
-            .record_vote(self.server_id)
-            .sync()?;
+            .record_vote(self.server_id);
+        // Each election is for a new term, so the term doubles as the election round
+        let span = info_span!(
+            "election",
+            election_round = storage.current_term().0,
+            votes_requested = self.other_servers.len(),
+        );
+        let _entered = span.enter();
+        trace!("Starting new election!");
+        metrics::election_started(self.server_id);
+        metrics::term_changed(self.server_id);
 
         let election_timeout = self.reset_election_timer(config, rng);
         self.inner.votes_received = HashSet::new();
@@ -471,13 +1092,12 @@ impl NodeState<Candidate> {
         for other_server in self.other_servers.iter() {
             start_tick_timer_and_request_votes.push(Action::OutgoingRpc(RpcMessage::request_vote(
                 RequestVote {
-                    request_id: Uuid::new_v4(),
+                    request_id: self.request_ids.next_id(),
                     from: self.server_id,
                     to: *other_server,
                     term: storage.current_term(),
-                    // TODO: Use last log entry for these
-                    last_log_index: LogIndex(0),
-                    last_log_term: TermIndex(0),
+                    last_log_index: storage.last_entry_index().unwrap_or(LogIndex(0)),
+                    last_log_term: storage.last_entry_term().unwrap_or(TermIndex(0)),
                 },
             )));
         }
@@ -500,12 +1120,11 @@ impl Transitions for NodeState<Candidate> {
         match event {
             Event::Tick(now) => {
                 let maybe_vote_requests = if now
-                    > self.inner.last_election_timer_started + self.inner.election_timeout
+                    >= self.inner.last_election_timer_started + self.inner.election_timeout
                 {
                     trace!(
-                        "{server_id:?}: In candidate mode, did not receive enough votes before election timeout {timeout:?}ms, starting new election",
-                        server_id = self.server_id,
-                        timeout=self.inner.election_timeout.as_millis()
+                        election_timeout_ms = self.inner.election_timeout.as_millis() as u64,
+                        "In candidate mode, did not receive enough votes before election timeout, starting new election"
                     );
                     self.start_new_election(config, storage, rng)?
                 } else {
@@ -515,7 +1134,9 @@ impl Transitions for NodeState<Candidate> {
                 Ok((self.into(), maybe_vote_requests))
             }
 
-            Event::LogEntryAppliedByApplication(_) => todo!(),
+            Event::TransferLeadership | Event::Propose(_) | Event::ReadIndex(_) => {
+                Ok((self.into(), vec![]))
+            }
 
             Event::IncomingRpc(RpcMessage::Request(rpc_req)) => match rpc_req {
                 Request::RequestVote(req) => {
@@ -530,46 +1151,58 @@ impl Transitions for NodeState<Candidate> {
 
                 Request::AppendEntries(req) => {
                     if req.term < storage.current_term() {
-                        let ack = self.ack_append_entries(storage, req, false);
+                        let ack = self.ack_append_entries(storage, req, false, LogIndex(0));
                         Ok((self.into(), ack))
                     } else if req.term == storage.current_term() {
-                        let mut follower_state: NodeState<Follower> = self.transition_to();
-                        let ack = follower_state.ack_append_entries(storage, req, true);
-                        follower_state.reset_election_timer(config, rng);
-                        Ok((follower_state.into(), ack))
+                        // Another candidate won the election for our term, follow it and handle its request as a follower
+                        let follower_state: NodeState<Follower> = self.transition_to();
+                        follower_state.handle_event(
+                            Event::IncomingRpc(RpcMessage::Request(Request::AppendEntries(req))),
+                            storage,
+                            config,
+                            rng,
+                        )
                     } else {
                         unreachable!("BUG: If candidate receives an append entries from a higher term, it should have become a follower already")
                     }
                 }
+
+                // We are already running an election, nothing to do
+                Request::TimeoutNow(req) => {
+                    let ack = self.ack_timeout_now(storage, req, false);
+                    Ok((self.into(), ack))
+                }
             },
 
             Event::IncomingRpc(RpcMessage::Reply(reply)) => match reply {
                 ReplyTo::RequestVote(vote) => {
-                    let qorum = DivCeil::div_ceil(self.other_servers.len() + 1, 2);
-
                     if vote.term == storage.current_term() && vote.vote_granted {
                         self.inner.votes_received.insert(vote.from);
 
-                        if self.inner.votes_received.len() >= qorum {
+                        if self.inner.votes_received.len() >= self.quorum() {
                             info!(
-                                "{server_id:?}: Received vote from {from:?} and won election with {votes:?} votes, becoming leader in term {term:?}",
-                                server_id=self.server_id,
-                                from=vote.from,
-                                votes = self.inner.votes_received,
-                                term=storage.current_term()
+                                election_round = storage.current_term().0,
+                                votes = self.inner.votes_received.len(),
+                                voters = ?self.inner.votes_received,
+                                "Won election, becoming leader"
                             );
                             let mut new_state: NodeState<Leader> = self.transition_to();
-                            let actions =
-                                new_state.send_leader_heartbeat_to_cluster(storage, config);
+                            new_state.initialize_follower_progress(storage);
+                            metrics::election_won(new_state.server_id);
+                            let mut actions =
+                                vec![Action::Transition(RaftTransition::BecameLeader {
+                                    term: storage.current_term(),
+                                })];
+                            // Entries from earlier terms are only known to be committed once one from our term is,
+                            // a no-op entry gets there without waiting for a proposal (§8)
+                            actions.append(&mut new_state.append_entry(storage, config, None)?);
                             Ok((new_state.into(), actions))
                         } else {
                             self.inner.votes_received.insert(vote.from);
                             info!(
-                                "{server_id:?}: Received vote from {from:?}, but still need {votes_needed:?} more votes to win election in term {term:?}",
-                                server_id=self.server_id,
-                                from=vote.from,
-                                votes_needed=qorum - self.inner.votes_received.len(),
-                                term=storage.current_term()
+                                election_round = storage.current_term().0,
+                                votes_needed = self.quorum() - self.inner.votes_received.len(),
+                                "Received vote, but still need more votes to win election"
                             );
                             Ok((self.into(), vec![]))
                         }
@@ -579,6 +1212,8 @@ impl Transitions for NodeState<Candidate> {
                 }
 
                 ReplyTo::AppendEntries(_) => Ok((self.into(), vec![])),
+
+                ReplyTo::TimeoutNow(_) => Ok((self.into(), vec![])),
             },
         }
     }
@@ -593,14 +1228,16 @@ impl NodeState<Follower> {
         other_servers: HashSet<ServerId>,
         config: &RaftConfig,
         rng: &mut ChaCha8Rng,
+        now: Instant,
     ) -> (Self, FirstElectionTimeout) {
-        let follower_state = Follower::new();
+        let follower_state = Follower::new(now);
 
         let mut node_state = Self {
-            start_time: system_clock::now(),
-            current_time: system_clock::now(),
+            start_time: now,
+            current_time: now,
             server_id,
-            other_servers,
+            other_servers: other_servers.into_iter().collect(),
+            request_ids: RequestIdGenerator::from_rng(rng),
             commit_index: LogIndex(0),
             last_applied: LogIndex(0),
             inner: follower_state,
@@ -622,8 +1259,14 @@ impl NodeState<Follower> {
 
         // Reply false if term < currentTerm (§5.1)
         // If votedFor is null or candidateId, and candidate’s log is at
-        // least as up-to-date as receiver’s log (TODO), grant vote (§5.2, §5.4)
+        // least as up-to-date as receiver’s log, grant vote (§5.2, §5.4)
         let candidate_has_same_or_newer_term = vote_req.term >= storage.current_term();
+        // Logs are compared by the term of their last entries first, then by their length (§5.4.1)
+        let candidate_log_is_up_to_date = (vote_req.last_log_term, vote_req.last_log_index)
+            >= (
+                storage.last_entry_term().unwrap_or(TermIndex(0)),
+                storage.last_entry_index().unwrap_or(LogIndex(0)),
+            );
         let we_voted_this_term_already = storage.vote_for_current_term().is_some();
         let we_voted_for_same_candidate_this_term_already = storage
             .vote_for_current_term()
@@ -631,25 +1274,93 @@ impl NodeState<Follower> {
             .unwrap_or(false);
 
         let vote_granted = candidate_has_same_or_newer_term
+            && candidate_log_is_up_to_date
             && (!we_voted_this_term_already || we_voted_for_same_candidate_this_term_already);
 
+        let mut actions = vec![];
         if vote_granted {
             info!(
-                "{server_id:?}: Voting for candidate {candidate_id:?} in term {term:?}",
-                server_id = self.server_id,
-                candidate_id = vote_req.from,
-                term = vote_req.term
+                candidate_id = vote_req.from.0,
+                candidate_term = vote_req.term.0,
+                "Voting for candidate"
             );
-            storage.record_vote(vote_req.from).sync()?;
+            storage.record_vote(vote_req.from);
+            // A repeated request from the candidate we already voted for is not a new vote
+            if !we_voted_this_term_already {
+                actions.push(Action::Transition(RaftTransition::VoteGranted {
+                    to: vote_req.from,
+                    term: storage.current_term(),
+                }));
+            }
         }
 
-        Ok(vec![Action::OutgoingRpc(RpcMessage::vote(Vote {
+        actions.push(Action::OutgoingRpc(RpcMessage::vote(Vote {
             request_id: vote_req.request_id,
             from: self.server_id,
             to: vote_req.from,
             term: storage.current_term(),
             vote_granted,
-        }))])
+        })));
+        Ok(actions)
+    }
+}
+
+impl NodeState<Follower> {
+    /// Appends entries from the leader if our log contains its previous entry, acks with the index of the last entry
+    /// that matches the leader's log on success or with the index of our last entry on failure (§5.3)
+    fn append_leader_entries<C, PS>(
+        &mut self,
+        storage: &mut PS,
+        req: AppendEntries<C>,
+    ) -> Result<Vec<Action<C>>, PersistentStorageError>
+    where
+        C: LogCommand,
+        PS: PersistentStorage<C>,
+    {
+        // Compacted entries were committed, the leader's log has the same ones (§5.4)
+        let compacted_index = compacted_index(storage);
+        let log_matches_leader = req.prev_log_index <= compacted_index
+            || storage.has_entry(req.prev_log_index, req.prev_log_term);
+        if !log_matches_leader {
+            trace!(
+                prev_log_index = req.prev_log_index.0,
+                prev_log_term = req.prev_log_term.0,
+                "Log does not contain the leader's previous entry"
+            );
+            let last_index = storage.last_entry_index().unwrap_or(LogIndex(0));
+            return Ok(self.ack_append_entries(storage, req, false, last_index));
+        }
+
+        let mut actions = Vec::new();
+        let match_index = LogIndex(req.prev_log_index.0 + req.entries.len() as u64);
+        // Entries we already have are skipped, conflicting entries are replaced by the storage (§5.3)
+        let new_entries: Vec<LogEntry<C>> = req
+            .entries
+            .iter()
+            .filter(|entry| {
+                entry.index > compacted_index && !storage.has_entry(entry.index, entry.term)
+            })
+            .cloned()
+            .collect();
+        if !new_entries.is_empty() {
+            storage.append(new_entries.clone());
+            actions.push(Action::AppendedToLog(new_entries));
+        }
+
+        if req.leader_commit > self.commit_index {
+            self.commit_index = req.leader_commit.min(match_index).max(self.commit_index);
+        }
+        actions.append(&mut self.apply_committed_entries(storage)?);
+
+        let leader_id = req.from;
+        let mut ack = self.ack_append_entries(storage, req, true, match_index);
+        actions.append(&mut ack);
+        trace!(
+            leader_id = leader_id.0,
+            match_index = match_index.0,
+            "Appended entries from leader"
+        );
+        Ok(actions)
     }
 }
 
@@ -667,11 +1378,10 @@ impl Transitions for NodeState<Follower> {
     {
         match event {
             Event::Tick(now) => {
-                if now > self.inner.last_election_timer_started + self.inner.election_timeout {
+                if now >= self.inner.last_election_timer_started + self.inner.election_timeout {
                     info!(
-                        "{server_id:?}: In follower state, did not receive heartbeat before election timeout {timeout:?}ms, becoming candidate...",
-                        server_id=self.server_id,
-                        timeout=self.inner.election_timeout.as_millis(),
+                        election_timeout_ms = self.inner.election_timeout.as_millis() as u64,
+                        "In follower state, did not receive heartbeat before election timeout, becoming candidate..."
                     );
                     let mut new_state: NodeState<Candidate> = self.transition_to();
                     let vote_requests = new_state.start_new_election(config, storage, rng)?;
@@ -681,7 +1391,9 @@ impl Transitions for NodeState<Follower> {
                 }
             }
 
-            Event::LogEntryAppliedByApplication(_) => todo!(),
+            Event::TransferLeadership | Event::Propose(_) | Event::ReadIndex(_) => {
+                Ok((self.into(), vec![]))
+            }
 
             Event::IncomingRpc(RpcMessage::Request(rpc_req)) => match rpc_req {
                 Request::RequestVote(req) => {
@@ -696,19 +1408,34 @@ impl Transitions for NodeState<Follower> {
                 }
 
                 Request::AppendEntries(req) => {
-                    let (ack_success, mut maybe_start_timer) = if req.term < storage.current_term()
-                    {
-                        (false, vec![])
+                    if req.term < storage.current_term() {
+                        let ack = self.ack_append_entries(storage, req, false, LogIndex(0));
+                        return Ok((self.into(), ack));
+                    }
+
+                    self.inner.leader_id = Some(req.from);
+                    let election_timeout = self.reset_election_timer(config, rng);
+                    let mut actions = self.append_leader_entries(storage, req)?;
+                    actions.push(Action::SetNextTimeout(election_timeout));
+                    Ok((self.into(), actions))
+                }
+
+                Request::TimeoutNow(req) => {
+                    if req.term < storage.current_term() {
+                        let ack = self.ack_timeout_now(storage, req, false);
+                        Ok((self.into(), ack))
                     } else {
-                        self.inner.leader_id = Some(req.from);
-                        //TODO: Check if we have all entries up to leader term & prev log index, and replicate entries
-                        let election_timeout = self.reset_election_timer(config, rng);
-                        (true, vec![Action::SetNextTimeout(election_timeout)])
-                    };
-                    let mut maybe_start_timer_and_ack =
-                        self.ack_append_entries(storage, req, ack_success);
-                    maybe_start_timer_and_ack.append(&mut maybe_start_timer);
-                    Ok((self.into(), maybe_start_timer_and_ack))
+                        info!(
+                            leader_id = req.from.0,
+                            "Leader is handing off leadership to us, becoming candidate..."
+                        );
+                        let mut ack = self.ack_timeout_now(storage, req, true);
+                        let mut new_state: NodeState<Candidate> = self.transition_to();
+                        let mut vote_requests =
+                            new_state.start_new_election(config, storage, rng)?;
+                        vote_requests.append(&mut ack);
+                        Ok((new_state.into(), vote_requests))
+                    }
                 }
             },
 
@@ -721,17 +1448,244 @@ impl Transitions for NodeState<Follower> {
 impl<InState, OutState> CanTransitionTo<OutState> for NodeState<InState>
 where
     InState: State,
-    OutState: State + From<InState>,
+    OutState: State + TransitionFrom<InState>,
 {
     fn transition_to(self) -> NodeState<OutState> {
         NodeState {
-            inner: self.inner.into(),
+            inner: OutState::transition_from(self.inner, self.current_time),
             server_id: self.server_id,
             start_time: self.start_time,
             current_time: self.current_time,
             other_servers: self.other_servers,
+            request_ids: self.request_ids,
             commit_index: self.commit_index,
             last_applied: self.last_applied,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_storage::MemoryStorage;
+    use rand::SeedableRng;
+
+    /// Starts a follower and lets its election timer run out, returning what it does when it becomes a candidate
+    fn first_election_actions(other_servers: HashSet<ServerId>) -> String {
+        let config = RaftConfig {
+            leader_heartbeat_interval: Duration::from_millis(50),
+            min_election_timeout_ms: 150,
+            max_election_timeout_ms: 300,
+        };
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let mut storage = MemoryStorage::<u64>::new();
+        let start = Instant::now();
+        let (node, _) = Node::new(ServerId(0), other_servers, &config, &mut rng, start);
+        let (node, actions) = node
+            .next(
+                Event::Tick(start + Duration::from_secs(1)),
+                &mut storage,
+                &config,
+                &mut rng,
+            )
+            .unwrap();
+        assert!(matches!(node, Node::Candidate(_)));
+        format!("{:?}", actions)
+    }
+
+    #[test]
+    fn should_produce_the_same_output_for_the_same_inputs() {
+        let servers: Vec<ServerId> = (1..10).map(ServerId).collect();
+        let expected = first_election_actions(servers.iter().copied().collect());
+        // Every set has its own hash keys, the order it was built in must not change the order of the messages
+        for _ in 0..10 {
+            let actual = first_election_actions(servers.iter().rev().copied().collect());
+            assert_eq!(actual, expected);
+        }
+    }
+
+    fn config() -> RaftConfig {
+        RaftConfig {
+            leader_heartbeat_interval: Duration::from_millis(50),
+            min_election_timeout_ms: 150,
+            max_election_timeout_ms: 300,
+        }
+    }
+
+    fn transitions(actions: &[Action<u64>]) -> Vec<RaftTransition> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Transition(transition) => Some(*transition),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn request_vote(from: u64, term: u64) -> Event<u64> {
+        Event::IncomingRpc(RpcMessage::request_vote(RequestVote {
+            request_id: Uuid::nil(),
+            from: ServerId(from),
+            to: ServerId(0),
+            term: TermIndex(term),
+            last_log_index: LogIndex(10),
+            last_log_term: TermIndex(term),
+        }))
+    }
+
+    /// Server 0 of a three server cluster, elected leader in term 1 with the vote of server 1
+    fn elect_leader(
+        storage: &mut MemoryStorage<u64>,
+        rng: &mut ChaCha8Rng,
+    ) -> (Node, Vec<Action<u64>>) {
+        let start = Instant::now();
+        let other_servers = [ServerId(1), ServerId(2)].into_iter().collect();
+        let (node, _) = Node::new(ServerId(0), other_servers, &config(), rng, start);
+        let (node, _) = node
+            .next(
+                Event::Tick(start + Duration::from_secs(1)),
+                storage,
+                &config(),
+                rng,
+            )
+            .unwrap();
+        let vote = Event::IncomingRpc(RpcMessage::vote(Vote {
+            request_id: Uuid::nil(),
+            from: ServerId(1),
+            to: ServerId(0),
+            term: storage.current_term(),
+            vote_granted: true,
+        }));
+        node.next(vote, storage, &config(), rng).unwrap()
+    }
+
+    #[test]
+    fn should_report_winning_an_election() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let mut storage = MemoryStorage::<u64>::new();
+
+        let (node, actions) = elect_leader(&mut storage, &mut rng);
+
+        assert!(node.is_leader());
+        assert_eq!(
+            transitions(&actions),
+            vec![RaftTransition::BecameLeader { term: TermIndex(1) }]
+        );
+    }
+
+    #[test]
+    fn should_append_and_send_a_noop_entry_when_elected() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let mut storage = MemoryStorage::<u64>::new();
+
+        let (_, actions) = elect_leader(&mut storage, &mut rng);
+
+        let noop = LogEntry {
+            index: LogIndex(1),
+            term: TermIndex(1),
+            command: None,
+        };
+        assert_eq!(storage.entries(LogIndex(1), 10).unwrap(), vec![noop]);
+        assert_eq!(entries_sent_to(&actions, ServerId(1)), vec![vec![1]]);
+    }
+
+    #[test]
+    fn should_report_stepping_down_before_the_vote_that_follows_it() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let mut storage = MemoryStorage::<u64>::new();
+        let (node, _) = elect_leader(&mut storage, &mut rng);
+
+        let (node, actions) = node
+            .next(request_vote(2, 5), &mut storage, &config(), &mut rng)
+            .unwrap();
+
+        assert!(matches!(node, Node::Follower(_)));
+        assert_eq!(
+            transitions(&actions),
+            vec![
+                RaftTransition::SteppedDown {
+                    term: TermIndex(1),
+                    reason: SteppedDownReason::HigherTermObserved(TermIndex(5)),
+                },
+                RaftTransition::VoteGranted {
+                    to: ServerId(2),
+                    term: TermIndex(5),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_report_a_vote_once_when_the_candidate_asks_again() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let mut storage = MemoryStorage::<u64>::new();
+        let start = Instant::now();
+        let other_servers = [ServerId(1), ServerId(2)].into_iter().collect();
+        let (node, _) = Node::new(ServerId(0), other_servers, &config(), &mut rng, start);
+
+        let (node, first) = node
+            .next(request_vote(1, 2), &mut storage, &config(), &mut rng)
+            .unwrap();
+        let (_, second) = node
+            .next(request_vote(1, 2), &mut storage, &config(), &mut rng)
+            .unwrap();
+
+        assert_eq!(
+            transitions(&first),
+            vec![RaftTransition::VoteGranted {
+                to: ServerId(1),
+                term: TermIndex(2),
+            }]
+        );
+        assert_eq!(transitions(&second), vec![]);
+    }
+    /// Indexes of the entries in each AppendEntries request to `follower`
+    fn entries_sent_to(actions: &[Action<u64>], follower: ServerId) -> Vec<Vec<u64>> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::OutgoingRpc(RpcMessage::Request(Request::AppendEntries(req)))
+                    if req.to == follower =>
+                {
+                    Some(req.entries.iter().map(|entry| entry.index.0).collect())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_only_send_entries_a_follower_has_not_been_sent_yet() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let mut storage = MemoryStorage::<u64>::new();
+        let (mut node, _) = elect_leader(&mut storage, &mut rng);
+
+        let mut sent = vec![];
+        for command in 1..=3 {
+            let actions;
+            (node, actions) = node
+                .next(Event::Propose(command), &mut storage, &config(), &mut rng)
+                .unwrap();
+            sent.append(&mut entries_sent_to(&actions, ServerId(1)));
+        }
+        // The no-op entry appended on election was sent with the first heartbeat
+        assert_eq!(sent, vec![vec![2], vec![3], vec![4]]);
+
+        // The follower missed the first two requests, so it rejects the third and gets everything again
+        let reject = Event::IncomingRpc(RpcMessage::ack_append_entries(AppendEntriesAck {
+            request_id: Uuid::nil(),
+            from: ServerId(1),
+            to: ServerId(0),
+            term: storage.current_term(),
+            success: false,
+            match_index: LogIndex(0),
+        }));
+        let (_, actions) = node
+            .next(reject, &mut storage, &config(), &mut rng)
+            .unwrap();
+        assert_eq!(
+            entries_sent_to(&actions, ServerId(1)),
+            vec![vec![1, 2, 3, 4]]
+        );
+    }
+}
@@ -1,12 +1,12 @@
 /// Tests consensus with simulator
 use crate::simulator::{
-    common::{SimTime, SimulatorAction, SimulatorEvent},
+    common::{SimLogCommand, SimTime, SimulatorAction, SimulatorEvent},
     sim_network::{LatencyMean, LatencyStdDev, PacketLossProbability, SimNetwork},
     ClusterSim,
 };
 use lazy_static::lazy_static;
 use quickcheck::{Arbitrary, QuickCheck, Testable};
-use raft_consensus::{RaftConfig, ServerId};
+use raft_consensus::{LogIndex, RaftConfig, RaftNodeState, ServerId};
 use rand::{RngCore, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 use rand_distr::num_traits::ToPrimitive;
@@ -18,7 +18,6 @@ use std::{
     sync::atomic::{AtomicI64, AtomicU64},
     time::Duration,
 };
-use tempfile::TempDir;
 use tracing::{debug, error, info};
 mod simulator;
 use fault_injection::{set_trigger_function, FAULT_INJECT_COUNTER};
@@ -31,6 +30,7 @@ use test_log::test;
 // - RunForDuration - Runs the simulation for a given duration without any other actions
 // - FailNode - Put one node in a network partition to simulate a failure of a node
 // - RecoverNode - Heal the network partition to simulate a recovery of a node
+// - ProposeCommand - Proposes a command to the current leader, every server must apply the same command at each index
 
 fn new_rng(maybe_seed: Option<u64>) -> ChaCha8Rng {
     match maybe_seed {
@@ -70,16 +70,7 @@ fn should_elect_leader_without_network_partition() {
         LatencyMean(5.0),
         LatencyStdDev(2.0),
     );
-    let temp_dir = TempDir::new().unwrap();
-    let temp_dir_path = temp_dir.path().to_str().unwrap();
-    let mut sim = ClusterSim::new(
-        5,
-        network,
-        config,
-        rng,
-        temp_dir_path.into(),
-        sim_log_path(None),
-    );
+    let mut sim = ClusterSim::new(5, network, config, rng, sim_log_path(None));
 
     sim.run_until_time(SIMULATION_DURATION);
 
@@ -101,17 +92,7 @@ fn should_elect_leader_during_network_partition_if_we_have_quorum() {
         LatencyMean(5.0),
         LatencyStdDev(2.0),
     );
-    let temp_dir = TempDir::new().unwrap();
-    let temp_dir_path = temp_dir.path().to_str().unwrap();
-
-    let mut sim = ClusterSim::new(
-        5,
-        network,
-        config,
-        rng,
-        temp_dir_path.into(),
-        sim_log_path(None),
-    );
+    let mut sim = ClusterSim::new(5, network, config, rng, sim_log_path(None));
 
     info!("Current sim time is {time:?}", time = SimTime::now());
 
@@ -157,18 +138,9 @@ fn should_not_be_able_to_elect_leader_without_quorum() {
         LatencyMean(5.0),
         LatencyStdDev(2.0),
     );
-    let temp_dir = TempDir::new().unwrap();
-    let temp_dir_path = temp_dir.path().to_str().unwrap();
     let pwd = std::env::current_dir().unwrap();
 
-    let mut sim = ClusterSim::new(
-        5,
-        network,
-        config,
-        rng,
-        temp_dir_path.into(),
-        sim_log_path(None),
-    );
+    let mut sim = ClusterSim::new(5, network, config, rng, sim_log_path(None));
 
     sim.enqueue_event(SimulatorEvent {
         time: SimTime::from_millis(0),
@@ -198,6 +170,220 @@ fn should_not_be_able_to_elect_leader_without_quorum() {
     drop(sim);
 }
 
+#[test]
+fn should_elect_new_leader_when_leader_hands_off_leadership_on_shutdown() {
+    let rng = new_rng(None);
+    let config = RaftConfig {
+        leader_heartbeat_interval: Duration::from_millis(100),
+        min_election_timeout_ms: 150,
+        max_election_timeout_ms: 300,
+    };
+
+    // No packet loss so the handoff message to the new leader is not dropped
+    let network = SimNetwork::with_defaults(
+        5,
+        PacketLossProbability(0.0),
+        LatencyMean(5.0),
+        LatencyStdDev(0.1),
+    );
+    let mut sim = ClusterSim::new(5, network, config, rng, sim_log_path(None));
+
+    // Shutdown the leader as soon as there is one
+    let mut maybe_leader = None;
+    while maybe_leader.is_none() && SimTime::now() < SimTime(SIMULATION_DURATION) {
+        sim.run_until_time((SimTime::now() + Duration::from_millis(10)).into());
+        maybe_leader = sim.current_leader();
+    }
+    let old_leader =
+        maybe_leader.expect("A leader should have been elected before shutting it down");
+
+    let shutdown_time = SimTime::now();
+    sim.enqueue_event(SimulatorEvent {
+        time: shutdown_time,
+        action: SimulatorAction::ShutdownNode {
+            server_id: old_leader,
+            transfer_leadership: true,
+        },
+    });
+    sim.reset_results();
+
+    // The leader notices the shutdown request the next time it wakes up and hands off leadership to the most up to
+    // date follower, which starts an election right away instead of waiting out its election timeout
+    let new_leader_elected = |sim: &ClusterSim| {
+        sim.results
+            .all_elected_leaders
+            .iter()
+            .any(|leader| *leader != old_leader)
+    };
+    while !new_leader_elected(&sim) && SimTime::now() < shutdown_time + SIMULATION_DURATION {
+        sim.run_until_time((SimTime::now() + Duration::from_millis(10)).into());
+    }
+    assert!(
+        new_leader_elected(&sim),
+        "A new leader should have been elected after {old_leader:?} handed off leadership"
+    );
+}
+
+#[test]
+fn should_report_replication_progress_of_followers_in_leader_status() {
+    let rng = new_rng(None);
+    let config = RaftConfig {
+        leader_heartbeat_interval: Duration::from_millis(100),
+        min_election_timeout_ms: 150,
+        max_election_timeout_ms: 300,
+    };
+
+    let network = SimNetwork::with_defaults(
+        5,
+        PacketLossProbability(0.0),
+        LatencyMean(5.0),
+        LatencyStdDev(0.1),
+    );
+    let mut sim = ClusterSim::new(5, network, config, rng, sim_log_path(None));
+
+    // Run until a leader has heard back from a follower since it was elected
+    let leader_status_with_contact = |sim: &ClusterSim| {
+        sim.current_leader()
+            .map(|leader| sim.server_status(leader))
+            .filter(|status| {
+                status
+                    .followers
+                    .iter()
+                    .any(|follower| follower.last_contact.is_some())
+            })
+    };
+    let mut maybe_status = None;
+    while maybe_status.is_none() && SimTime::now() < SimTime(SIMULATION_DURATION) {
+        sim.run_until_time((SimTime::now() + Duration::from_millis(10)).into());
+        maybe_status = leader_status_with_contact(&sim);
+    }
+    let status = maybe_status.expect("Leader should have received a heartbeat reply");
+
+    assert_eq!(status.state, RaftNodeState::Leader);
+    assert_eq!(status.leader_for_term, Some(status.server_id));
+    assert_eq!(
+        status
+            .followers
+            .iter()
+            .map(|follower| follower.server_id)
+            .collect::<Vec<_>>(),
+        NODES
+            .iter()
+            .copied()
+            .filter(|server_id| *server_id != status.server_id)
+            .collect::<Vec<_>>(),
+    );
+    // Only the leader's no-op entry has been appended to the log, by the followers that acknowledged it
+    for follower in &status.followers {
+        if follower.match_index == LogIndex(1) {
+            assert_eq!(follower.next_index, LogIndex(2));
+        } else {
+            assert_eq!(follower.next_index, LogIndex(1));
+            assert_eq!(follower.match_index, LogIndex(0));
+        }
+    }
+}
+
+#[test]
+fn should_apply_proposed_commands_on_every_server() {
+    let rng = new_rng(None);
+    let config = RaftConfig {
+        leader_heartbeat_interval: Duration::from_millis(100),
+        min_election_timeout_ms: 150,
+        max_election_timeout_ms: 300,
+    };
+
+    let network = SimNetwork::with_defaults(
+        5,
+        PacketLossProbability(0.01),
+        LatencyMean(5.0),
+        LatencyStdDev(2.0),
+    );
+    let mut sim = ClusterSim::new(5, network, config, rng, sim_log_path(None));
+
+    // The raft threads run alongside the simulation, so a leader can lose its leadership before it commits what was
+    // proposed to it. Keep proposing until a few commands have been applied.
+    let mut next_command = 1;
+    while sim.applied_commands().len() < 3 && SimTime::now() < SimTime(SIMULATION_DURATION) {
+        sim.enqueue_event(SimulatorEvent {
+            time: SimTime::now(),
+            action: SimulatorAction::ProposeCommand(SimLogCommand(next_command)),
+        });
+        next_command += 1;
+        sim.run_until_time((SimTime::now() + Duration::from_millis(100)).into());
+    }
+    let applied_commands = sim.applied_commands();
+    assert!(
+        applied_commands.len() >= 3,
+        "Only {} proposed commands were applied",
+        applied_commands.len()
+    );
+    // Some proposals may be dropped, but the ones that are applied are applied in the order they were proposed
+    let applied_values: Vec<u64> = applied_commands.values().map(|command| command.0).collect();
+    let mut sorted_values = applied_values.clone();
+    sorted_values.sort();
+    assert_eq!(applied_values, sorted_values);
+
+    // Followers learn that the last command was committed from the leader's next heartbeat. A leader elected after
+    // that can only commit it along with an entry from its own term, so keep proposing until every server caught up.
+    let last_applied = *applied_commands.keys().last().unwrap();
+    let every_server_applied = |sim: &ClusterSim| {
+        NODES
+            .iter()
+            .all(|server_id| sim.server_status(*server_id).last_applied >= last_applied)
+    };
+    while !every_server_applied(&sim) && SimTime::now() < SimTime(SIMULATION_DURATION) {
+        sim.enqueue_event(SimulatorEvent {
+            time: SimTime::now(),
+            action: SimulatorAction::ProposeCommand(SimLogCommand(next_command)),
+        });
+        next_command += 1;
+        sim.run_until_time((SimTime::now() + Duration::from_millis(100)).into());
+    }
+    assert!(
+        every_server_applied(&sim),
+        "Every server should have applied the commands up to {:?}",
+        last_applied
+    );
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn should_count_elections_in_metrics() {
+    let rng = new_rng(None);
+    let config = RaftConfig {
+        leader_heartbeat_interval: Duration::from_millis(100),
+        min_election_timeout_ms: 150,
+        max_election_timeout_ms: 300,
+    };
+
+    let network = SimNetwork::with_defaults(
+        5,
+        PacketLossProbability(0.0),
+        LatencyMean(5.0),
+        LatencyStdDev(0.1),
+    );
+    let mut sim = ClusterSim::new(5, network, config, rng, sim_log_path(None));
+
+    let mut maybe_leader = None;
+    while maybe_leader.is_none() && SimTime::now() < SimTime(SIMULATION_DURATION) {
+        sim.run_until_time((SimTime::now() + Duration::from_millis(10)).into());
+        maybe_leader = sim.current_leader();
+    }
+    let leader = maybe_leader.expect("A leader should have been elected");
+
+    let metrics = raft_consensus::encode_metrics().unwrap();
+    let leader_label = format!("server_id=\"{}\"", leader.0);
+    assert!(
+        metrics.lines().any(
+            |line| line.starts_with("raft_elections_won_total") && line.contains(&leader_label)
+        ),
+        "Leader {leader:?} should have counted the election it won:\n{metrics}"
+    );
+    assert!(metrics.contains("raft_rpcs_sent_total{rpc=\"request_vote\""));
+    assert!(metrics.contains("raft_storage_sync_duration_seconds_bucket"));
+}
+
 #[derive(Debug, Clone)]
 struct SimInstructionSequence {
     generated_state_changes: Vec<SimulatorEvent>,
@@ -222,6 +408,7 @@ const INSTRUCTION_FAIL_NODE: &str = "FailNode";
 const INSTRUCTION_RECOVER_NODE: &str = "RecoverNode";
 const INJECT_IO_FAILURES: &str = "FailNextIOOperation";
 const RESTORE_IO_FUNCTIONING: &str = "RestoreIOFunctioning";
+const INSTRUCTION_PROPOSE_COMMAND: &str = "ProposeCommand";
 const FAIL_EVERY_N_IO_OPS_CHOICES: [u64; 6] = [5, 5, 5, 100, 100, u64::MAX];
 
 impl Arbitrary for SimInstructionSequence {
@@ -233,6 +420,7 @@ impl Arbitrary for SimInstructionSequence {
         let mut sequence_of_events = Vec::<SimulatorEvent>::new();
 
         let mut clock: u64 = 0;
+        let mut next_command: u64 = 1;
 
         let num_instructions = g.size();
         debug!("num_instructions: {}", num_instructions);
@@ -250,6 +438,8 @@ impl Arbitrary for SimInstructionSequence {
                 None => options.push(INSTRUCTION_PARTITION_NETWORK),
             }
 
+            options.push(INSTRUCTION_PROPOSE_COMMAND);
+
             // if failed_nodes.len() < NUM_NODES_IN_CLUSTER {
             //     options.push(INSTRUCTION_FAIL_NODE);
             // }
@@ -321,6 +511,13 @@ impl Arbitrary for SimInstructionSequence {
                     });
                     reduced_io_functioning = false;
                 }
+                INSTRUCTION_PROPOSE_COMMAND => {
+                    sequence_of_events.push(SimulatorEvent {
+                        time: SimTime::from_millis(clock),
+                        action: SimulatorAction::ProposeCommand(SimLogCommand(next_command)),
+                    });
+                    next_command += 1;
+                }
                 _ => panic!("Unknown instruction type"),
             }
         }
@@ -353,17 +550,7 @@ fn run_simulation_with_sequence_of_events(
         LatencyMean(5.0),
         LatencyStdDev(2.0),
     );
-    let temp_dir = TempDir::new().unwrap();
-    let temp_dir_path = temp_dir.path().to_str().unwrap();
-
-    let mut sim = ClusterSim::new(
-        5,
-        network,
-        config,
-        rng,
-        temp_dir_path.into(),
-        sim_log_path(maybe_log_file_path),
-    );
+    let mut sim = ClusterSim::new(5, network, config, rng, sim_log_path(maybe_log_file_path));
 
     let run_until_time = events
         .generated_state_changes
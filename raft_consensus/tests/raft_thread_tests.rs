@@ -0,0 +1,338 @@
+/// Tests for proposing commands to raft nodes running in their own threads, connected by in-process channels
+use mock_instant::MockClock;
+use raft_consensus::{
+    start_raft_in_new_thread, ApplicationThatNeedsConsensus, LogIndex, MemoryStorage,
+    NoOpRaftEventCollector, ProposeError, RaftConfig, RaftNodeHandle, RaftNodeSetup, RaftNodeState,
+    RaftThreadExit, RaftTransportConnector, RaftTransportError, ReplyTo, Request, RpcMessage,
+    ServerId,
+};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Delivers messages straight to the other servers' inboxes, polls its own inbox every millisecond
+struct ChannelTransport {
+    inbox: mpsc::Receiver<RpcMessage<u64>>,
+    peers: HashMap<ServerId, mpsc::Sender<RpcMessage<u64>>>,
+    /// Messages from or to these servers are dropped
+    isolated: Arc<Mutex<HashSet<ServerId>>>,
+}
+impl ChannelTransport {
+    fn send(&self, message: RpcMessage<u64>) {
+        let isolated = self.isolated.lock().unwrap();
+        if isolated.contains(&message.from()) || isolated.contains(&message.to()) {
+            return;
+        }
+        // The receiving server may have been shut down already
+        let _ = self.peers[&message.to()].send(message);
+    }
+}
+impl RaftTransportConnector<u64> for ChannelTransport {
+    fn wait_for_next_incoming_message(
+        &mut self,
+        max_wait: Duration,
+    ) -> Result<Option<RpcMessage<u64>>, RaftTransportError> {
+        match self.inbox.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(mpsc::TryRecvError::Empty) => {
+                thread::park_timeout(max_wait.min(Duration::from_millis(1)));
+                Ok(None)
+            }
+            Err(mpsc::TryRecvError::Disconnected) => Err(RaftTransportError::TransportShutdown),
+        }
+    }
+
+    fn enqueue_reply(&mut self, reply: ReplyTo) -> Result<(), RaftTransportError> {
+        self.send(RpcMessage::Reply(reply));
+        Ok(())
+    }
+
+    fn enqueue_outgoing_request(
+        &mut self,
+        request: Request<u64>,
+    ) -> Result<(), RaftTransportError> {
+        self.send(RpcMessage::Request(request));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RecordingApplication {
+    applied: Arc<Mutex<Vec<(LogIndex, u64)>>>,
+}
+impl ApplicationThatNeedsConsensus for RecordingApplication {
+    type Command = u64;
+    type Response = u64;
+    type Error = ();
+
+    /// Responds with the number of commands applied so far
+    fn apply(&mut self, log_index: LogIndex, command: u64) -> Result<u64, ()> {
+        let mut applied = self.applied.lock().unwrap();
+        applied.push((log_index, command));
+        Ok(applied.len() as u64)
+    }
+
+    fn last_applied_index(&self) -> LogIndex {
+        self.applied
+            .lock()
+            .unwrap()
+            .last()
+            .map(|(index, _)| *index)
+            .unwrap_or(LogIndex(0))
+    }
+}
+
+struct Cluster {
+    nodes: BTreeMap<ServerId, RaftNodeHandle<u64, u64>>,
+    applications: BTreeMap<ServerId, RecordingApplication>,
+    isolated: Arc<Mutex<HashSet<ServerId>>>,
+}
+impl Cluster {
+    fn start(size: u64) -> Self {
+        let server_ids: Vec<ServerId> = (0..size).map(ServerId).collect();
+        let (senders, mut inboxes): (HashMap<_, _>, HashMap<_, _>) = server_ids
+            .iter()
+            .map(|server_id| {
+                let (tx, rx) = mpsc::channel();
+                ((*server_id, tx), (*server_id, rx))
+            })
+            .unzip();
+
+        let mut nodes = BTreeMap::new();
+        let mut applications = BTreeMap::new();
+        let isolated = Arc::new(Mutex::new(HashSet::new()));
+        for server_id in server_ids.iter().copied() {
+            let application = RecordingApplication {
+                applied: Arc::new(Mutex::new(vec![])),
+            };
+            let transport = ChannelTransport {
+                inbox: inboxes.remove(&server_id).unwrap(),
+                peers: senders.clone(),
+                isolated: isolated.clone(),
+            };
+            let node = start_raft_in_new_thread(RaftNodeSetup {
+                server_id,
+                other_servers: server_ids
+                    .iter()
+                    .filter(|id| **id != server_id)
+                    .copied()
+                    .collect(),
+                storage: MemoryStorage::new(),
+                application: application.clone(),
+                config: RaftConfig {
+                    leader_heartbeat_interval: Duration::from_millis(50),
+                    min_election_timeout_ms: 150,
+                    max_election_timeout_ms: 300,
+                },
+                rng: ChaCha8Rng::seed_from_u64(server_id.0),
+                transport_connector: transport,
+                event_collector: NoOpRaftEventCollector {},
+            });
+            let _ = nodes.insert(server_id, node);
+            let _ = applications.insert(server_id, application);
+        }
+        Cluster {
+            nodes,
+            applications,
+            isolated,
+        }
+    }
+
+    /// Moves the mock clock forward until `condition` holds, panics if it doesn't within ten seconds
+    fn run_until(&self, mut condition: impl FnMut(&Self) -> bool) {
+        let started = Instant::now();
+        while !condition(self) {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "Condition did not hold in time"
+            );
+            MockClock::advance(Duration::from_millis(5));
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    fn leader(&self) -> Option<ServerId> {
+        self.nodes
+            .iter()
+            .find(|(_, node)| node.status().state == RaftNodeState::Leader)
+            .map(|(server_id, _)| *server_id)
+    }
+
+    /// Drops the messages from and to `server_id` until `reconnect` is called
+    fn isolate(&self, server_id: ServerId) {
+        let _ = self.isolated.lock().unwrap().insert(server_id);
+    }
+
+    fn reconnect(&self) {
+        self.isolated.lock().unwrap().clear();
+    }
+
+    fn wait_for_leader(&self) -> ServerId {
+        self.run_until(|cluster| cluster.leader().is_some());
+        self.leader().unwrap()
+    }
+
+    fn applied(&self, server_id: ServerId) -> Vec<(LogIndex, u64)> {
+        self.applications[&server_id]
+            .applied
+            .lock()
+            .unwrap()
+            .clone()
+    }
+
+    fn shutdown(self) {
+        for (_, node) in self.nodes {
+            let _ = node.shutdown(false);
+        }
+    }
+}
+
+#[test]
+fn should_apply_proposal_on_every_server_once_committed() {
+    let cluster = Cluster::start(3);
+    let leader = cluster.wait_for_leader();
+
+    let result = cluster.nodes[&leader]
+        .proposer()
+        .propose(42)
+        .recv_timeout(Duration::from_secs(10))
+        .expect("Raft thread did not resolve the proposal");
+
+    // The leader's no-op entry comes first and isn't handed to the application
+    assert_eq!(result.unwrap(), (LogIndex(2), 1));
+    assert_eq!(cluster.applied(leader), vec![(LogIndex(2), 42)]);
+    // Followers apply the entry once the next heartbeat tells them it is committed
+    cluster.run_until(|cluster| {
+        cluster
+            .nodes
+            .keys()
+            .all(|server_id| cluster.applied(*server_id) == vec![(LogIndex(2), 42)])
+    });
+    cluster.shutdown();
+}
+
+#[test]
+fn should_reject_proposal_on_follower_with_leader_hint() {
+    let cluster = Cluster::start(3);
+    let leader = cluster.wait_for_leader();
+    let follower = *cluster.nodes.keys().find(|id| **id != leader).unwrap();
+    cluster.run_until(|cluster| cluster.nodes[&follower].status().leader_for_term == Some(leader));
+
+    let result = cluster.nodes[&follower]
+        .proposer()
+        .propose(7)
+        .recv_timeout(Duration::from_secs(10))
+        .expect("Raft thread did not resolve the proposal");
+
+    match result {
+        Err(ProposeError::NotLeader { leader_hint }) => assert_eq!(leader_hint, Some(leader)),
+        other => panic!("Expected NotLeader error, got {:?}", other),
+    }
+    assert!(cluster.applied(follower).is_empty());
+    cluster.shutdown();
+}
+
+#[test]
+fn should_fail_pending_proposal_of_a_deposed_leader_as_leadership_lost() {
+    let cluster = Cluster::start(3);
+    let old_leader = cluster.wait_for_leader();
+    cluster.isolate(old_leader);
+
+    // Appended to the old leader's log, but it can't be committed without the other servers
+    let result = cluster.nodes[&old_leader].proposer().propose(7);
+    cluster.run_until(|cluster| {
+        cluster.nodes.iter().any(|(server_id, node)| {
+            *server_id != old_leader && node.status().state == RaftNodeState::Leader
+        })
+    });
+    cluster.reconnect();
+    cluster.run_until(|cluster| cluster.nodes[&old_leader].status().state != RaftNodeState::Leader);
+
+    let result = result
+        .recv_timeout(Duration::from_secs(10))
+        .expect("Raft thread did not resolve the proposal");
+    match result {
+        Err(ProposeError::LeadershipLost { leader_hint }) => {
+            assert_ne!(leader_hint, Some(old_leader))
+        }
+        other => panic!("Expected LeadershipLost error, got {:?}", other),
+    }
+    cluster.shutdown();
+}
+
+#[test]
+fn should_confirm_read_on_leader_without_appending_to_the_log() {
+    let cluster = Cluster::start(3);
+    let leader = cluster.wait_for_leader();
+    // The no-op entry appended on election lets the new leader serve reads without a proposal
+    let result = cluster.nodes[&leader]
+        .proposer()
+        .read_index()
+        .recv_timeout(Duration::from_secs(10))
+        .expect("Raft thread did not resolve the read");
+
+    assert_eq!(result.unwrap(), LogIndex(1));
+    assert_eq!(cluster.nodes[&leader].status().last_log_index, LogIndex(1));
+    cluster.shutdown();
+}
+
+#[test]
+fn should_reject_read_on_follower_with_leader_hint() {
+    let cluster = Cluster::start(3);
+    let leader = cluster.wait_for_leader();
+    let follower = *cluster.nodes.keys().find(|id| **id != leader).unwrap();
+    cluster.run_until(|cluster| cluster.nodes[&follower].status().leader_for_term == Some(leader));
+
+    let result = cluster.nodes[&follower]
+        .proposer()
+        .read_index()
+        .recv_timeout(Duration::from_secs(10))
+        .expect("Raft thread did not resolve the read");
+
+    match result {
+        Err(ProposeError::NotLeader { leader_hint }) => assert_eq!(leader_hint, Some(leader)),
+        other => panic!("Expected NotLeader error, got {:?}", other),
+    }
+    cluster.shutdown();
+}
+
+#[test]
+fn should_hand_off_leadership_before_shutting_down() {
+    let mut cluster = Cluster::start(3);
+    let old_leader = cluster.wait_for_leader();
+    let node = cluster.nodes.remove(&old_leader).unwrap();
+
+    let shutdown = thread::spawn(move || node.shutdown(true));
+    cluster.run_until(|_| shutdown.is_finished());
+
+    let transferred_to = match shutdown.join().unwrap() {
+        Ok(RaftThreadExit::Shutdown(status)) => status.leadership_transferred_to,
+        other => panic!("Expected a graceful shutdown, got {:?}", other),
+    };
+    let new_leader = transferred_to.expect("Leadership should have been handed off");
+    assert_ne!(new_leader, old_leader);
+    cluster.run_until(|cluster| cluster.leader() == Some(new_leader));
+    cluster.shutdown();
+}
+
+#[test]
+fn should_give_up_handing_off_leadership_after_an_election_timeout() {
+    let mut cluster = Cluster::start(3);
+    let old_leader = cluster.wait_for_leader();
+    for server_id in cluster.nodes.keys().filter(|id| **id != old_leader) {
+        cluster.isolate(*server_id);
+    }
+    let node = cluster.nodes.remove(&old_leader).unwrap();
+
+    let shutdown = thread::spawn(move || node.shutdown(true));
+    cluster.run_until(|_| shutdown.is_finished());
+
+    match shutdown.join().unwrap() {
+        Ok(RaftThreadExit::Shutdown(status)) => assert_eq!(status.leadership_transferred_to, None),
+        other => panic!("Expected a graceful shutdown, got {:?}", other),
+    }
+    cluster.shutdown();
+}
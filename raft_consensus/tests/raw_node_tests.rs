@@ -0,0 +1,380 @@
+/// Tests for driving the raft protocol by hand through `RawNode`, without the raft thread or a simulated network
+use raft_consensus::system_clock::{self, Instant};
+use raft_consensus::{
+    LogIndex, MemoryStorage, PersistentStorage, ProposeError, RaftConfig, RaftNodeState, RawNode,
+    ReadState, ReplyTo, Request, RpcMessage, ServerId, TimeoutNow,
+};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+use uuid::Uuid;
+
+type TestNode = RawNode<u64, MemoryStorage<u64>>;
+
+fn config() -> RaftConfig {
+    RaftConfig {
+        leader_heartbeat_interval: Duration::from_millis(50),
+        min_election_timeout_ms: 150,
+        max_election_timeout_ms: 300,
+    }
+}
+
+/// A cluster whose messages are delivered by hand, servers in `isolated` neither send nor receive messages
+struct Cluster {
+    nodes: BTreeMap<ServerId, TestNode>,
+    applied: BTreeMap<ServerId, Vec<u64>>,
+    /// Reads confirmed by any server, in the order they were
+    confirmed_reads: Vec<ReadState>,
+    isolated: HashSet<ServerId>,
+    now: Instant,
+}
+impl Cluster {
+    fn new(size: u64, seed: u64) -> Self {
+        let now = system_clock::now();
+        let server_ids: HashSet<ServerId> = (0..size).map(ServerId).collect();
+        let nodes = server_ids
+            .iter()
+            .map(|server_id| {
+                let other_servers = server_ids
+                    .iter()
+                    .filter(|other| *other != server_id)
+                    .copied()
+                    .collect();
+                let node = RawNode::new(
+                    *server_id,
+                    other_servers,
+                    MemoryStorage::new(),
+                    config(),
+                    ChaCha8Rng::seed_from_u64(seed + server_id.0),
+                    now,
+                );
+                (*server_id, node)
+            })
+            .collect();
+        Cluster {
+            nodes,
+            applied: server_ids.iter().map(|id| (*id, vec![])).collect(),
+            confirmed_reads: vec![],
+            isolated: HashSet::new(),
+            now,
+        }
+    }
+
+    fn node(&mut self, server_id: u64) -> &mut TestNode {
+        self.nodes.get_mut(&ServerId(server_id)).unwrap()
+    }
+
+    /// Handles every ready until no messages are left in flight, returns the messages that were delivered
+    fn deliver_messages(&mut self) -> Vec<RpcMessage<u64>> {
+        let mut delivered = vec![];
+        loop {
+            let in_flight = self.deliver_round();
+            if in_flight.is_empty() {
+                return delivered;
+            }
+            delivered.extend(in_flight);
+        }
+    }
+
+    /// Handles the ready of every server once and delivers the messages it sent, returns the ones that were delivered
+    fn deliver_round(&mut self) -> Vec<RpcMessage<u64>> {
+        let mut in_flight = vec![];
+        for (server_id, node) in self.nodes.iter_mut() {
+            let ready = node.ready();
+            node.storage_mut().sync().unwrap();
+            self.applied.get_mut(server_id).unwrap().extend(
+                ready
+                    .committed_entries
+                    .iter()
+                    .filter_map(|entry| entry.command),
+            );
+            self.confirmed_reads.extend(ready.read_states);
+            if !self.isolated.contains(server_id) {
+                in_flight.extend(ready.messages);
+            }
+        }
+        let mut delivered = vec![];
+        for message in in_flight {
+            if !self.isolated.contains(&message.to()) {
+                self.nodes
+                    .get_mut(&message.to())
+                    .unwrap()
+                    .step(message.clone())
+                    .unwrap();
+                delivered.push(message);
+            }
+        }
+        delivered
+    }
+
+    /// Makes `server_id` start an election right away, like a leader handing off leadership would
+    fn start_election(&mut self, server_id: u64) {
+        self.send_timeout_now(server_id);
+        self.deliver_messages();
+    }
+
+    fn send_timeout_now(&mut self, server_id: u64) {
+        let term = self.node(server_id).state().current_term;
+        let from = *self.nodes.keys().find(|id| id.0 != server_id).unwrap();
+        self.node(server_id)
+            .step(RpcMessage::timeout_now(TimeoutNow {
+                request_id: Uuid::new_v4(),
+                from,
+                to: ServerId(server_id),
+                term,
+            }))
+            .unwrap();
+    }
+
+    fn elect(&mut self, server_id: u64) {
+        self.start_election(server_id);
+        assert_eq!(
+            self.node(server_id).state().current_state,
+            RaftNodeState::Leader
+        );
+    }
+
+    fn tick_all(&mut self, elapsed: Duration) {
+        self.now += elapsed;
+        let now = self.now;
+        for node in self.nodes.values_mut() {
+            node.tick(now).unwrap();
+        }
+        self.deliver_messages();
+    }
+}
+
+#[test]
+fn should_commit_and_apply_proposals_on_every_server() {
+    let mut cluster = Cluster::new(3, 1);
+    cluster.elect(0);
+
+    for command in [10, 20, 30] {
+        cluster.node(0).propose(command).unwrap();
+    }
+    // Followers learn the new commit index from the next heartbeat
+    cluster.deliver_messages();
+    cluster.tick_all(Duration::from_millis(60));
+
+    // The no-op entry appended on election is committed with them but isn't applied
+    for server_id in 0..3 {
+        assert_eq!(cluster.applied[&ServerId(server_id)], vec![10, 20, 30]);
+        let status = cluster.node(server_id).status();
+        assert_eq!(status.commit_index.0, 4);
+        assert_eq!(status.last_log_index.0, 4);
+    }
+}
+
+#[test]
+fn should_reject_proposals_on_followers_with_leader_hint() {
+    let mut cluster = Cluster::new(3, 2);
+    cluster.elect(1);
+
+    match cluster.node(2).propose(7) {
+        Err(ProposeError::NotLeader { leader_hint }) => assert_eq!(leader_hint, Some(ServerId(1))),
+        other => panic!("Expected NotLeader error, got {:?}", other),
+    }
+}
+
+#[test]
+fn should_not_commit_without_a_majority() {
+    let mut cluster = Cluster::new(3, 3);
+    cluster.elect(0);
+    cluster.isolated.extend([ServerId(1), ServerId(2)]);
+
+    let index = cluster.node(0).propose(5).unwrap();
+    cluster.deliver_messages();
+
+    // Only the no-op entry appended on election is committed
+    assert_eq!(index.0, 2);
+    assert_eq!(cluster.node(0).status().commit_index.0, 1);
+    assert!(cluster.applied[&ServerId(0)].is_empty());
+}
+
+#[test]
+fn should_confirm_reads_once_a_majority_answers_after_they_arrive() {
+    let mut cluster = Cluster::new(3, 6);
+    cluster.elect(0);
+    cluster.node(0).propose(5).unwrap();
+    cluster.deliver_messages();
+    cluster.isolated.extend([ServerId(1), ServerId(2)]);
+
+    let read_id = cluster.node(0).read_index().unwrap();
+    cluster.deliver_messages();
+    // A leader that was deposed without knowing it looks the same
+    assert!(cluster.confirmed_reads.is_empty());
+    assert_eq!(cluster.node(0).status().last_log_index.0, 2);
+
+    cluster.isolated.remove(&ServerId(1));
+    cluster.tick_all(Duration::from_millis(60));
+    assert_eq!(
+        cluster.confirmed_reads,
+        vec![ReadState {
+            read_id,
+            index: LogIndex(2),
+        }]
+    );
+    // The read did not go through the log
+    assert_eq!(cluster.node(0).status().last_log_index.0, 2);
+}
+
+#[test]
+fn should_not_confirm_reads_before_committing_an_entry_from_the_leaders_term() {
+    let mut cluster = Cluster::new(3, 7);
+    cluster.elect(0);
+    cluster.node(0).propose(5).unwrap();
+    cluster.deliver_messages();
+    // Stops as soon as server 1 has won the vote, before it has sent out its no-op entry
+    cluster.send_timeout_now(1);
+    cluster.deliver_round();
+    cluster.deliver_round();
+    assert_eq!(cluster.node(1).state().current_state, RaftNodeState::Leader);
+    assert_eq!(cluster.node(1).status().last_log_index.0, 3);
+
+    let read_id = cluster.node(1).read_index().unwrap();
+    cluster.deliver_messages();
+    // Confirmed without a proposal, at the index of the no-op entry rather than the commit index it was elected with
+    assert_eq!(
+        cluster.confirmed_reads,
+        vec![ReadState {
+            read_id,
+            index: LogIndex(3),
+        }]
+    );
+}
+
+#[test]
+fn should_catch_up_follower_that_missed_entries() {
+    let mut cluster = Cluster::new(3, 4);
+    cluster.elect(0);
+    cluster.isolated.insert(ServerId(2));
+    for command in 1..=5 {
+        cluster.node(0).propose(command).unwrap();
+    }
+    cluster.deliver_messages();
+    assert!(cluster.applied[&ServerId(2)].is_empty());
+
+    cluster.isolated.clear();
+    cluster.tick_all(Duration::from_millis(60));
+    cluster.tick_all(Duration::from_millis(60));
+
+    assert_eq!(cluster.applied[&ServerId(2)], vec![1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn should_send_timeout_now_once_the_transfer_target_has_caught_up() {
+    let mut cluster = Cluster::new(3, 9);
+    cluster.elect(0);
+    cluster.isolated.extend([ServerId(1), ServerId(2)]);
+    for command in 1..=3 {
+        cluster.node(0).propose(command).unwrap();
+    }
+    cluster.deliver_messages();
+    cluster.isolated.clear();
+
+    cluster.node(0).transfer_leadership().unwrap();
+    match cluster.node(0).propose(4) {
+        Err(ProposeError::NotLeader { leader_hint }) => assert_eq!(leader_hint, None),
+        other => panic!("Expected NotLeader error, got {:?}", other),
+    }
+    let delivered = cluster.deliver_messages();
+
+    let is_timeout_now =
+        |message: &RpcMessage<u64>| matches!(message, RpcMessage::Request(Request::TimeoutNow(_)));
+    let timeout_now = delivered.iter().position(is_timeout_now).unwrap();
+    // The target was sent the entries it was missing first
+    assert!(delivered[..timeout_now].iter().any(|message| matches!(
+        message,
+        RpcMessage::Reply(ReplyTo::AppendEntries(ack)) if ack.from == ServerId(1) && ack.match_index.0 == 4
+    )));
+    assert_eq!(delivered.iter().filter(|m| is_timeout_now(m)).count(), 1);
+    assert_eq!(cluster.node(1).state().current_state, RaftNodeState::Leader);
+}
+
+#[test]
+fn should_catch_up_follower_from_the_entries_after_the_compacted_ones() {
+    let mut cluster = Cluster::new(3, 8);
+    cluster.elect(0);
+    for command in 1..=2 {
+        cluster.node(0).propose(command).unwrap();
+    }
+    cluster.tick_all(Duration::from_millis(60));
+    cluster.tick_all(Duration::from_millis(60));
+    cluster.isolated.insert(ServerId(2));
+    for command in 3..=5 {
+        cluster.node(0).propose(command).unwrap();
+    }
+    cluster.deliver_messages();
+    for server_id in 0..3 {
+        cluster.node(server_id).storage_mut().compact(LogIndex(2));
+        assert_eq!(
+            cluster
+                .node(server_id)
+                .storage()
+                .compacted_through()
+                .map(|(index, _)| index),
+            Some(LogIndex(2))
+        );
+    }
+
+    cluster.isolated.clear();
+    cluster.tick_all(Duration::from_millis(60));
+    cluster.tick_all(Duration::from_millis(60));
+
+    assert_eq!(cluster.applied[&ServerId(2)], vec![1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn should_keep_follower_missing_compacted_entries_from_starting_an_election() {
+    let mut cluster = Cluster::new(3, 9);
+    cluster.elect(0);
+    cluster.isolated.insert(ServerId(2));
+    for command in 1..=3 {
+        cluster.node(0).propose(command).unwrap();
+    }
+    cluster.tick_all(Duration::from_millis(60));
+    cluster.node(0).storage_mut().compact(LogIndex(3));
+    let term = cluster.node(0).state().current_term;
+
+    // The follower can't be caught up without a snapshot, but each heartbeat is answered once rather than retried
+    cluster.isolated.clear();
+    for _ in 0..10 {
+        cluster.tick_all(Duration::from_millis(60));
+    }
+
+    assert!(cluster.applied[&ServerId(2)].is_empty());
+    assert_eq!(cluster.node(0).state().current_state, RaftNodeState::Leader);
+    assert_eq!(cluster.node(2).state().current_term, term);
+    assert_eq!(cluster.node(2).state().leader_for_term, Some(ServerId(0)));
+}
+
+#[test]
+fn should_not_vote_for_candidate_with_an_outdated_log() {
+    let mut cluster = Cluster::new(3, 5);
+    cluster.elect(0);
+    cluster.isolated.insert(ServerId(2));
+    cluster.node(0).propose(1).unwrap();
+    cluster.deliver_messages();
+
+    // Server 2 missed the entry so it can't win an election, server 1 has it and can
+    cluster.isolated.clear();
+    cluster.isolated.insert(ServerId(0));
+    cluster.start_election(2);
+    assert_ne!(cluster.node(2).state().current_state, RaftNodeState::Leader);
+
+    cluster.elect(1);
+}
+
+#[test]
+fn should_produce_the_same_output_for_the_same_inputs() {
+    let run = || {
+        let mut cluster = Cluster::new(3, 6);
+        cluster.elect(0);
+        cluster.node(0).propose(42).unwrap();
+        cluster.deliver_messages()
+    };
+
+    // Request IDs are generated from the seeded RNG, so even they match
+    assert_eq!(run(), run());
+}
@@ -1,10 +1,57 @@
 use mock_instant::MockClock;
-use raft_consensus::{rpc_messages::RpcMessage, ServerId};
-use std::{collections::HashSet, ops::Add, time::Duration};
+use raft_consensus::{rpc_messages::RpcMessage, ApplicationThatNeedsConsensus, LogIndex, ServerId};
+use std::{collections::HashSet, ops::Add, sync::mpsc, time::Duration};
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub(crate) struct SimLogCommand(pub(crate) u64);
 
+/// A command that a server applied to its application
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AppliedCommand {
+    pub(crate) server_id: ServerId,
+    pub(crate) log_index: LogIndex,
+    pub(crate) command: SimLogCommand,
+}
+
+/// Application of a simulated server, it reports every command it applies so the simulation can check that all servers
+/// apply the same command at each index. Its state is not persisted, a restarted server applies its log from the start.
+#[derive(Debug, Clone)]
+pub(crate) struct SimApplication {
+    server_id: ServerId,
+    last_applied: LogIndex,
+    applied_tx: mpsc::Sender<AppliedCommand>,
+}
+impl SimApplication {
+    pub(crate) fn new(server_id: ServerId, applied_tx: mpsc::Sender<AppliedCommand>) -> Self {
+        SimApplication {
+            server_id,
+            last_applied: LogIndex(0),
+            applied_tx,
+        }
+    }
+}
+impl ApplicationThatNeedsConsensus for SimApplication {
+    type Command = SimLogCommand;
+    type Response = ();
+    type Error = ();
+
+    fn apply(&mut self, log_index: LogIndex, command: SimLogCommand) -> Result<(), ()> {
+        self.last_applied = log_index;
+        self.applied_tx
+            .send(AppliedCommand {
+                server_id: self.server_id,
+                log_index,
+                command,
+            })
+            .unwrap_or_default();
+        Ok(())
+    }
+
+    fn last_applied_index(&self) -> LogIndex {
+        self.last_applied
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone, Copy, PartialOrd, Ord, Hash)]
 pub(crate) struct SimTime(pub(crate) Duration);
 impl SimTime {
@@ -55,6 +102,12 @@ pub(crate) enum SimulatorAction {
     HealNetworkPartition,
     InjectIOFailureEveryNOps(u64),
     RestoreIOFunctioning,
+    ShutdownNode {
+        server_id: ServerId,
+        transfer_leadership: bool,
+    },
+    /// Proposes the command to the server that currently believes it is the leader, it is dropped if there is none
+    ProposeCommand(SimLogCommand),
 }
 #[derive(Eq, PartialEq, Debug, Clone)]
 pub(crate) struct SimulatorEvent {
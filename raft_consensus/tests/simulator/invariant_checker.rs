@@ -1,13 +1,16 @@
-use raft_consensus::{RaftNodeState, RaftStateEvent, RaftStateEventCollector, ServerId, TermIndex};
+use raft_consensus::{
+    LogIndex, RaftEvent, RaftEventKind, RaftNodeState, RaftStateEvent, RaftStateEventCollector,
+    RaftTransition, ServerId, TermIndex,
+};
 use tracing::info;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     sync::mpsc,
 };
 
 use super::{
-    common::SimTime,
+    common::{AppliedCommand, SimApplication, SimLogCommand, SimTime},
     sim_log::{SimLog, SimLogEntry},
 };
 
@@ -18,10 +21,17 @@ use super::{
 /// simulation to check invariants. The channel is needed since the simulated raft node runs in a separate thread.
 #[derive(Clone)]
 pub(crate) struct ServerProcessRaftStateEventCollector {
-    event_tx: mpsc::Sender<RaftStateEvent>,
+    event_tx: mpsc::Sender<RaftEvent>,
 }
 impl RaftStateEventCollector for ServerProcessRaftStateEventCollector {
-    fn push_event(&mut self, event: RaftStateEvent) {
+    fn is_subscribed_to(&self, kind: RaftEventKind) -> bool {
+        matches!(
+            kind,
+            RaftEventKind::StateChanged | RaftEventKind::BecameLeader
+        )
+    }
+
+    fn push_event(&mut self, event: RaftEvent) {
         self.event_tx.send(event).unwrap_or_default();
     }
 }
@@ -31,16 +41,29 @@ impl RaftStateEventCollector for ServerProcessRaftStateEventCollector {
 /// the server the event is from. It then uses the states of the servers to check that invariants are not violated.
 pub(crate) struct InvariantChecker {
     server_states: HashMap<ServerId, RaftStateEvent>,
-    event_tx: mpsc::Sender<RaftStateEvent>,
-    event_rx: mpsc::Receiver<RaftStateEvent>,
+    /// Last term each server became leader in
+    leader_terms: HashMap<ServerId, TermIndex>,
+    stopped_servers: HashSet<ServerId>,
+    /// First command applied at each index and the server that applied it
+    applied_commands: BTreeMap<LogIndex, (ServerId, SimLogCommand)>,
+    event_tx: mpsc::Sender<RaftEvent>,
+    event_rx: mpsc::Receiver<RaftEvent>,
+    applied_tx: mpsc::Sender<AppliedCommand>,
+    applied_rx: mpsc::Receiver<AppliedCommand>,
 }
 impl InvariantChecker {
     pub(crate) fn new() -> Self {
         let (event_tx, event_rx) = mpsc::channel();
+        let (applied_tx, applied_rx) = mpsc::channel();
         Self {
             server_states: HashMap::new(),
+            leader_terms: HashMap::new(),
+            stopped_servers: HashSet::new(),
+            applied_commands: BTreeMap::new(),
             event_tx,
             event_rx,
+            applied_tx,
+            applied_rx,
         }
     }
 
@@ -51,6 +74,24 @@ impl InvariantChecker {
         }
     }
 
+    /// Get a new application for a server process that reports the commands it applies to the checker.
+    pub(crate) fn application_for_server(&self, server_id: ServerId) -> SimApplication {
+        SimApplication::new(server_id, self.applied_tx.clone())
+    }
+
+    /// Commands that have been applied by at least one server, by index
+    pub(crate) fn applied_commands(&self) -> BTreeMap<LogIndex, SimLogCommand> {
+        self.applied_commands
+            .iter()
+            .map(|(index, (_, command))| (*index, *command))
+            .collect()
+    }
+
+    /// Stop tracking a server that has been shutdown, the last state it reported no longer reflects the cluster
+    pub(crate) fn server_stopped(&mut self, server_id: ServerId) {
+        self.stopped_servers.insert(server_id);
+    }
+
     /// Get the current state of all servers. Returns a cloned copy of the state.
     pub(crate) fn get_current_state(&self) -> HashMap<ServerId, RaftStateEvent> {
         self.server_states
@@ -72,8 +113,22 @@ impl InvariantChecker {
     pub(crate) fn check_invariants(&mut self, time: SimTime, log: &mut SimLog) {
         let old_server_states = self.server_states.clone();
         while let Ok(event) = self.event_rx.try_recv() {
-            self.check_state_change_invariants(event);
-            self.server_states.insert(event.server_id, event);
+            match event.transition {
+                RaftTransition::StateChanged(state) => {
+                    self.check_state_change_invariants(state);
+                    self.server_states.insert(state.server_id, state);
+                }
+                RaftTransition::BecameLeader { term } => {
+                    self.check_became_leader_invariants(event.server_id, term)
+                }
+                _ => (),
+            }
+        }
+        while let Ok(applied) = self.applied_rx.try_recv() {
+            self.check_applied_command_invariants(applied);
+        }
+        for server_id in &self.stopped_servers {
+            self.server_states.remove(server_id);
         }
         let current_state = self.get_current_state();
 
@@ -112,6 +167,37 @@ impl InvariantChecker {
         }
     }
 
+    /// A server can only win one election per term, and terms only increase so each election it wins should be
+    /// for a newer term than the last one
+    fn check_became_leader_invariants(&mut self, server_id: ServerId, term: TermIndex) {
+        if let Some(last_leader_term) = self.leader_terms.get(&server_id) {
+            assert!(
+                term > *last_leader_term,
+                "{:?}: Became leader for term {term:?} after already being leader for term {last:?}",
+                server_id,
+                term = term,
+                last = last_leader_term
+            );
+        }
+        self.leader_terms.insert(server_id, term);
+    }
+
+    /// See: <https://raft.github.io/raft.pdf>
+    /// State Machine Safety: if a server has applied a log entry at a given index to its state machine, no other server
+    /// will ever apply a different log entry for the same index. Servers apply their log again after restarting, that
+    /// has to apply the same commands as before too.
+    fn check_applied_command_invariants(&mut self, applied: AppliedCommand) {
+        let (first_server, first_command) = *self
+            .applied_commands
+            .entry(applied.log_index)
+            .or_insert((applied.server_id, applied.command));
+        assert_eq!(
+            applied.command, first_command,
+            "CLUSTER INVARIANT VIOLATED: {:?} applied {:?} at index {:?} but {:?} applied {:?}!",
+            applied.server_id, applied.command, applied.log_index, first_server, first_command
+        );
+    }
+
     /// There should only be one leader chosen for a term, this means that:
     /// - Only one node that believes it is the leader for a term
     /// - All nodes should agree on who the leader is for that term
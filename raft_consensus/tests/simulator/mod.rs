@@ -7,13 +7,14 @@ pub(crate) mod sim_transport;
 
 use fault_injection::{set_trigger_function, FAULT_INJECT_COUNTER};
 use mock_instant::MockClock;
-use raft_consensus::{RaftConfig, ServerId};
+use raft_consensus::{LogIndex, RaftConfig, RaftNodeStatus, ServerId};
 use tracing::{debug, warn};
 use tracing::{info, trace};
 
 use invariant_checker::InvariantChecker;
 
 use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
@@ -21,6 +22,7 @@ use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicU64;
 use std::sync::mpsc;
+use std::thread;
 use std::time::Duration;
 
 use rand_chacha::ChaCha8Rng;
@@ -28,6 +30,7 @@ use rand_chacha::ChaCha8Rng;
 use crate::simulator::common::SimulatorAction;
 use crate::simulator::sim_log::SimLogEntry;
 
+use self::common::SimLogCommand;
 use self::common::SimTime;
 use self::common::SimulatorEvent;
 use self::common::WakeUpAtOrBefore;
@@ -76,7 +79,6 @@ impl ClusterSim {
         mut network: SimNetwork,
         config: RaftConfig,
         rng: ChaCha8Rng,
-        storage_temp_dir: String,
         log_file_path: Option<PathBuf>,
     ) -> Self {
         assert_eq!(
@@ -111,9 +113,9 @@ impl ClusterSim {
                 sid,
                 num_servers,
                 config.clone(),
-                storage_temp_dir.clone(),
                 rng.clone(),
                 &mut network,
+                invariant_checker.application_for_server(sid),
                 invariant_checker.event_collector_for_server(),
             );
             servers.insert(sid, process);
@@ -143,6 +145,24 @@ impl ClusterSim {
         self.log.reset();
     }
 
+    /// The server that currently believes it is the leader, if any
+    pub(crate) fn current_leader(&self) -> Option<ServerId> {
+        self.invariant_checker.get_current_leader()
+    }
+
+    /// Commands that have been applied by at least one server, by index
+    pub(crate) fn applied_commands(&self) -> BTreeMap<LogIndex, SimLogCommand> {
+        self.invariant_checker.applied_commands()
+    }
+
+    /// Latest status published by a server's raft thread
+    pub(crate) fn server_status(&self, server_id: ServerId) -> RaftNodeStatus {
+        self.servers
+            .get(&server_id)
+            .expect("SIM: No such server")
+            .status()
+    }
+
     /// Provides a way for tests to inject messages into the simulation.
     pub(crate) fn enqueue_event(&mut self, msg: SimulatorEvent) {
         assert!(
@@ -159,13 +179,21 @@ impl ClusterSim {
 
     /// Runs a single step of the simulation, this doess...
     /// 1. Interrupt network transports for each node so they can check the current simulation time and time out waiting if necessary
-    /// 2. Fetch all messages that have been sent over the nextwork since the last iteration
-    /// 3. Get the next simulator message to be processed and run the appropriate action (i.e. deliver a message to a server, partion the network, etc.)
-    /// 4. Checks that no Raft invariants have been violated in the cluster
+    /// 2. Wait for every server to handle what it was woken up for, so the clock doesn't move on while a server is still busy
+    /// 3. Fetch all messages that have been sent over the nextwork since the last iteration
+    /// 4. Get the next simulator message to be processed and run the appropriate action (i.e. deliver a message to a server, partion the network, etc.)
+    /// 5. Checks that no Raft invariants have been violated in the cluster
     fn run_step(&mut self) {
         for (_, server_process) in self.servers.iter_mut() {
             server_process.restart_if_needed(&mut self.network);
         }
+        while !self
+            .servers
+            .values()
+            .all(|server_process| server_process.is_waiting())
+        {
+            thread::yield_now();
+        }
 
         let outbound_messages = self
             .network
@@ -258,6 +286,26 @@ impl ClusterSim {
                 SimulatorAction::RestoreIOFunctioning => {
                     FAULT_INJECT_COUNTER.store(u64::MAX, std::sync::atomic::Ordering::Release);
                 }
+                SimulatorAction::ShutdownNode {
+                    server_id,
+                    transfer_leadership,
+                } => {
+                    self.servers
+                        .get_mut(&server_id)
+                        .expect("SIM: Should have a server process for server being shutdown")
+                        .shutdown(transfer_leadership);
+                    self.invariant_checker.server_stopped(server_id);
+                }
+                SimulatorAction::ProposeCommand(command) => {
+                    match self.invariant_checker.get_current_leader() {
+                        Some(leader) => self
+                            .servers
+                            .get(&leader)
+                            .expect("SIM: Should have a server process for the leader")
+                            .propose(command),
+                        None => debug!("No leader to propose {:?} to, dropping it", command),
+                    }
+                }
             }
 
             self.invariant_checker
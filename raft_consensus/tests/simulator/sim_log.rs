@@ -15,6 +15,8 @@ pub(crate) enum LoggedSimEvent {
     HealNetworkPartition,
     InjectIOFaultEveryNOps(u64),
     RestoreIOFunctioning,
+    ShutdownNode(ServerId, bool),
+    ProposeCommand(SimLogCommand),
 }
 impl LoggedSimEvent {
     fn from_sim_event(event: &SimulatorEvent) -> Self {
@@ -39,6 +41,13 @@ impl LoggedSimEvent {
             super::common::SimulatorAction::RestoreIOFunctioning => {
                 LoggedSimEvent::RestoreIOFunctioning
             }
+            super::common::SimulatorAction::ShutdownNode {
+                server_id,
+                transfer_leadership,
+            } => LoggedSimEvent::ShutdownNode(*server_id, *transfer_leadership),
+            super::common::SimulatorAction::ProposeCommand(command) => {
+                LoggedSimEvent::ProposeCommand(*command)
+            }
         }
     }
 }
@@ -92,6 +101,13 @@ fn write_event_to_log_file(log_file: &mut File, event: &SimLogEntry) -> Result<(
                             req.request_id
                         )?;
                     }
+                    Request::TimeoutNow(req) => {
+                        writeln!(
+                            log_file,
+                            "TIME {:?}ms: SEND TimeoutNow from {:?} to {:?} for term {:?} with latency {:?}ms tbd at {:?} (req id: {:?})",
+                            queued_time.as_millis(), req.from, req.to, req.term, delivery_time.as_millis() - queued_time.as_millis(), delivery_time.as_millis(), req.request_id
+                        )?;
+                    }
                 },
                 RpcMessage::Reply(reply) => match reply {
                     ReplyTo::AppendEntries(reply) => {
@@ -108,12 +124,21 @@ fn write_event_to_log_file(log_file: &mut File, event: &SimLogEntry) -> Result<(
                             time=queued_time.as_millis(), vote=reply.vote_granted, from=reply.from, to=reply.to, term=reply.term, latency=delivery_time.as_millis() - queued_time.as_millis(), delivery_time=delivery_time.as_millis(), req_id=reply.request_id
                         )?;
                     }
+                    ReplyTo::TimeoutNow(reply) => {
+                        writeln!(
+                            log_file,
+                            "TIME {:?}ms: SEND TimeoutNowReply(success={:?}) from {:?} to {:?} for term {:?} with latency {:?}ms tbd at {:?} (req id: {:?})",
+                            queued_time.as_millis(), reply.success, reply.from, reply.to, reply.term, delivery_time.as_millis() - queued_time.as_millis(), delivery_time.as_millis(), reply.request_id
+                        )?;
+                    }
                 },
             },
             LoggedSimEvent::PartitionNetwork(_) => {}
             LoggedSimEvent::HealNetworkPartition => {}
             LoggedSimEvent::InjectIOFaultEveryNOps(_) => {}
             LoggedSimEvent::RestoreIOFunctioning => {}
+            LoggedSimEvent::ShutdownNode(_, _) => {}
+            LoggedSimEvent::ProposeCommand(_) => {}
         },
         SimLogEntry::EventProcessed(time, event) => match event {
             LoggedSimEvent::DroppedNetworkMessage(_, msg) => match msg {
@@ -132,6 +157,13 @@ fn write_event_to_log_file(log_file: &mut File, event: &SimLogEntry) -> Result<(
                             time.as_millis(), req.from, req.to, req.term, req.request_id
                         )?;
                     }
+                    rpc_messages::Request::TimeoutNow(req) => {
+                        writeln!(
+                            log_file,
+                            "TIME {:?}ms: DROPPED TimeoutNow from {:?} to {:?} for term {:?} (req id: {:?})",
+                            time.as_millis(), req.from, req.to, req.term, req.request_id
+                        )?;
+                    }
                 },
                 RpcMessage::Reply(reply) => match reply {
                     rpc_messages::ReplyTo::AppendEntries(reply) => {
@@ -148,6 +180,13 @@ fn write_event_to_log_file(log_file: &mut File, event: &SimLogEntry) -> Result<(
                             time.as_millis(), reply.from, reply.to, reply.term, reply.request_id
                         )?;
                     }
+                    rpc_messages::ReplyTo::TimeoutNow(reply) => {
+                        writeln!(
+                            log_file,
+                            "TIME {:?}ms: DROPPED TimeoutNowReply from {:?} to {:?} for term {:?} (req id: {:?})",
+                            time.as_millis(), reply.from, reply.to, reply.term, reply.request_id
+                        )?;
+                    }
                 },
             },
             LoggedSimEvent::SendOverNetwork(_, msg) => match msg {
@@ -166,6 +205,13 @@ fn write_event_to_log_file(log_file: &mut File, event: &SimLogEntry) -> Result<(
                             time.as_millis(), req.from, req.to, req.term, req.request_id
                         )?;
                     }
+                    rpc_messages::Request::TimeoutNow(req) => {
+                        writeln!(
+                            log_file,
+                            "TIME {:?}ms: RECV TimeoutNow from {:?} to {:?} for term {:?} (req id: {:?})",
+                            time.as_millis(), req.from, req.to, req.term, req.request_id
+                        )?;
+                    }
                 },
                 RpcMessage::Reply(reply) => match reply {
                     rpc_messages::ReplyTo::AppendEntries(reply) => {
@@ -182,6 +228,13 @@ fn write_event_to_log_file(log_file: &mut File, event: &SimLogEntry) -> Result<(
                             time=time.as_millis(), vote=reply.vote_granted, from=reply.from, to=reply.to, term=reply.term, req_id=reply.request_id
                         )?;
                     }
+                    rpc_messages::ReplyTo::TimeoutNow(reply) => {
+                        writeln!(
+                            log_file,
+                            "TIME {time:?}ms: RECV TimeoutNowReply(success={success:?}) from {from:?} to {to:?} for term {term:?} (req id: {req_id:?})",
+                            time=time.as_millis(), success=reply.success, from=reply.from, to=reply.to, term=reply.term, req_id=reply.request_id
+                        )?;
+                    }
                 },
             },
             LoggedSimEvent::PartitionNetwork(partitions) => {
@@ -216,6 +269,23 @@ fn write_event_to_log_file(log_file: &mut File, event: &SimLogEntry) -> Result<(
                     time.as_millis()
                 )?;
             }
+            LoggedSimEvent::ShutdownNode(server_id, transfer_leadership) => {
+                writeln!(
+                    log_file,
+                    "TIME {:?}ms: ShutdownNode({:?}, transfer_leadership={:?})",
+                    time.as_millis(),
+                    server_id,
+                    transfer_leadership
+                )?;
+            }
+            LoggedSimEvent::ProposeCommand(command) => {
+                writeln!(
+                    log_file,
+                    "TIME {:?}ms: ProposeCommand({:?})",
+                    time.as_millis(),
+                    command.0
+                )?;
+            }
         },
         SimLogEntry::ServerStateUpdate(time, server_states) => {
             writeln!(
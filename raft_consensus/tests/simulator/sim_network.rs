@@ -24,7 +24,7 @@ use tracing::{debug, trace};
 use super::{
     common::{SimLogCommand, SimTime, WakeUpAtOrBefore},
     sim_log::{LoggedSimEvent, SimLog, SimLogEntry},
-    sim_transport::SimNetworkRaftTransportConnector,
+    sim_transport::{SimNetworkRaftTransportConnector, TransportActivity},
 };
 
 use rand_distr::num_traits::ToPrimitive;
@@ -45,6 +45,7 @@ pub(crate) struct NetworkConnectionQuality {
 
 struct NetworkNode<C: LogCommand> {
     incoming_message_tx: mpsc::Sender<RpcMessage<C>>,
+    activity: TransportActivity,
 }
 
 /// Models a network with packet loss and latency, uses Bernoulli distribution for packet loss and log-normal distribution for latency
@@ -161,16 +162,19 @@ impl SimNetwork {
         server_id: ServerId,
     ) -> SimNetworkRaftTransportConnector {
         let (inbound_message_tx, inbound_message_rx) = mpsc::channel();
+        let activity = TransportActivity::new();
         self.servers.insert(
             server_id,
             NetworkNode {
                 incoming_message_tx: inbound_message_tx,
+                activity: activity.clone(),
             },
         );
         SimNetworkRaftTransportConnector::new(
             self.outbound_message_tx.clone(),
             inbound_message_rx,
             self.timer_tx.clone(),
+            activity,
         )
     }
 
@@ -358,6 +362,16 @@ impl SimNetwork {
         if let Err(_) = network_node.incoming_message_tx.send(message) {
             debug!("SIM: Could not send network message to server (raft thread shutdown?)");
         }
+        network_node.activity.signal();
+    }
+
+    /// Activity of the transport the server's raft thread is currently using
+    pub(crate) fn transport_activity(&self, server_id: ServerId) -> TransportActivity {
+        self.servers
+            .get(&server_id)
+            .expect("SIM: Server has not joined the network")
+            .activity
+            .clone()
     }
 }
 
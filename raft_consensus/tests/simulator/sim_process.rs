@@ -1,30 +1,56 @@
-use std::{collections::HashSet, thread::JoinHandle};
+use std::collections::HashSet;
 
-use raft_consensus::{start_raft_in_new_thread, RaftConfig, RaftStateEventCollector, ServerId};
+use raft_consensus::{
+    start_raft_in_new_thread, MemoryStorage, PersistentStorageError, RaftConfig, RaftNodeHandle,
+    RaftNodeSetup, RaftNodeStatus, RaftStateEventCollector, RaftThreadExit, ServerId,
+    StorageErrorKind,
+};
+use rand::{RngCore, SeedableRng};
 use rand_chacha::ChaCha8Rng;
 
-use super::{sim_network::SimNetwork, sim_transport::SimNetworkRaftTransportConnector};
+use super::common::{SimApplication, SimLogCommand};
+use super::{sim_network::SimNetwork, sim_transport::TransportActivity};
+
+/// Injected IO faults fail the sync before anything is persisted, so they should never look like corruption
+fn assert_injected_storage_error(server_id: ServerId, error: &PersistentStorageError) {
+    assert_eq!(
+        error.kind(),
+        StorageErrorKind::Transient,
+        "Server {} got unexpected storage error: {}",
+        server_id.0,
+        error
+    );
+}
 
 /// A process in the simulation that represents a single server.
 /// This runs the Raft algorithm for this simulated server in it's own thread.
 /// It uses the provided transport to send and to receive messages from other servers.
+///
+/// The server's storage simulates crashes, a server that stopped because a sync failed is restarted with only what it
+/// had synced before, like a server that lost power before its fsync completed.
 pub(crate) struct SimRaftProcess<E: RaftStateEventCollector + Clone> {
     server_id: ServerId,
     config: RaftConfig,
     rng: ChaCha8Rng,
     other_servers: HashSet<ServerId>,
-    storage_path: String,
+    /// Holds what the server has synced across restarts, the raft thread works on a storage restarted from it
+    storage: MemoryStorage<SimLogCommand>,
+    /// Restarted servers start over with a copy of this application, which has not applied anything
+    application: SimApplication,
     event_collector: E,
-    thread_handle: JoinHandle<()>,
+    raft_node: RaftNodeHandle<SimLogCommand, ()>,
+    /// Activity of the transport the current raft thread waits on
+    activity: TransportActivity,
+    stopped: bool,
 }
 impl<E: RaftStateEventCollector + Clone + 'static> SimRaftProcess<E> {
     pub(crate) fn new(
         server_id: ServerId,
         max_id: u64,
         config: RaftConfig,
-        storage_path: String,
         mut rng: ChaCha8Rng,
         network_to_join: &mut SimNetwork,
+        application: SimApplication,
         event_collector: E,
     ) -> Self {
         rng.set_stream(server_id.0 as u64);
@@ -40,42 +66,81 @@ impl<E: RaftStateEventCollector + Clone + 'static> SimRaftProcess<E> {
             }
         }
 
-        let raft_thread_handle = start_raft_in_new_thread(
+        let storage = MemoryStorage::with_crash_simulation();
+        let raft_node = start_raft_in_new_thread(RaftNodeSetup {
             server_id,
-            other_servers.clone(),
-            storage_path.clone(),
+            other_servers: other_servers.clone(),
+            storage: storage.restart(),
+            application: application.clone(),
             config,
-            rng.clone(),
-            network_to_join.join_network_and_take_transport_connector(server_id),
-            event_collector.clone(),
-        );
+            rng: rng.clone(),
+            transport_connector: network_to_join
+                .join_network_and_take_transport_connector(server_id),
+            event_collector: event_collector.clone(),
+        });
         SimRaftProcess {
             server_id,
             rng,
             config,
             other_servers,
-            storage_path,
+            storage,
+            application,
             event_collector,
-            thread_handle: raft_thread_handle,
+            raft_node,
+            activity: network_to_join.transport_activity(server_id),
+            stopped: false,
         }
     }
 
     pub(crate) fn restart_if_needed(&mut self, network_to_join: &mut SimNetwork) {
-        if self.thread_handle.is_finished() {
-            println!("Restarting server {}...", self.server_id.0);
-            self.thread_handle = start_raft_in_new_thread(
-                self.server_id,
-                self.other_servers.clone(),
-                self.storage_path.clone(),
-                self.config,
-                self.rng.clone(),
-                network_to_join.join_network_and_take_transport_connector(self.server_id),
-                self.event_collector.clone(),
+        if self.raft_node.is_finished() && !self.stopped {
+            let restarted_raft_node = start_raft_in_new_thread(RaftNodeSetup {
+                server_id: self.server_id,
+                other_servers: self.other_servers.clone(),
+                storage: self.storage.restart(),
+                application: self.application.clone(),
+                config: self.config,
+                // A restarted server must not repeat the request IDs it generated before the restart
+                rng: ChaCha8Rng::seed_from_u64(self.rng.next_u64()),
+                transport_connector: network_to_join
+                    .join_network_and_take_transport_connector(self.server_id),
+                event_collector: self.event_collector.clone(),
+            });
+            self.activity = network_to_join.transport_activity(self.server_id);
+            let exit = std::mem::replace(&mut self.raft_node, restarted_raft_node).join();
+            if let Ok(RaftThreadExit::PersistentStorageError(e)) = &exit {
+                assert_injected_storage_error(self.server_id, e);
+            }
+            println!(
+                "Restarting server {} after it stopped: {:?}...",
+                self.server_id.0, exit
             );
         }
     }
 
+    /// Gracefully shuts down the server, it will not be restarted. The raft thread only notices the
+    /// shutdown request the next time it wakes up so the simulation needs to keep running for it to stop.
+    pub(crate) fn shutdown(&mut self, transfer_leadership: bool) {
+        self.stopped = true;
+        self.raft_node.request_shutdown(transfer_leadership);
+    }
+
+    /// Proposes a command without waiting for the result, the invariant checker sees it once it is applied
+    pub(crate) fn propose(&self, command: SimLogCommand) {
+        let _ = self.raft_node.proposer().propose(command);
+    }
+
+    pub(crate) fn status(&self) -> RaftNodeStatus {
+        self.raft_node.status()
+    }
+
     pub(crate) fn wake_up_transport_connector(&self) {
-        self.thread_handle.thread().unpark();
+        self.activity.signal();
+    }
+
+    /// Whether the raft thread has handled everything delivered to it and is waiting for its next message, a thread
+    /// that stopped isn't going to handle anything
+    pub(crate) fn is_waiting(&self) -> bool {
+        self.raft_node.is_finished() || self.activity.is_waiting()
     }
 }
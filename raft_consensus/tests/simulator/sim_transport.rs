@@ -1,5 +1,9 @@
 use std::{
-    sync::mpsc::{self, SendError, TryRecvError},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SendError, TryRecvError},
+        Arc, Mutex,
+    },
     thread,
     time::Duration,
 };
@@ -14,6 +18,58 @@ use crate::simulator::common::SimTime;
 
 use super::common::{SimLogCommand, WakeUpAtOrBefore};
 
+/// Tracks whether a raft node's thread is parked waiting for its next message. The raft threads run alongside the
+/// simulator, which must not move the clock forward while a thread still has a message or a wake up to handle,
+/// otherwise the thread handles it late and e.g. times out on a heartbeat that was delivered in time.
+///
+/// The simulator bumps the signal count every time it gives the thread something to do, the transport records the
+/// count it has seen right before parking. The thread is waiting once it has seen every signal.
+#[derive(Clone)]
+pub(crate) struct TransportActivity {
+    signalled: Arc<AtomicU64>,
+    seen: Arc<AtomicU64>,
+    thread: Arc<Mutex<Option<thread::Thread>>>,
+}
+impl TransportActivity {
+    pub(crate) fn new() -> Self {
+        // A thread that hasn't started waiting yet is busy
+        Self {
+            signalled: Arc::new(AtomicU64::new(1)),
+            seen: Arc::new(AtomicU64::new(0)),
+            thread: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Wakes up the thread so it checks for messages and the time again. Called after the message is sent, so the
+    /// thread is guaranteed to see it once it has seen the signal.
+    pub(crate) fn signal(&self) {
+        self.signalled.fetch_add(1, Ordering::AcqRel);
+        if let Some(thread) = self.thread.lock().expect("SIM: Lock poisoned").as_ref() {
+            thread.unpark();
+        }
+    }
+
+    /// Whether the thread is parked and has handled everything it was signalled about
+    pub(crate) fn is_waiting(&self) -> bool {
+        self.seen.load(Ordering::Acquire) == self.signalled.load(Ordering::Acquire)
+    }
+
+    fn register_thread(&self, thread: thread::Thread) {
+        self.thread
+            .lock()
+            .expect("SIM: Lock poisoned")
+            .get_or_insert(thread);
+    }
+
+    fn signals(&self) -> u64 {
+        self.signalled.load(Ordering::Acquire)
+    }
+
+    fn parking(&self, signals_seen: u64) {
+        self.seen.store(signals_seen, Ordering::Release);
+    }
+}
+
 /// Transport used by raft nodes in the simulator. Allows the simulated network to send/receive messages from the raft nodes.
 /// Parks the Raft node's thread when it is waiting for the next message, and unparks it when the simulator clock is updated
 /// so that it can check if the wait timeout has been reached.
@@ -21,6 +77,7 @@ pub(crate) struct SimNetworkRaftTransportConnector {
     outbound_message_tx: mpsc::Sender<RpcMessage<SimLogCommand>>,
     inbound_message_rx: mpsc::Receiver<RpcMessage<SimLogCommand>>,
     wake_up_tx: mpsc::Sender<WakeUpAtOrBefore>,
+    activity: TransportActivity,
     thread_handle: Option<thread::Thread>,
 }
 impl SimNetworkRaftTransportConnector {
@@ -28,11 +85,13 @@ impl SimNetworkRaftTransportConnector {
         outbound_message_tx: mpsc::Sender<RpcMessage<SimLogCommand>>,
         inbound_message_rx: mpsc::Receiver<RpcMessage<SimLogCommand>>,
         timer_tx: mpsc::Sender<WakeUpAtOrBefore>,
+        activity: TransportActivity,
     ) -> Self {
         Self {
             outbound_message_tx,
             inbound_message_rx,
             wake_up_tx: timer_tx,
+            activity,
             thread_handle: None,
         }
     }
@@ -46,6 +105,7 @@ impl RaftTransportConnector<SimLogCommand> for SimNetworkRaftTransportConnector
         let current_thread = thread::current();
         let current_thread_id = current_thread.id();
         let saved_handle = self.thread_handle.get_or_insert(current_thread);
+        self.activity.register_thread(saved_handle.clone());
 
         assert_eq!(
             saved_handle.id(),
@@ -67,6 +127,7 @@ impl RaftTransportConnector<SimLogCommand> for SimNetworkRaftTransportConnector
 
         loop {
             trace!("Simulated network transport checking for incoming messages...");
+            let signals = self.activity.signals();
             match self.inbound_message_rx.try_recv() {
                 Ok(message) => return Ok(Some(message)),
                 Err(TryRecvError::Empty) => {
@@ -74,6 +135,7 @@ impl RaftTransportConnector<SimLogCommand> for SimNetworkRaftTransportConnector
                     if time_waited >= max_wait {
                         return Ok(None);
                     }
+                    self.activity.parking(signals);
                     thread::park();
                 }
                 Err(TryRecvError::Disconnected) => {
@@ -126,8 +188,12 @@ mod tests {
         let (inbound_tx, inbound_rx) = std::sync::mpsc::channel();
         let (timer_tx, _timer_rx) = std::sync::mpsc::channel();
 
-        let mut transport =
-            super::SimNetworkRaftTransportConnector::new(outbound_tx, inbound_rx, timer_tx);
+        let mut transport = super::SimNetworkRaftTransportConnector::new(
+            outbound_tx,
+            inbound_rx,
+            timer_tx,
+            super::TransportActivity::new(),
+        );
 
         let thread_handle = std::thread::spawn(move || {
             match transport.wait_for_next_incoming_message(Duration::from_millis(127)) {
@@ -161,8 +227,13 @@ mod tests {
         let (_inbound_tx, inbound_rx) = std::sync::mpsc::channel();
         let (timer_tx, _timer_rx) = std::sync::mpsc::channel();
 
-        let mut transport =
-            super::SimNetworkRaftTransportConnector::new(outbound_tx, inbound_rx, timer_tx);
+        let activity = super::TransportActivity::new();
+        let mut transport = super::SimNetworkRaftTransportConnector::new(
+            outbound_tx,
+            inbound_rx,
+            timer_tx,
+            activity.clone(),
+        );
 
         let thread_handle = std::thread::spawn(move || {
             let message = transport.wait_for_next_incoming_message(Duration::from_millis(127));
@@ -175,21 +246,22 @@ mod tests {
 
         debug!("Waiting for thread to park itself...");
 
-        // Wait for the thread to park itself (TODO - is there a better way to do this?)
-        thread::sleep(Duration::from_millis(1000));
+        while !activity.is_waiting() {
+            thread::yield_now();
+        }
 
-        debug!("Unparking thread...");
+        debug!("Waking up thread...");
 
         // Should park itself again since the clock hasn't changed
-        thread_handle.thread().unpark();
-        // Note: There is no guarantee the thread was actually unparked
-        // before this assertion, so passing this doesn't neccessarily mean
-        // it works, but failing this would mean it definitely does not work properly
+        activity.signal();
+        while !activity.is_waiting() {
+            thread::yield_now();
+        }
         assert!(!thread_handle.is_finished());
 
-        // Now if we advance the clock, it should timeout when it is unparked
+        // Now if we advance the clock, it should timeout when it is woken up
         MockClock::advance(Duration::from_millis(128));
-        thread_handle.thread().unpark();
+        activity.signal();
 
         assert_eq!(true, thread_handle.join().unwrap());
     }
@@ -0,0 +1,547 @@
+/// Tests for the persistent storage implementations
+use raft_consensus::{
+    inspect_election_file, DefaultPersistentStorage, LogEntry, LogIndex, MemoryStorage,
+    PersistentStorage, ServerId, StorageErrorKind, TermIndex,
+};
+use tempfile::TempDir;
+
+fn entry(index: u64, term: u64, command: u64) -> LogEntry<u64> {
+    LogEntry {
+        index: LogIndex(index),
+        term: TermIndex(term),
+        command: Some(command),
+    }
+}
+
+fn noop(index: u64, term: u64) -> LogEntry<u64> {
+    LogEntry {
+        index: LogIndex(index),
+        term: TermIndex(term),
+        command: None,
+    }
+}
+
+#[test]
+fn memory_storage_keeps_unsynced_writes_across_restart() {
+    let mut storage = MemoryStorage::<u64>::new();
+    storage.update_term(TermIndex(3)).record_vote(ServerId(1));
+
+    let restarted = storage.restart();
+    assert_eq!(restarted.current_term(), TermIndex(3));
+    assert_eq!(restarted.vote_for_current_term(), Some(ServerId(1)));
+}
+
+#[test]
+fn memory_storage_with_crash_simulation_discards_unsynced_writes_on_restart() {
+    let mut storage = MemoryStorage::<u64>::with_crash_simulation();
+    storage.update_term(TermIndex(2));
+    storage.sync().unwrap();
+    // Crash before this vote is fsynced
+    storage.update_term(TermIndex(3)).record_vote(ServerId(1));
+
+    let restarted = storage.restart();
+    assert_eq!(restarted.current_term(), TermIndex(2));
+    assert_eq!(restarted.vote_for_current_term(), None);
+}
+
+#[test]
+fn memory_storage_persists_replaced_and_compacted_entries_on_sync() {
+    let mut storage = MemoryStorage::<u64>::with_crash_simulation();
+    storage.append(vec![entry(1, 1, 10), entry(2, 1, 20), entry(3, 1, 30)]);
+    storage.sync().unwrap();
+    // A new leader replaces the last two entries, then the first one is compacted
+    storage.append(vec![entry(2, 2, 21), entry(3, 2, 31), entry(4, 2, 41)]);
+    storage.compact(LogIndex(1));
+    storage.sync().unwrap();
+    // Syncing again with nothing pending changes nothing
+    storage.sync().unwrap();
+
+    let restarted = storage.restart();
+    assert_eq!(
+        restarted.compacted_through(),
+        Some((LogIndex(1), TermIndex(1)))
+    );
+    assert_eq!(
+        restarted.entries(LogIndex(1), 10).unwrap(),
+        vec![entry(2, 2, 21), entry(3, 2, 31), entry(4, 2, 41)]
+    );
+    assert_eq!(restarted.entry_term(LogIndex(3)), Some(TermIndex(2)));
+    assert_eq!(restarted.entry_term(LogIndex(5)), None);
+}
+
+#[test]
+fn memory_storage_keeps_the_last_compacted_entry_term() {
+    let mut storage = MemoryStorage::<u64>::with_crash_simulation();
+    storage.append(vec![entry(1, 1, 10), entry(2, 2, 20)]);
+    storage.compact(LogIndex(2));
+    storage.sync().unwrap();
+
+    let mut restarted = storage.restart();
+    assert_eq!(
+        restarted.compacted_through(),
+        Some((LogIndex(2), TermIndex(2)))
+    );
+    assert_eq!(restarted.last_entry_index(), Some(LogIndex(2)));
+    assert!(restarted.has_entry(LogIndex(2), TermIndex(2)));
+    assert_eq!(restarted.entry_term(LogIndex(1)), None);
+    // The leader resending compacted entries doesn't change anything
+    restarted.append(vec![entry(2, 2, 20), entry(3, 2, 30)]);
+    assert_eq!(
+        restarted.entries(LogIndex(1), 10).unwrap(),
+        vec![entry(3, 2, 30)]
+    );
+}
+
+#[test]
+fn default_storage_refuses_to_open_corrupted_election_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    storage.update_term(TermIndex(7)).record_vote(ServerId(2));
+    storage.sync().unwrap();
+    drop(storage);
+
+    let info = inspect_election_file(temp_dir.path()).unwrap();
+    assert_eq!(info.current_term, TermIndex(7));
+    assert_eq!(info.voted_for, Some((TermIndex(7), ServerId(2))));
+
+    // Flip a bit in the term, like a torn write would
+    let election_path = temp_dir.path().join("election");
+    let mut bytes = std::fs::read(&election_path).unwrap();
+    *bytes.last_mut().unwrap() ^= 1;
+    std::fs::write(&election_path, bytes).unwrap();
+
+    let error = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap_err();
+    assert_eq!(error.kind(), StorageErrorKind::Corruption);
+}
+
+#[test]
+fn default_storage_persists_log_and_replaces_conflicting_entries_on_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    storage.append(vec![entry(1, 1, 10), entry(2, 1, 20), entry(3, 1, 30)]);
+    storage.sync().unwrap();
+    // Conflicts with the entry at index 2, so it and everything after it is replaced
+    storage.append(vec![entry(2, 2, 21)]);
+    storage.sync().unwrap();
+    storage.append(vec![entry(3, 2, 31)]);
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    assert_eq!(storage.last_entry_index(), Some(LogIndex(3)));
+    assert_eq!(storage.last_entry_term(), Some(TermIndex(2)));
+    assert!(storage.has_entry(LogIndex(1), TermIndex(1)));
+    assert!(!storage.has_entry(LogIndex(2), TermIndex(1)));
+    assert_eq!(storage.entry_term(LogIndex(4)), None);
+    assert_eq!(
+        storage.entries(LogIndex(2), 10).unwrap(),
+        vec![entry(2, 2, 21), entry(3, 2, 31)]
+    );
+    assert_eq!(
+        storage.entries(LogIndex(1), 1).unwrap(),
+        vec![entry(1, 1, 10)]
+    );
+}
+
+#[test]
+fn default_storage_persists_noop_entries() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    storage.append(vec![entry(1, 1, 10), noop(2, 2), entry(3, 2, 30)]);
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    assert_eq!(
+        storage.entries(LogIndex(1), 10).unwrap(),
+        vec![entry(1, 1, 10), noop(2, 2), entry(3, 2, 30)]
+    );
+}
+
+#[test]
+fn default_storage_drops_unsynced_and_torn_entries_on_restart() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    storage.append(vec![entry(1, 1, 10), entry(2, 1, 20)]);
+    storage.sync().unwrap();
+    // Crash before this entry is fsynced
+    storage.append(vec![entry(3, 1, 30)]);
+    drop(storage);
+
+    // A crash in the middle of appending the record of entry 2 leaves only part of it behind
+    let log_path = temp_dir.path().join("log");
+    let bytes = std::fs::read(&log_path).unwrap();
+    std::fs::write(&log_path, &bytes[..bytes.len() - 3]).unwrap();
+
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    assert_eq!(storage.last_entry_index(), Some(LogIndex(1)));
+    storage.append(vec![entry(2, 2, 21)]);
+    storage.sync().unwrap();
+    drop(storage);
+
+    let storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    assert_eq!(
+        storage.entries(LogIndex(1), 10).unwrap(),
+        vec![entry(1, 1, 10), entry(2, 2, 21)]
+    );
+}
+
+#[test]
+fn default_storage_refuses_to_open_log_corrupted_before_its_last_entry() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    storage.append(vec![entry(1, 1, 10), entry(2, 1, 20)]);
+    storage.sync().unwrap();
+    drop(storage);
+
+    // Flip a bit in the command of the first entry, dropping it and the committed entry after it would lose data
+    let log_path = temp_dir.path().join("log");
+    let mut bytes = std::fs::read(&log_path).unwrap();
+    // File header (6 bytes), then the record of entry 1: record header (12 bytes) + record kind (4 bytes) + index,
+    // term and command (24 bytes)
+    let first_record_end = 6 + 12 + 4 + 24;
+    bytes[first_record_end - 1] ^= 1;
+    std::fs::write(&log_path, bytes).unwrap();
+
+    let error = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap_err();
+    assert_eq!(error.kind(), StorageErrorKind::Corruption);
+}
+
+#[test]
+fn default_storage_refuses_to_open_log_with_a_corrupted_record_length() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    storage.append(vec![entry(1, 1, 10), entry(2, 1, 20), entry(3, 1, 30)]);
+    storage.sync().unwrap();
+    drop(storage);
+
+    // Flip a high bit in the length of entry 2's record so it seems to run past the end of the file, like the torn
+    // last record of a crash would
+    let log_path = temp_dir.path().join("log");
+    let mut bytes = std::fs::read(&log_path).unwrap();
+    let second_record_start = 6 + 12 + 4 + 24;
+    bytes[second_record_start + 3] ^= 0x80;
+    std::fs::write(&log_path, bytes).unwrap();
+
+    let error = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap_err();
+    assert_eq!(error.kind(), StorageErrorKind::Corruption);
+    // The node must not have cut the committed entries off the file either
+    let error = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap_err();
+    assert_eq!(error.kind(), StorageErrorKind::Corruption);
+}
+
+#[test]
+fn default_storage_with_hard_state_in_log_persists_vote_with_entries() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage =
+        DefaultPersistentStorage::<u64>::with_hard_state_in_log(temp_dir.path()).unwrap();
+    storage
+        .update_term(TermIndex(2))
+        .record_vote(ServerId(1))
+        .append(vec![entry(1, 1, 10), entry(2, 2, 20)]);
+    storage.sync().unwrap();
+    // Conflicts with the entry at index 2 in a later term
+    storage
+        .update_term(TermIndex(3))
+        .append(vec![entry(2, 3, 21)]);
+    storage.sync().unwrap();
+    drop(storage);
+
+    // Everything went to the log file
+    assert!(!temp_dir.path().join("election").exists());
+    let storage = DefaultPersistentStorage::<u64>::with_hard_state_in_log(temp_dir.path()).unwrap();
+    assert_eq!(storage.current_term(), TermIndex(3));
+    assert_eq!(storage.vote_for_current_term(), None);
+    assert_eq!(
+        storage.entries(LogIndex(1), 10).unwrap(),
+        vec![entry(1, 1, 10), entry(2, 3, 21)]
+    );
+}
+
+#[test]
+fn default_storage_with_hard_state_in_log_drops_torn_vote_and_entries_together() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage =
+        DefaultPersistentStorage::<u64>::with_hard_state_in_log(temp_dir.path()).unwrap();
+    storage
+        .update_term(TermIndex(1))
+        .append(vec![entry(1, 1, 10)]);
+    storage.sync().unwrap();
+    let synced_len = std::fs::metadata(temp_dir.path().join("log"))
+        .unwrap()
+        .len();
+    storage
+        .update_term(TermIndex(2))
+        .record_vote(ServerId(3))
+        .append(vec![entry(2, 2, 20)]);
+    storage.sync().unwrap();
+    drop(storage);
+
+    // A crash in the middle of the single write leaves only part of the vote and the entry behind
+    let log_path = temp_dir.path().join("log");
+    let bytes = std::fs::read(&log_path).unwrap();
+    std::fs::write(&log_path, &bytes[..synced_len as usize + 10]).unwrap();
+
+    let storage = DefaultPersistentStorage::<u64>::with_hard_state_in_log(temp_dir.path()).unwrap();
+    assert_eq!(storage.current_term(), TermIndex(1));
+    assert_eq!(storage.vote_for_current_term(), None);
+    assert_eq!(storage.last_entry_index(), Some(LogIndex(1)));
+}
+
+#[test]
+fn default_storage_opens_with_the_newer_hard_state_after_switching_modes() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    storage.update_term(TermIndex(4)).record_vote(ServerId(2));
+    storage.sync().unwrap();
+    drop(storage);
+
+    // The log has no hard state yet, so the election file is used
+    let mut storage =
+        DefaultPersistentStorage::<u64>::with_hard_state_in_log(temp_dir.path()).unwrap();
+    assert_eq!(storage.current_term(), TermIndex(4));
+    assert_eq!(storage.vote_for_current_term(), Some(ServerId(2)));
+    storage.update_term(TermIndex(5));
+    storage.sync().unwrap();
+    drop(storage);
+
+    // The election file is older than the hard state in the log now
+    let storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    assert_eq!(storage.current_term(), TermIndex(5));
+    assert_eq!(storage.vote_for_current_term(), None);
+}
+
+#[test]
+fn default_storage_rewrites_the_log_file_without_compacted_entries() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    storage
+        .update_term(TermIndex(2))
+        .record_vote(ServerId(1))
+        .append((1..=20).map(|index| entry(index, 1, index * 10)).collect());
+    storage.sync().unwrap();
+    let log_len = std::fs::metadata(temp_dir.path().join("log"))
+        .unwrap()
+        .len();
+    // Appended before the compaction is synced, written to the new log file with the other entries
+    storage
+        .compact(LogIndex(19))
+        .append(vec![entry(21, 2, 210)]);
+    storage.sync().unwrap();
+    storage.append(vec![entry(22, 2, 220)]);
+    storage.sync().unwrap();
+    drop(storage);
+
+    assert!(
+        std::fs::metadata(temp_dir.path().join("log"))
+            .unwrap()
+            .len()
+            < log_len
+    );
+    let storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    assert_eq!(
+        storage.compacted_through(),
+        Some((LogIndex(19), TermIndex(1)))
+    );
+    assert_eq!(storage.current_term(), TermIndex(2));
+    assert_eq!(storage.vote_for_current_term(), Some(ServerId(1)));
+    assert_eq!(
+        storage.entries(LogIndex(1), 10).unwrap(),
+        vec![entry(20, 1, 200), entry(21, 2, 210), entry(22, 2, 220)]
+    );
+}
+
+#[cfg(feature = "redb_storage")]
+mod redb_storage {
+    use raft_consensus::{
+        inspect_redb_storage, truncate_redb_log_after, LogEntry, LogIndex, PersistentStorage,
+        RedbPersistentStorage, ServerId, StorageErrorKind, TermIndex,
+    };
+    use tempfile::TempDir;
+
+    fn entry(index: u64, term: u64, command: u64) -> LogEntry<u64> {
+        LogEntry {
+            index: LogIndex(index),
+            term: TermIndex(term),
+            command: Some(command),
+        }
+    }
+
+    fn noop(index: u64, term: u64) -> LogEntry<u64> {
+        LogEntry {
+            index: LogIndex(index),
+            term: TermIndex(term),
+            command: None,
+        }
+    }
+
+    #[test]
+    fn redb_storage_persists_hard_state_and_truncated_log_on_sync() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("raft.redb");
+
+        let mut storage = RedbPersistentStorage::<u64>::new(&path).unwrap();
+        storage
+            .update_term(TermIndex(2))
+            .record_vote(ServerId(1))
+            .append(vec![entry(1, 1, 10), entry(2, 1, 20), entry(3, 1, 30)]);
+        storage.sync().unwrap();
+        // Conflicts with the entry at index 2, so it and everything after it is replaced
+        storage.append(vec![entry(2, 2, 21)]);
+        storage.sync().unwrap();
+        // Never synced, should be lost when the storage is reopened
+        storage.update_term(TermIndex(3));
+        drop(storage);
+
+        let reopened = RedbPersistentStorage::<u64>::new(&path).unwrap();
+        assert_eq!(reopened.current_term(), TermIndex(2));
+        assert_eq!(reopened.vote_for_current_term(), Some(ServerId(1)));
+        assert_eq!(reopened.last_entry_index(), Some(LogIndex(2)));
+        assert_eq!(reopened.entry(LogIndex(2)).unwrap(), Some(entry(2, 2, 21)));
+        assert!(reopened.has_entry(LogIndex(1), TermIndex(1)));
+    }
+
+    #[test]
+    fn redb_storage_persists_noop_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("raft.redb");
+
+        let mut storage = RedbPersistentStorage::<u64>::new(&path).unwrap();
+        storage.append(vec![entry(1, 1, 10), noop(2, 2)]);
+        storage.sync().unwrap();
+        drop(storage);
+
+        let reopened = RedbPersistentStorage::<u64>::new(&path).unwrap();
+        assert_eq!(reopened.last_entry_term(), Some(TermIndex(2)));
+        assert_eq!(reopened.entry(LogIndex(2)).unwrap(), Some(noop(2, 2)));
+    }
+
+    #[test]
+    fn redb_storage_deletes_compacted_entries_on_sync() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("raft.redb");
+        let mut storage = RedbPersistentStorage::<u64>::new(&path).unwrap();
+        storage.append(vec![entry(1, 1, 10), entry(2, 1, 20), entry(3, 2, 30)]);
+        storage.sync().unwrap();
+        storage.compact(LogIndex(2)).append(vec![entry(4, 2, 40)]);
+        storage.sync().unwrap();
+        drop(storage);
+
+        let info = inspect_redb_storage(&path).unwrap();
+        assert_eq!(
+            info.log.iter().map(|entry| entry.index).collect::<Vec<_>>(),
+            vec![LogIndex(3), LogIndex(4)]
+        );
+        let reopened = RedbPersistentStorage::<u64>::new(&path).unwrap();
+        assert_eq!(
+            reopened.compacted_through(),
+            Some((LogIndex(2), TermIndex(1)))
+        );
+        assert!(reopened.has_entry(LogIndex(2), TermIndex(1)));
+        assert_eq!(
+            reopened.entries(LogIndex(1), 10).unwrap(),
+            vec![entry(3, 2, 30), entry(4, 2, 40)]
+        );
+    }
+
+    #[test]
+    fn redb_storage_reads_synced_and_unsynced_entries_in_order() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut storage =
+            RedbPersistentStorage::<u64>::new(&temp_dir.path().join("raft.redb")).unwrap();
+        storage.append(vec![entry(1, 1, 10), entry(2, 1, 20), entry(3, 1, 30)]);
+        storage.sync().unwrap();
+        // Replaces the synced entries 2 and 3 before it is synced itself
+        storage.append(vec![entry(2, 2, 21)]);
+        assert_eq!(
+            storage.entries(LogIndex(1), 10).unwrap(),
+            vec![entry(1, 1, 10), entry(2, 2, 21)]
+        );
+        storage.append(vec![entry(3, 2, 31), entry(4, 2, 41)]);
+        assert_eq!(
+            storage.entries(LogIndex(2), 2).unwrap(),
+            vec![entry(2, 2, 21), entry(3, 2, 31)]
+        );
+        assert_eq!(storage.entries(LogIndex(5), 10).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn inspect_redb_storage_reads_terms_and_sizes_without_decoding_commands() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("raft.redb");
+        let mut storage = RedbPersistentStorage::<String>::new(&path).unwrap();
+        storage
+            .update_term(TermIndex(3))
+            .record_vote(ServerId(2))
+            .append(vec![
+                LogEntry {
+                    index: LogIndex(1),
+                    term: TermIndex(1),
+                    command: Some("a".to_string()),
+                },
+                LogEntry {
+                    index: LogIndex(2),
+                    term: TermIndex(3),
+                    command: Some("longer".to_string()),
+                },
+            ]);
+        storage.sync().unwrap();
+        drop(storage);
+
+        let info = inspect_redb_storage(&path).unwrap();
+        assert_eq!(info.current_term, TermIndex(3));
+        assert_eq!(info.voted_for, Some((TermIndex(3), ServerId(2))));
+        assert_eq!(info.snapshot_metadata, None);
+        let log: Vec<_> = info
+            .log
+            .iter()
+            .map(|entry| (entry.index, entry.term))
+            .collect();
+        assert_eq!(
+            log,
+            vec![(LogIndex(1), TermIndex(1)), (LogIndex(2), TermIndex(3))]
+        );
+        // Term and string length are 8 bytes each
+        assert_eq!(info.log[0].size, 8 + 8 + 1);
+        assert_eq!(info.log[1].size, 8 + 8 + 6);
+    }
+
+    #[test]
+    fn inspect_redb_storage_reports_undecodable_entries_as_corruption() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("raft.redb");
+        drop(RedbPersistentStorage::<u64>::new(&path).unwrap());
+
+        // Too short to hold a term
+        let database = redb::Database::open(&path).unwrap();
+        let txn = database.begin_write().unwrap();
+        {
+            let mut log_table = txn
+                .open_table(redb::TableDefinition::<u64, &[u8]>::new("raft_log"))
+                .unwrap();
+            let _ = log_table.insert(1, [1u8, 2, 3].as_slice()).unwrap();
+        }
+        txn.commit().unwrap();
+        drop(database);
+
+        let error = inspect_redb_storage(&path).unwrap_err();
+        assert_eq!(error.kind(), StorageErrorKind::Corruption);
+    }
+
+    #[test]
+    fn truncate_redb_log_after_removes_later_entries_only() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("raft.redb");
+        let mut storage = RedbPersistentStorage::<u64>::new(&path).unwrap();
+        storage.append(vec![entry(1, 1, 10), entry(2, 1, 20), entry(3, 2, 30)]);
+        storage.sync().unwrap();
+        drop(storage);
+
+        assert_eq!(truncate_redb_log_after(&path, LogIndex(1)).unwrap(), 2);
+        assert_eq!(truncate_redb_log_after(&path, LogIndex(1)).unwrap(), 0);
+
+        let reopened = RedbPersistentStorage::<u64>::new(&path).unwrap();
+        assert_eq!(reopened.last_entry_index(), Some(LogIndex(1)));
+        assert_eq!(reopened.entry(LogIndex(1)).unwrap(), Some(entry(1, 1, 10)));
+    }
+}
@@ -0,0 +1,200 @@
+//! Tests for the `raft_wal_tool` binary, run against directories written by `DefaultPersistentStorage`
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+use raft_consensus::{
+    DefaultPersistentStorage, LogEntry, LogIndex, PersistentStorage, ServerId, TermIndex,
+};
+use tempfile::TempDir;
+
+fn run_tool(wal_log_dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_raft_wal_tool"))
+        .arg("--wal-log-dir")
+        .arg(wal_log_dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+/// Writes a log with entries 1 to `last_index`, the term goes up every 10 entries
+fn write_log(storage: &mut DefaultPersistentStorage<u64>, last_index: u64) {
+    let entries = (1..=last_index)
+        .map(|index| LogEntry {
+            index: LogIndex(index),
+            term: TermIndex(index / 10 + 1),
+            command: Some(index * 100),
+        })
+        .collect();
+    storage
+        .update_term(TermIndex(last_index / 10 + 1))
+        .append(entries);
+    storage.sync().unwrap();
+}
+
+fn read_dir_files(wal_log_dir: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut files: Vec<_> = std::fs::read_dir(wal_log_dir)
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (
+                entry.file_name().into_string().unwrap(),
+                std::fs::read(entry.path()).unwrap(),
+            )
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn should_fail_without_a_log_file() {
+    let temp_dir = TempDir::new().unwrap();
+
+    let output = run_tool(temp_dir.path(), &["dump"]);
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn should_dump_and_verify_a_valid_log_without_modifying_it() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    write_log(&mut storage, 25);
+    drop(storage);
+    // A torn record the node would cut off when it starts
+    let log_path = temp_dir.path().join("log");
+    let mut bytes = std::fs::read(&log_path).unwrap();
+    bytes.extend_from_slice(&[1, 2, 3]);
+    std::fs::write(&log_path, bytes).unwrap();
+    let files = read_dir_files(temp_dir.path());
+
+    let dump = run_tool(temp_dir.path(), &["dump"]);
+    assert!(dump.status.success());
+    assert!(stdout(&dump).contains("Current term:   3"));
+    assert!(stdout(&dump).contains("25 entries, 1 (term 1) to 25 (term 3)"));
+    assert!(stdout(&dump).contains("Torn record:    3 bytes"));
+
+    let verify = run_tool(temp_dir.path(), &["verify"]);
+    assert!(verify.status.success());
+    assert!(stdout(&verify).contains("OK"));
+
+    assert_eq!(read_dir_files(temp_dir.path()), files);
+}
+
+#[test]
+fn should_read_the_hard_state_from_the_log_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage =
+        DefaultPersistentStorage::<u64>::with_hard_state_in_log(temp_dir.path()).unwrap();
+    write_log(&mut storage, 3);
+    storage.record_vote(ServerId(2));
+    storage.sync().unwrap();
+    drop(storage);
+
+    let dump = run_tool(temp_dir.path(), &["dump"]);
+    assert!(dump.status.success());
+    assert!(stdout(&dump).contains("Election file: none"));
+    assert!(stdout(&dump).contains("Voted for:      2 in term 1"));
+}
+
+#[test]
+fn should_list_every_log_record() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage =
+        DefaultPersistentStorage::<u64>::with_hard_state_in_log(temp_dir.path()).unwrap();
+    write_log(&mut storage, 12);
+    drop(storage);
+
+    let output = run_tool(temp_dir.path(), &["list"]);
+
+    assert!(output.status.success());
+    let lines: Vec<_> = stdout(&output)
+        .lines()
+        .skip(1)
+        .map(|line| {
+            line.split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    // The hard state is written before the entries synced with it
+    assert_eq!(lines.len(), 13);
+    assert_eq!(lines[0][0], "6");
+    assert_eq!(lines[0][3..], ["-", "term", "2"]);
+    // Record header, record kind, index, term, the tag of the optional command and the command
+    assert_eq!(lines[12][1], (12 + 4 + 8 + 8 + 1 + 8).to_string());
+    assert_eq!(lines[12][3..], ["12", "entry", "in", "term", "2"]);
+}
+
+#[test]
+fn should_fail_to_verify_a_record_with_a_bad_checksum() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    write_log(&mut storage, 5);
+    drop(storage);
+
+    // Flip a bit in the command of the first entry
+    let log_path = temp_dir.path().join("log");
+    let mut bytes = std::fs::read(&log_path).unwrap();
+    bytes[6 + 12 + 4 + 24 - 1] ^= 1;
+    std::fs::write(&log_path, bytes).unwrap();
+
+    let verify = run_tool(temp_dir.path(), &["verify"]);
+    assert!(!verify.status.success());
+    assert!(String::from_utf8(verify.stderr)
+        .unwrap()
+        .contains("Log record at offset 6 has checksum"));
+}
+
+#[test]
+fn should_truncate_the_log_and_keep_the_hard_state() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage =
+        DefaultPersistentStorage::<u64>::with_hard_state_in_log(temp_dir.path()).unwrap();
+    write_log(&mut storage, 10);
+    storage.record_vote(ServerId(3));
+    storage.sync().unwrap();
+    drop(storage);
+
+    let truncated = run_tool(temp_dir.path(), &["truncate", "--after", "7"]);
+    assert!(truncated.status.success());
+    assert!(stdout(&truncated).contains("Removed 3 entries"));
+
+    let storage = DefaultPersistentStorage::<u64>::with_hard_state_in_log(temp_dir.path()).unwrap();
+    assert_eq!(storage.last_entry_index(), Some(LogIndex(7)));
+    assert_eq!(
+        storage.entries(LogIndex(7), 1).unwrap()[0].command,
+        Some(700)
+    );
+    assert_eq!(storage.current_term(), TermIndex(2));
+    assert_eq!(storage.vote_for_current_term(), Some(ServerId(3)));
+}
+
+#[test]
+fn should_show_where_the_log_was_compacted() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    write_log(&mut storage, 12);
+    storage.compact(LogIndex(10));
+    storage.sync().unwrap();
+    drop(storage);
+
+    let dump = run_tool(temp_dir.path(), &["dump"]);
+    assert!(dump.status.success());
+    assert!(stdout(&dump).contains("2 entries, 11 (term 2) to 12 (term 2)"));
+    assert!(stdout(&dump).contains("Compacted:      entries up to 10 (term 2) were dropped"));
+
+    let truncated = run_tool(temp_dir.path(), &["truncate", "--after", "11"]);
+    assert!(truncated.status.success());
+    let storage = DefaultPersistentStorage::<u64>::new(temp_dir.path()).unwrap();
+    assert_eq!(
+        storage.compacted_through(),
+        Some((LogIndex(10), TermIndex(2)))
+    );
+    assert_eq!(storage.last_entry_index(), Some(LogIndex(11)));
+}
@@ -0,0 +1,74 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+
+/// Error returned when an application command cannot be encoded to/decoded from the bytes carried
+/// in `ApplicationCommand.serialized`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecError(pub String);
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Command codec error: {}", self.0)
+    }
+}
+impl std::error::Error for CodecError {}
+
+/// Converts application commands to and from the opaque bytes that are sent over the wire in
+/// `ApplicationCommand.serialized`. The Raft consensus module is generic over the command type, so the
+/// gRPC transport needs to be told how to serialize whatever command type the application uses.
+///
+/// The codec is only used at the type level, implementations should not need any state.
+pub trait CommandCodec<C>: Send + Sync + 'static {
+    /// Serializes a command so it can be carried in an AppendEntries request.
+    fn encode(command: &C) -> Result<Vec<u8>, CodecError>;
+    /// Deserializes a command received in an AppendEntries request.
+    fn decode(serialized: &[u8]) -> Result<C, CodecError>;
+}
+
+/// Default codec, serializes commands with serde/bincode so any command type that implements
+/// `Serialize` and `Deserialize` can be used with the gRPC transport.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+impl<C: Serialize + DeserializeOwned> CommandCodec<C> for BincodeCodec {
+    fn encode(command: &C) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(command).map_err(|e| CodecError(format!("{:?}", e)))
+    }
+
+    fn decode(serialized: &[u8]) -> Result<C, CodecError> {
+        bincode::deserialize(serialized).map_err(|e| CodecError(format!("{:?}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BincodeCodec, CommandCodec};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    struct SetValue {
+        key: String,
+        value: u64,
+    }
+
+    #[test]
+    fn bincode_codec_should_round_trip_struct_commands() {
+        let command = SetValue {
+            key: "answer".to_string(),
+            value: 42,
+        };
+
+        let serialized = BincodeCodec::encode(&command).unwrap();
+        let decoded: SetValue = BincodeCodec::decode(&serialized).unwrap();
+
+        assert_eq!(command, decoded);
+    }
+
+    #[test]
+    fn bincode_codec_should_reject_truncated_commands() {
+        let serialized = <BincodeCodec as CommandCodec<u64>>::encode(&12345).unwrap();
+
+        let decoded = <BincodeCodec as CommandCodec<u64>>::decode(&serialized[..4]);
+
+        assert!(decoded.is_err());
+    }
+}
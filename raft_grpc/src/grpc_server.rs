@@ -1,30 +1,50 @@
+use crate::codec::{BincodeCodec, CommandCodec};
 use crate::grpc_transport::TransportMessage;
 use crate::proto::raft_consensus_server::RaftConsensus;
-use crate::proto::{AppendEntriesRequest, AppendEntriesResponse, VoteRequest, VoteResponse};
+use crate::proto::{
+    AppendEntriesRequest, AppendEntriesResponse, GetStatusRequest, NodeStatus, TimeoutNowRequest,
+    TimeoutNowResponse, VoteRequest, VoteResponse,
+};
+use crate::tracing_context::set_parent_from_request;
 use raft_consensus::rpc_messages;
+use raft_consensus::LogCommand;
+use raft_consensus::RaftNodeStatusReader;
+use std::marker::PhantomData;
 use std::thread;
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::{mpsc, oneshot};
 use tonic::{Request, Response, Status};
+use tracing::{info_span, Span};
 
 /// Raft gRPC server implementation. Uses the RaftTransportBridge to send incoming requests to the
 /// Raft thrad and to receive outgoing requests from the Raft thread.
+/// Application commands in AppendEntries requests are decoded with `Codec`.
 #[derive(Debug)]
-pub struct RaftGrpcServerImpl {
-    raft_input_tx: mpsc::UnboundedSender<TransportMessage>,
-    maybe_transport_thread_handle: Option<thread::JoinHandle<()>>,
+pub struct RaftGrpcServerImpl<C: LogCommand, Codec: CommandCodec<C> = BincodeCodec> {
+    raft_input_tx: mpsc::UnboundedSender<TransportMessage<C>>,
+    maybe_transport_thread: Option<thread::Thread>,
+    maybe_status_reader: Option<RaftNodeStatusReader>,
+    /// Codec is only used at the type level to decode incoming application commands
+    _codec: PhantomData<fn() -> Codec>,
 }
 
-impl RaftGrpcServerImpl {
-    pub fn new(raft_input_tx: mpsc::UnboundedSender<TransportMessage>) -> RaftGrpcServerImpl {
+impl<C: LogCommand, Codec: CommandCodec<C>> RaftGrpcServerImpl<C, Codec> {
+    pub fn new(raft_input_tx: mpsc::UnboundedSender<TransportMessage<C>>) -> Self {
         RaftGrpcServerImpl {
             raft_input_tx,
-            maybe_transport_thread_handle: None,
+            maybe_transport_thread: None,
+            maybe_status_reader: None,
+            _codec: PhantomData,
         }
     }
 
-    pub fn register_raft_thread(&mut self, transport_thread_handle: thread::JoinHandle<()>) {
-        self.maybe_transport_thread_handle = Some(transport_thread_handle);
+    pub fn register_raft_thread(&mut self, transport_thread: thread::Thread) {
+        self.maybe_transport_thread = Some(transport_thread);
+    }
+
+    /// Status reader used to answer GetStatus requests, see `RaftNodeHandle::status_reader()`
+    pub fn register_status_reader(&mut self, status_reader: RaftNodeStatusReader) {
+        self.maybe_status_reader = Some(status_reader);
     }
 
     /// Send an incoming request to the Raft thread's message queue for processing
@@ -36,31 +56,44 @@ impl RaftGrpcServerImpl {
     fn send_incoming_request_to_transport(
         &self,
         reply_tx: oneshot::Sender<rpc_messages::ReplyTo>,
-        incoming_request: rpc_messages::Request<u64>,
-    ) -> Result<(), SendError<TransportMessage>> {
+        incoming_request: rpc_messages::Request<C>,
+        span: Span,
+    ) -> Result<(), SendError<TransportMessage<C>>> {
         self.raft_input_tx
-            .send(TransportMessage::Request(reply_tx, incoming_request))?;
-        self.maybe_transport_thread_handle
+            .send(TransportMessage::Request(reply_tx, incoming_request, span))?;
+        self.maybe_transport_thread
             .as_ref()
             .expect("GRPC BUG ALERT: Transport thread not registered!")
-            .thread()
             .unpark();
         Ok(())
     }
 }
 
+/// Span the raft thread handles an incoming request in, a child of the sender's span if it propagated one
+fn handle_rpc_span<T>(rpc: &'static str, request: &Request<T>) -> Span {
+    let span = info_span!("handle_rpc", rpc);
+    set_parent_from_request(&span, request);
+    span
+}
+
 #[tonic::async_trait]
-impl RaftConsensus for RaftGrpcServerImpl {
+impl<C, Codec> RaftConsensus for RaftGrpcServerImpl<C, Codec>
+where
+    C: LogCommand + Sync + 'static,
+    Codec: CommandCodec<C>,
+{
     async fn request_vote(
         &self,
         request: Request<VoteRequest>,
     ) -> Result<Response<VoteResponse>, Status> {
-        let vote_req = request.into_inner();
+        let span = handle_rpc_span("request_vote", &request);
+        let vote_req = request.into_inner().try_into()?;
 
         let (reply_tx, reply_rx) = oneshot::channel();
         if let Err(_) = self.send_incoming_request_to_transport(
             reply_tx,
-            rpc_messages::Request::RequestVote(vote_req.into()),
+            rpc_messages::Request::RequestVote(vote_req),
+            span,
         ) {
             return Err(Status::internal("Raft state machine shutdown!"));
         }
@@ -78,12 +111,14 @@ impl RaftConsensus for RaftGrpcServerImpl {
         &self,
         request: Request<AppendEntriesRequest>,
     ) -> Result<Response<AppendEntriesResponse>, Status> {
-        let append_entries_req = request.into_inner();
+        let span = handle_rpc_span("append_entries", &request);
+        let append_entries_req = request.into_inner().into_rpc_message::<C, Codec>()?;
 
         let (reply_tx, reply_rx) = oneshot::channel();
         if let Err(_) = self.send_incoming_request_to_transport(
             reply_tx,
-            rpc_messages::Request::AppendEntries(append_entries_req.into()),
+            rpc_messages::Request::AppendEntries(append_entries_req),
+            span,
         ) {
             return Err(Status::internal("Raft state machine shutdown!"));
         }
@@ -98,4 +133,41 @@ impl RaftConsensus for RaftGrpcServerImpl {
             _ => unreachable!("BUG ALERT: Unexpected response type, expected AppendEntries!"),
         }
     }
+    async fn timeout_now(
+        &self,
+        request: Request<TimeoutNowRequest>,
+    ) -> Result<Response<TimeoutNowResponse>, Status> {
+        let span = handle_rpc_span("timeout_now", &request);
+        let timeout_now_req = request.into_inner().try_into()?;
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if let Err(_) = self.send_incoming_request_to_transport(
+            reply_tx,
+            rpc_messages::Request::TimeoutNow(timeout_now_req),
+            span,
+        ) {
+            return Err(Status::internal("Raft state machine shutdown!"));
+        }
+
+        let timeout_now_response = reply_rx.await;
+
+        match timeout_now_response {
+            Ok(rpc_messages::ReplyTo::TimeoutNow(timeout_now)) => {
+                Ok(Response::new(timeout_now.into()))
+            }
+            Err(_) => Err(Status::internal("Raft state machine shutdown!")),
+            _ => unreachable!("BUG ALERT: Unexpected response type, expected TimeoutNow!"),
+        }
+    }
+    /// Answered from the status last published by the raft thread, so this does not wait for the raft thread to
+    /// wake up and works even if it is stuck
+    async fn get_status(
+        &self,
+        _request: Request<GetStatusRequest>,
+    ) -> Result<Response<NodeStatus>, Status> {
+        match &self.maybe_status_reader {
+            Some(status_reader) => Ok(Response::new(status_reader.status().into())),
+            None => Err(Status::unavailable("Raft node has not started yet!")),
+        }
+    }
 }
@@ -1,9 +1,12 @@
+use crate::codec::{BincodeCodec, CommandCodec};
 use crate::grpc_server::RaftGrpcServerImpl;
 use crate::proto;
 use crate::proto::raft_consensus_client::RaftConsensusClient;
+use crate::tracing_context::request_in_span;
 pub use raft_consensus::rpc_messages;
 use raft_consensus::rpc_messages::RpcMessage;
 use raft_consensus::system_clock;
+use raft_consensus::LogCommand;
 use raft_consensus::RaftTransportError;
 use raft_consensus::ServerId;
 use tonic::transport::Channel;
@@ -11,55 +14,73 @@ use tonic::transport::Channel;
 use raft_consensus::RaftTransportConnector;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use tracing::{info, trace};
+use std::time::Duration;
+use tracing::{debug, info, info_span, trace, Span};
 use uuid::Uuid;
 
-use tonic::{Request, Status};
+use tonic::Status;
 
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 
+/// Requests waiting to be sent to a peer, more are dropped until the peer answers or its requests time out
+const PEER_QUEUE_LEN: usize = 256;
+/// A peer that takes longer than this to answer is treated as unreachable for that request
+const RAFT_RPC_TIMEOUT: Duration = Duration::from_millis(500);
+const RAFT_CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Messages for the raft thread, each with the span the raft thread should handle it in
 #[derive(Debug)]
-pub enum TransportMessage {
+pub enum TransportMessage<C: LogCommand> {
     Request(
         oneshot::Sender<rpc_messages::ReplyTo>,
-        rpc_messages::Request<u64>,
+        rpc_messages::Request<C>,
+        Span,
     ),
-    Reply(rpc_messages::ReplyTo),
+    Reply(rpc_messages::ReplyTo, Span),
 }
 
 #[derive(Debug)]
-pub struct RaftGrpcTransportConnector {
-    raft_input_rx: mpsc::UnboundedReceiver<TransportMessage>,
-    raft_output_tx: mpsc::UnboundedSender<rpc_messages::Request<u64>>,
+pub struct RaftGrpcTransportConnector<C: LogCommand> {
+    raft_input_rx: mpsc::UnboundedReceiver<TransportMessage<C>>,
+    raft_output_tx: mpsc::UnboundedSender<(Span, rpc_messages::Request<C>)>,
     thread_handle: Option<thread::Thread>,
+    /// Shared with the message sender, which unparks the raft thread when a reply to one of its requests arrives
+    raft_thread: Arc<Mutex<Option<thread::Thread>>>,
     reply_channels: HashMap<Uuid, oneshot::Sender<rpc_messages::ReplyTo>>,
+    incoming_message_span: Option<Span>,
 }
-impl RaftGrpcTransportConnector {
+impl<C: LogCommand> RaftGrpcTransportConnector<C> {
     pub fn new(
-        raft_input_rx: mpsc::UnboundedReceiver<TransportMessage>,
-        raft_output_tx: mpsc::UnboundedSender<rpc_messages::Request<u64>>,
-    ) -> RaftGrpcTransportConnector {
+        raft_input_rx: mpsc::UnboundedReceiver<TransportMessage<C>>,
+        raft_output_tx: mpsc::UnboundedSender<(Span, rpc_messages::Request<C>)>,
+    ) -> RaftGrpcTransportConnector<C> {
         RaftGrpcTransportConnector {
             raft_input_rx,
             raft_output_tx,
             thread_handle: None,
+            raft_thread: Arc::new(Mutex::new(None)),
             reply_channels: HashMap::new(),
+            incoming_message_span: None,
         }
     }
 }
 
-impl RaftTransportConnector<u64> for RaftGrpcTransportConnector {
+impl<C: LogCommand> RaftTransportConnector<C> for RaftGrpcTransportConnector<C> {
     /// Raft thread calls this method to retrieve the next message to process
     /// This method blocks the Raft thread until a message is available or the max_wait time has elapsed
     /// If the max_wait time has elapsed without receiving a message, this method returns None
     fn wait_for_next_incoming_message(
         &mut self,
         max_wait: std::time::Duration,
-    ) -> Result<Option<RpcMessage<u64>>, RaftTransportError> {
+    ) -> Result<Option<RpcMessage<C>>, RaftTransportError> {
         let current_thread = thread::current();
         let current_thread_id = current_thread.id();
+        if self.thread_handle.is_none() {
+            *self.raft_thread.lock().unwrap() = Some(current_thread.clone());
+        }
         let saved_handle = self.thread_handle.get_or_insert(current_thread);
 
         assert_eq!(
@@ -69,22 +90,27 @@ impl RaftTransportConnector<u64> for RaftGrpcTransportConnector {
         );
 
         let started_waiting_at = system_clock::now();
+        let mut parked = false;
 
         loop {
             match self.raft_input_rx.try_recv() {
-                Ok(TransportMessage::Request(reply_tx, message)) => {
+                Ok(TransportMessage::Request(reply_tx, message, span)) => {
                     self.reply_channels.insert(message.request_id(), reply_tx);
+                    self.incoming_message_span = Some(span);
                     break Ok(Some(RpcMessage::Request(message)));
                 }
-                Ok(TransportMessage::Reply(reply)) => {
+                Ok(TransportMessage::Reply(reply, span)) => {
+                    self.incoming_message_span = Some(span);
                     break Ok(Some(RpcMessage::Reply(reply)));
                 }
                 Err(mpsc::error::TryRecvError::Empty) => {
+                    // Once unparked with no message, return so the raft thread can check for proposals
                     let time_waited = system_clock::now() - started_waiting_at;
-                    if time_waited >= max_wait {
+                    if parked || time_waited >= max_wait {
                         break Ok(None);
                     }
                     thread::park_timeout(max_wait - time_waited);
+                    parked = true;
                 }
                 Err(mpsc::error::TryRecvError::Disconnected) => {
                     break Err(RaftTransportError::TransportShutdown);
@@ -107,109 +133,204 @@ impl RaftTransportConnector<u64> for RaftGrpcTransportConnector {
 
     fn enqueue_outgoing_request(
         &mut self,
-        request: rpc_messages::Request<u64>,
+        request: rpc_messages::Request<C>,
     ) -> Result<(), RaftTransportError> {
-        match self.raft_output_tx.send(request) {
+        // The raft thread enqueues requests inside their send_rpc span
+        match self.raft_output_tx.send((Span::current(), request)) {
             Ok(_) => Ok(()),
             Err(_) => Err(RaftTransportError::TransportShutdown),
         }
     }
+
+    fn take_incoming_message_span(&mut self) -> Option<Span> {
+        self.incoming_message_span.take()
+    }
 }
 
-async fn start_outgoing_message_sender(
-    mut server_grpc_clients: HashMap<ServerId, RaftConsensusClient<Channel>>,
-    raft_input_tx: mpsc::UnboundedSender<TransportMessage>,
-    mut raft_output_rx: mpsc::UnboundedReceiver<rpc_messages::Request<u64>>,
-) -> tokio::task::JoinHandle<()> {
+/// Sends the requests the raft thread enqueues, each peer has its own task so a peer that is slow or not answering
+/// only delays the requests to it. The task stops once the raft thread has dropped its connector and the requests
+/// queued before that have been sent.
+async fn start_outgoing_message_sender<C, Codec>(
+    server_grpc_clients: HashMap<ServerId, RaftConsensusClient<Channel>>,
+    raft_input_tx: mpsc::UnboundedSender<TransportMessage<C>>,
+    raft_thread: Arc<Mutex<Option<thread::Thread>>>,
+    mut raft_output_rx: mpsc::UnboundedReceiver<(Span, rpc_messages::Request<C>)>,
+) -> tokio::task::JoinHandle<()>
+where
+    C: LogCommand + 'static,
+    Codec: CommandCodec<C>,
+{
     tokio::spawn(async move {
         info!("Starting gRPC transport message sender task...");
-        loop {
-            if let Some(message) = raft_output_rx.recv().await {
-                match message {
-                    rpc_messages::Request::RequestVote(vote_req) => {
-                        let vote_req: proto::VoteRequest = vote_req.into();
-                        let to = ServerId(vote_req.to);
-
-                        let client = server_grpc_clients
-                            .get_mut(&to)
-                            .expect("GRPC BUG ALERT: No gRPC client for this server!");
-
-                        let _ = client
-                            .request_vote(Request::new(vote_req))
-                            .await
-                            .and_then(|response| {
-                                raft_input_tx
-                                    .send(TransportMessage::Reply(
-                                        rpc_messages::ReplyTo::RequestVote(
-                                            response.into_inner().into(),
-                                        ),
-                                    ))
-                                    .map(|_| ())
-                                    .map_err(|e| match e {
-                                        mpsc::error::SendError(_) => Status::internal(
-                                            "Raft gRPC transport bridge disconnected!",
-                                        ),
-                                    })
+        let mut peer_queues = HashMap::new();
+        let mut peer_senders = vec![];
+        for (server_id, client) in server_grpc_clients {
+            let (queue_tx, queue_rx) = mpsc::channel(PEER_QUEUE_LEN);
+            peer_senders.push(tokio::spawn(send_to_peer::<C, Codec>(
+                client,
+                raft_input_tx.clone(),
+                raft_thread.clone(),
+                queue_rx,
+            )));
+            let _ = peer_queues.insert(server_id, queue_tx);
+        }
+
+        while let Some((span, message)) = raft_output_rx.recv().await {
+            let to = message.to();
+            let queue = peer_queues
+                .get(&to)
+                .expect("GRPC BUG ALERT: No gRPC client for this server!");
+            // Raft sends whatever the peer is missing again once it answers
+            if queue.try_send((span, message)).is_err() {
+                debug!("Request queue to {:?} is full, dropping message", to);
+            }
+        }
+        info!("Raft gRPC transport message sender exiting, raft state machine receiver disconnected/closed!");
+        // The peer senders stop once they have sent what is left in their queues
+        drop(peer_queues);
+        for peer_sender in peer_senders {
+            let _ = peer_sender.await;
+        }
+    })
+}
+
+/// Sends the requests queued for one peer one at a time, in the order the raft thread sent them
+async fn send_to_peer<C, Codec>(
+    mut client: RaftConsensusClient<Channel>,
+    raft_input_tx: mpsc::UnboundedSender<TransportMessage<C>>,
+    raft_thread: Arc<Mutex<Option<thread::Thread>>>,
+    mut queue_rx: mpsc::Receiver<(Span, rpc_messages::Request<C>)>,
+) where
+    C: LogCommand + 'static,
+    Codec: CommandCodec<C>,
+{
+    while let Some((parent_span, message)) = queue_rx.recv().await {
+        // Context of this span is sent with the request, replies are handled by the raft thread inside it
+        let span = info_span!(parent: &parent_span, "grpc_client_call", to = message.to().0);
+        match message {
+            rpc_messages::Request::RequestVote(vote_req) => {
+                let vote_req: proto::VoteRequest = vote_req.into();
+
+                let _ = client
+                    .request_vote(request_in_span(&span, vote_req))
+                    .await
+                    .and_then(|response| {
+                        raft_input_tx
+                            .send(TransportMessage::Reply(
+                                rpc_messages::ReplyTo::RequestVote(
+                                    response.into_inner().try_into()?,
+                                ),
+                                span.clone(),
+                            ))
+                            .map(|_| unpark_raft_thread(&raft_thread))
+                            .map_err(|e| match e {
+                                mpsc::error::SendError(_) => {
+                                    Status::internal("Raft gRPC transport bridge disconnected!")
+                                }
                             })
-                            .map_err(|e| {
-                                trace!("Failed to send request vote request: {:?}", e);
-                            });
-                    }
-                    rpc_messages::Request::AppendEntries(append_entries_req) => {
-                        let append_entries_req: proto::AppendEntriesRequest =
-                            append_entries_req.into();
-                        let to = ServerId(append_entries_req.to);
-
-                        let client = server_grpc_clients
-                            .get_mut(&to)
-                            .expect("GRPC BUG ALERT: No gRPC client for this server!");
-
-                        let _ = client
-                                .append_entries(Request::new(append_entries_req))
-                                .await
-                                .and_then(|response| {
-                                    raft_input_tx
-                                        .send(TransportMessage::Reply(
-                                            rpc_messages::ReplyTo::AppendEntries(
-                                                response.into_inner().into(),
-                                            ),
-                                        ))
-                                        .map(|_| ())
-                                        .map_err(|e| match e {
-                                            mpsc::error::SendError(_) => {
-                                                Status::internal("Raft gRPC transport bridge is disconnected, dropping message!")
-                                            }
-                                        })
-                                })
-                                .map_err(|e| {
-                                    trace!("Failed to send append entries request to {:?}: {:?}", to, e);
-                                });
+                    })
+                    .map_err(|e| {
+                        trace!("Failed to send request vote request: {:?}", e);
+                    });
+            }
+            rpc_messages::Request::AppendEntries(append_entries_req) => {
+                let to = append_entries_req.to;
+                let append_entries_req = match proto::AppendEntriesRequest::from_rpc_message::<
+                    C,
+                    Codec,
+                >(append_entries_req)
+                {
+                    Ok(append_entries_req) => append_entries_req,
+                    Err(e) => {
+                        trace!("Failed to encode append entries request to {:?}, dropping message: {:?}", to, e);
+                        continue;
                     }
-                }
-            } else {
-                info!("Raft gRPC transport message sender exiting, raft state machine receiver disconnected/closed!");
-                break;
+                };
+
+                let _ = client
+                    .append_entries(request_in_span(&span, append_entries_req))
+                    .await
+                    .and_then(|response| {
+                        raft_input_tx
+                            .send(TransportMessage::Reply(
+                                rpc_messages::ReplyTo::AppendEntries(
+                                    response.into_inner().try_into()?,
+                                ),
+                                span.clone(),
+                            ))
+                            .map(|_| unpark_raft_thread(&raft_thread))
+                            .map_err(|e| match e {
+                                mpsc::error::SendError(_) => Status::internal(
+                                    "Raft gRPC transport bridge is disconnected, dropping message!",
+                                ),
+                            })
+                    })
+                    .map_err(|e| {
+                        trace!("Failed to send append entries request to {:?}: {:?}", to, e);
+                    });
+            }
+            rpc_messages::Request::TimeoutNow(timeout_now_req) => {
+                let timeout_now_req: proto::TimeoutNowRequest = timeout_now_req.into();
+                let to = ServerId(timeout_now_req.to);
+
+                let _ = client
+                    .timeout_now(request_in_span(&span, timeout_now_req))
+                    .await
+                    .and_then(|response| {
+                        raft_input_tx
+                            .send(TransportMessage::Reply(
+                                rpc_messages::ReplyTo::TimeoutNow(
+                                    response.into_inner().try_into()?,
+                                ),
+                                span.clone(),
+                            ))
+                            .map(|_| unpark_raft_thread(&raft_thread))
+                            .map_err(|e| match e {
+                                mpsc::error::SendError(_) => {
+                                    Status::internal("Raft gRPC transport bridge disconnected!")
+                                }
+                            })
+                    })
+                    .map_err(|e| {
+                        trace!("Failed to send timeout now request to {:?}: {:?}", to, e);
+                    });
             }
         }
-    })
+    }
+}
+
+/// The raft thread parks itself while waiting for a message, the gRPC server unparks it for requests and this for
+/// replies. It has not waited for a message yet if it isn't known.
+fn unpark_raft_thread(raft_thread: &Mutex<Option<thread::Thread>>) {
+    if let Some(raft_thread) = raft_thread.lock().unwrap().as_ref() {
+        raft_thread.unpark();
+    }
 }
 
-pub struct RaftGrpcTransport {
-    pub grpc_server: RaftGrpcServerImpl,
-    pub transport_bridge: RaftGrpcTransportConnector,
+/// gRPC transport for a Raft node whose log contains commands of type `C`.
+/// Commands are serialized into `ApplicationCommand.serialized` using `Codec`, which defaults to serde/bincode.
+pub struct RaftGrpcTransport<C: LogCommand, Codec: CommandCodec<C> = BincodeCodec> {
+    pub grpc_server: RaftGrpcServerImpl<C, Codec>,
+    pub transport_bridge: RaftGrpcTransportConnector<C>,
     pub message_sender_task: tokio::task::JoinHandle<()>,
 }
-impl RaftGrpcTransport {
+impl<C, Codec> RaftGrpcTransport<C, Codec>
+where
+    C: LogCommand + Sync + 'static,
+    Codec: CommandCodec<C>,
+{
     pub async fn start_grpc_transport(
         server_id: ServerId,
         server_addresses: HashMap<ServerId, SocketAddr>,
-    ) -> RaftGrpcTransport {
+    ) -> RaftGrpcTransport<C, Codec> {
         let mut server_grpc_clients: HashMap<ServerId, RaftConsensusClient<Channel>> =
             HashMap::new();
         for (other_server_id, server_address) in server_addresses {
             if other_server_id != server_id {
                 let channel = Channel::from_shared(format!("http://{}", server_address))
                     .expect("GRPC INIT: Failed to create channel")
+                    .connect_timeout(RAFT_CONNECT_TIMEOUT)
+                    .timeout(RAFT_RPC_TIMEOUT)
                     .connect_lazy();
                 server_grpc_clients
                     .insert(other_server_id, RaftConsensusClient::new(channel.clone()));
@@ -218,17 +339,23 @@ impl RaftGrpcTransport {
 
         // Message queues between raft thread and gRPC transport
         // Each runs in a separate thread so need to communicate with channels
-        let (raft_input_tx, raft_input_rx) = mpsc::unbounded_channel::<TransportMessage>();
+        let (raft_input_tx, raft_input_rx) = mpsc::unbounded_channel::<TransportMessage<C>>();
         let (raft_output_tx, raft_output_rx) =
-            mpsc::unbounded_channel::<rpc_messages::Request<u64>>();
+            mpsc::unbounded_channel::<(Span, rpc_messages::Request<C>)>();
 
         let transport_bridge =
             RaftGrpcTransportConnector::new(raft_input_rx, raft_output_tx.clone());
+        let raft_thread = transport_bridge.raft_thread.clone();
         let grpc_server = RaftGrpcServerImpl::new(raft_input_tx.clone());
 
         // Outbound RPC messages from raft thread are sent here
-        let message_sender =
-            start_outgoing_message_sender(server_grpc_clients, raft_input_tx, raft_output_rx).await;
+        let message_sender = start_outgoing_message_sender::<C, Codec>(
+            server_grpc_clients,
+            raft_input_tx,
+            raft_thread,
+            raft_output_rx,
+        )
+        .await;
         RaftGrpcTransport {
             grpc_server,
             transport_bridge,
@@ -1,3 +1,5 @@
+pub mod codec;
 pub(crate) mod grpc_server;
 pub mod grpc_transport;
 pub mod proto;
+pub mod tracing_context;
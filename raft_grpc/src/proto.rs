@@ -1,83 +1,171 @@
+use crate::codec::{CodecError, CommandCodec};
 use raft_consensus::rpc_messages;
-use raft_consensus::{LogIndex, ServerId, TermIndex};
+use raft_consensus::system_clock;
+use raft_consensus::{LogCommand, LogIndex, RaftNodeState, RaftNodeStatus, ServerId, TermIndex};
 use tonic;
 use uuid::Uuid;
 
 tonic::include_proto!("raft"); // The string specified here must match the proto package name
 
+/// Request IDs come from other servers, so a malformed one is rejected instead of trusted
+fn parse_request_id(request_id: &str) -> Result<Uuid, tonic::Status> {
+    Uuid::parse_str(request_id).map_err(|e| {
+        tonic::Status::invalid_argument(format!("Invalid request ID {:?}: {}", request_id, e))
+    })
+}
+
 // These convert the protobuf representation of the messages into the form needed for the Raft consensus module.
 // The module does not make any assumptions about the transport layer, so it uses it's own types to represent the messages received from the network.
 
-impl From<VoteRequest> for rpc_messages::RequestVote {
-    fn from(vote_request: VoteRequest) -> Self {
-        rpc_messages::RequestVote {
-            request_id: Uuid::parse_str(&vote_request.request_id)
-                .expect("GRPC CONVERT: Invalid UUID!"),
+impl TryFrom<VoteRequest> for rpc_messages::RequestVote {
+    type Error = tonic::Status;
+
+    fn try_from(vote_request: VoteRequest) -> Result<Self, Self::Error> {
+        Ok(rpc_messages::RequestVote {
+            request_id: parse_request_id(&vote_request.request_id)?,
             from: ServerId(vote_request.from),
             to: ServerId(vote_request.to),
             term: TermIndex(vote_request.term),
             last_log_index: LogIndex(vote_request.last_log_index),
             last_log_term: TermIndex(vote_request.last_log_term),
-        }
+        })
     }
 }
-impl From<VoteResponse> for rpc_messages::Vote {
-    fn from(vote_response: VoteResponse) -> Self {
-        rpc_messages::Vote {
-            request_id: Uuid::parse_str(&vote_response.request_id)
-                .expect("GRPC CONVERT: Invalid UUID!"),
+impl TryFrom<VoteResponse> for rpc_messages::Vote {
+    type Error = tonic::Status;
+
+    fn try_from(vote_response: VoteResponse) -> Result<Self, Self::Error> {
+        Ok(rpc_messages::Vote {
+            request_id: parse_request_id(&vote_response.request_id)?,
             from: ServerId(vote_response.from),
             to: ServerId(vote_response.to),
             term: TermIndex(vote_response.term),
             vote_granted: vote_response.vote_granted,
-        }
+        })
     }
 }
-impl From<AppendEntriesRequest> for rpc_messages::AppendEntries<u64> {
-    fn from(append_entries_request: AppendEntriesRequest) -> Self {
-        rpc_messages::AppendEntries {
-            request_id: Uuid::parse_str(&append_entries_request.request_id)
-                .expect("GRPC CONVERT: Invalid UUID!"),
-            from: ServerId(append_entries_request.from),
-            to: ServerId(append_entries_request.to),
-            term: TermIndex(append_entries_request.term),
-            entries: append_entries_request
-                .entries
-                .into_iter()
-                .map(|entry| raft_consensus::LogEntry {
+impl AppendEntriesRequest {
+    /// Converts the protobuf representation of an AppendEntries request into the form used by the Raft consensus module,
+    /// application commands are decoded from `ApplicationCommand.serialized` with the given codec.
+    pub fn into_rpc_message<C, Codec>(self) -> Result<rpc_messages::AppendEntries<C>, tonic::Status>
+    where
+        C: LogCommand,
+        Codec: CommandCodec<C>,
+    {
+        let request_id = parse_request_id(&self.request_id)?;
+        let entries = self
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let command = match entry.command {
+                    Some(log_entry::Command::ApplicationCommand(ApplicationCommand {
+                        serialized,
+                    })) => Some(Codec::decode(&serialized)?),
+                    Some(log_entry::Command::Noop(Noop {})) => None,
+                    Some(log_entry::Command::ClusterMembershipChange(_)) => {
+                        return Err(CodecError(
+                            "Cluster membership changes are not supported yet!".to_string(),
+                        ))
+                    }
+                    None => return Err(CodecError("Log entry has no command!".to_string())),
+                };
+                Ok(raft_consensus::LogEntry {
                     term: TermIndex(entry.term),
                     index: LogIndex(entry.log_index),
-                    command: entry
-                        .command
-                        .map(|c| match c {
-                            log_entry::Command::ApplicationCommand(ApplicationCommand {
-                                serialized,
-                            }) => u64::from_be_bytes(
-                                serialized
-                                    .try_into()
-                                    .expect("GRPC CONVERT: Invalid application command!"),
-                            ),
-                            _ => panic!("Unexpected command!"),
-                        })
-                        .expect("GRPC CONVERT: No command"),
+                    command,
                 })
-                .collect(),
-            prev_log_index: LogIndex(append_entries_request.prev_log_index),
-            prev_log_term: TermIndex(append_entries_request.prev_log_term),
-            leader_commit: LogIndex(append_entries_request.leader_commit_index),
-        }
+            })
+            .collect::<Result<Vec<_>, CodecError>>()
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+
+        Ok(rpc_messages::AppendEntries {
+            request_id,
+            from: ServerId(self.from),
+            to: ServerId(self.to),
+            term: TermIndex(self.term),
+            entries,
+            prev_log_index: LogIndex(self.prev_log_index),
+            prev_log_term: TermIndex(self.prev_log_term),
+            leader_commit: LogIndex(self.leader_commit_index),
+        })
+    }
+
+    /// Converts an AppendEntries request from the Raft consensus module into its protobuf representation,
+    /// application commands are encoded into `ApplicationCommand.serialized` with the given codec.
+    pub fn from_rpc_message<C, Codec>(
+        append_entries_request: rpc_messages::AppendEntries<C>,
+    ) -> Result<Self, CodecError>
+    where
+        C: LogCommand,
+        Codec: CommandCodec<C>,
+    {
+        let entries = append_entries_request
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let command = match &entry.command {
+                    Some(command) => log_entry::Command::ApplicationCommand(ApplicationCommand {
+                        serialized: Codec::encode(command)?,
+                    }),
+                    None => log_entry::Command::Noop(Noop {}),
+                };
+                Ok(LogEntry {
+                    term: entry.term.0,
+                    log_index: entry.index.0,
+                    command: Some(command),
+                })
+            })
+            .collect::<Result<Vec<_>, CodecError>>()?;
+
+        Ok(AppendEntriesRequest {
+            request_id: append_entries_request.request_id.to_string(),
+            from: append_entries_request.from.0,
+            to: append_entries_request.to.0,
+            term: append_entries_request.term.0,
+            entries,
+            prev_log_index: append_entries_request.prev_log_index.0,
+            prev_log_term: append_entries_request.prev_log_term.0,
+            leader_commit_index: append_entries_request.leader_commit.0,
+        })
     }
 }
-impl From<AppendEntriesResponse> for rpc_messages::AppendEntriesAck {
-    fn from(append_entries_response: AppendEntriesResponse) -> Self {
-        rpc_messages::AppendEntriesAck {
-            request_id: Uuid::parse_str(&append_entries_response.request_id)
-                .expect("GRPC CONVERT: Invalid UUID!"),
+impl TryFrom<AppendEntriesResponse> for rpc_messages::AppendEntriesAck {
+    type Error = tonic::Status;
+
+    fn try_from(append_entries_response: AppendEntriesResponse) -> Result<Self, Self::Error> {
+        Ok(rpc_messages::AppendEntriesAck {
+            request_id: parse_request_id(&append_entries_response.request_id)?,
             from: ServerId(append_entries_response.from),
             to: ServerId(append_entries_response.to),
             term: TermIndex(append_entries_response.term),
             success: append_entries_response.added_entries_successfully,
-        }
+            match_index: LogIndex(append_entries_response.match_index),
+        })
+    }
+}
+impl TryFrom<TimeoutNowRequest> for rpc_messages::TimeoutNow {
+    type Error = tonic::Status;
+
+    fn try_from(timeout_now_request: TimeoutNowRequest) -> Result<Self, Self::Error> {
+        Ok(rpc_messages::TimeoutNow {
+            request_id: parse_request_id(&timeout_now_request.request_id)?,
+            from: ServerId(timeout_now_request.from),
+            to: ServerId(timeout_now_request.to),
+            term: TermIndex(timeout_now_request.term),
+        })
+    }
+}
+impl TryFrom<TimeoutNowResponse> for rpc_messages::TimeoutNowAck {
+    type Error = tonic::Status;
+
+    fn try_from(timeout_now_response: TimeoutNowResponse) -> Result<Self, Self::Error> {
+        Ok(rpc_messages::TimeoutNowAck {
+            request_id: parse_request_id(&timeout_now_response.request_id)?,
+            from: ServerId(timeout_now_response.from),
+            to: ServerId(timeout_now_response.to),
+            term: TermIndex(timeout_now_response.term),
+            success: timeout_now_response.started_election,
+        })
     }
 }
 
@@ -106,31 +194,6 @@ impl From<rpc_messages::Vote> for VoteResponse {
     }
 }
 
-impl From<rpc_messages::AppendEntries<u64>> for AppendEntriesRequest {
-    fn from(append_entries_request: rpc_messages::AppendEntries<u64>) -> Self {
-        AppendEntriesRequest {
-            request_id: append_entries_request.request_id.to_string(),
-            from: append_entries_request.from.0,
-            to: append_entries_request.to.0,
-            term: append_entries_request.term.0,
-            entries: append_entries_request
-                .entries
-                .into_iter()
-                .map(|entry| LogEntry {
-                    term: entry.term.0,
-                    log_index: entry.index.0,
-                    command: Some(log_entry::Command::ApplicationCommand(ApplicationCommand {
-                        serialized: entry.command.to_be_bytes().to_vec(),
-                    })),
-                })
-                .collect(),
-            prev_log_index: append_entries_request.prev_log_index.0,
-            prev_log_term: append_entries_request.prev_log_term.0,
-            leader_commit_index: append_entries_request.leader_commit.0,
-        }
-    }
-}
-
 impl From<rpc_messages::AppendEntriesAck> for AppendEntriesResponse {
     fn from(append_entries_response: rpc_messages::AppendEntriesAck) -> Self {
         AppendEntriesResponse {
@@ -139,6 +202,123 @@ impl From<rpc_messages::AppendEntriesAck> for AppendEntriesResponse {
             to: append_entries_response.to.0,
             term: append_entries_response.term.0,
             added_entries_successfully: append_entries_response.success,
+            match_index: append_entries_response.match_index.0,
+        }
+    }
+}
+
+impl From<rpc_messages::TimeoutNow> for TimeoutNowRequest {
+    fn from(timeout_now_request: rpc_messages::TimeoutNow) -> Self {
+        TimeoutNowRequest {
+            request_id: timeout_now_request.request_id.to_string(),
+            from: timeout_now_request.from.0,
+            to: timeout_now_request.to.0,
+            term: timeout_now_request.term.0,
+        }
+    }
+}
+
+impl From<rpc_messages::TimeoutNowAck> for TimeoutNowResponse {
+    fn from(timeout_now_response: rpc_messages::TimeoutNowAck) -> Self {
+        TimeoutNowResponse {
+            request_id: timeout_now_response.request_id.to_string(),
+            from: timeout_now_response.from.0,
+            to: timeout_now_response.to.0,
+            term: timeout_now_response.term.0,
+            started_election: timeout_now_response.success,
+        }
+    }
+}
+
+impl From<RaftNodeStatus> for NodeStatus {
+    fn from(status: RaftNodeStatus) -> Self {
+        let role = match status.state {
+            RaftNodeState::Follower => node_status::Role::Follower,
+            RaftNodeState::Candidate => node_status::Role::Candidate,
+            RaftNodeState::Leader => node_status::Role::Leader,
+        };
+        let now = system_clock::now();
+        NodeStatus {
+            server_id: status.server_id.0,
+            role: role as i32,
+            term: status.current_term.0,
+            has_leader: status.leader_for_term.is_some(),
+            leader_id: status.leader_for_term.map(|leader| leader.0).unwrap_or(0),
+            commit_index: status.commit_index.0,
+            last_applied: status.last_applied.0,
+            last_log_index: status.last_log_index.0,
+            last_log_term: status.last_log_term.0,
+            followers: status
+                .followers
+                .into_iter()
+                .map(|follower| FollowerStatus {
+                    server_id: follower.server_id.0,
+                    next_index: follower.next_index.0,
+                    match_index: follower.match_index.0,
+                    has_contacted_leader: follower.last_contact.is_some(),
+                    millis_since_last_contact: follower
+                        .last_contact
+                        .map(|last_contact| (now - last_contact).as_millis() as u64)
+                        .unwrap_or(0),
+                })
+                .collect(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::BincodeCodec;
+
+    #[test]
+    fn should_reject_malformed_request_ids() {
+        let vote_request = VoteRequest {
+            request_id: "not-a-uuid".to_string(),
+            from: 1,
+            to: 2,
+            term: 3,
+            last_log_index: 0,
+            last_log_term: 0,
+        };
+
+        let error = rpc_messages::RequestVote::try_from(vote_request).unwrap_err();
+
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn should_reject_append_entries_with_malformed_request_ids() {
+        let append_entries_request = AppendEntriesRequest {
+            request_id: String::new(),
+            from: 1,
+            to: 2,
+            term: 3,
+            entries: vec![],
+            prev_log_index: 0,
+            prev_log_term: 0,
+            leader_commit_index: 0,
+        };
+
+        let error = append_entries_request
+            .into_rpc_message::<u64, BincodeCodec>()
+            .unwrap_err();
+
+        assert_eq!(error.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn should_convert_well_formed_request_ids() {
+        let request_id = Uuid::new_v4();
+        let timeout_now_request = TimeoutNowRequest {
+            request_id: request_id.to_string(),
+            from: 1,
+            to: 2,
+            term: 3,
+        };
+
+        let timeout_now = rpc_messages::TimeoutNow::try_from(timeout_now_request).unwrap();
+
+        assert_eq!(timeout_now.request_id, request_id);
+    }
+}
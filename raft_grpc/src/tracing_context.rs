@@ -0,0 +1,94 @@
+//! Propagation of tracing span context between servers in gRPC request metadata, so one RPC can be followed from the
+//! sender to the receiver in a trace. Uses the globally registered OpenTelemetry propagator, see
+//! `install_trace_context_propagator`. Without an OpenTelemetry tracing layer spans have no context and nothing is
+//! propagated.
+use opentelemetry::global;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use tonic::metadata::{KeyRef, MetadataKey, MetadataMap, MetadataValue};
+use tonic::Request;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(&value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .map(|key| match key {
+                KeyRef::Ascii(key) => key.as_str(),
+                KeyRef::Binary(key) => key.as_str(),
+            })
+            .collect()
+    }
+}
+
+/// Propagate span context using the W3C trace context headers (`traceparent`/`tracestate`)
+pub fn install_trace_context_propagator() {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+}
+
+/// Builds a gRPC request carrying the context of `span` in its metadata
+pub(crate) fn request_in_span<T>(span: &Span, message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut MetadataInjector(request.metadata_mut()))
+    });
+    request
+}
+
+/// Makes the span context the sender propagated in `request`'s metadata the parent of `span`
+pub(crate) fn set_parent_from_request<T>(span: &Span, request: &Request<T>) {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MetadataExtractor(request.metadata()))
+    });
+    span.set_parent(parent);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::sdk::trace::TracerProvider;
+    use opentelemetry::trace::{TraceContextExt, TraceId, TracerProvider as _};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn should_propagate_span_context_through_request_metadata() {
+        install_trace_context_propagator();
+        // The tracer only holds a weak reference to its provider
+        let provider = TracerProvider::builder().build();
+        let tracer = provider.tracer("tracing_context_tests");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let sender_span = tracing::info_span!("send");
+            let request = request_in_span(&sender_span, ());
+            assert!(request.metadata().contains_key("traceparent"));
+
+            let receiver_span = tracing::info_span!("receive");
+            set_parent_from_request(&receiver_span, &request);
+
+            let trace_id = |span: &Span| span.context().span().span_context().trace_id();
+            assert_ne!(trace_id(&sender_span), TraceId::INVALID);
+            assert_eq!(trace_id(&receiver_span), trace_id(&sender_span));
+        });
+    }
+}
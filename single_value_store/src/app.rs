@@ -1,18 +1,66 @@
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::{stream, Stream, StreamExt};
 use single_value_store_proto::single_value_store;
 use single_value_store_proto::single_value_store::single_value_store_server::SingleValueStore;
-use tracing::info;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::replication::Replication;
+use crate::state_machine::{
+    StoreCommand, StoreResponse, StoreStateMachine, ValueChange, WatchStart,
+};
+
+type WatchStream =
+    Pin<Box<dyn Stream<Item = Result<single_value_store::ValueChange, tonic::Status>> + Send>>;
 
-pub(crate) struct SingleValueStoreImpl {}
+fn to_proto(change: ValueChange, snapshot: bool) -> single_value_store::ValueChange {
+    single_value_store::ValueChange {
+        value: change.value,
+        version: change.version,
+        snapshot,
+    }
+}
+
+/// Whether the value was written, and the value and version after the command was applied
+fn value_response(response: StoreResponse) -> Result<(bool, u64, u64), tonic::Status> {
+    match response {
+        StoreResponse::Value {
+            written,
+            value,
+            version,
+        } => Ok((written, value, version)),
+        response => Err(tonic::Status::internal(format!(
+            "Single value store command applied with unexpected response: {:?}",
+            response
+        ))),
+    }
+}
+
+pub(crate) struct SingleValueStoreImpl {
+    pub(crate) state_machine: StoreStateMachine,
+    pub(crate) replication: Arc<Replication>,
+}
 
 #[tonic::async_trait]
 impl SingleValueStore for SingleValueStoreImpl {
+    type WatchStream = WatchStream;
+
     async fn get(
         &self,
         _: tonic::Request<single_value_store::GetRequest>,
     ) -> Result<tonic::Response<single_value_store::GetResponse>, tonic::Status> {
-        info!("Client requested value: {:?}", 0);
+        let leader_hint = self.replication.read_index().await?;
+        let (value, version) = {
+            let state = self.state_machine.read();
+            (state.value(), state.version())
+        };
+        info!("Client requested value: {:?}", value);
         Ok(tonic::Response::new(single_value_store::GetResponse {
-            value: 0,
+            value,
+            leader_hint: Some(leader_hint),
+            version,
         }))
     }
 
@@ -20,7 +68,113 @@ impl SingleValueStore for SingleValueStoreImpl {
         &self,
         request: tonic::Request<single_value_store::SetRequest>,
     ) -> Result<tonic::Response<single_value_store::SetResponse>, tonic::Status> {
-        info!("Client set value: {:?}", request.into_inner().value);
-        Ok(tonic::Response::new(single_value_store::SetResponse {}))
+        let single_value_store::SetRequest { value, session } = request.into_inner();
+        info!("Client set value: {:?}", value);
+        let (response, leader_hint) = self
+            .replication
+            .propose(StoreCommand::SetValue(value), session)
+            .await?;
+        let (_, _, version) = value_response(response)?;
+        Ok(tonic::Response::new(single_value_store::SetResponse {
+            leader_hint: Some(leader_hint),
+            version,
+        }))
+    }
+
+    async fn compare_and_set(
+        &self,
+        request: tonic::Request<single_value_store::CompareAndSetRequest>,
+    ) -> Result<tonic::Response<single_value_store::CompareAndSetResponse>, tonic::Status> {
+        let request = request.into_inner();
+        info!(
+            "Client compare and set value: {:?} -> {:?} (expected version: {:?})",
+            request.expected, request.new_value, request.expected_version
+        );
+        let (response, leader_hint) = self
+            .replication
+            .propose(
+                StoreCommand::CompareAndSet {
+                    expected: request.expected,
+                    expected_version: request.expected_version,
+                    new_value: request.new_value,
+                },
+                request.session,
+            )
+            .await?;
+        let (succeeded, value, version) = value_response(response)?;
+        Ok(tonic::Response::new(
+            single_value_store::CompareAndSetResponse {
+                succeeded,
+                value,
+                version,
+                leader_hint: Some(leader_hint),
+            },
+        ))
+    }
+
+    async fn register_client(
+        &self,
+        _: tonic::Request<single_value_store::RegisterClientRequest>,
+    ) -> Result<tonic::Response<single_value_store::RegisterClientResponse>, tonic::Status> {
+        let (session_id, leader_hint) = self.replication.register_client().await?;
+        info!("Registered client session {:?}", session_id);
+        Ok(tonic::Response::new(
+            single_value_store::RegisterClientResponse {
+                session_id,
+                leader_hint: Some(leader_hint),
+            },
+        ))
+    }
+
+    async fn watch(
+        &self,
+        request: tonic::Request<single_value_store::WatchRequest>,
+    ) -> Result<tonic::Response<Self::WatchStream>, tonic::Status> {
+        let from_index = request.into_inner().from_index;
+        info!("Client started watch from index {:?}", from_index);
+        let (start, changes) = self.state_machine.watch(from_index);
+        let (initial, last_sent) = match start {
+            WatchStart::Changes(changes) => {
+                let last_sent = changes
+                    .last()
+                    .map_or(from_index.unwrap_or_default().saturating_sub(1), |change| {
+                        change.version
+                    });
+                let initial: Vec<_> = changes
+                    .into_iter()
+                    .map(|change| Ok(to_proto(change, false)))
+                    .collect();
+                (initial, last_sent)
+            }
+            WatchStart::Snapshot(current) => (vec![Ok(to_proto(current, true))], current.version),
+        };
+        // The stream is dropped when the client goes away, which unsubscribes it from the changes
+        let state_machine = self.state_machine.clone();
+        let live = stream::unfold((changes, last_sent), move |(mut changes, last_sent)| {
+            let state_machine = state_machine.clone();
+            async move {
+                loop {
+                    let (change, snapshot) = match changes.recv().await {
+                        Ok(change) => (change, false),
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!(
+                                "Watch fell behind by {} changes, sending a snapshot",
+                                skipped
+                            );
+                            (state_machine.read().current_value(), true)
+                        }
+                        Err(RecvError::Closed) => return None,
+                    };
+                    // Already sent from the history, or no change since the last one when we fell behind
+                    if change.version <= last_sent {
+                        continue;
+                    }
+                    return Some((Ok(to_proto(change, snapshot)), (changes, change.version)));
+                }
+            }
+        });
+        Ok(tonic::Response::new(Box::pin(
+            stream::iter(initial).chain(live),
+        )))
     }
 }
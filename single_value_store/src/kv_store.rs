@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use single_value_store_proto::kv_store;
+use single_value_store_proto::kv_store::kv_store_server::KvStore;
+use tonic::{Request, Response, Status};
+
+use crate::replication::Replication;
+use crate::state_machine::{StoreCommand, StoreResponse, StoreStateMachine};
+
+/// Most entries a single scan returns
+const MAX_SCAN_ENTRIES: usize = 1000;
+
+pub(crate) struct KvStoreImpl {
+    pub(crate) state_machine: StoreStateMachine,
+    pub(crate) replication: Arc<Replication>,
+}
+
+#[tonic::async_trait]
+impl KvStore for KvStoreImpl {
+    async fn get(
+        &self,
+        request: Request<kv_store::GetRequest>,
+    ) -> Result<Response<kv_store::GetResponse>, Status> {
+        let leader_hint = self.replication.read_index().await?;
+        let key = request.into_inner().key;
+        let value = self.state_machine.read().get(&key).cloned();
+        Ok(Response::new(kv_store::GetResponse {
+            found: value.is_some(),
+            value: value.unwrap_or_default(),
+            leader_hint: Some(leader_hint),
+        }))
+    }
+
+    async fn put(
+        &self,
+        request: Request<kv_store::PutRequest>,
+    ) -> Result<Response<kv_store::PutResponse>, Status> {
+        let kv_store::PutRequest {
+            key,
+            value,
+            session,
+        } = request.into_inner();
+        let (_, leader_hint) = self
+            .replication
+            .propose(StoreCommand::Put { key, value }, session)
+            .await?;
+        Ok(Response::new(kv_store::PutResponse {
+            leader_hint: Some(leader_hint),
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<kv_store::DeleteRequest>,
+    ) -> Result<Response<kv_store::DeleteResponse>, Status> {
+        let kv_store::DeleteRequest { key, session } = request.into_inner();
+        let (response, leader_hint) = self
+            .replication
+            .propose(StoreCommand::Delete { key }, session)
+            .await?;
+        Ok(Response::new(kv_store::DeleteResponse {
+            deleted: response == StoreResponse::Deleted(true),
+            leader_hint: Some(leader_hint),
+        }))
+    }
+
+    async fn scan(
+        &self,
+        request: Request<kv_store::ScanRequest>,
+    ) -> Result<Response<kv_store::ScanResponse>, Status> {
+        let leader_hint = self.replication.read_index().await?;
+        let request = request.into_inner();
+        let limit = match request.limit as usize {
+            0 => MAX_SCAN_ENTRIES,
+            limit => limit.min(MAX_SCAN_ENTRIES),
+        };
+        let end_key = Some(request.end_key.as_slice()).filter(|end_key| !end_key.is_empty());
+        let entries = self
+            .state_machine
+            .read()
+            .scan(&request.start_key, end_key, limit)
+            .into_iter()
+            .map(|(key, value)| kv_store::KeyValue { key, value })
+            .collect();
+        Ok(Response::new(kv_store::ScanResponse {
+            entries,
+            leader_hint: Some(leader_hint),
+        }))
+    }
+}
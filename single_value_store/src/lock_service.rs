@@ -0,0 +1,164 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use single_value_store_proto::lock_service;
+use single_value_store_proto::lock_service::lock_service_server::LockService;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use crate::replication::{timestamp_ms, Replication};
+use crate::state_machine::{Lease, StoreCommand, StoreResponse, StoreStateMachine};
+
+/// Longest lease a client can ask for
+const MAX_LEASE_TTL_MS: u64 = 60 * 60 * 1000;
+/// How often the leader checks whether to write its time to the log, leases expire at most this late
+const LOG_TIME_INTERVAL: Duration = Duration::from_secs(1);
+
+fn check_ttl(ttl_ms: u64) -> Result<(), Status> {
+    if ttl_ms == 0 || ttl_ms > MAX_LEASE_TTL_MS {
+        return Err(Status::invalid_argument(format!(
+            "Lease TTL must be between 1 and {} ms",
+            MAX_LEASE_TTL_MS
+        )));
+    }
+    Ok(())
+}
+
+fn to_proto(lease: Lease) -> lock_service::Lease {
+    lock_service::Lease {
+        owner: lease.owner,
+        fencing_token: lease.fencing_token,
+        expires_at_ms: lease.expires_at_ms,
+    }
+}
+
+/// Whether the lease was granted and the lease that holds the lock
+fn lease_response(response: StoreResponse) -> Result<(bool, Option<lock_service::Lease>), Status> {
+    match response {
+        StoreResponse::Lease { granted, lease } => Ok((granted, lease.map(to_proto))),
+        response => Err(Status::internal(format!(
+            "Lock command applied with unexpected response: {:?}",
+            response
+        ))),
+    }
+}
+
+/// Writes the leader's time to the log every `LOG_TIME_INTERVAL` until the server stops, but only when a lease or
+/// session expires before the next check. Holding a long lease or an idle session doesn't grow the log, the time is
+/// written once it is due to expire.
+pub(crate) async fn advance_log_time(
+    replication: Arc<Replication>,
+    state_machine: StoreStateMachine,
+) {
+    let mut interval = tokio::time::interval(LOG_TIME_INTERVAL);
+    loop {
+        let _ = interval.tick().await;
+        let next_check_ms = timestamp_ms() + LOG_TIME_INTERVAL.as_millis() as u64;
+        if !matches!(state_machine.read().next_expiry_ms(), Some(expiry_ms) if expiry_ms <= next_check_ms)
+        {
+            continue;
+        }
+        if let Err(status) = replication.advance_log_time().await {
+            warn!("Could not write the leader's time to the log: {}", status);
+        }
+    }
+}
+
+pub(crate) struct LockServiceImpl {
+    pub(crate) replication: Arc<Replication>,
+}
+
+#[tonic::async_trait]
+impl LockService for LockServiceImpl {
+    async fn acquire(
+        &self,
+        request: Request<lock_service::AcquireRequest>,
+    ) -> Result<Response<lock_service::AcquireResponse>, Status> {
+        let lock_service::AcquireRequest {
+            name,
+            owner,
+            ttl_ms,
+        } = request.into_inner();
+        check_ttl(ttl_ms)?;
+        if owner.is_empty() {
+            return Err(Status::invalid_argument("Lock owner must not be empty"));
+        }
+        info!(
+            "Client {:?} acquiring lock {:?} for {} ms",
+            owner, name, ttl_ms
+        );
+        let (response, leader_hint) = self
+            .replication
+            .propose(
+                StoreCommand::AcquireLock {
+                    name,
+                    owner,
+                    ttl_ms,
+                    timestamp_ms: timestamp_ms(),
+                },
+                None,
+            )
+            .await?;
+        let (acquired, lease) = lease_response(response)?;
+        Ok(Response::new(lock_service::AcquireResponse {
+            acquired,
+            lease,
+            leader_hint: Some(leader_hint),
+        }))
+    }
+
+    async fn renew(
+        &self,
+        request: Request<lock_service::RenewRequest>,
+    ) -> Result<Response<lock_service::RenewResponse>, Status> {
+        let lock_service::RenewRequest {
+            name,
+            fencing_token,
+            ttl_ms,
+        } = request.into_inner();
+        check_ttl(ttl_ms)?;
+        let (response, leader_hint) = self
+            .replication
+            .propose(
+                StoreCommand::RenewLock {
+                    name,
+                    fencing_token,
+                    ttl_ms,
+                    timestamp_ms: timestamp_ms(),
+                },
+                None,
+            )
+            .await?;
+        let (renewed, lease) = lease_response(response)?;
+        Ok(Response::new(lock_service::RenewResponse {
+            renewed,
+            lease,
+            leader_hint: Some(leader_hint),
+        }))
+    }
+
+    async fn release(
+        &self,
+        request: Request<lock_service::ReleaseRequest>,
+    ) -> Result<Response<lock_service::ReleaseResponse>, Status> {
+        let lock_service::ReleaseRequest {
+            name,
+            fencing_token,
+        } = request.into_inner();
+        info!("Client releasing lock {:?} ({})", name, fencing_token);
+        let (response, leader_hint) = self
+            .replication
+            .propose(
+                StoreCommand::ReleaseLock {
+                    name,
+                    fencing_token,
+                },
+                None,
+            )
+            .await?;
+        Ok(Response::new(lock_service::ReleaseResponse {
+            released: response == StoreResponse::Released(true),
+            leader_hint: Some(leader_hint),
+        }))
+    }
+}
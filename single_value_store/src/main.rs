@@ -1,20 +1,43 @@
 mod app;
+mod kv_store;
+mod lock_service;
+mod metrics;
+mod replication;
+mod state_machine;
+mod tracing_setup;
 
-use std::{collections::HashMap, net::SocketAddr, time::Duration};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use crate::app::SingleValueStoreImpl;
-use raft_consensus::{start_raft_in_new_thread, NoOpRaftEventCollector, RaftConfig, ServerId};
+use crate::kv_store::KvStoreImpl;
+use crate::lock_service::LockServiceImpl;
+use crate::replication::Replication;
+use crate::state_machine::{StoreCommand, StoreStateMachine};
+use cluster_config::{parse_cluster_members, ClusterConfig, RaftSettings};
+use raft_consensus::{
+    start_raft_in_new_thread, NoOpRaftEventCollector, RaftConfig, RaftNodeSetup,
+    RedbPersistentStorage, ServerId,
+};
 use raft_grpc::grpc_transport::RaftGrpcTransport;
 use raft_grpc::proto::raft_consensus_server::RaftConsensusServer;
+use redb::Database;
+use single_value_store_proto::kv_store::kv_store_server::KvStoreServer;
+use single_value_store_proto::lock_service::lock_service_server::LockServiceServer;
 use single_value_store_proto::single_value_store::single_value_store_server::SingleValueStoreServer;
 use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::timeout;
 use tonic::transport::Server;
+use tracing::{error, info};
 
 use rand::SeedableRng;
 use rand_chacha::ChaCha8Rng;
 
 use clap::Parser;
 
+/// How long to wait on shutdown for the raft node's last messages to be sent to the other servers
+const MESSAGE_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -23,97 +46,233 @@ struct Args {
     #[arg(short, long)]
     server_id: u32,
 
+    /// TOML file describing the members of the cluster, their data directories and the Raft timing (see
+    /// `cluster.toml`), instead of passing them as the arguments below
+    #[arg(long, conflicts_with_all = ["port", "cluster_members", "wal_log_dir", "leader_heartbeat_ms"])]
+    cluster_config: Option<PathBuf>,
+
     /// Port to listen on
-    #[arg(short, long)]
-    port: u16,
+    #[arg(short, long, required_unless_present = "cluster_config")]
+    port: Option<u16>,
 
     /// Arg that contains the members of the cluster
     /// Command delimited list of server IDs/addresses
     /// Ex:
     /// 1,127.0.0.1:123,2,127.0.0.1:234,3,127.0.0.1:345
-    #[arg(short, long)]
-    cluster_members: String,
+    #[arg(short, long, required_unless_present = "cluster_config")]
+    cluster_members: Option<String>,
 
-    /// Path to directory to store write ahead logs
-    #[arg(short, long)]
-    wal_log_dir: String,
+    /// Path to directory to store the raft log, term, vote and snapshots of the store in
+    #[arg(short, long, required_unless_present = "cluster_config")]
+    wal_log_dir: Option<PathBuf>,
 
     /// Leader heartbeat interval in milliseconds
-    #[arg(short, long)]
-    leader_heartbeat_ms: u64,
+    #[arg(short, long, required_unless_present = "cluster_config")]
+    leader_heartbeat_ms: Option<u64>,
+
+    /// Port to serve prometheus metrics on at `/metrics`, metrics are not served if this is not set. Overrides the
+    /// cluster config's.
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// OTLP gRPC endpoint (i.e. Jaeger at http://127.0.0.1:4317) to export tracing spans to, spans are only logged
+    /// if this is not set
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
 }
 
-fn parse_cluster_members(cluster_members: &str) -> HashMap<ServerId, SocketAddr> {
-    let mut cluster_members = cluster_members.split(',');
-    let mut cluster = HashMap::new();
-    while let Some(id) = cluster_members.next() {
-        let id: u64 = id.parse().unwrap();
-        let addr = cluster_members
-            .next()
-            .unwrap()
-            .to_string()
-            .parse()
-            .expect("SERVER INIT: Could not parse server address");
-        cluster.insert(ServerId(id), addr);
-    }
-    cluster
+/// Addresses of the members in `--cluster-members`, see `cluster_config::parse_cluster_members`
+fn cluster_member_addresses(
+    cluster_members: &str,
+) -> Result<HashMap<ServerId, SocketAddr>, Box<dyn std::error::Error>> {
+    parse_cluster_members(cluster_members)?
+        .into_iter()
+        .map(|(id, address)| {
+            let address = address
+                .parse()
+                .map_err(|e| format!("Invalid address {:?} of server {}: {}", address, id, e))?;
+            Ok((ServerId(id), address))
+        })
+        .collect()
+}
+
+/// What this server needs to know about the cluster, from the cluster config or the arguments
+struct ServerSettings {
+    port: u16,
+    server_id_to_addr: HashMap<ServerId, SocketAddr>,
+    wal_log_dir: PathBuf,
+    raft_config: RaftConfig,
+    metrics_port: Option<u16>,
+}
+
+fn server_settings(args: &Args) -> Result<ServerSettings, Box<dyn std::error::Error>> {
+    let path = match &args.cluster_config {
+        Some(path) => path,
+        // Clap makes sure the other arguments are there without a cluster config
+        None => {
+            let raft_settings = RaftSettings {
+                leader_heartbeat_ms: args
+                    .leader_heartbeat_ms
+                    .expect("SERVER INIT: Missing leader heartbeat"),
+                ..RaftSettings::default()
+            };
+            return Ok(ServerSettings {
+                port: args.port.expect("SERVER INIT: Missing port"),
+                server_id_to_addr: cluster_member_addresses(
+                    args.cluster_members
+                        .as_deref()
+                        .expect("SERVER INIT: Missing cluster members"),
+                )?,
+                wal_log_dir: args
+                    .wal_log_dir
+                    .clone()
+                    .expect("SERVER INIT: Missing WAL log dir"),
+                raft_config: raft_settings.raft_config(),
+                metrics_port: args.metrics_port,
+            });
+        }
+    };
+    let config = ClusterConfig::load(path)?;
+    let server = config
+        .server(args.server_id.into())
+        .ok_or_else(|| format!("Server ID {} is not a member in {:?}", args.server_id, path))?;
+    Ok(ServerSettings {
+        port: server.address.port(),
+        server_id_to_addr: config
+            .addresses()
+            .into_iter()
+            .map(|(id, addr)| (ServerId(id), addr))
+            .collect(),
+        wal_log_dir: server.data_dir.clone(),
+        raft_config: config.raft.raft_config(),
+        metrics_port: args.metrics_port.or(server.metrics_port),
+    })
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_max_level(tracing::Level::INFO)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
-
     let args = Args::parse();
+    tracing_setup::init_tracing(args.server_id, args.otlp_endpoint.as_deref())?;
+    let settings = server_settings(&args)?;
+    std::fs::create_dir_all(&settings.wal_log_dir)?;
 
     let addr = SocketAddr::new(
         "0.0.0.0"
             .parse()
             .expect("SERVER INIT: Could not parse server IP"),
-        args.port,
+        settings.port,
     );
     let server_id = ServerId(args.server_id.into());
 
-    let server_id_to_addr = parse_cluster_members(&args.cluster_members);
+    let server_id_to_addr = settings.server_id_to_addr;
     let other_servers = server_id_to_addr
         .iter()
         .filter(|(id, _)| **id != server_id)
         .map(|(id, _)| *id)
         .collect();
 
-    let mut raft_grpc_transport =
-        RaftGrpcTransport::start_grpc_transport(server_id.clone(), server_id_to_addr).await;
-    let config = RaftConfig {
-        leader_heartbeat_interval: Duration::from_millis(args.leader_heartbeat_ms),
-        min_election_timeout_ms: 150,
-        max_election_timeout_ms: 300,
-    };
+    let mut raft_grpc_transport = RaftGrpcTransport::<StoreCommand>::start_grpc_transport(
+        server_id,
+        server_id_to_addr.clone(),
+    )
+    .await;
     let rng = ChaCha8Rng::from_entropy();
     let event_collector = NoOpRaftEventCollector {};
-    let raft_thread = start_raft_in_new_thread(
-        server_id.clone(),
+    // The store's snapshots are kept in the same database as the raft log
+    let database_path = settings.wal_log_dir.join("raft.redb");
+    let database = Arc::new(Database::create(&database_path)?);
+    let storage = RedbPersistentStorage::from_database(database.clone(), &database_path)?;
+    let state_machine = StoreStateMachine::open(database)?;
+    let raft_node = start_raft_in_new_thread(RaftNodeSetup {
+        server_id,
         other_servers,
-        args.wal_log_dir,
-        config,
+        storage,
+        application: state_machine.clone(),
+        config: settings.raft_config,
         rng,
-        raft_grpc_transport.transport_bridge,
+        transport_connector: raft_grpc_transport.transport_bridge,
         event_collector,
-    );
+    });
     raft_grpc_transport
         .grpc_server
-        .register_raft_thread(raft_thread);
+        .register_raft_thread(raft_node.thread().clone());
+    raft_grpc_transport
+        .grpc_server
+        .register_status_reader(raft_node.status_reader());
 
-    let app = SingleValueStoreImpl {};
+    let replication = Arc::new(Replication::new(
+        raft_node.proposer(),
+        raft_node.status_reader(),
+        server_id_to_addr,
+    ));
+    let app = SingleValueStoreImpl {
+        state_machine: state_machine.clone(),
+        replication: replication.clone(),
+    };
+    let kv_store = KvStoreImpl {
+        state_machine: state_machine.clone(),
+        replication: replication.clone(),
+    };
+    let lock_service = LockServiceImpl {
+        replication: replication.clone(),
+    };
+    tokio::spawn(lock_service::advance_log_time(replication, state_machine));
 
-    select! {
-        _ = raft_grpc_transport.message_sender_task => {},
-        _ = Server::builder()
+    if let Some(metrics_port) = settings.metrics_port {
+        let metrics_addr = SocketAddr::new(addr.ip(), metrics_port);
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve_metrics(metrics_addr).await {
+                error!("Metrics server stopped: {}", e);
+            }
+        });
+    }
+
+    let mut server = tokio::spawn(
+        Server::builder()
             .add_service(RaftConsensusServer::new(raft_grpc_transport.grpc_server))
             .add_service(SingleValueStoreServer::new(app))
-            .serve(addr) => {},
+            .add_service(KvStoreServer::new(kv_store))
+            .add_service(LockServiceServer::new(lock_service))
+            .serve(addr),
+    );
+    let mut message_sender_task = raft_grpc_transport.message_sender_task;
+
+    // Process managers like systemd and docker stop the server with SIGTERM, not an interrupt
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut server_stopped = false;
+    let mut message_sender_stopped = false;
+    select! {
+        _ = &mut message_sender_task => message_sender_stopped = true,
+        _ = &mut server => server_stopped = true,
+        _ = tokio::signal::ctrl_c() => {
+            info!("Received interrupt signal, shutting down...");
+        },
+        _ = terminate.recv() => {
+            info!("Received terminate signal, shutting down...");
+        },
+    }
+
+    // The server and the message sender keep running while the raft node shuts down, so a leader can still catch up
+    // the follower taking over and hear back from it. Shutdown blocks until the raft thread stops, so don't block the
+    // async runtime while waiting.
+    let raft_exit = tokio::task::spawn_blocking(move || raft_node.shutdown(true)).await?;
+    match raft_exit {
+        Ok(exit) => info!("Raft node stopped: {:?}", exit),
+        Err(_) => error!("Raft thread panicked while shutting down!"),
+    }
+    // The raft thread dropped its end of the transport, the sender stops once the messages it queued were sent
+    if !message_sender_stopped
+        && timeout(MESSAGE_FLUSH_TIMEOUT, message_sender_task)
+            .await
+            .is_err()
+    {
+        error!("Timed out sending the raft node's last messages");
+    }
+    // Client streams such as watches never end on their own, so don't wait for them
+    if !server_stopped {
+        server.abort();
     }
+    tracing_setup::shutdown_tracing();
 
     Ok(())
 }
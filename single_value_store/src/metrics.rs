@@ -0,0 +1,36 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use raft_consensus::{encode_metrics, METRICS_CONTENT_TYPE};
+use tracing::{error, info};
+
+async fn handle_request(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => match encode_metrics() {
+            Ok(metrics) => Response::builder()
+                .header(CONTENT_TYPE, METRICS_CONTENT_TYPE)
+                .body(Body::from(metrics)),
+            Err(e) => {
+                error!("Could not encode metrics: {}", e);
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+            }
+        },
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.expect("METRICS: Could not build response"))
+}
+
+/// Serves raft metrics in the prometheus text format at `http://<addr>/metrics`
+pub(crate) async fn serve_metrics(addr: SocketAddr) -> Result<(), hyper::Error> {
+    info!("Serving metrics at http://{}/metrics", addr);
+    let make_service =
+        make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle_request)) });
+    Server::bind(&addr).serve(make_service).await
+}
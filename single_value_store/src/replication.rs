@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use raft_consensus::{ProposeError, RaftNodeState, RaftNodeStatusReader, RaftProposer, ServerId};
+use single_value_store_proto::single_value_store::{ClientSession, LeaderHint};
+use tonic::metadata::MetadataValue;
+use tonic::Status;
+
+use crate::state_machine::{StoreCommand, StoreResponse};
+
+/// Routes client requests to the leader, shared by the gRPC services. Writes go through the raft log, reads are
+/// served from the leader's state once raft has confirmed it is still the leader.
+pub(crate) struct Replication {
+    proposer: RaftProposer<StoreCommand, StoreResponse>,
+    status_reader: RaftNodeStatusReader,
+    /// Addresses of the servers in the cluster, the store is served on the same address as raft
+    server_addresses: HashMap<ServerId, SocketAddr>,
+}
+impl Replication {
+    pub(crate) fn new(
+        proposer: RaftProposer<StoreCommand, StoreResponse>,
+        status_reader: RaftNodeStatusReader,
+        server_addresses: HashMap<ServerId, SocketAddr>,
+    ) -> Self {
+        Replication {
+            proposer,
+            status_reader,
+            server_addresses,
+        }
+    }
+
+    fn leader_hint(&self, leader_id: ServerId) -> LeaderHint {
+        LeaderHint {
+            leader_id: leader_id.0,
+            leader_address: self
+                .server_addresses
+                .get(&leader_id)
+                .map(|address| address.to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Status for a request that reached a follower, points the client at the leader if we know it
+    fn not_leader(&self, leader_hint: Option<ServerId>) -> Status {
+        match leader_hint {
+            Some(leader_id) => {
+                let hint = self.leader_hint(leader_id);
+                let mut status = Status::failed_precondition(format!(
+                    "Not the leader, send the request to server {} at {}",
+                    hint.leader_id, hint.leader_address
+                ));
+                let metadata = status.metadata_mut();
+                let _ = metadata.insert("leader-id", MetadataValue::from(hint.leader_id));
+                if let Ok(address) = hint.leader_address.parse() {
+                    let _ = metadata.insert("leader-address", address);
+                }
+                status
+            }
+            None => Status::unavailable("No leader has been elected yet, retry later"),
+        }
+    }
+
+    /// Waits until the state machine can be read without returning stale state, fails with a redirect unless we are
+    /// the leader. Returns the hint to send back with the read's response.
+    ///
+    /// Believing we are the leader is not enough, we may have been deposed without knowing it yet and the new leader
+    /// may have committed writes we don't have. So raft confirms with a round of heartbeats that a majority still
+    /// follows us, then the read waits until every write committed by then has been applied. Nothing is written to
+    /// the log for the read.
+    pub(crate) async fn read_index(&self) -> Result<LeaderHint, Status> {
+        let status = self.status_reader.status();
+        match self.proposer.read_index().await {
+            Ok(Ok(_)) => Ok(self.leader_hint(status.server_id)),
+            // Nothing was written for the read, so it can be redirected either way
+            Ok(Err(
+                ProposeError::NotLeader { leader_hint }
+                | ProposeError::LeadershipLost { leader_hint },
+            )) => Err(self.not_leader(leader_hint)),
+            Ok(Err(ProposeError::Storage(e))) => Err(Status::internal(e.to_string())),
+            Err(_) => Err(Status::unavailable("Raft node stopped!")),
+        }
+    }
+
+    /// Appends `command` to the log and waits until it has been committed and applied. Commands sent in a session
+    /// are applied at most once, a retry gets the response of the first attempt. If we lose leadership before the
+    /// command is committed it may or may not be applied, so the client is not redirected: a command in a session
+    /// fails as unavailable for the client to retry, one without a session is aborted.
+    pub(crate) async fn propose(
+        &self,
+        command: StoreCommand,
+        session: Option<ClientSession>,
+    ) -> Result<(StoreResponse, LeaderHint), Status> {
+        let in_session = session.is_some();
+        let command = match session {
+            // The state machine caches a response for the last sequence number, it starts out at 0 with none
+            Some(session) if session.sequence == 0 => {
+                return Err(Status::invalid_argument(
+                    "Sequence numbers in a session start at 1",
+                ))
+            }
+            Some(session) => StoreCommand::InSession {
+                session_id: session.session_id,
+                sequence: session.sequence,
+                timestamp_ms: timestamp_ms(),
+                command: Box::new(command),
+            },
+            None => command,
+        };
+        match self.proposer.propose(command).await {
+            Ok(Ok((_, StoreResponse::SessionExpired))) => Err(Status::not_found(
+                "Client session expired or was never registered, register a new one",
+            )),
+            Ok(Ok((_, StoreResponse::SequenceTooOld { last_sequence }))) => {
+                Err(Status::invalid_argument(format!(
+                    "Sequence number is older than the last one applied in the session ({})",
+                    last_sequence
+                )))
+            }
+            Ok(Ok((_, StoreResponse::SequenceReused))) => Err(Status::invalid_argument(
+                "Sequence number was already used for a different request in the session",
+            )),
+            Ok(Ok((_, response))) => Ok((
+                response,
+                self.leader_hint(self.status_reader.status().server_id),
+            )),
+            Ok(Err(ProposeError::NotLeader { leader_hint })) => Err(self.not_leader(leader_hint)),
+            // The new leader may still apply the command, redirecting the client there could apply it twice
+            Ok(Err(ProposeError::LeadershipLost { .. })) if in_session => Err(Status::unavailable(
+                "Lost leadership before the request was committed, its outcome is unknown, retry it with the same \
+                 sequence number",
+            )),
+            Ok(Err(ProposeError::LeadershipLost { .. })) => Err(Status::aborted(
+                "Lost leadership before the request was committed, its outcome is unknown, send it in a client \
+                 session to retry it safely",
+            )),
+            Ok(Err(ProposeError::Storage(e))) => Err(Status::internal(e.to_string())),
+            Err(_) => Err(Status::unavailable("Raft node stopped!")),
+        }
+    }
+
+    /// Writes the current time to the log if we are the leader, so leases expire even if nothing else is written
+    pub(crate) async fn advance_log_time(&self) -> Result<(), Status> {
+        if self.status_reader.status().state != RaftNodeState::Leader {
+            return Ok(());
+        }
+        let command = StoreCommand::AdvanceTime {
+            timestamp_ms: timestamp_ms(),
+        };
+        let _ = self.propose(command, None).await?;
+        Ok(())
+    }
+
+    /// Opens a client session through the log, returns its ID
+    pub(crate) async fn register_client(&self) -> Result<(u64, LeaderHint), Status> {
+        let command = StoreCommand::RegisterClient {
+            timestamp_ms: timestamp_ms(),
+        };
+        match self.propose(command, None).await? {
+            (StoreResponse::SessionRegistered { session_id }, leader_hint) => {
+                Ok((session_id, leader_hint))
+            }
+            (response, _) => Err(Status::internal(format!(
+                "Client registration applied with unexpected response: {:?}",
+                response
+            ))),
+        }
+    }
+}
+
+/// The leader's wall clock time, the state machine expires sessions and leases based on the times written to the log
+pub(crate) fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,940 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use raft_consensus::{ApplicationThatNeedsConsensus, LogIndex};
+use redb::{Database, TableDefinition};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{error, info};
+
+/// Snapshots of the store's state, kept in the same database as the raft log
+const SNAPSHOT_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("store_snapshot");
+const LATEST_SNAPSHOT_KEY: &str = "latest";
+/// Index the latest snapshot was taken at, bincode encoded, so tools can read it without decoding the snapshot
+const LATEST_SNAPSHOT_INDEX_KEY: &str = "latest_index";
+/// A snapshot is taken every time this many entries have been applied since the last one
+const SNAPSHOT_INTERVAL: u64 = 1000;
+/// Client sessions expire when they have not been used for this long in log time
+const SESSION_TIMEOUT_MS: u64 = 10 * 60 * 1000;
+/// Number of value changes kept in memory for watches to resume from
+const WATCH_HISTORY_LEN: usize = 1000;
+/// Changes a watch can fall behind by before it has to start over from the current value
+const WATCH_CHANNEL_CAPACITY: usize = 1024;
+
+/// Commands replicated through the raft log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum StoreCommand {
+    /// Replaces the value of the single value store
+    SetValue(u64),
+    /// Replaces the value of the single value store if it (and its version, if given) still is what the client
+    /// expects
+    CompareAndSet {
+        expected: u64,
+        expected_version: Option<u64>,
+        new_value: u64,
+    },
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    /// Opens a client session, its ID is the index of this entry
+    RegisterClient {
+        timestamp_ms: u64,
+    },
+    /// Grants the lock to `owner` for `ttl_ms` unless someone else holds it, the fencing token of a new lease is the
+    /// index of this entry. Acquiring a lock the owner already holds renews it.
+    AcquireLock {
+        name: String,
+        owner: String,
+        ttl_ms: u64,
+        timestamp_ms: u64,
+    },
+    /// Extends the lease with this fencing token to `ttl_ms` from now
+    RenewLock {
+        name: String,
+        fencing_token: u64,
+        ttl_ms: u64,
+        timestamp_ms: u64,
+    },
+    ReleaseLock {
+        name: String,
+        fencing_token: u64,
+    },
+    /// Moves log time forward, the leader proposes this regularly so leases expire without other writes
+    AdvanceTime {
+        timestamp_ms: u64,
+    },
+    /// A command sent in a client session, it is applied at most once for each sequence number
+    InSession {
+        session_id: u64,
+        sequence: u64,
+        timestamp_ms: u64,
+        command: Box<StoreCommand>,
+    },
+}
+
+/// Result of applying a `StoreCommand`, returned to the client that sent it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum StoreResponse {
+    Done,
+    /// The value of the single value store and its version after the command was applied, `written` is false if a
+    /// compare and set did not match
+    Value {
+        written: bool,
+        value: u64,
+        version: u64,
+    },
+    /// Whether the deleted key existed
+    Deleted(bool),
+    /// Whether the lock was acquired or renewed, and the lease that holds it after the command was applied
+    Lease {
+        granted: bool,
+        lease: Option<Lease>,
+    },
+    /// Whether the lease was released, false if it had expired or the fencing token is not the current one
+    Released(bool),
+    SessionRegistered {
+        session_id: u64,
+    },
+    /// The session was never registered or has expired, the command was not applied
+    SessionExpired,
+    /// The session already applied a later request than this one, its response is no longer cached
+    SequenceTooOld {
+        last_sequence: u64,
+    },
+    /// The session already applied a different command with this sequence number, the command was not applied
+    SequenceReused,
+}
+
+/// The last request a client applied in its session, see section 6.3 of the Raft thesis:
+/// <https://github.com/ongardie/dissertation>
+#[derive(Debug, Serialize, Deserialize)]
+struct ClientSession {
+    last_sequence: u64,
+    /// The command applied with `last_sequence`, a retry has to send the same one to get its response
+    last_command: Option<StoreCommand>,
+    last_response: StoreResponse,
+    /// Log time the session was last used at
+    last_active_ms: u64,
+}
+
+/// A lock held by `owner` until `expires_at_ms` in log time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Lease {
+    pub(crate) owner: String,
+    /// Index of the entry that granted the lease. It goes up with every lease granted, so the resources the lock
+    /// protects can reject writes from owners whose lease has since expired.
+    pub(crate) fencing_token: u64,
+    pub(crate) expires_at_ms: u64,
+}
+
+/// A write to the single value store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ValueChange {
+    pub(crate) value: u64,
+    /// Index of the entry that wrote the value
+    pub(crate) version: u64,
+}
+
+/// What a new watch sends before the changes it receives as they are applied
+#[derive(Debug)]
+pub(crate) enum WatchStart {
+    /// The changes from the requested index on
+    Changes(Vec<ValueChange>),
+    /// The current value, either because no index was requested or because the changes from it are no longer kept
+    Snapshot(ValueChange),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct StoreState {
+    value: u64,
+    /// Index of the entry that last wrote `value`, 0 if it was never written
+    version: u64,
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+    sessions: BTreeMap<u64, ClientSession>,
+    locks: BTreeMap<String, Lease>,
+    /// Latest timestamp the leader put in an entry, it never goes backwards even if a new leader's clock is behind
+    log_time_ms: u64,
+    /// Index of the last applied entry, a snapshot of this state covers the log up to and including it
+    last_applied: u64,
+    /// The latest changes to the value, oldest first
+    #[serde(skip)]
+    history: VecDeque<ValueChange>,
+    /// `history` has every change from this index on
+    #[serde(skip)]
+    history_start: u64,
+}
+impl StoreState {
+    pub(crate) fn value(&self) -> u64 {
+        self.value
+    }
+
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Earliest log time at which a lease runs out or a session expires, `None` if there is nothing to expire
+    pub(crate) fn next_expiry_ms(&self) -> Option<u64> {
+        let leases = self.locks.values().map(|lease| lease.expires_at_ms);
+        let sessions = self
+            .sessions
+            .values()
+            .map(|session| session.last_active_ms + SESSION_TIMEOUT_MS);
+        leases.chain(sessions).min()
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
+        self.entries.get(key)
+    }
+
+    /// Up to `limit` entries with keys from `start` (inclusive) to `end` (exclusive, no end if `None`) in key order
+    pub(crate) fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        if matches!(end, Some(end) if end <= start) {
+            return vec![];
+        }
+        let end = end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.to_vec()));
+        self.entries
+            .range((Bound::Included(start.to_vec()), end))
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    pub(crate) fn current_value(&self) -> ValueChange {
+        ValueChange {
+            value: self.value,
+            version: self.version,
+        }
+    }
+
+    /// The changes from `from_index` on, `None` if some of them are no longer kept
+    fn changes_since(&self, from_index: u64) -> Option<Vec<ValueChange>> {
+        if from_index < self.history_start {
+            return None;
+        }
+        Some(
+            self.history
+                .iter()
+                .filter(|change| change.version >= from_index)
+                .copied()
+                .collect(),
+        )
+    }
+
+    fn record_change(&mut self, change: ValueChange) {
+        self.history.push_back(change);
+        if self.history.len() > WATCH_HISTORY_LEN {
+            if let Some(dropped) = self.history.pop_front() {
+                self.history_start = dropped.version + 1;
+            }
+        }
+    }
+
+    fn value_response(&self, written: bool) -> StoreResponse {
+        StoreResponse::Value {
+            written,
+            value: self.value,
+            version: self.version,
+        }
+    }
+
+    /// Only depends on the state and the command, so every server ends up with the same state and response
+    fn apply(&mut self, log_index: LogIndex, command: StoreCommand) -> StoreResponse {
+        match command {
+            StoreCommand::SetValue(value) => {
+                self.value = value;
+                self.version = log_index.0;
+                self.value_response(true)
+            }
+            StoreCommand::CompareAndSet {
+                expected,
+                expected_version,
+                new_value,
+            } => {
+                let matches = self.value == expected
+                    && expected_version.is_none_or(|version| version == self.version);
+                if matches {
+                    self.value = new_value;
+                    self.version = log_index.0;
+                }
+                self.value_response(matches)
+            }
+            StoreCommand::Put { key, value } => {
+                let _ = self.entries.insert(key, value);
+                StoreResponse::Done
+            }
+            StoreCommand::Delete { key } => {
+                StoreResponse::Deleted(self.entries.remove(&key).is_some())
+            }
+            StoreCommand::AcquireLock {
+                name,
+                owner,
+                ttl_ms,
+                timestamp_ms,
+            } => {
+                self.advance_log_time(timestamp_ms);
+                let expires_at_ms = self.log_time_ms + ttl_ms;
+                let lease = self.locks.entry(name).or_insert_with(|| Lease {
+                    owner: owner.clone(),
+                    fencing_token: log_index.0,
+                    expires_at_ms,
+                });
+                let granted = lease.owner == owner;
+                if granted {
+                    lease.expires_at_ms = expires_at_ms;
+                }
+                StoreResponse::Lease {
+                    granted,
+                    lease: Some(lease.clone()),
+                }
+            }
+            StoreCommand::RenewLock {
+                name,
+                fencing_token,
+                ttl_ms,
+                timestamp_ms,
+            } => {
+                self.advance_log_time(timestamp_ms);
+                let log_time_ms = self.log_time_ms;
+                let lease = self.locks.get_mut(&name);
+                let granted = matches!(&lease, Some(lease) if lease.fencing_token == fencing_token);
+                let lease = lease.map(|lease| {
+                    if granted {
+                        lease.expires_at_ms = log_time_ms + ttl_ms;
+                    }
+                    lease.clone()
+                });
+                StoreResponse::Lease { granted, lease }
+            }
+            StoreCommand::ReleaseLock {
+                name,
+                fencing_token,
+            } => {
+                let released = matches!(self.locks.get(&name), Some(lease) if lease.fencing_token == fencing_token);
+                if released {
+                    let _ = self.locks.remove(&name);
+                }
+                StoreResponse::Released(released)
+            }
+            StoreCommand::AdvanceTime { timestamp_ms } => {
+                self.advance_log_time(timestamp_ms);
+                StoreResponse::Done
+            }
+            StoreCommand::RegisterClient { timestamp_ms } => {
+                self.advance_log_time(timestamp_ms);
+                let _ = self.sessions.insert(
+                    log_index.0,
+                    ClientSession {
+                        // Sequence numbers start at 1
+                        last_sequence: 0,
+                        last_command: None,
+                        last_response: StoreResponse::Done,
+                        last_active_ms: self.log_time_ms,
+                    },
+                );
+                StoreResponse::SessionRegistered {
+                    session_id: log_index.0,
+                }
+            }
+            StoreCommand::InSession {
+                session_id,
+                sequence,
+                timestamp_ms,
+                command,
+            } => {
+                self.advance_log_time(timestamp_ms);
+                let log_time_ms = self.log_time_ms;
+                let session = match self.sessions.get_mut(&session_id) {
+                    Some(session) => session,
+                    None => return StoreResponse::SessionExpired,
+                };
+                session.last_active_ms = log_time_ms;
+                if sequence == session.last_sequence {
+                    // The cached response only answers a retry of the same command
+                    return match &session.last_command {
+                        Some(last_command) if *last_command == *command => {
+                            session.last_response.clone()
+                        }
+                        _ => StoreResponse::SequenceReused,
+                    };
+                }
+                if sequence < session.last_sequence {
+                    return StoreResponse::SequenceTooOld {
+                        last_sequence: session.last_sequence,
+                    };
+                }
+                let response = self.apply(log_index, (*command).clone());
+                if let Some(session) = self.sessions.get_mut(&session_id) {
+                    session.last_sequence = sequence;
+                    session.last_command = Some(*command);
+                    session.last_response = response.clone();
+                }
+                response
+            }
+        }
+    }
+
+    /// Moves log time forward to `timestamp_ms`, expires the sessions that have been idle for too long and the
+    /// leases that have run out. Expiry only depends on timestamps in the log, so every server expires the same
+    /// sessions and leases at the same entry.
+    fn advance_log_time(&mut self, timestamp_ms: u64) {
+        self.log_time_ms = self.log_time_ms.max(timestamp_ms);
+        let log_time_ms = self.log_time_ms;
+        self.sessions
+            .retain(|_, session| log_time_ms - session.last_active_ms < SESSION_TIMEOUT_MS);
+        self.locks
+            .retain(|_, lease| lease.expires_at_ms > log_time_ms);
+    }
+}
+
+/// The store's state, kept in memory and snapshotted to the raft database. The raft thread applies committed
+/// commands to it and the gRPC services read it through clones.
+///
+/// On startup the state is restored from the latest snapshot, raft then only hands us the entries after it. Raft keeps
+/// the whole log, it can't send a snapshot to a follower that fell behind yet.
+#[derive(Debug, Clone)]
+pub(crate) struct StoreStateMachine {
+    state: Arc<RwLock<StoreState>>,
+    database: Arc<Database>,
+    /// Every change to the value is sent to the watches as it is applied
+    changes: broadcast::Sender<ValueChange>,
+    /// Index the last snapshot was taken at, only the raft thread's copy applies entries and keeps this up to date
+    last_snapshot: u64,
+}
+impl StoreStateMachine {
+    /// Restores the state from the latest snapshot in `database`, or starts empty if there is none
+    pub(crate) fn open(database: Arc<Database>) -> Result<Self, Box<dyn Error>> {
+        // Make sure the table exists so the read transaction doesn't fail on a new database
+        let txn = database.begin_write()?;
+        let _ = txn.open_table(SNAPSHOT_TABLE)?;
+        txn.commit()?;
+
+        let txn = database.begin_read()?;
+        let mut state: StoreState =
+            match txn.open_table(SNAPSHOT_TABLE)?.get(LATEST_SNAPSHOT_KEY)? {
+                Some(bytes) => bincode::deserialize(bytes.value())?,
+                None => StoreState::default(),
+            };
+        info!(
+            "Restored store state from snapshot at index {:?} ({} keys)",
+            state.last_applied,
+            state.entries.len()
+        );
+        // Changes before the snapshot are not kept, the entries replayed after it are
+        state.history_start = state.last_applied + 1;
+        let (changes, _) = broadcast::channel(WATCH_CHANNEL_CAPACITY);
+        Ok(StoreStateMachine {
+            last_snapshot: state.last_applied,
+            state: Arc::new(RwLock::new(state)),
+            database,
+            changes,
+        })
+    }
+
+    pub(crate) fn read(&self) -> RwLockReadGuard<'_, StoreState> {
+        self.state.read().expect("APP: Store state lock poisoned!")
+    }
+
+    /// Starts watching the value from `from_index` on, or from the current value if it is `None`. The receiver gets
+    /// every change applied after the watch started, it may repeat the last ones in `WatchStart`.
+    pub(crate) fn watch(
+        &self,
+        from_index: Option<u64>,
+    ) -> (WatchStart, broadcast::Receiver<ValueChange>) {
+        // Subscribe before reading the state, changes are recorded and sent under the write lock so none are missed
+        let receiver = self.changes.subscribe();
+        let state = self.read();
+        let start = match from_index.and_then(|from_index| state.changes_since(from_index)) {
+            Some(changes) => WatchStart::Changes(changes),
+            None => WatchStart::Snapshot(state.current_value()),
+        };
+        (start, receiver)
+    }
+
+    fn take_snapshot(&mut self) -> Result<(), Box<dyn Error>> {
+        let (last_applied, bytes) = {
+            let state = self.read();
+            (state.last_applied, bincode::serialize(&*state)?)
+        };
+        let txn = self.database.begin_write()?;
+        {
+            let mut table = txn.open_table(SNAPSHOT_TABLE)?;
+            let _ = table.insert(LATEST_SNAPSHOT_KEY, bytes.as_slice())?;
+            let index_bytes = bincode::serialize(&last_applied)?;
+            let _ = table.insert(LATEST_SNAPSHOT_INDEX_KEY, index_bytes.as_slice())?;
+        }
+        txn.commit()?;
+        info!("Took snapshot of store state at index {:?}", last_applied);
+        Ok(())
+    }
+}
+impl ApplicationThatNeedsConsensus for StoreStateMachine {
+    type Command = StoreCommand;
+    type Response = StoreResponse;
+    type Error = ();
+
+    fn apply(&mut self, log_index: LogIndex, command: StoreCommand) -> Result<StoreResponse, ()> {
+        let response = {
+            let mut state = self.state.write().expect("APP: Store state lock poisoned!");
+            state.last_applied = log_index.0;
+            let version = state.version;
+            let response = state.apply(log_index, command);
+            if state.version != version {
+                let change = state.current_value();
+                state.record_change(change);
+                // Fails if nobody is watching
+                let _ = self.changes.send(change);
+            }
+            response
+        };
+        if log_index.0 - self.last_snapshot >= SNAPSHOT_INTERVAL {
+            // Raft keeps the whole log, so a failed snapshot only means more entries are replayed on restart
+            if let Err(e) = self.take_snapshot() {
+                error!("Could not take snapshot of store state: {}", e);
+            }
+            self.last_snapshot = log_index.0;
+        }
+        Ok(response)
+    }
+
+    fn last_applied_index(&self) -> LogIndex {
+        LogIndex(self.read().last_applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &str, value: &str) -> StoreCommand {
+        StoreCommand::Put {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    fn open_database(dir: &tempfile::TempDir) -> Arc<Database> {
+        Arc::new(Database::create(dir.path().join("raft.redb")).unwrap())
+    }
+
+    fn keys(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<String> {
+        entries
+            .into_iter()
+            .map(|(key, _)| String::from_utf8(key).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn should_put_and_delete_keys() {
+        let mut state = StoreState::default();
+        assert_eq!(state.apply(LogIndex(1), put("a", "1")), StoreResponse::Done);
+        assert_eq!(state.apply(LogIndex(2), put("a", "2")), StoreResponse::Done);
+        assert_eq!(state.get(b"a"), Some(&b"2".to_vec()));
+
+        let delete = |key: &str| StoreCommand::Delete {
+            key: key.as_bytes().to_vec(),
+        };
+        assert_eq!(
+            state.apply(LogIndex(3), delete("a")),
+            StoreResponse::Deleted(true)
+        );
+        assert_eq!(
+            state.apply(LogIndex(4), delete("a")),
+            StoreResponse::Deleted(false)
+        );
+        assert_eq!(state.get(b"a"), None);
+    }
+
+    #[test]
+    fn should_scan_keys_in_range_up_to_limit() {
+        let mut state = StoreState::default();
+        for (i, key) in ["d", "a", "c", "b", "e"].iter().enumerate() {
+            let _ = state.apply(LogIndex(i as u64 + 1), put(key, key));
+        }
+        assert_eq!(keys(state.scan(b"b", Some(b"d"), 10)), ["b", "c"]);
+        assert_eq!(keys(state.scan(b"b", None, 10)), ["b", "c", "d", "e"]);
+        assert_eq!(keys(state.scan(b"", None, 2)), ["a", "b"]);
+        assert_eq!(keys(state.scan(b"f", None, 10)), Vec::<String>::new());
+    }
+
+    #[test]
+    fn should_scan_nothing_for_empty_or_inverted_ranges() {
+        let mut state = StoreState::default();
+        let _ = state.apply(LogIndex(1), put("a", "1"));
+        let _ = state.apply(LogIndex(2), put("b", "2"));
+        assert!(state.scan(b"a", Some(b"a"), 10).is_empty());
+        // A BTreeMap range panics if the start is after the end
+        assert!(state.scan(b"b", Some(b"a"), 10).is_empty());
+        assert!(state.scan(b"a", None, 0).is_empty());
+    }
+
+    fn value(written: bool, value: u64, version: u64) -> StoreResponse {
+        StoreResponse::Value {
+            written,
+            value,
+            version,
+        }
+    }
+
+    #[test]
+    fn should_set_version_to_index_of_every_write() {
+        let mut state = StoreState::default();
+        assert_eq!(
+            state.current_value(),
+            ValueChange {
+                value: 0,
+                version: 0
+            }
+        );
+        assert_eq!(
+            state.apply(LogIndex(3), StoreCommand::SetValue(7)),
+            value(true, 7, 3)
+        );
+        // Writing the same value again is still a new version
+        assert_eq!(
+            state.apply(LogIndex(5), StoreCommand::SetValue(7)),
+            value(true, 7, 5)
+        );
+        let _ = state.apply(LogIndex(6), put("a", "1"));
+        assert_eq!(state.version(), 5);
+    }
+
+    #[test]
+    fn should_compare_and_set_value_without_expected_version() {
+        let mut state = StoreState::default();
+        let _ = state.apply(LogIndex(1), StoreCommand::SetValue(7));
+        let cas = |expected, new_value| StoreCommand::CompareAndSet {
+            expected,
+            expected_version: None,
+            new_value,
+        };
+        assert_eq!(state.apply(LogIndex(2), cas(8, 9)), value(false, 7, 1));
+        assert_eq!(state.apply(LogIndex(3), cas(7, 9)), value(true, 9, 3));
+        assert_eq!(state.apply(LogIndex(4), cas(7, 10)), value(false, 9, 3));
+    }
+
+    #[test]
+    fn should_compare_and_set_value_only_if_expected_version_matches() {
+        let mut state = StoreState::default();
+        let _ = state.apply(LogIndex(1), StoreCommand::SetValue(7));
+        let _ = state.apply(LogIndex(2), StoreCommand::SetValue(7));
+        let cas = |expected, expected_version, new_value| StoreCommand::CompareAndSet {
+            expected,
+            expected_version: Some(expected_version),
+            new_value,
+        };
+        // The value matches, but it was written again since version 1
+        assert_eq!(state.apply(LogIndex(3), cas(7, 1, 9)), value(false, 7, 2));
+        assert_eq!(state.apply(LogIndex(4), cas(8, 2, 9)), value(false, 7, 2));
+        assert_eq!(state.apply(LogIndex(5), cas(7, 2, 9)), value(true, 9, 5));
+    }
+
+    #[test]
+    fn should_answer_retry_in_session_with_cached_response_only_for_same_command() {
+        let mut state = StoreState::default();
+        let _ = state.apply(
+            LogIndex(1),
+            StoreCommand::RegisterClient { timestamp_ms: 0 },
+        );
+        let in_session = |sequence, command| StoreCommand::InSession {
+            session_id: 1,
+            sequence,
+            timestamp_ms: 0,
+            command: Box::new(command),
+        };
+        // Nothing was applied with sequence 0, there is no response to return for it
+        assert_eq!(
+            state.apply(LogIndex(2), in_session(0, StoreCommand::SetValue(7))),
+            StoreResponse::SequenceReused
+        );
+        assert_eq!(
+            state.apply(LogIndex(3), in_session(1, StoreCommand::SetValue(7))),
+            value(true, 7, 3)
+        );
+        let _ = state.apply(LogIndex(4), StoreCommand::SetValue(8));
+        assert_eq!(
+            state.apply(LogIndex(5), in_session(1, StoreCommand::SetValue(7))),
+            value(true, 7, 3)
+        );
+        assert_eq!(state.value(), 8);
+        assert_eq!(
+            state.apply(LogIndex(6), in_session(1, put("a", "1"))),
+            StoreResponse::SequenceReused
+        );
+        assert_eq!(state.get(b"a"), None);
+        let _ = state.apply(LogIndex(7), in_session(2, put("a", "1")));
+        assert_eq!(
+            state.apply(LogIndex(8), in_session(1, StoreCommand::SetValue(7))),
+            StoreResponse::SequenceTooOld { last_sequence: 2 }
+        );
+    }
+
+    fn acquire(owner: &str, ttl_ms: u64, timestamp_ms: u64) -> StoreCommand {
+        StoreCommand::AcquireLock {
+            name: "lock".to_string(),
+            owner: owner.to_string(),
+            ttl_ms,
+            timestamp_ms,
+        }
+    }
+
+    fn lease(owner: &str, fencing_token: u64, expires_at_ms: u64) -> Option<Lease> {
+        Some(Lease {
+            owner: owner.to_string(),
+            fencing_token,
+            expires_at_ms,
+        })
+    }
+
+    #[test]
+    fn should_expire_lease_when_log_time_passes_its_ttl() {
+        let mut state = StoreState::default();
+        assert_eq!(state.next_expiry_ms(), None);
+        let _ = state.apply(LogIndex(1), acquire("a", 100, 1000));
+        assert_eq!(state.next_expiry_ms(), Some(1100));
+        assert_eq!(
+            state.apply(LogIndex(2), acquire("b", 100, 1099)),
+            StoreResponse::Lease {
+                granted: false,
+                lease: lease("a", 1, 1100)
+            }
+        );
+        let _ = state.apply(
+            LogIndex(3),
+            StoreCommand::AdvanceTime { timestamp_ms: 1100 },
+        );
+        assert_eq!(state.next_expiry_ms(), None);
+        // A new leader's clock may be behind, log time never goes backwards
+        assert_eq!(
+            state.apply(LogIndex(4), acquire("b", 100, 900)),
+            StoreResponse::Lease {
+                granted: true,
+                lease: lease("b", 4, 1200)
+            }
+        );
+    }
+
+    #[test]
+    fn should_expire_sessions_idle_for_the_session_timeout() {
+        let mut state = StoreState::default();
+        let _ = state.apply(
+            LogIndex(1),
+            StoreCommand::RegisterClient { timestamp_ms: 0 },
+        );
+        let _ = state.apply(LogIndex(2), acquire("a", 100, 50));
+        // The lease runs out first, the session is due once it has been idle for the timeout
+        assert_eq!(state.next_expiry_ms(), Some(150));
+        let _ = state.apply(LogIndex(3), StoreCommand::AdvanceTime { timestamp_ms: 150 });
+        assert_eq!(state.next_expiry_ms(), Some(SESSION_TIMEOUT_MS));
+        let _ = state.apply(
+            LogIndex(4),
+            StoreCommand::AdvanceTime {
+                timestamp_ms: SESSION_TIMEOUT_MS,
+            },
+        );
+        assert_eq!(state.next_expiry_ms(), None);
+    }
+
+    #[test]
+    fn should_grant_new_fencing_token_only_to_new_lease() {
+        let mut state = StoreState::default();
+        let _ = state.apply(LogIndex(1), acquire("a", 100, 0));
+        // Acquiring a lock the owner holds renews the lease it has
+        assert_eq!(
+            state.apply(LogIndex(2), acquire("a", 100, 50)),
+            StoreResponse::Lease {
+                granted: true,
+                lease: lease("a", 1, 150)
+            }
+        );
+        let _ = state.apply(LogIndex(3), StoreCommand::AdvanceTime { timestamp_ms: 150 });
+        assert_eq!(
+            state.apply(LogIndex(4), acquire("a", 100, 150)),
+            StoreResponse::Lease {
+                granted: true,
+                lease: lease("a", 4, 250)
+            }
+        );
+    }
+
+    #[test]
+    fn should_not_renew_or_release_lease_with_stale_fencing_token() {
+        let mut state = StoreState::default();
+        let _ = state.apply(LogIndex(1), acquire("a", 100, 0));
+        let _ = state.apply(LogIndex(2), StoreCommand::AdvanceTime { timestamp_ms: 100 });
+        let _ = state.apply(LogIndex(3), acquire("b", 100, 100));
+        let renew = |fencing_token| StoreCommand::RenewLock {
+            name: "lock".to_string(),
+            fencing_token,
+            ttl_ms: 100,
+            timestamp_ms: 150,
+        };
+        let release = |fencing_token| StoreCommand::ReleaseLock {
+            name: "lock".to_string(),
+            fencing_token,
+        };
+        assert_eq!(
+            state.apply(LogIndex(4), renew(1)),
+            StoreResponse::Lease {
+                granted: false,
+                lease: lease("b", 3, 200)
+            }
+        );
+        assert_eq!(
+            state.apply(LogIndex(5), release(1)),
+            StoreResponse::Released(false)
+        );
+        assert_eq!(
+            state.apply(LogIndex(6), renew(3)),
+            StoreResponse::Lease {
+                granted: true,
+                lease: lease("b", 3, 250)
+            }
+        );
+        assert_eq!(
+            state.apply(LogIndex(7), release(3)),
+            StoreResponse::Released(true)
+        );
+        assert_eq!(
+            state.apply(LogIndex(8), renew(3)),
+            StoreResponse::Lease {
+                granted: false,
+                lease: None
+            }
+        );
+    }
+
+    fn set_value(machine: &mut StoreStateMachine, index: u64) {
+        let _ = machine
+            .apply(LogIndex(index), StoreCommand::SetValue(index * 10))
+            .unwrap();
+    }
+
+    fn change(version: u64) -> ValueChange {
+        ValueChange {
+            value: version * 10,
+            version,
+        }
+    }
+
+    #[test]
+    fn should_resume_watch_from_an_index_in_the_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut machine = StoreStateMachine::open(open_database(&dir)).unwrap();
+        for index in 1..=3 {
+            set_value(&mut machine, index);
+        }
+
+        let (start, mut receiver) = machine.watch(Some(2));
+        match start {
+            WatchStart::Changes(changes) => assert_eq!(changes, vec![change(2), change(3)]),
+            other => panic!("Expected the changes from index 2 on, got {:?}", other),
+        }
+        // Changes applied after the watch started are sent to it
+        set_value(&mut machine, 4);
+        assert_eq!(receiver.try_recv().unwrap(), change(4));
+    }
+
+    #[test]
+    fn should_fall_back_to_snapshot_when_resuming_before_the_history() {
+        let mut state = StoreState::default();
+        let last_version = WATCH_HISTORY_LEN as u64 + 2;
+        for version in 1..=last_version {
+            state.record_change(change(version));
+        }
+        // The two oldest changes were dropped
+        assert_eq!(state.history_start, 3);
+        assert_eq!(state.changes_since(2), None);
+        let changes = state.changes_since(3).unwrap();
+        assert_eq!(changes.len(), WATCH_HISTORY_LEN);
+        assert_eq!(changes.first(), Some(&change(3)));
+        assert_eq!(changes.last(), Some(&change(last_version)));
+    }
+
+    #[test]
+    fn should_only_resume_watch_after_the_snapshot_on_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut machine = StoreStateMachine::open(open_database(&dir)).unwrap();
+        for index in 1..=3 {
+            set_value(&mut machine, index);
+        }
+        machine.take_snapshot().unwrap();
+        drop(machine);
+
+        let mut machine = StoreStateMachine::open(open_database(&dir)).unwrap();
+        assert_eq!(machine.read().history_start, 4);
+        // Raft replays the entries after the snapshot
+        set_value(&mut machine, 4);
+
+        match machine.watch(Some(3)).0 {
+            WatchStart::Snapshot(current) => assert_eq!(current, change(4)),
+            other => panic!("Expected the current value, got {:?}", other),
+        }
+        match machine.watch(Some(4)).0 {
+            WatchStart::Changes(changes) => assert_eq!(changes, vec![change(4)]),
+            other => panic!("Expected the changes from index 4 on, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_restore_state_from_snapshot_on_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut machine = StoreStateMachine::open(open_database(&dir)).unwrap();
+        assert_eq!(machine.last_applied_index(), LogIndex(0));
+        let _ = machine
+            .apply(LogIndex(1), StoreCommand::SetValue(42))
+            .unwrap();
+        let _ = machine.apply(LogIndex(2), put("a", "1")).unwrap();
+        machine.take_snapshot().unwrap();
+        // Applied after the snapshot, raft replays it on restart
+        let _ = machine.apply(LogIndex(3), put("b", "2")).unwrap();
+        drop(machine);
+
+        let machine = StoreStateMachine::open(open_database(&dir)).unwrap();
+        assert_eq!(machine.last_applied_index(), LogIndex(2));
+        let state = machine.read();
+        assert_eq!(
+            state.current_value(),
+            ValueChange {
+                value: 42,
+                version: 1
+            }
+        );
+        assert_eq!(state.get(b"a"), Some(&b"1".to_vec()));
+        assert_eq!(state.get(b"b"), None);
+    }
+
+    #[test]
+    fn should_snapshot_every_interval_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut machine = StoreStateMachine::open(open_database(&dir)).unwrap();
+        for index in 1..=SNAPSHOT_INTERVAL + 1 {
+            let _ = machine
+                .apply(LogIndex(index), StoreCommand::SetValue(index))
+                .unwrap();
+        }
+        drop(machine);
+
+        let machine = StoreStateMachine::open(open_database(&dir)).unwrap();
+        assert_eq!(machine.last_applied_index(), LogIndex(SNAPSHOT_INTERVAL));
+        assert_eq!(machine.read().value(), SNAPSHOT_INTERVAL);
+        // Changes before the snapshot are gone, a watch from before it starts from the current value
+        assert!(matches!(
+            machine.watch(Some(1)).0,
+            WatchStart::Snapshot(ValueChange {
+                version: SNAPSHOT_INTERVAL,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,51 @@
+use opentelemetry::sdk::trace::{config, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::TraceError;
+use opentelemetry::{global, runtime, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use raft_grpc::tracing_context::install_trace_context_propagator;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+fn otlp_tracer(server_id: u32, otlp_endpoint: &str) -> Result<Tracer, TraceError> {
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(otlp_endpoint),
+        )
+        .with_trace_config(config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", "single_value_store"),
+            KeyValue::new("service.instance.id", server_id.to_string()),
+        ])))
+        .install_batch(runtime::Tokio)
+}
+
+/// Logs to stdout and, if an OTLP endpoint is given, exports spans to it.
+/// Trace context is propagated to other servers either way so traces aren't broken by servers that don't export.
+pub(crate) fn init_tracing(
+    server_id: u32,
+    otlp_endpoint: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    install_trace_context_propagator();
+
+    let otlp_layer = match otlp_endpoint {
+        Some(otlp_endpoint) => {
+            Some(tracing_opentelemetry::layer().with_tracer(otlp_tracer(server_id, otlp_endpoint)?))
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer())
+        .with(otlp_layer)
+        .try_init()?;
+    Ok(())
+}
+
+/// Flushes spans that haven't been exported yet
+pub(crate) fn shutdown_tracing() {
+    global::shutdown_tracer_provider();
+}
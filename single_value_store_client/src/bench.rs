@@ -0,0 +1,236 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hdrhistogram::Histogram;
+use rand::Rng;
+use serde::Serialize;
+use single_value_store_client::StoreClient;
+use single_value_store_proto::single_value_store::SetRequest;
+use tracing::{info, warn};
+
+/// Latencies above this are recorded as this, in microseconds
+const MAX_LATENCY_MICROS: u64 = 60 * 1000 * 1000;
+/// Runs for this long if neither a duration nor an operation count is given
+const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct BenchArgs {
+    /// Number of concurrent workers, each sends one request at a time
+    #[arg(long, default_value_t = 16)]
+    workers: usize,
+    /// Fraction of operations that are gets, the others are sets
+    #[arg(long, default_value_t = 0.9)]
+    read_ratio: f64,
+    /// Stop after this many seconds
+    #[arg(long)]
+    duration_secs: Option<u64>,
+    /// Stop after this many operations over all workers
+    #[arg(long)]
+    ops: Option<u64>,
+    /// Write the results as JSON to this file
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Get,
+    Set,
+}
+
+/// Latencies of the operations a worker completed, in microseconds
+struct WorkerResult {
+    get: Histogram<u64>,
+    set: Histogram<u64>,
+    errors: u64,
+}
+impl WorkerResult {
+    fn new() -> Self {
+        WorkerResult {
+            get: new_histogram(),
+            set: new_histogram(),
+            errors: 0,
+        }
+    }
+}
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY_MICROS, 3).expect("BENCH: Invalid histogram bounds")
+}
+
+#[derive(Debug, Serialize)]
+struct LatencySummary {
+    count: u64,
+    mean_us: f64,
+    p50_us: u64,
+    p99_us: u64,
+    p999_us: u64,
+    max_us: u64,
+}
+impl LatencySummary {
+    fn from_histogram(histogram: &Histogram<u64>) -> Self {
+        LatencySummary {
+            count: histogram.len(),
+            mean_us: histogram.mean(),
+            p50_us: histogram.value_at_quantile(0.5),
+            p99_us: histogram.value_at_quantile(0.99),
+            p999_us: histogram.value_at_quantile(0.999),
+            max_us: histogram.max(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BenchReport {
+    workers: usize,
+    read_ratio: f64,
+    elapsed_secs: f64,
+    operations: u64,
+    errors: u64,
+    /// Completed operations per second, failed ones are not counted
+    throughput_ops_per_sec: f64,
+    all: LatencySummary,
+    get: LatencySummary,
+    set: LatencySummary,
+}
+
+/// Whether a worker should start another operation, claims it from the operation budget if there is one
+fn claim_operation(args: &BenchArgs, started: Instant, claimed: &AtomicU64) -> bool {
+    let duration = match (args.duration_secs, args.ops) {
+        (Some(duration_secs), _) => Some(Duration::from_secs(duration_secs)),
+        (None, None) => Some(DEFAULT_DURATION),
+        (None, Some(_)) => None,
+    };
+    if matches!(duration, Some(duration) if started.elapsed() >= duration) {
+        return false;
+    }
+    match args.ops {
+        Some(ops) => claimed.fetch_add(1, Ordering::Relaxed) < ops,
+        None => true,
+    }
+}
+
+async fn run_worker(
+    client: Arc<StoreClient>,
+    args: BenchArgs,
+    started: Instant,
+    claimed: Arc<AtomicU64>,
+) -> WorkerResult {
+    let mut result = WorkerResult::new();
+    while claim_operation(&args, started, &claimed) {
+        // The thread's RNG can't be held across awaits, the task may move to another thread
+        let (operation, value) = {
+            let mut rng = rand::thread_rng();
+            let operation = if rng.gen_bool(args.read_ratio) {
+                Operation::Get
+            } else {
+                Operation::Set
+            };
+            (operation, rng.gen())
+        };
+        let sent = Instant::now();
+        let response = match operation {
+            Operation::Get => client.get().await.map(|_| ()),
+            Operation::Set => client
+                .set(SetRequest {
+                    value,
+                    session: None,
+                })
+                .await
+                .map(|_| ()),
+        };
+        let latency_micros = sent.elapsed().as_micros() as u64;
+        match response {
+            Ok(()) => {
+                let histogram = match operation {
+                    Operation::Get => &mut result.get,
+                    Operation::Set => &mut result.set,
+                };
+                histogram.saturating_record(latency_micros.max(1));
+            }
+            Err(status) => {
+                warn!("{:?} failed: {}", operation, status);
+                result.errors += 1;
+            }
+        }
+    }
+    result
+}
+
+/// Runs the workers until the duration or operation count is reached and reports throughput and latencies
+pub(crate) async fn run_bench(
+    client: StoreClient,
+    args: BenchArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !(0.0..=1.0).contains(&args.read_ratio) {
+        return Err("--read-ratio must be between 0 and 1".into());
+    }
+    info!(
+        "Running benchmark with {} workers, {}% gets",
+        args.workers,
+        args.read_ratio * 100.0
+    );
+    let client = Arc::new(client);
+    let claimed = Arc::new(AtomicU64::new(0));
+    let started = Instant::now();
+    let workers: Vec<_> = (0..args.workers)
+        .map(|_| {
+            tokio::spawn(run_worker(
+                client.clone(),
+                args.clone(),
+                started,
+                claimed.clone(),
+            ))
+        })
+        .collect();
+
+    let mut total = WorkerResult::new();
+    for worker in workers {
+        let result = worker.await?;
+        total.get.add(&result.get)?;
+        total.set.add(&result.set)?;
+        total.errors += result.errors;
+    }
+    let elapsed = started.elapsed();
+    let mut all = total.get.clone();
+    all.add(&total.set)?;
+
+    let report = BenchReport {
+        workers: args.workers,
+        read_ratio: args.read_ratio,
+        elapsed_secs: elapsed.as_secs_f64(),
+        operations: all.len(),
+        errors: total.errors,
+        throughput_ops_per_sec: all.len() as f64 / elapsed.as_secs_f64(),
+        all: LatencySummary::from_histogram(&all),
+        get: LatencySummary::from_histogram(&total.get),
+        set: LatencySummary::from_histogram(&total.set),
+    };
+    info!(
+        "{} operations ({} errors) in {:.2}s: {:.1} ops/s",
+        report.operations, report.errors, report.elapsed_secs, report.throughput_ops_per_sec
+    );
+    for (name, summary) in [
+        ("all", &report.all),
+        ("get", &report.get),
+        ("set", &report.set),
+    ] {
+        info!(
+            "{:>3}: count {} mean {:.0}us p50 {}us p99 {}us p999 {}us max {}us",
+            name,
+            summary.count,
+            summary.mean_us,
+            summary.p50_us,
+            summary.p99_us,
+            summary.p999_us,
+            summary.max_us
+        );
+    }
+    if let Some(output) = &args.output {
+        std::fs::write(output, serde_json::to_string_pretty(&report)?)?;
+        info!("Wrote results to {:?}", output);
+    }
+    Ok(())
+}
@@ -0,0 +1,617 @@
+//! Client for the single value store servers that talks to the whole cluster: it sends requests to the last known
+//! leader, follows the leader hints of followers, and retries on other servers with backoff when a server is down or
+//! there is no leader, until the request's deadline.
+//!
+//! Retried writes may be applied twice, tag them with a client session (see `register_client`) to apply them exactly
+//! once.
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+pub use cluster_config::{parse_cluster_members, InvalidClusterMembers};
+use single_value_store_proto::kv_store;
+use single_value_store_proto::kv_store::kv_store_client::KvStoreClient;
+use single_value_store_proto::lock_service;
+use single_value_store_proto::lock_service::lock_service_client::LockServiceClient;
+use single_value_store_proto::single_value_store;
+use single_value_store_proto::single_value_store::single_value_store_client::SingleValueStoreClient;
+use tokio::time::Instant;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Code, Status, Streaming};
+use tracing::{debug, info};
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Time to wait for a connection to a server before trying the next one
+    pub connect_timeout: Duration,
+    /// Time to wait for a server's response before trying the next one
+    pub attempt_timeout: Duration,
+    /// Time to keep retrying a request for before giving up
+    pub deadline: Duration,
+    /// Wait before the first retry, doubled after every attempt that failed without pointing us at the leader
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: Duration::from_millis(500),
+            attempt_timeout: Duration::from_secs(2),
+            deadline: Duration::from_secs(10),
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Failures that another server, or the same one a bit later, may not run into
+fn should_retry(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        // Unknown and Cancelled are what transport errors and attempt timeouts come back as
+        Code::Unavailable | Code::DeadlineExceeded | Code::Cancelled | Code::Unknown
+    )
+}
+
+/// ID of the leader a follower pointed us at
+fn leader_hint(status: &Status) -> Option<u64> {
+    if status.code() != Code::FailedPrecondition {
+        return None;
+    }
+    status
+        .metadata()
+        .get("leader-id")
+        .and_then(|leader_id| leader_id.to_str().ok())
+        .and_then(|leader_id| leader_id.parse().ok())
+}
+
+struct Server {
+    id: u64,
+    address: String,
+    channel: Channel,
+}
+
+/// Sends requests to the cluster's leader. Cheap to share between tasks, connections are made on first use and
+/// reused.
+pub struct StoreClient {
+    servers: Vec<Server>,
+    /// Index in `servers` of the last known leader, or of the next server to try if the leader is not known
+    leader: AtomicUsize,
+    config: ClientConfig,
+}
+impl StoreClient {
+    pub fn new(
+        cluster_members: BTreeMap<u64, String>,
+        config: ClientConfig,
+    ) -> Result<Self, InvalidClusterMembers> {
+        if cluster_members.is_empty() {
+            return Err(InvalidClusterMembers("No servers given".to_string()));
+        }
+        let servers = cluster_members
+            .into_iter()
+            .map(|(id, address)| {
+                let endpoint = Endpoint::from_shared(format!("http://{}", address))
+                    .map_err(|e| InvalidClusterMembers(format!("{:?}: {}", address, e)))?
+                    .connect_timeout(config.connect_timeout)
+                    .timeout(config.attempt_timeout);
+                Ok(Server {
+                    id,
+                    address,
+                    channel: endpoint.connect_lazy(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(StoreClient {
+            servers,
+            leader: AtomicUsize::new(0),
+            config,
+        })
+    }
+
+    /// Client for the members in the `--cluster-members` format, see `parse_cluster_members`
+    pub fn from_cluster_members(
+        cluster_members: &str,
+        config: ClientConfig,
+    ) -> Result<Self, InvalidClusterMembers> {
+        StoreClient::new(parse_cluster_members(cluster_members)?, config)
+    }
+
+    /// Sends the next request to this server first, i.e. if the caller knows which server is the leader
+    pub fn prefer_server(&self, server_id: u64) {
+        if let Some(index) = self
+            .servers
+            .iter()
+            .position(|server| server.id == server_id)
+        {
+            self.leader.store(index, Ordering::Relaxed);
+        }
+    }
+
+    /// The server the next request is sent to first, the last one that answered a request if there has been one
+    pub fn last_known_leader(&self) -> u64 {
+        self.servers[self.leader.load(Ordering::Relaxed) % self.servers.len()].id
+    }
+
+    /// Sends the request to the leader, retrying until it succeeds, fails with an error that retrying won't fix or the
+    /// deadline passes
+    ///
+    /// Redirects to a known server are followed right away, up to one for each server before backing off. A redirect
+    /// that can't be followed, because the budget is used up or because the hint is a server ID we don't know (i.e. a
+    /// server added after the client was configured), is retried on the next server after a backoff like any other
+    /// failure that retrying may fix.
+    async fn call<T, F, Fut>(&self, mut request: F) -> Result<T, Status>
+    where
+        F: FnMut(Channel) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+    {
+        let deadline = Instant::now() + self.config.deadline;
+        let mut backoff = self.config.initial_backoff;
+        // Followers can point at each other while a new leader is being elected, so don't follow hints forever
+        let mut redirects = 0;
+        loop {
+            let index = self.leader.load(Ordering::Relaxed) % self.servers.len();
+            let server = &self.servers[index];
+            let status = match request(server.channel.clone()).await {
+                Ok(response) => return Ok(response.into_inner()),
+                Err(status) => status,
+            };
+            let hint = leader_hint(&status);
+            match hint.filter(|leader_id| self.servers.iter().any(|server| server.id == *leader_id))
+            {
+                Some(leader_id) if redirects < self.servers.len() => {
+                    debug!("Server {} redirected us to leader {}", server.id, leader_id);
+                    redirects += 1;
+                    self.prefer_server(leader_id);
+                    if Instant::now() >= deadline {
+                        return Err(give_up(status));
+                    }
+                    continue;
+                }
+                // A hint for a server we don't know about is treated like any other failure
+                _ if should_retry(&status) || hint.is_some() => {}
+                _ => return Err(status),
+            }
+            info!(
+                "Request to server {} at {} failed, trying the next server: {}",
+                server.id,
+                server.address,
+                status.message()
+            );
+            // Another request may have found the leader in the meantime
+            let _ = self.leader.compare_exchange(
+                index,
+                (index + 1) % self.servers.len(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            if Instant::now() + backoff >= deadline {
+                return Err(give_up(status));
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.config.max_backoff);
+            redirects = 0;
+        }
+    }
+
+    pub async fn get(&self) -> Result<single_value_store::GetResponse, Status> {
+        self.call(|channel| async move {
+            SingleValueStoreClient::new(channel)
+                .get(single_value_store::GetRequest {})
+                .await
+        })
+        .await
+    }
+
+    pub async fn set(
+        &self,
+        request: single_value_store::SetRequest,
+    ) -> Result<single_value_store::SetResponse, Status> {
+        self.call(|channel| {
+            let request = request.clone();
+            async move { SingleValueStoreClient::new(channel).set(request).await }
+        })
+        .await
+    }
+
+    pub async fn compare_and_set(
+        &self,
+        request: single_value_store::CompareAndSetRequest,
+    ) -> Result<single_value_store::CompareAndSetResponse, Status> {
+        self.call(|channel| {
+            let request = request.clone();
+            async move {
+                SingleValueStoreClient::new(channel)
+                    .compare_and_set(request)
+                    .await
+            }
+        })
+        .await
+    }
+
+    /// Opens a session to tag writes with, so that retrying them applies them exactly once
+    pub async fn register_client(
+        &self,
+    ) -> Result<single_value_store::RegisterClientResponse, Status> {
+        self.call(|channel| async move {
+            SingleValueStoreClient::new(channel)
+                .register_client(single_value_store::RegisterClientRequest {})
+                .await
+        })
+        .await
+    }
+
+    /// Starts a watch, only starting it is retried. If the stream fails, start a new watch from the version of the
+    /// last change received plus one.
+    pub async fn watch(
+        &self,
+        request: single_value_store::WatchRequest,
+    ) -> Result<Streaming<single_value_store::ValueChange>, Status> {
+        self.call(|channel| {
+            let request = request.clone();
+            async move { SingleValueStoreClient::new(channel).watch(request).await }
+        })
+        .await
+    }
+
+    pub async fn kv_get(&self, key: Vec<u8>) -> Result<kv_store::GetResponse, Status> {
+        self.call(|channel| {
+            let key = key.clone();
+            async move {
+                KvStoreClient::new(channel)
+                    .get(kv_store::GetRequest { key })
+                    .await
+            }
+        })
+        .await
+    }
+
+    pub async fn put(
+        &self,
+        request: kv_store::PutRequest,
+    ) -> Result<kv_store::PutResponse, Status> {
+        self.call(|channel| {
+            let request = request.clone();
+            async move { KvStoreClient::new(channel).put(request).await }
+        })
+        .await
+    }
+
+    pub async fn delete(
+        &self,
+        request: kv_store::DeleteRequest,
+    ) -> Result<kv_store::DeleteResponse, Status> {
+        self.call(|channel| {
+            let request = request.clone();
+            async move { KvStoreClient::new(channel).delete(request).await }
+        })
+        .await
+    }
+
+    pub async fn scan(
+        &self,
+        request: kv_store::ScanRequest,
+    ) -> Result<kv_store::ScanResponse, Status> {
+        self.call(|channel| {
+            let request = request.clone();
+            async move { KvStoreClient::new(channel).scan(request).await }
+        })
+        .await
+    }
+
+    pub async fn acquire(
+        &self,
+        request: lock_service::AcquireRequest,
+    ) -> Result<lock_service::AcquireResponse, Status> {
+        self.call(|channel| {
+            let request = request.clone();
+            async move { LockServiceClient::new(channel).acquire(request).await }
+        })
+        .await
+    }
+
+    pub async fn renew(
+        &self,
+        request: lock_service::RenewRequest,
+    ) -> Result<lock_service::RenewResponse, Status> {
+        self.call(|channel| {
+            let request = request.clone();
+            async move { LockServiceClient::new(channel).renew(request).await }
+        })
+        .await
+    }
+
+    pub async fn release(
+        &self,
+        request: lock_service::ReleaseRequest,
+    ) -> Result<lock_service::ReleaseResponse, Status> {
+        self.call(|channel| {
+            let request = request.clone();
+            async move { LockServiceClient::new(channel).release(request).await }
+        })
+        .await
+    }
+}
+
+fn give_up(last_status: Status) -> Status {
+    Status::deadline_exceeded(format!(
+        "Gave up on finding the leader, last error: {:?}: {}",
+        last_status.code(),
+        last_status.message()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    use single_value_store_proto::single_value_store::single_value_store_server::{
+        SingleValueStore, SingleValueStoreServer,
+    };
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::metadata::MetadataValue;
+    use tonic::transport::Server;
+
+    fn client(servers: u64) -> StoreClient {
+        let cluster_members = (0..servers)
+            .map(|id| (id, format!("127.0.0.1:{}", 5000 + id)))
+            .collect();
+        let config = ClientConfig {
+            deadline: Duration::from_secs(1),
+            ..ClientConfig::default()
+        };
+        StoreClient::new(cluster_members, config).unwrap()
+    }
+
+    /// What a follower answers when it knows the leader
+    fn redirect(leader_id: u64) -> Status {
+        let mut status = Status::failed_precondition("Not the leader");
+        let _ = status
+            .metadata_mut()
+            .insert("leader-id", MetadataValue::from(leader_id));
+        status
+    }
+
+    /// Sends a request that the server with the ID it is sent to answers with `respond`, returns the result and the
+    /// servers the attempts were sent to with the time they were sent at
+    async fn call(
+        client: &StoreClient,
+        respond: impl Fn(u64) -> Result<u64, Status>,
+    ) -> (Result<u64, Status>, Vec<(u64, Instant)>) {
+        let attempts = RefCell::new(vec![]);
+        let result = client
+            .call(|_| {
+                let server_id = client.last_known_leader();
+                attempts.borrow_mut().push((server_id, Instant::now()));
+                let response = respond(server_id).map(tonic::Response::new);
+                async move { response }
+            })
+            .await;
+        (result, attempts.into_inner())
+    }
+
+    #[test]
+    fn should_retry_only_failures_another_attempt_may_not_run_into() {
+        for status in [
+            Status::unavailable(""),
+            Status::deadline_exceeded(""),
+            Status::cancelled(""),
+            Status::unknown(""),
+        ] {
+            assert!(should_retry(&status), "{:?}", status);
+        }
+        for status in [
+            Status::invalid_argument(""),
+            Status::not_found(""),
+            Status::failed_precondition(""),
+            Status::internal(""),
+        ] {
+            assert!(!should_retry(&status), "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn should_read_leader_hint_only_from_redirects() {
+        assert_eq!(leader_hint(&redirect(3)), Some(3));
+        assert_eq!(leader_hint(&Status::failed_precondition("")), None);
+        let mut status = Status::unavailable("");
+        let _ = status
+            .metadata_mut()
+            .insert("leader-id", MetadataValue::from(3));
+        assert_eq!(leader_hint(&status), None);
+        let mut status = Status::failed_precondition("");
+        let _ = status
+            .metadata_mut()
+            .insert("leader-id", MetadataValue::from_static("leader"));
+        assert_eq!(leader_hint(&status), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_follow_redirect_to_leader_without_backing_off() {
+        let client = client(3);
+        let (result, attempts) = call(&client, |server_id| match server_id {
+            2 => Ok(7),
+            _ => Err(redirect(2)),
+        })
+        .await;
+        assert_eq!(result.unwrap(), 7);
+        let start = attempts[0].1;
+        assert_eq!(attempts, [(0, start), (2, start)]);
+        assert_eq!(client.last_known_leader(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_try_next_server_with_backoff_when_server_is_down() {
+        let client = client(3);
+        let (result, attempts) = call(&client, |server_id| match server_id {
+            2 => Ok(7),
+            _ => Err(Status::unavailable("Connection refused")),
+        })
+        .await;
+        assert_eq!(result.unwrap(), 7);
+        let start = attempts[0].1;
+        assert_eq!(
+            attempts,
+            [
+                (0, start),
+                (1, start + Duration::from_millis(50)),
+                (2, start + Duration::from_millis(150))
+            ]
+        );
+        assert_eq!(client.last_known_leader(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_return_errors_retrying_does_not_fix() {
+        let client = client(3);
+        let (result, attempts) = call(&client, |_| Err(Status::invalid_argument("No"))).await;
+        assert_eq!(result.unwrap_err().code(), Code::InvalidArgument);
+        assert_eq!(attempts.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_back_off_when_followers_redirect_in_circles() {
+        let client = client(3);
+        let (result, attempts) =
+            call(&client, |server_id| Err(redirect((server_id + 1) % 3))).await;
+        assert_eq!(result.unwrap_err().code(), Code::DeadlineExceeded);
+        // Every round follows one redirect for each server and then backs off
+        let mut rounds: Vec<Vec<u64>> = vec![];
+        for (i, (server_id, sent_at)) in attempts.iter().enumerate() {
+            match i.checked_sub(1).map(|previous| attempts[previous].1) {
+                Some(previous_sent_at) if previous_sent_at == *sent_at => {
+                    rounds.last_mut().unwrap().push(*server_id)
+                }
+                _ => rounds.push(vec![*server_id]),
+            }
+        }
+        assert!(rounds.len() > 1, "{:?}", rounds);
+        assert!(rounds.iter().all(|round| round.len() == 4), "{:?}", rounds);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_back_off_on_redirects_to_unknown_servers() {
+        let client = client(3);
+        let (result, attempts) = call(&client, |server_id| match server_id {
+            0 => Err(redirect(1)),
+            1 => Err(redirect(0)),
+            _ => Err(redirect(9)),
+        })
+        .await;
+        assert_eq!(result.unwrap_err().code(), Code::DeadlineExceeded);
+        let start = attempts[0].1;
+        // After one redirect for each server the client backs off, server 2's hint can't be followed at all
+        assert_eq!(
+            attempts[..6],
+            [
+                (0, start),
+                (1, start),
+                (0, start),
+                (1, start),
+                (2, start + Duration::from_millis(50)),
+                (0, start + Duration::from_millis(150))
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn should_give_up_before_deadline() {
+        let client = client(3);
+        let start = Instant::now();
+        let (result, attempts) =
+            call(&client, |_| Err(Status::unavailable("Connection refused"))).await;
+        let status = result.unwrap_err();
+        assert_eq!(status.code(), Code::DeadlineExceeded);
+        assert!(
+            status.message().contains("Connection refused"),
+            "{}",
+            status
+        );
+        assert!(attempts.len() > 3);
+        assert!(Instant::now() - start < Duration::from_secs(1));
+    }
+
+    /// A server that redirects to `leader_id`, or answers reads with 7 if it is `None`
+    struct StubServer {
+        leader_id: Option<u64>,
+    }
+
+    #[tonic::async_trait]
+    impl SingleValueStore for StubServer {
+        type WatchStream = tokio_stream::Empty<Result<single_value_store::ValueChange, Status>>;
+
+        async fn get(
+            &self,
+            _: tonic::Request<single_value_store::GetRequest>,
+        ) -> Result<tonic::Response<single_value_store::GetResponse>, Status> {
+            match self.leader_id {
+                Some(leader_id) => Err(redirect(leader_id)),
+                None => Ok(tonic::Response::new(single_value_store::GetResponse {
+                    value: 7,
+                    ..Default::default()
+                })),
+            }
+        }
+
+        async fn set(
+            &self,
+            _: tonic::Request<single_value_store::SetRequest>,
+        ) -> Result<tonic::Response<single_value_store::SetResponse>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn compare_and_set(
+            &self,
+            _: tonic::Request<single_value_store::CompareAndSetRequest>,
+        ) -> Result<tonic::Response<single_value_store::CompareAndSetResponse>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn register_client(
+            &self,
+            _: tonic::Request<single_value_store::RegisterClientRequest>,
+        ) -> Result<tonic::Response<single_value_store::RegisterClientResponse>, Status> {
+            Err(Status::unimplemented(""))
+        }
+
+        async fn watch(
+            &self,
+            _: tonic::Request<single_value_store::WatchRequest>,
+        ) -> Result<tonic::Response<Self::WatchStream>, Status> {
+            Err(Status::unimplemented(""))
+        }
+    }
+
+    async fn start_stub_server(leader_id: Option<u64>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(
+            Server::builder()
+                .add_service(SingleValueStoreServer::new(StubServer { leader_id }))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        address
+    }
+
+    #[tokio::test]
+    async fn should_find_leader_of_stub_cluster() {
+        // Nothing listens on the first server's address once the listener is dropped
+        let down = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let follower = start_stub_server(Some(2)).await;
+        let leader = start_stub_server(None).await;
+        let client = StoreClient::new(
+            BTreeMap::from([(0, down), (1, follower), (2, leader)]),
+            ClientConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(client.get().await.unwrap().value, 7);
+        assert_eq!(client.last_known_leader(), 2);
+    }
+}
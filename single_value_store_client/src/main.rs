@@ -1,7 +1,18 @@
+mod bench;
+mod record_history;
+
+use std::time::Duration;
+
+use bench::BenchArgs;
 use clap::{Parser, Subcommand};
-use single_value_store_proto::single_value_store::single_value_store_client::SingleValueStoreClient;
-use single_value_store_proto::single_value_store::{GetRequest, SetRequest};
-use tonic::transport::Channel;
+use record_history::RecordHistoryArgs;
+use single_value_store_client::{ClientConfig, StoreClient};
+use single_value_store_proto::kv_store;
+use single_value_store_proto::lock_service;
+use single_value_store_proto::single_value_store::{
+    ClientSession, CompareAndSetRequest, SetRequest, WatchRequest,
+};
+use tonic::Status;
 use tracing::info;
 
 #[derive(Parser)]
@@ -11,50 +22,297 @@ struct Cli {
     #[command(subcommand)]
     command: Commands,
 
+    /// Comma delimited list of server IDs and addresses, the same as the servers' `--cluster-members`
     #[arg(short, long)]
-    server_address: String,
+    cluster_members: String,
+
+    /// Server to send the request to first, requests find their way to the leader from any server
+    #[arg(long)]
+    server: Option<u64>,
+
+    /// Seconds to keep retrying the request for while servers are down or there is no leader
+    #[arg(long, default_value_t = 10)]
+    deadline_secs: u64,
+
+    /// Send writes in this session (see `register-client`), they are applied at most once for each sequence number
+    #[arg(long)]
+    session_id: Option<u64>,
+
+    /// Sequence number of the write in the session, use the next one for every new write and the same one to retry
+    #[arg(long, default_value_t = 1, requires = "session_id")]
+    sequence: u64,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Get,
-    Set { value: u64 },
+    Set {
+        value: u64,
+    },
+    /// Set the value to `new_value` only if it currently is `expected`
+    Cas {
+        expected: u64,
+        new_value: u64,
+        /// Also require the value's version (as returned by get) to match
+        #[arg(long)]
+        expected_version: Option<u64>,
+    },
+    /// Print every change to the value until interrupted
+    Watch {
+        /// Start from the changes at this log index instead of the current value, i.e. to resume after the last
+        /// version seen
+        #[arg(long)]
+        from_index: Option<u64>,
+    },
+    /// Get the value of a key in the key-value store
+    KvGet {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: String,
+    },
+    Delete {
+        key: String,
+    },
+    /// Open a session for exactly-once writes and print its ID
+    RegisterClient,
+    /// Acquire a lock for `owner`, prints the fencing token of the lease
+    Acquire {
+        name: String,
+        owner: String,
+        #[arg(long, default_value_t = 10_000)]
+        ttl_ms: u64,
+    },
+    /// Extend the lease with this fencing token
+    Renew {
+        name: String,
+        fencing_token: u64,
+        #[arg(long, default_value_t = 10_000)]
+        ttl_ms: u64,
+    },
+    Release {
+        name: String,
+        fencing_token: u64,
+    },
+    /// Send a mix of gets and sets from concurrent workers and report throughput and latency percentiles
+    Bench(BenchArgs),
+    /// Run concurrent clients doing random reads, writes and compare and sets, write their history to a file and
+    /// check it for linearizability
+    RecordHistory(RecordHistoryArgs),
+    /// List the entries with keys from `start` (inclusive) to `end` (exclusive)
+    Scan {
+        #[arg(long, default_value = "")]
+        start: String,
+        #[arg(long)]
+        end: Option<String>,
+        /// At most 1000 entries are returned, 0 means the maximum
+        #[arg(long, default_value_t = 0)]
+        limit: u32,
+    },
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_max_level(tracing::Level::INFO)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
-
-    let cli = Cli::parse();
-
-    let channel = Channel::from_shared(format!("http://{}", cli.server_address))
-        .expect("CLIENT INIT: Failed to create channel")
-        .connect()
-        .await
-        .expect("CLIENT INIT: Failed to connect to server");
-
-    let mut client = SingleValueStoreClient::new(channel.clone());
-
-    info!("Connected to server: {:?}", cli.server_address);
-
+async fn send_command(
+    client: &StoreClient,
+    command: &Commands,
+    session: Option<ClientSession>,
+) -> Result<(), Status> {
     // You can check for the existence of subcommands, and if found use their
     // matches just as you would the top level cmd
-    match &cli.command {
+    match command {
         Commands::Get => {
             info!("GET");
-            let result = client.get(tonic::Request::new(GetRequest {})).await?;
+            let result = client.get().await?;
             info!("Result: {:?}", result);
         }
         Commands::Set { value } => {
             info!("SET {}", value);
             let result = client
-                .set(tonic::Request::new(SetRequest { value: *value }))
+                .set(SetRequest {
+                    value: *value,
+                    session,
+                })
                 .await?;
             info!("Result: {:?}", result);
         }
+        Commands::Cas {
+            expected,
+            new_value,
+            expected_version,
+        } => {
+            info!("CAS {} -> {}", expected, new_value);
+            let result = client
+                .compare_and_set(CompareAndSetRequest {
+                    expected: *expected,
+                    new_value: *new_value,
+                    expected_version: *expected_version,
+                    session,
+                })
+                .await?;
+            if result.succeeded {
+                info!("Value set to {} (version {})", result.value, result.version);
+            } else {
+                info!(
+                    "Not set, current value is {} (version {})",
+                    result.value, result.version
+                );
+            }
+        }
+        Commands::Watch { from_index } => {
+            info!("WATCH from {:?}", from_index);
+            let mut changes = client
+                .watch(WatchRequest {
+                    from_index: *from_index,
+                })
+                .await?;
+            while let Some(change) = changes.message().await? {
+                if change.snapshot {
+                    info!("Value is {} (version {})", change.value, change.version);
+                } else {
+                    info!("Value set to {} (version {})", change.value, change.version);
+                }
+            }
+        }
+        Commands::KvGet { key } => {
+            info!("KV GET {}", key);
+            let result = client.kv_get(key.clone().into_bytes()).await?;
+            if result.found {
+                info!("Value: {}", String::from_utf8_lossy(&result.value));
+            } else {
+                info!("Key not found");
+            }
+        }
+        Commands::Put { key, value } => {
+            info!("PUT {} {}", key, value);
+            let result = client
+                .put(kv_store::PutRequest {
+                    key: key.clone().into_bytes(),
+                    value: value.clone().into_bytes(),
+                    session,
+                })
+                .await?;
+            info!("Result: {:?}", result);
+        }
+        Commands::Delete { key } => {
+            info!("DELETE {}", key);
+            let result = client
+                .delete(kv_store::DeleteRequest {
+                    key: key.clone().into_bytes(),
+                    session,
+                })
+                .await?;
+            info!("Deleted: {}", result.deleted);
+        }
+        Commands::RegisterClient => {
+            info!("REGISTER CLIENT");
+            let result = client.register_client().await?;
+            info!("Session ID: {}", result.session_id);
+        }
+        Commands::Acquire {
+            name,
+            owner,
+            ttl_ms,
+        } => {
+            info!("ACQUIRE {} for {}", name, owner);
+            let result = client
+                .acquire(lock_service::AcquireRequest {
+                    name: name.clone(),
+                    owner: owner.clone(),
+                    ttl_ms: *ttl_ms,
+                })
+                .await?;
+            if result.acquired {
+                info!("Acquired: {:?}", result.lease);
+            } else {
+                info!("Held by someone else: {:?}", result.lease);
+            }
+        }
+        Commands::Renew {
+            name,
+            fencing_token,
+            ttl_ms,
+        } => {
+            info!("RENEW {} ({})", name, fencing_token);
+            let result = client
+                .renew(lock_service::RenewRequest {
+                    name: name.clone(),
+                    fencing_token: *fencing_token,
+                    ttl_ms: *ttl_ms,
+                })
+                .await?;
+            if result.renewed {
+                info!("Renewed: {:?}", result.lease);
+            } else {
+                info!("Lease lost, lock is held by: {:?}", result.lease);
+            }
+        }
+        Commands::Release {
+            name,
+            fencing_token,
+        } => {
+            info!("RELEASE {} ({})", name, fencing_token);
+            let result = client
+                .release(lock_service::ReleaseRequest {
+                    name: name.clone(),
+                    fencing_token: *fencing_token,
+                })
+                .await?;
+            info!("Released: {}", result.released);
+        }
+        Commands::Bench(_) | Commands::RecordHistory(_) => {
+            unreachable!("CLIENT: Benchmarks and histories are run on their own")
+        }
+        Commands::Scan { start, end, limit } => {
+            info!("SCAN {:?}..{:?}", start, end);
+            let result = client
+                .scan(kv_store::ScanRequest {
+                    start_key: start.clone().into_bytes(),
+                    end_key: end.clone().unwrap_or_default().into_bytes(),
+                    limit: *limit,
+                })
+                .await?;
+            for entry in result.entries {
+                info!(
+                    "{} = {}",
+                    String::from_utf8_lossy(&entry.key),
+                    String::from_utf8_lossy(&entry.value)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let subscriber = tracing_subscriber::FmtSubscriber::builder()
+        .with_max_level(tracing::Level::INFO)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let cli = Cli::parse();
+
+    let client = StoreClient::from_cluster_members(
+        &cli.cluster_members,
+        ClientConfig {
+            deadline: Duration::from_secs(cli.deadline_secs),
+            ..ClientConfig::default()
+        },
+    )?;
+    if let Some(server) = cli.server {
+        client.prefer_server(server);
+    }
+    match cli.command {
+        Commands::Bench(args) => return bench::run_bench(client, args).await,
+        Commands::RecordHistory(args) => return record_history::record_history(client, args).await,
+        _ => {}
     }
+    let session = cli.session_id.map(|session_id| ClientSession {
+        session_id,
+        sequence: cli.sequence,
+    });
+    // Retried writes keep their sequence number, so they are not applied twice if an earlier attempt got through
+    send_command(&client, &cli.command, session).await?;
+    info!("Leader: server {}", client.last_known_leader());
     Ok(())
 }
@@ -0,0 +1,183 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use linearizability_checker::{
+    check_history, CheckResult, Event, EventType, History, Register, RegisterOp,
+};
+use rand::Rng;
+use single_value_store_client::StoreClient;
+use single_value_store_proto::single_value_store::{
+    ClientSession, CompareAndSetRequest, SetRequest,
+};
+use tonic::Status;
+use tracing::{info, warn};
+
+/// Values are picked from this range so that compare and sets match often enough to be interesting
+const VALUES: u64 = 5;
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct RecordHistoryArgs {
+    /// Number of concurrent clients, each sends one request at a time
+    #[arg(long, default_value_t = 5)]
+    workers: u64,
+    /// Number of operations over all clients
+    #[arg(long, default_value_t = 500)]
+    ops: u64,
+    /// File to write the history to, it can be checked again with `check_history`
+    #[arg(long, default_value = "history.txt")]
+    output: PathBuf,
+}
+
+/// Events of all workers, timestamped in the order they are recorded
+struct Recorder {
+    started: Instant,
+    history: Mutex<History>,
+}
+impl Recorder {
+    fn record(&self, process: u64, event_type: EventType, op: RegisterOp) {
+        let mut history = self.history.lock().expect("CLIENT: History lock poisoned!");
+        history.push(Event {
+            time_ns: self.started.elapsed().as_nanos() as u64,
+            process,
+            event_type,
+            op,
+        });
+    }
+}
+
+async fn run_worker(
+    client: Arc<StoreClient>,
+    recorder: Arc<Recorder>,
+    worker: u64,
+    args: RecordHistoryArgs,
+    claimed: Arc<AtomicU64>,
+) -> Result<(), Status> {
+    // Writes in a session are applied once even if the client retries them
+    let session_id = client.register_client().await?.session_id;
+    let mut sequence = 0;
+    let mut process = worker;
+    while claimed.fetch_add(1, Ordering::Relaxed) < args.ops {
+        let op = {
+            let mut rng = rand::thread_rng();
+            match rng.gen_range(0..3) {
+                0 => RegisterOp::Read(None),
+                1 => RegisterOp::Write(rng.gen_range(0..VALUES)),
+                _ => RegisterOp::Cas {
+                    expected: rng.gen_range(0..VALUES),
+                    new: rng.gen_range(0..VALUES),
+                },
+            }
+        };
+        sequence += 1;
+        let session = Some(ClientSession {
+            session_id,
+            sequence,
+        });
+        recorder.record(process, EventType::Invoke, op);
+        let (event_type, op) = match op {
+            RegisterOp::Read(_) => match client.get().await {
+                Ok(response) => (EventType::Ok, RegisterOp::Read(Some(response.value))),
+                // Reads don't change the register, so a read that may have happened can be left out
+                Err(_) => (EventType::Fail, op),
+            },
+            RegisterOp::Write(value) => match client.set(SetRequest { value, session }).await {
+                Ok(_) => (EventType::Ok, op),
+                Err(_) => (EventType::Info, op),
+            },
+            RegisterOp::Cas { expected, new } => {
+                let request = CompareAndSetRequest {
+                    expected,
+                    new_value: new,
+                    expected_version: None,
+                    session,
+                };
+                match client.compare_and_set(request).await {
+                    Ok(response) if response.succeeded => (EventType::Ok, op),
+                    Ok(_) => (EventType::Fail, op),
+                    Err(_) => (EventType::Info, op),
+                }
+            }
+        };
+        recorder.record(process, event_type, op);
+        if event_type == EventType::Info {
+            // The operation may still take effect, so this process never completes it, go on as a new process
+            process += args.workers;
+        }
+    }
+    Ok(())
+}
+
+/// Runs concurrent clients doing random reads, writes and compare and sets, records their history and checks it
+/// for linearizability
+pub(crate) async fn record_history(
+    client: StoreClient,
+    args: RecordHistoryArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Start from a known value, so the history can be checked from it
+    let _ = client
+        .set(SetRequest {
+            value: 0,
+            session: None,
+        })
+        .await?;
+    info!(
+        "Recording {} operations from {} clients",
+        args.ops, args.workers
+    );
+    let client = Arc::new(client);
+    let recorder = Arc::new(Recorder {
+        started: Instant::now(),
+        history: Mutex::new(History::new()),
+    });
+    let claimed = Arc::new(AtomicU64::new(0));
+    let workers: Vec<_> = (0..args.workers)
+        .map(|worker| {
+            tokio::spawn(run_worker(
+                client.clone(),
+                recorder.clone(),
+                worker,
+                args.clone(),
+                claimed.clone(),
+            ))
+        })
+        .collect();
+    for worker in workers {
+        if let Err(status) = worker.await? {
+            warn!("Client could not register a session: {}", status);
+        }
+    }
+
+    let history = recorder
+        .history
+        .lock()
+        .expect("CLIENT: History lock poisoned!")
+        .clone();
+    std::fs::write(&args.output, history.to_string())?;
+    info!(
+        "Wrote {} events to {:?}",
+        history.events().len(),
+        args.output
+    );
+    let (operations, result) = check_history(&history, Register { value: 0 })?;
+    match result {
+        CheckResult::Linearizable(_) => {
+            info!(
+                "History of {} operations is linearizable",
+                operations.len()
+            );
+            Ok(())
+        }
+        CheckResult::NotLinearizable {
+            longest_linearizable,
+            ..
+        } => Err(format!(
+            "History is not linearizable, only {} of {} operations can be put in order, run check_history on {:?} for details",
+            longest_linearizable.len(),
+            operations.len(),
+            args.output
+        )
+        .into()),
+    }
+}
@@ -0,0 +1,413 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use cluster_config::{ClusterConfig, ServerConfig};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use raft_grpc::proto::raft_consensus_client::RaftConsensusClient;
+use raft_grpc::proto::GetStatusRequest;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use tokio::process::{Child, Command};
+use tonic::transport::Endpoint;
+use tracing::{debug, error, info, warn};
+
+/// How often every server is asked for its term and leader
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, clap::Args)]
+pub(crate) struct ChaosArgs {
+    /// Randomly kill, restart and pause the servers while the cluster runs
+    #[arg(long)]
+    pub(crate) chaos: bool,
+
+    /// Seed of the chaos schedule, the same seed gives the same actions. A random one is picked and logged if not set.
+    #[arg(long, requires = "chaos")]
+    seed: Option<u64>,
+
+    /// Average time between two actions, the actual time is picked between half and one and a half times this
+    #[arg(long, default_value_t = 3000, requires = "chaos")]
+    chaos_interval_ms: u64,
+
+    /// Also pause servers with SIGSTOP until the next action, to simulate long GC pauses and stalled disks
+    #[arg(long, requires = "chaos")]
+    pause: bool,
+
+    /// Most servers killed or paused at the same time, a minority of the cluster by default so it stays available
+    #[arg(long, requires = "chaos")]
+    max_down: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeState {
+    Running,
+    Paused,
+    Killed,
+}
+
+struct Node {
+    server: ServerConfig,
+    child: Option<Child>,
+    state: NodeState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Kill,
+    Pause,
+    Restart,
+}
+
+fn start_server(
+    binary: &Path,
+    server: &ServerConfig,
+    config_path: &Path,
+) -> std::io::Result<Child> {
+    Command::new(binary)
+        .arg("--server-id")
+        .arg(server.id.to_string())
+        .arg("--cluster-config")
+        .arg(config_path)
+        .kill_on_drop(true)
+        .spawn()
+}
+
+fn send_signal(child: &Child, signal: Signal) -> nix::Result<()> {
+    // The ID is only gone once the child has been waited for, which only happens when it is killed
+    let pid = child
+        .id()
+        .expect("CHAOS: Signalling a server that was reaped");
+    kill(Pid::from_raw(pid as i32), signal)
+}
+
+/// Picks the next action and the index of the server to apply it to, killing or pausing a running server while fewer
+/// than `max_down` are down, or restarting a killed one
+fn next_action(
+    nodes: &[Node],
+    args: &ChaosArgs,
+    max_down: usize,
+    rng: &mut ChaCha8Rng,
+) -> Option<(usize, Action)> {
+    let down = nodes
+        .iter()
+        .filter(|node| node.state != NodeState::Running)
+        .count();
+    let mut actions = vec![];
+    for (index, node) in nodes.iter().enumerate() {
+        match node.state {
+            NodeState::Running if down < max_down => {
+                actions.push((index, Action::Kill));
+                if args.pause {
+                    actions.push((index, Action::Pause));
+                }
+            }
+            NodeState::Killed => actions.push((index, Action::Restart)),
+            NodeState::Running | NodeState::Paused => {}
+        }
+    }
+    actions.choose(rng).copied()
+}
+
+async fn apply_action(
+    node: &mut Node,
+    action: Action,
+    binary: &Path,
+    config_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server_id = node.server.id;
+    match action {
+        Action::Kill => {
+            info!("CHAOS: Killing server {}", server_id);
+            if let Some(mut child) = node.child.take() {
+                child.kill().await?;
+            }
+            node.state = NodeState::Killed;
+        }
+        Action::Pause => {
+            info!("CHAOS: Pausing server {}", server_id);
+            if let Some(child) = &node.child {
+                send_signal(child, Signal::SIGSTOP)?;
+            }
+            node.state = NodeState::Paused;
+        }
+        Action::Restart => {
+            info!("CHAOS: Restarting server {}", server_id);
+            node.child = Some(start_server(binary, &node.server, config_path)?);
+            node.state = NodeState::Running;
+        }
+    }
+    Ok(())
+}
+
+/// Restarts servers that exited on their own. They are not part of the seeded schedule, which still sees them as
+/// running, so the same seed keeps picking the same actions whatever else happens to the servers.
+fn restart_exited(nodes: &mut [Node], binary: &Path, config_path: &Path) -> std::io::Result<()> {
+    for node in nodes {
+        if let Some(child) = &mut node.child {
+            if let Some(status) = child.try_wait()? {
+                warn!(
+                    "CHAOS: Server {} exited on its own ({}), restarting it outside the schedule",
+                    node.server.id, status
+                );
+                node.child = Some(start_server(binary, &node.server, config_path)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn resume_paused(nodes: &mut [Node]) -> nix::Result<()> {
+    for node in nodes {
+        if node.state != NodeState::Paused {
+            continue;
+        }
+        info!("CHAOS: Resuming server {}", node.server.id);
+        if let Some(child) = &node.child {
+            send_signal(child, Signal::SIGCONT)?;
+        }
+        node.state = NodeState::Running;
+    }
+    Ok(())
+}
+
+/// Logs every change of the term or leader a server reports. Servers that are down or paused don't answer and are
+/// skipped until they do.
+async fn watch_leader(server: ServerConfig) {
+    let endpoint = Endpoint::from_shared(format!("http://{}", server.address))
+        .expect("CHAOS: Invalid server address")
+        .connect_timeout(STATUS_POLL_INTERVAL)
+        .timeout(STATUS_POLL_INTERVAL);
+    let mut client = RaftConsensusClient::new(endpoint.connect_lazy());
+    let mut last_seen = None;
+    let mut interval = tokio::time::interval(STATUS_POLL_INTERVAL);
+    loop {
+        let _ = interval.tick().await;
+        let status = match client.get_status(GetStatusRequest {}).await {
+            Ok(response) => response.into_inner(),
+            Err(status) => {
+                debug!(
+                    "CHAOS: Server {} did not report its status: {}",
+                    server.id, status
+                );
+                continue;
+            }
+        };
+        let leader = status.has_leader.then_some(status.leader_id);
+        if last_seen == Some((status.term, leader)) {
+            continue;
+        }
+        last_seen = Some((status.term, leader));
+        match leader {
+            Some(leader_id) => info!(
+                "CHAOS: Server {} sees leader {} in term {}",
+                server.id, leader_id, status.term
+            ),
+            None => info!(
+                "CHAOS: Server {} has no leader in term {}",
+                server.id, status.term
+            ),
+        }
+    }
+}
+
+async fn build_server(root: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    // Run the binary itself rather than through `cargo run`, so signals reach the server and not cargo
+    let status = Command::new("cargo")
+        .args(["build", "--bin", "single_value_store"])
+        .current_dir(root)
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(format!("Building the server failed: {}", status).into());
+    }
+    Ok(root.join("target/debug/single_value_store"))
+}
+
+/// Runs the servers of the cluster and kills, restarts and pauses them on a seeded schedule until interrupted
+pub(crate) async fn run_chaos(
+    root: &Path,
+    config: ClusterConfig,
+    config_path: &Path,
+    args: ChaosArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let max_down = args
+        .max_down
+        .unwrap_or((config.servers.len() - 1) / 2)
+        .min(config.servers.len());
+    let seed = args.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    info!(
+        "CHAOS: Seed {} (rerun with --seed {}), at most {} of {} servers down at once",
+        seed,
+        seed,
+        max_down,
+        config.servers.len()
+    );
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+    let binary = build_server(root).await?;
+    let mut nodes = vec![];
+    for server in config.servers {
+        info!("CHAOS: Starting server {}", server.id);
+        let child = start_server(&binary, &server, config_path)?;
+        tokio::spawn(watch_leader(server.clone()));
+        nodes.push(Node {
+            server,
+            child: Some(child),
+            state: NodeState::Running,
+        });
+    }
+
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+    loop {
+        let delay_ms = rng.gen_range(args.chaos_interval_ms / 2..=args.chaos_interval_ms * 3 / 2);
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(delay_ms)) => {},
+            _ = &mut shutdown => break,
+        }
+        restart_exited(&mut nodes, &binary, config_path)?;
+        resume_paused(&mut nodes)?;
+        if let Some((index, action)) = next_action(&nodes, &args, max_down, &mut rng) {
+            apply_action(&mut nodes[index], action, &binary, config_path).await?;
+        }
+    }
+
+    info!("CHAOS: Received interrupt signal, stopping the servers...");
+    for node in &mut nodes {
+        if let Some(mut child) = node.child.take() {
+            if let Err(e) = child.kill().await {
+                error!("CHAOS: Could not stop server {}: {}", node.server.id, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestArgs {
+        #[command(flatten)]
+        chaos: ChaosArgs,
+    }
+
+    fn chaos_args(pause: bool) -> ChaosArgs {
+        let mut args = vec!["chaos", "--chaos"];
+        if pause {
+            args.push("--pause");
+        }
+        TestArgs::parse_from(args).chaos
+    }
+
+    fn nodes(states: &[NodeState]) -> Vec<Node> {
+        states
+            .iter()
+            .enumerate()
+            .map(|(id, state)| Node {
+                server: ServerConfig {
+                    id: id as u64,
+                    address: ([127, 0, 0, 1], 5000 + id as u16).into(),
+                    data_dir: PathBuf::from(id.to_string()),
+                    metrics_port: None,
+                },
+                child: None,
+                state: *state,
+            })
+            .collect()
+    }
+
+    /// Picks the actions for `steps` intervals and applies them to the nodes' states the way `run_chaos` does, returns
+    /// every action with the number of servers down after it
+    fn schedule(
+        seed: u64,
+        servers: usize,
+        max_down: usize,
+        steps: usize,
+    ) -> Vec<(usize, Action, usize)> {
+        let args = chaos_args(true);
+        let mut cluster = nodes(&vec![NodeState::Running; servers]);
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let mut actions = vec![];
+        for _ in 0..steps {
+            for node in &mut cluster {
+                if node.state == NodeState::Paused {
+                    node.state = NodeState::Running;
+                }
+            }
+            if let Some((index, action)) = next_action(&cluster, &args, max_down, &mut rng) {
+                cluster[index].state = match action {
+                    Action::Kill => NodeState::Killed,
+                    Action::Pause => NodeState::Paused,
+                    Action::Restart => NodeState::Running,
+                };
+                let down = cluster
+                    .iter()
+                    .filter(|node| node.state != NodeState::Running)
+                    .count();
+                actions.push((index, action, down));
+            }
+        }
+        actions
+    }
+
+    #[test]
+    fn should_only_restart_when_max_down_servers_are_down() {
+        use NodeState::*;
+        let args = chaos_args(true);
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let cluster = nodes(&[Killed, Paused, Running, Running, Running]);
+        for _ in 0..100 {
+            assert_eq!(
+                next_action(&cluster, &args, 2, &mut rng),
+                Some((0, Action::Restart))
+            );
+        }
+        // Paused servers are resumed, not restarted
+        let cluster = nodes(&[Paused, Paused, Running]);
+        assert_eq!(next_action(&cluster, &args, 2, &mut rng), None);
+    }
+
+    #[test]
+    fn should_kill_or_pause_running_servers_below_max_down() {
+        use NodeState::*;
+        let cluster = nodes(&[Killed, Running, Running]);
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        let mut picked: Vec<_> = (0..100)
+            .filter_map(|_| next_action(&cluster, &chaos_args(false), 2, &mut rng))
+            .collect();
+        picked.sort_by_key(|(index, action)| (*index, *action as u8));
+        picked.dedup();
+        assert_eq!(
+            picked,
+            [(0, Action::Restart), (1, Action::Kill), (2, Action::Kill)]
+        );
+        assert!((0..100).any(|_| matches!(
+            next_action(&cluster, &chaos_args(true), 2, &mut rng),
+            Some((_, Action::Pause))
+        )));
+    }
+
+    #[test]
+    fn should_never_take_more_than_max_down_servers_down() {
+        for seed in 0..20 {
+            let actions = schedule(seed, 5, 2, 200);
+            assert!(actions.iter().any(|(_, _, down)| *down == 2));
+            assert!(
+                actions.iter().all(|(_, _, down)| *down <= 2),
+                "Seed {}: {:?}",
+                seed,
+                actions
+            );
+        }
+    }
+
+    #[test]
+    fn should_pick_the_same_actions_for_the_same_seed() {
+        assert_eq!(schedule(42, 5, 2, 100), schedule(42, 5, 2, 100));
+        assert_ne!(schedule(42, 5, 2, 100), schedule(43, 5, 2, 100));
+    }
+}
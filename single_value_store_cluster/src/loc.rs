@@ -60,7 +60,7 @@ impl Loc {
         }
     }
 
-    fn join<P: AsRef<Path>>(&self, path: P) -> Self {
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> Self {
         Self(self.as_path().join(path))
     }
 
@@ -3,32 +3,45 @@ extern crate steward;
 #[macro_use]
 extern crate lazy_static;
 
+mod chaos;
 mod loc;
 
+use std::path::{Path, PathBuf};
+
+use chaos::ChaosArgs;
+use clap::Parser;
+use cluster_config::{ClusterConfig, ServerConfig};
 use loc::Loc;
 use steward::{Env, Process, ProcessPool};
 
+/// Runs the servers of a cluster locally
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// TOML file describing the servers to run, relative to the root of the repository
+    #[arg(long, default_value = "cluster.toml")]
+    cluster_config: PathBuf,
+
+    #[command(flatten)]
+    chaos: ChaosArgs,
+}
+
 #[tokio::main]
-async fn main() -> steward::Result<()> {
-    let num_raft_nodes = 5;
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let config_path = Loc::root().join(&args.cluster_config).path().clone();
+    let config =
+        ClusterConfig::load(&config_path).unwrap_or_else(|e| panic!("CLUSTER INIT: {}", e));
 
-    let server_tags = vec![
-        "server-node(0)",
-        "server-node(1)",
-        "server-node(2)",
-        "server-node(3)",
-        "server-node(4)",
-    ];
+    if args.chaos.chaos {
+        tracing_subscriber::fmt().init();
+        return chaos::run_chaos(Loc::root().path(), config, &config_path, args.chaos).await;
+    }
 
-    let processes = (0..4)
-        .map(|i| {
-            raft_node(
-                i.try_into().unwrap(),
-                server_tags.get(i).unwrap(),
-                num_raft_nodes,
-                5000,
-            )
-        })
+    let processes = config
+        .servers
+        .iter()
+        .map(|server| raft_node(server, &config_path))
         .collect();
 
     ProcessPool::run(processes).await?;
@@ -36,46 +49,19 @@ async fn main() -> steward::Result<()> {
     Ok(())
 }
 
-fn cluster_members_arg(num_servers: u16, starting_port: u16) -> String {
-    let mut cluster_members = String::new();
-    for i in 0..num_servers {
-        if i > 0 {
-            cluster_members.push_str(",");
-        }
-        cluster_members.push_str(&format!("{},127.0.0.1:{}", i, starting_port + i));
-    }
-    cluster_members
-}
-
-fn raft_node(
-    server_id: u16,
-    server_tag: &'static str,
-    num_servers: u16,
-    starting_port: u16,
-) -> Process<Loc> {
-    let cluster_members = cluster_members_arg(num_servers, starting_port);
+fn raft_node(server: &ServerConfig, config_path: &Path) -> Process<Loc> {
+    // Tags are borrowed for the life of the pool, which runs until the launcher exits
+    let server_tag: &'static str =
+        Box::leak(format!("server-node({})", server.id).into_boxed_str());
     let root_loc = Loc::root();
-    let wal_root_loc = root_loc
-        .as_ref()
-        .join(format!("target/wal-logs/{}", server_id));
-
-    let wal_root_loc_cloned = wal_root_loc.clone();
-    let wal_path = wal_root_loc_cloned.to_str().unwrap();
-
-    std::fs::create_dir_all(wal_root_loc).expect(&format!(
-        "CLUSTER INIT: Could not create wal log dir for server ID {:?}",
-        server_id
-    ));
 
     process! {
       tag: server_tag,
       cmd: cmd! {
         exe: format!(
-            "cargo run --bin single_value_store -- --server-id {server_id} --cluster-members {cluster_members} --port {port} --wal-log-dir {wal_log_dir} --leader-heartbeat-ms 50",
-            server_id = server_id,
-            cluster_members = cluster_members,
-            port = starting_port + server_id,
-            wal_log_dir = wal_path),
+            "cargo run --bin single_value_store -- --server-id {server_id} --cluster-config {cluster_config}",
+            server_id = server.id,
+            cluster_config = config_path.display()),
         env: Env::empty(),
         pwd: root_loc,
         msg: "Running a reloadable server",
@@ -1,4 +1,11 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/single_value_store.proto")?;
+    tonic_build::configure().compile(
+        &[
+            "proto/single_value_store.proto",
+            "proto/kv_store.proto",
+            "proto/lock_service.proto",
+        ],
+        &["proto"],
+    )?;
     Ok(())
 }
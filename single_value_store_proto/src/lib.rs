@@ -1,3 +1,11 @@
 pub mod single_value_store {
     tonic::include_proto!("single_value_store"); // The string specified here must match the proto package name
 }
+
+pub mod kv_store {
+    tonic::include_proto!("kv_store");
+}
+
+pub mod lock_service {
+    tonic::include_proto!("lock_service");
+}